@@ -0,0 +1,281 @@
+use crate::types::{ClosedOrderPolicy, Order, PriceFormat, RoundingMode, SelfTradePrevention, Side, Stock, Symbol, SymbolCase, TradingSchedule};
+use serde::Deserialize;
+use std::io;
+use std::path::Path;
+
+/// ID that orders listed with a stock at startup are placed under. Connections are numbered from 1, so these orders
+/// never belong to a client.
+pub const STARTING_ORDER_CREATOR_ID: usize = 0;
+
+/// Who is told about each trade.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FillRouting {
+    /// Only the buyer and seller are sent the fill.
+    #[default]
+    Counterparties,
+    /// The buyer and seller are sent the fill, and connections subscribed to the stock are sent a print of the trade
+    /// without user IDs.
+    Tape,
+}
+
+impl TryFrom<&str> for FillRouting {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, ()> {
+        match s {
+            "counterparties" => Ok(FillRouting::Counterparties),
+            "tape" => Ok(FillRouting::Tape),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for FillRouting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FillRouting::Counterparties => write!(f, "counterparties"),
+            FillRouting::Tape => write!(f, "tape"),
+        }
+    }
+}
+
+/// A stock listed at startup, as read from a stocks file.
+#[derive(Debug, Deserialize)]
+pub struct StockConfig {
+    pub symbol: String,
+    pub name: String,
+    /// Orders resting in the stock's book when the market opens.
+    #[serde(default)]
+    pub orders: Vec<StartingOrder>,
+}
+
+/// An order resting in a stock's book at startup.
+#[derive(Debug, Deserialize)]
+pub struct StartingOrder {
+    pub side: Side,
+    pub price: f64,
+    pub quantity: usize,
+}
+
+impl StockConfig {
+    /// Reads a JSON array of stocks from a file.
+    ///
+    /// A file that isn't a valid list of stocks is reported as `InvalidData`.
+    pub fn load_all(path: impl AsRef<Path>) -> io::Result<Vec<Self>> {
+        let json = std::fs::read_to_string(path)?;
+        serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Creates the stock, trading in the given tick size, with its starting orders, failing with the reason if the symbol
+    /// or an order is invalid. Starting orders must be priced on the tick grid, like any other order.
+    pub fn build(self, tick_size: f64) -> Result<(Symbol, Stock), String> {
+        let symbol = Symbol::try_from(self.symbol.as_str()).map_err(|()| format!("invalid symbol {:?}", self.symbol))?;
+        let mut stock = Stock::new(&self.name);
+        stock.set_tick_size(tick_size);
+        for order in self.orders {
+            if !Order::is_price_representable(order.price) || order.quantity == 0 {
+                return Err(format!("invalid starting order for {}", symbol));
+            }
+            if !stock.is_on_tick(order.price) {
+                return Err(format!("starting order for {} at {} is off the {} tick", symbol, order.price, tick_size));
+            }
+            let starting = Order::new(STARTING_ORDER_CREATOR_ID, order.price, order.quantity);
+            match order.side {
+                Side::Buy => stock.add_buy_order(starting),
+                Side::Sell => stock.add_sell_order(starting),
+            }
+        }
+        Ok((symbol, stock))
+    }
+}
+
+/// Runtime configuration for the server, filled in from command-line arguments.
+pub struct Config {
+    /// Address the server listens on for client connections.
+    pub listener_address: String,
+    /// Address the server listens on for admin connections, if any.
+    ///
+    /// Only connections accepted here can issue privileged queries, like listing stocks and halting trading.
+    pub admin_address: Option<String>,
+    /// Maximum number of pending connections the listener queues before they are accepted.
+    pub backlog: u32,
+    /// How often, in milliseconds, the market loop ticks to match orders and handle queries.
+    pub tick_interval_ms: u64,
+    /// Token a connection must present to gain admin privileges.
+    ///
+    /// Admin queries are refused for everyone if this is not set.
+    pub admin_token: Option<String>,
+    /// Orders worth more than this are still accepted, but the response carries a warning.
+    pub warn_notional: Option<f64>,
+    /// Orders worth less than this are rejected.
+    pub min_notional: f64,
+    /// Orders priced above this are rejected.
+    pub max_price: Option<f64>,
+    /// Limit orders must be priced at a whole number of these.
+    pub tick_size: f64,
+    /// Limit orders priced more than this percentage away from the stock's last trade price are rejected. Stocks that
+    /// haven't traded this session accept any price.
+    pub price_band_pct: Option<f64>,
+    /// Orders for more than this many shares are rejected.
+    pub max_quantity: Option<usize>,
+    /// How often, in seconds, the session resets and clears each stock's OHLC.
+    pub session_secs: Option<u64>,
+    /// How long each candle lasts, in seconds.
+    pub candle_secs: u64,
+    /// Maximum number of stocks the market will list at runtime.
+    pub max_stocks: Option<usize>,
+    /// How the case of symbols from clients is treated.
+    pub symbol_case: SymbolCase,
+    /// How amounts of money that fall between two cents are rounded.
+    pub rounding: RoundingMode,
+    /// Resting orders with less than this quantity left are skipped by incoming orders instead of being matched.
+    ///
+    /// This cuts down on tiny fills, but dust orders can be left resting in the book indefinitely.
+    pub min_fill_qty: usize,
+    /// What happens when two of a user's orders would trade with each other. They never do.
+    pub self_trade: SelfTradePrevention,
+    /// Minimum time, in milliseconds, a user must wait after posting an order before posting another.
+    pub order_cooldown_ms: Option<u64>,
+    /// Daily hours, in UTC, during which orders are matched. The market is always open if this isn't set.
+    pub schedule: Option<TradingSchedule>,
+    /// What happens to orders that arrive outside trading hours.
+    pub closed_order_policy: ClosedOrderPolicy,
+    /// Resting orders older than this many seconds are cancelled on the next tick, and their creators told they expired.
+    ///
+    /// This applies to every order in the book, as orders can't set a lifetime of their own.
+    pub max_resting_age_secs: Option<u64>,
+    /// Maximum number of trades each stock makes per tick. Crossing orders beyond the cap are matched on later ticks.
+    pub max_matches_per_tick: Option<usize>,
+    /// How prices are written in responses to clients.
+    pub price_format: PriceFormat,
+    /// Who is told about each trade.
+    pub fill_routing: FillRouting,
+    /// Ticks after a trade before sale proceeds can be spent and bought shares sold. 0 settles immediately.
+    pub settle_ticks: u64,
+    /// Fee, in basis points of the notional, charged to the aggressor of each trade.
+    pub taker_fee_bps: u32,
+    /// Rebate, in basis points of the notional, paid to the resting side of each trade.
+    pub maker_rebate_bps: u32,
+    /// Cash each user starts with. Buy orders are checked against users' cash, so nobody spends more than they have.
+    pub starting_cash: f64,
+    /// Shares of each stock each user starts with. Sell orders are checked against users' holdings, so nobody sells
+    /// short.
+    pub starting_shares: usize,
+    /// Shares of particular stocks each user starts with, overriding `starting_shares` for those stocks.
+    pub starting_positions: Vec<(String, usize)>,
+    /// A write to a client that takes longer than this many milliseconds marks the connection as dead, and its orders
+    /// are cancelled. Writes can otherwise block forever on a client that has stopped reading.
+    pub write_timeout_ms: Option<u64>,
+    /// Whether a connection's resting orders stay in the book after it disconnects or is dropped. By default they are
+    /// cancelled, so that a crashed client doesn't leave stale liquidity behind.
+    pub keep_orders_on_disconnect: bool,
+    /// Maximum number of queries each connection can send per second. Queries over the limit are answered with an error
+    /// and never reach the market.
+    pub max_queries_per_sec: u32,
+    /// Maximum number of resting orders a book query returns, across both sides.
+    pub max_book_orders: usize,
+    /// Maximum number of clients connected at once. Connections beyond it are refused. Unlimited if not set.
+    pub max_connections: Option<usize>,
+    /// File the market's stocks, resting orders and users are saved to on shutdown, and restored from on startup.
+    pub snapshot_path: Option<String>,
+    /// File listing the stocks, and any orders resting in them, the market starts with. Only `V` is listed if not set.
+    pub stocks_path: Option<String>,
+    /// File every executed trade is appended to, one line of JSON each.
+    pub trade_log_path: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listener_address: String::from("127.0.0.1:8080"),
+            admin_address: None,
+            backlog: 1024,
+            tick_interval_ms: 10,
+            admin_token: None,
+            warn_notional: None,
+            min_notional: 0.0,
+            max_price: None,
+            tick_size: 0.01,
+            price_band_pct: None,
+            max_quantity: None,
+            session_secs: None,
+            candle_secs: 60,
+            max_stocks: None,
+            symbol_case: SymbolCase::default(),
+            rounding: RoundingMode::default(),
+            min_fill_qty: 0,
+            self_trade: SelfTradePrevention::default(),
+            order_cooldown_ms: None,
+            schedule: None,
+            closed_order_policy: ClosedOrderPolicy::default(),
+            max_resting_age_secs: None,
+            max_matches_per_tick: None,
+            price_format: PriceFormat::default(),
+            fill_routing: FillRouting::default(),
+            settle_ticks: 0,
+            taker_fee_bps: 0,
+            maker_rebate_bps: 0,
+            starting_cash: 100_000.0,
+            starting_shares: 0,
+            starting_positions: Vec::new(),
+            write_timeout_ms: None,
+            keep_orders_on_disconnect: false,
+            max_queries_per_sec: 1000,
+            max_book_orders: 500,
+            max_connections: None,
+            snapshot_path: None,
+            stocks_path: None,
+            trade_log_path: None,
+        }
+    }
+}
+
+impl Config {
+    /// Returns the effective configuration as JSON, for admins diagnosing rejected orders.
+    ///
+    /// The admin token is never included, only whether one is set.
+    pub fn snapshot(&self) -> serde_json::Value {
+        serde_json::json!({
+            "listener_address": self.listener_address,
+            "admin_address": self.admin_address,
+            "backlog": self.backlog,
+            "tick_interval_ms": self.tick_interval_ms,
+            "admin_token": self.admin_token.as_ref().map(|_| "<redacted>"),
+            "warn_notional": self.warn_notional,
+            "min_notional": self.min_notional,
+            "max_price": self.max_price,
+            "tick_size": self.tick_size,
+            "price_band_pct": self.price_band_pct,
+            "max_quantity": self.max_quantity,
+            "session_secs": self.session_secs,
+            "candle_secs": self.candle_secs,
+            "max_stocks": self.max_stocks,
+            "symbol_case": self.symbol_case.to_string(),
+            "rounding": self.rounding.to_string(),
+            "min_fill_qty": self.min_fill_qty,
+            "self_trade": self.self_trade.to_string(),
+            "order_cooldown_ms": self.order_cooldown_ms,
+            "open": self.schedule.map(|schedule| schedule.get_open().format("%H:%M").to_string()),
+            "close": self.schedule.map(|schedule| schedule.get_close().format("%H:%M").to_string()),
+            "closed_order_policy": self.closed_order_policy.to_string(),
+            "max_resting_age_secs": self.max_resting_age_secs,
+            "max_matches_per_tick": self.max_matches_per_tick,
+            "price_as_string": self.price_format == PriceFormat::String,
+            "fill_routing": self.fill_routing.to_string(),
+            "settle_ticks": self.settle_ticks,
+            "taker_fee_bps": self.taker_fee_bps,
+            "maker_rebate_bps": self.maker_rebate_bps,
+            "starting_cash": self.starting_cash,
+            "starting_shares": self.starting_shares,
+            "starting_positions": self.starting_positions,
+            "write_timeout_ms": self.write_timeout_ms,
+            "keep_orders_on_disconnect": self.keep_orders_on_disconnect,
+            "max_queries_per_sec": self.max_queries_per_sec,
+            "max_book_orders": self.max_book_orders,
+            "max_connections": self.max_connections,
+            "snapshot_path": self.snapshot_path,
+            "stocks_path": self.stocks_path,
+            "trade_log_path": self.trade_log_path,
+        })
+    }
+}
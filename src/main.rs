@@ -1,348 +1,3388 @@
+mod config;
+mod rate_limit;
+mod trade_log;
 mod types;
 
-use std::collections::HashMap;
+use config::{Config, FillRouting, StockConfig};
+use rate_limit::RateLimiter;
+use trade_log::TradeLog;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, watch};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{broadcast, mpsc, watch};
 use tokio::sync::mpsc::error::SendError;
 use tokio::{select, signal, task, time};
-use types::{Market, Query, QueryResponse, Stock, Symbol};
+use tracing::{error, info, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+use types::{is_valid_tick, money, AddStockError, AdminQuery, ClosedOrderPolicy, ErrorCode, Market, MarketSnapshot, MAX_FRAME_BYTES, Order, OrderKind, OrderTag, PriceFormat, Query, QueryParseError, QueryResponse, QUERY_SCHEMA, RejectReason, Rejection, Resumed, RoundingMode, SelfTradePrevention, Side, Stock, Symbol, SymbolCase, Trade, TradingSchedule};
 
-const TICK_INTERVAL_MILLISECS: u64 = 10;
-const MARKET_OUTPUT_COLOUR: Color = Color::Yellow;
+/// Bytes read from a socket at a time. Longer queries are collected over several reads.
+const READ_BUFFER_BYTES: usize = 4096;
+/// Number of trade prints kept for subscribers that fall behind. A subscriber further behind skips the prints it missed.
+const TAPE_CAPACITY: usize = 1024;
 
 #[tokio::main]
 async fn main() {
-    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+    // Logs are filtered by `RUST_LOG`, and everything at info level or above is shown by default.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_ansi(false)
+        .init();
 
     let (server_tx, mut market_rx) = mpsc::channel::<(usize, Query)>(32);
+    let (tape_tx, _) = broadcast::channel(TAPE_CAPACITY);
+    let (market_shutdown_tx, mut market_shutdown_rx) = watch::channel(false);
+
+    let config = parse_args(env::args());
 
     let mut market = Market::new();
-    let initial_stocks = vec![(Symbol::try_from("V").expect("`V` should be a valid symbol"), Stock::new("Vulyenne"))];
-    market.extend_stocks(initial_stocks.into_iter());
+    match config.snapshot_path.as_ref().map(MarketSnapshot::load) {
+        Some(Ok(snapshot)) => {
+            market.restore(snapshot);
+            info!("Restored the market from {}.", config.snapshot_path.as_deref().unwrap_or_default());
+            // Restored orders are priced on the stored tick grid, so it wins over the configured one.
+            for (symbol, _) in market.list_stocks() {
+                let stock = Symbol::try_from(symbol.as_str()).ok().and_then(|symbol| market.get_stock(&symbol));
+                let tick_size = stock.map(|stock| stock.get_symbol_info().tick_size);
+                if let Some(tick_size) = tick_size.filter(|&tick_size| tick_size != config.tick_size) {
+                    warn!("{} keeps its tick size of {} from the snapshot instead of the configured {}.", symbol, tick_size, config.tick_size);
+                }
+            }
+        }
+        loaded => {
+            if let Some(Err(e)) = loaded {
+                error!("Couldn't load snapshot: {}, starting fresh.", e);
+            }
+            market.extend_stocks(initial_stocks(&config));
+        }
+    }
 
-    let mut tick_interval = time::interval(time::Duration::from_millis(TICK_INTERVAL_MILLISECS));
+    let mut tick_interval = time::interval(time::Duration::from_millis(config.tick_interval_ms));
     tick_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
 
-    let mut cmd_args = env::args();
-    let mut listener_address = String::from("127.0.0.1:8080");
+    configure_market(&mut market, &config);
 
-    while let Some(arg) = cmd_args.next() {
-        if arg == "-p" {
-            if let Some(url) = cmd_args.next() {
-                listener_address = url;
-            }
+    let mut trade_log = config.trade_log_path.as_ref().and_then(|path| match TradeLog::open(path) {
+        Ok(trade_log) => Some(trade_log),
+        Err(e) => {
+            error!("Couldn't open trade log {}: {}, trades won't be logged.", path, e);
+            None
         }
-    }
+    });
 
     // a unique ID is mapped to each connection
-    let mut connections: HashMap<usize, mpsc::Sender<QueryResponse>> = HashMap::new();
-    market_speak(format!("Starting server at {}. Press Ctrl+C to shut down.", &listener_address), &mut stdout, false);
-    let server = task::spawn(serve(server_tx, listener_address));
+    let mut connections: HashMap<usize, Connection> = HashMap::new();
+    info!("Starting server at {}. Press Ctrl+C to shut down.", &config.listener_address);
+    if let Some(admin_address) = &config.admin_address {
+        info!("Accepting admin connections at {}.", admin_address);
+    }
+    let server = task::spawn(serve(
+        server_tx,
+        tape_tx.clone(),
+        ListenerOptions { first_connection_id: market.get_first_free_user_id(), ..ListenerOptions::from(&config) },
+        ConnectionOptions::from(&config),
+        market_shutdown_tx,
+    ));
 
     'market_loop: loop {
-        tick_interval.tick().await;
+        // Once the server stops accepting queries, one last tick resolves whatever crosses before the market closes.
+        // If the server has already gone, there's nothing left to wait for either.
+        let closing = select! {
+            _ = tick_interval.tick() => false,
+            _ = market_shutdown_rx.changed() => true,
+        };
+        market.start_tick();
+        let now = market.now();
+        market.update_candles(now);
+        if market.roll_session(now) {
+            info!("Market says> New session started.");
+        }
+        if let Some(max_age) = config.max_resting_age_secs.and_then(|secs| chrono::Duration::try_seconds(secs.try_into().ok()?)) {
+            expire_orders(&mut market, &connections, max_age).await;
+        }
+
         loop {
-            let executed_trades = market.resolve();
-
-            for (symbol, trades) in executed_trades.into_iter() {
-                for trade in trades.into_iter() {
-                    market_speak(
-                        format!("Market says> Trade executed for {}: {:#?}", symbol, &trade),
-                        &mut stdout,
-                        false,
-                    );
-
-                    if let Some(buyer_tx) = connections.get(&trade.buyer_id) {
-                        if let Err(e) = buyer_tx.send(QueryResponse::ExecutedTrade(trade)).await {
-                            market_speak(
-                                format!("Error while sending trade to buyer: {:#?}", e),
-                                &mut stdout,
-                                true,
-                            );
-                        }
-                    } else {
-                        market_speak(
-                            format!("Buyer with id {} not connected.", trade.buyer_id),
-                            &mut stdout,
-                            true,
-                        );
-                    }
-
-                    if let Some(seller_tx) = connections.get(&trade.seller_id) {
-                        if let Err(e) = seller_tx.send(QueryResponse::ExecutedTrade(trade)).await {
-                            market_speak(
-                                format!("Error while sending trade to seller: {:#?}", e),
-                                &mut stdout,
-                                true,
-                            );
-                        }
-                    } else {
-                        market_speak(
-                            format!("Seller with id {} not connected.", trade.seller_id),
-                            &mut stdout,
-                            true,
-                        );
-                    }
-                }
-            }
+            resolve_trades(&mut market, &connections, &config, &tape_tx, &mut trade_log).await;
 
             match market_rx.try_recv() {
                 Ok((id, query)) => {
-                    let status = resolve_query(id, query, &mut connections, &mut market, &mut stdout).await;
+                    let status = resolve_query(id, query, &mut connections, &mut market, &config)
+                        .instrument(tracing::info_span!("connection", id))
+                        .await;
                     if let Err(e) = status {
-                        market_speak(format!("Error: {:#?}", e), &mut stdout, true);
+                        error!("Error: {:#?}", e);
                     }
                 }
                 Err(mpsc::error::TryRecvError::Empty) => {
                     break;
                 }
                 Err(mpsc::error::TryRecvError::Disconnected) => {
-                    market_speak("Server disconnected, market shutting down.".to_string(), &mut stdout, false);
+                    info!("Server disconnected, market shutting down.");
                     break 'market_loop;
                 }
             }
         }
+
+        if closing {
+            info!("Server shutting down, market closed after resolving the last orders.");
+            break;
+        }
+    }
+    // Lets the server know every last trade has been handed to its connections, so they can be closed. Queries sent
+    // after this fail instead of waiting on a market that's no longer listening.
+    drop(market_rx);
+    drop(market_shutdown_rx);
+
+    if let Some(path) = &config.snapshot_path {
+        match market.snapshot().save(path) {
+            Ok(()) => info!("Saved the market to {}.", path),
+            Err(e) => error!("Couldn't save snapshot to {}: {}", path, e),
+        }
     }
 
     if let Err(server_status) = server.await {
-        eprintln!("Error: {:#?}", server_status);
+        error!("Error: {:#?}", server_status);
     } else {
-        println!("Bbye!");
+        info!("Bbye!");
+    }
+}
+
+/// The market's handle on a client connection.
+struct Connection {
+    /// Sends responses to the connection's socket.
+    tx: mpsc::Sender<QueryResponse>,
+    /// Whether the connection came in on the admin port or logged in with the admin token, so can issue `AdminQuery`s.
+    admin: bool,
+}
+
+/// Applies the configured trading rules and user funding to the market. Starting positions in invalid symbols are
+/// skipped with a warning.
+fn configure_market(market: &mut Market, config: &Config) {
+    market.set_max_stocks(config.max_stocks);
+    market.set_schedule(config.schedule);
+    market.set_symbol_case(config.symbol_case);
+    market.set_min_fill_qty(config.min_fill_qty);
+    market.set_self_trade_prevention(config.self_trade);
+    market.set_max_matches_per_tick(config.max_matches_per_tick);
+    market.set_settle_ticks(config.settle_ticks);
+    market.set_rounding(config.rounding);
+    market.set_fees(config.taker_fee_bps, config.maker_rebate_bps);
+    market.set_starting_cash_cents(money::to_cents(config.starting_cash, config.rounding));
+    market.set_starting_shares(config.starting_shares);
+    let mut starting_positions = HashMap::new();
+    for (symbol, shares) in &config.starting_positions {
+        match market.parse_symbol(symbol) {
+            Ok(symbol) => {
+                starting_positions.insert(symbol, *shares);
+            }
+            Err(()) => warn!("Skipping starting position in invalid symbol {:?}.", symbol),
+        }
+    }
+    market.set_starting_positions(starting_positions);
+    market.set_candle_interval(chrono::Duration::seconds(config.candle_secs as i64));
+    market.set_session_reset_interval(config.session_secs.map(|secs| chrono::Duration::seconds(secs as i64)));
+}
+
+/// Returns the stocks the market starts with: those in the stocks file if one is configured and can be read, otherwise
+/// just `V`. Stocks in the file that are invalid are skipped with a warning.
+fn initial_stocks(config: &Config) -> Vec<(Symbol, Stock)> {
+    if let Some(path) = &config.stocks_path {
+        match StockConfig::load_all(path) {
+            Ok(stocks) => {
+                return stocks
+                    .into_iter()
+                    .filter_map(|stock| match stock.build(config.tick_size) {
+                        Ok(stock) => Some(stock),
+                        Err(reason) => {
+                            warn!("Skipping stock in {}: {}.", path, reason);
+                            None
+                        }
+                    })
+                    .collect();
+            }
+            Err(e) => warn!("Couldn't load stocks from {}: {}, listing the default stock.", path, e),
+        }
+    }
+
+    vec![(Symbol::try_from("V").expect("`V` should be a valid symbol"), new_stock("Vulyenne", config))]
+}
+
+/// Creates a stock to list, trading in the configured tick size.
+fn new_stock(name: &str, config: &Config) -> Stock {
+    let mut stock = Stock::new(name);
+    stock.set_tick_size(config.tick_size);
+    stock
+}
+
+/// Builds the configuration from command-line arguments, warning about and skipping any invalid values.
+fn parse_args(mut cmd_args: impl Iterator<Item = String>) -> Config {
+    let mut config = Config::default();
+    let (mut open, mut close) = (None, None);
+
+    while let Some(arg) = cmd_args.next() {
+        if arg == "-p" {
+            if let Some(url) = cmd_args.next() {
+                config.listener_address = url;
+            }
+        } else if arg == "-a" || arg == "--admin-address" {
+            config.admin_address = cmd_args.next();
+        } else if arg == "-t" {
+            match cmd_args.next().and_then(|millis| millis.parse().ok()).filter(|millis| *millis > 0) {
+                Some(millis) => config.tick_interval_ms = millis,
+                None => warn!("Invalid tick interval, using {}ms.", config.tick_interval_ms),
+            }
+        } else if arg == "--backlog" {
+            match cmd_args.next().and_then(|backlog| backlog.parse().ok()) {
+                Some(backlog) => config.backlog = backlog,
+                None => warn!("Invalid backlog, using {}.", config.backlog),
+            }
+        } else if arg == "--admin-token" {
+            config.admin_token = cmd_args.next();
+        } else if arg == "--warn-notional" {
+            config.warn_notional = cmd_args.next().and_then(|amount| amount.parse().ok());
+        } else if arg == "--min-notional" {
+            match cmd_args.next().and_then(|amount| amount.parse().ok()) {
+                Some(amount) => config.min_notional = amount,
+                None => warn!("Invalid minimum notional, using {}.", config.min_notional),
+            }
+        } else if arg == "--max-price" {
+            config.max_price = cmd_args.next().and_then(|price| price.parse().ok());
+        } else if arg == "--price-band" {
+            config.price_band_pct = cmd_args.next().and_then(|pct| pct.parse().ok());
+        } else if arg == "--tick-size" {
+            match cmd_args.next().and_then(|tick| tick.parse().ok()) {
+                Some(tick) if tick >= 0.01 && is_valid_tick(tick, 0.01) => config.tick_size = tick,
+                _ => warn!("Invalid tick size, using {}.", config.tick_size),
+            }
+        } else if arg == "--max-quantity" {
+            config.max_quantity = cmd_args.next().and_then(|quantity| quantity.parse().ok());
+        } else if arg == "--session-secs" {
+            config.session_secs = cmd_args.next().and_then(|secs| secs.parse().ok());
+        } else if arg == "--candle-secs" {
+            match cmd_args.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) => config.candle_secs = secs,
+                None => warn!("Invalid candle length, using {}s.", config.candle_secs),
+            }
+        } else if arg == "--max-stocks" {
+            config.max_stocks = cmd_args.next().and_then(|max_stocks| max_stocks.parse().ok());
+        } else if arg == "--symbol-case" {
+            match cmd_args.next().map(|policy| SymbolCase::try_from(policy.as_str())) {
+                Some(Ok(policy)) => config.symbol_case = policy,
+                _ => warn!("Invalid symbol case policy, using {}.", config.symbol_case),
+            }
+        } else if arg == "--rounding" {
+            match cmd_args.next().map(|mode| RoundingMode::try_from(mode.as_str())) {
+                Some(Ok(mode)) => config.rounding = mode,
+                _ => warn!("Invalid rounding mode, using {}.", config.rounding),
+            }
+        } else if arg == "--min-fill-qty" {
+            match cmd_args.next().and_then(|qty| qty.parse().ok()) {
+                Some(qty) => config.min_fill_qty = qty,
+                None => warn!("Invalid minimum fill quantity, using {}.", config.min_fill_qty),
+            }
+        } else if arg == "--self-trade" {
+            match cmd_args.next().map(|policy| SelfTradePrevention::try_from(policy.as_str())) {
+                Some(Ok(policy)) => config.self_trade = policy,
+                _ => warn!("Invalid self-trade prevention, using {}.", config.self_trade),
+            }
+        } else if arg == "--max-resting-age-secs" {
+            config.max_resting_age_secs = cmd_args.next().and_then(|secs| secs.parse().ok());
+        } else if arg == "--max-matches-per-tick" {
+            config.max_matches_per_tick = cmd_args.next().and_then(|max_matches| max_matches.parse().ok());
+        } else if arg == "--fill-routing" {
+            match cmd_args.next().map(|routing| FillRouting::try_from(routing.as_str())) {
+                Some(Ok(routing)) => config.fill_routing = routing,
+                _ => warn!("Invalid fill routing, using {}.", config.fill_routing),
+            }
+        } else if arg == "--settle-ticks" {
+            match cmd_args.next().and_then(|ticks| ticks.parse().ok()) {
+                Some(ticks) => config.settle_ticks = ticks,
+                None => warn!("Invalid settlement delay, using {} ticks.", config.settle_ticks),
+            }
+        } else if arg == "-fee" || arg == "--taker-fee" {
+            match cmd_args.next().and_then(|bps| bps.parse().ok()) {
+                Some(bps) => config.taker_fee_bps = bps,
+                None => warn!("Invalid taker fee, using {}bps.", config.taker_fee_bps),
+            }
+        } else if arg == "--maker-rebate" {
+            match cmd_args.next().and_then(|bps| bps.parse().ok()) {
+                Some(bps) => config.maker_rebate_bps = bps,
+                None => warn!("Invalid maker rebate, using {}bps.", config.maker_rebate_bps),
+            }
+        } else if arg == "--starting-cash" || arg == "-b" {
+            match cmd_args.next().and_then(|cash| cash.parse().ok()).filter(|cash: &f64| cash.is_finite() && *cash >= 0.0) {
+                Some(cash) => config.starting_cash = cash,
+                None => warn!("Invalid starting cash, using {}.", config.starting_cash),
+            }
+        } else if arg == "--starting-shares" {
+            match cmd_args.next().and_then(|shares| shares.parse().ok()) {
+                Some(shares) => config.starting_shares = shares,
+                None => warn!("Invalid starting shares, using {}.", config.starting_shares),
+            }
+        } else if arg == "--starting-position" {
+            // Given as `SYMBOL:SHARES`, once per stock.
+            let position = cmd_args.next().and_then(|position| {
+                let (symbol, shares) = position.split_once(':')?;
+                Some((symbol.to_string(), shares.parse().ok()?))
+            });
+            match position {
+                Some(position) => config.starting_positions.push(position),
+                None => warn!("Invalid starting position, expected SYMBOL:SHARES."),
+            }
+        } else if arg == "-l" {
+            config.trade_log_path = cmd_args.next();
+        } else if arg == "--max-book-orders" {
+            match cmd_args.next().and_then(|max| max.parse().ok()) {
+                Some(max) => config.max_book_orders = max,
+                None => warn!("Invalid book order limit, using {}.", config.max_book_orders),
+            }
+        } else if arg == "-c" {
+            config.max_connections = cmd_args.next().and_then(|max_connections| max_connections.parse().ok());
+        } else if arg == "-r" {
+            match cmd_args.next().and_then(|rate| rate.parse().ok()).filter(|rate| *rate > 0) {
+                Some(rate) => config.max_queries_per_sec = rate,
+                None => warn!("Invalid rate limit, using {} queries per second.", config.max_queries_per_sec),
+            }
+        } else if arg == "-f" {
+            config.stocks_path = cmd_args.next();
+        } else if arg == "-s" {
+            config.snapshot_path = cmd_args.next();
+        } else if arg == "--write-timeout-ms" {
+            config.write_timeout_ms = cmd_args.next().and_then(|timeout| timeout.parse().ok());
+        } else if arg == "--keep-orders-on-disconnect" {
+            config.keep_orders_on_disconnect = true;
+        } else if arg == "--price-as-string" {
+            config.price_format = PriceFormat::String;
+        } else if arg == "--order-cooldown-ms" {
+            config.order_cooldown_ms = cmd_args.next().and_then(|cooldown| cooldown.parse().ok());
+        } else if arg == "--open" {
+            match cmd_args.next().and_then(|time| chrono::NaiveTime::parse_from_str(&time, "%H:%M").ok()) {
+                Some(time) => open = Some(time),
+                None => warn!("Invalid opening time, expected HH:MM."),
+            }
+        } else if arg == "--close" {
+            match cmd_args.next().and_then(|time| chrono::NaiveTime::parse_from_str(&time, "%H:%M").ok()) {
+                Some(time) => close = Some(time),
+                None => warn!("Invalid closing time, expected HH:MM."),
+            }
+        } else if arg == "--closed-orders" {
+            match cmd_args.next().map(|policy| ClosedOrderPolicy::try_from(policy.as_str())) {
+                Some(Ok(policy)) => config.closed_order_policy = policy,
+                _ => warn!("Invalid closed order policy, using {}.", config.closed_order_policy),
+            }
+        }
+    }
+    match (open, close) {
+        (Some(open), Some(close)) => config.schedule = Some(TradingSchedule::new(open, close)),
+        (None, None) => {}
+        _ => warn!("Both --open and --close are needed for a trading schedule, trading all day."),
     }
+    config
 }
 
-async fn resolve_query(id: usize, query: Query, connections: &mut HashMap<usize, mpsc::Sender<QueryResponse>>, market: &mut Market, stdout: &mut StandardStream) -> Result<(), SendError<QueryResponse>> {
+async fn resolve_query(id: usize, query: Query, connections: &mut HashMap<usize, Connection>, market: &mut Market, config: &Config) -> Result<(), SendError<QueryResponse>> {
+    let (query, req_id) = query.split_req_id();
+
     // If there is a new connection, add it, otherwise check if the ID exists first.
-    let socket_tx = match query {
-        Query::Connect(socket_tx) => {
-            connections.insert(id, socket_tx);
+    let admin_port = matches!(query, Query::AdminConnect(_));
+    let (tx, admin) = match query {
+        Query::Connect(socket_tx) | Query::AdminConnect(socket_tx) => {
+            connections.insert(id, Connection { tx: socket_tx, admin: admin_port });
             let t = connections.get(&id).expect("This key was just added, it must exist.");
-            t.send(QueryResponse::Connected).await?;
+            t.tx.send(QueryResponse::Connected).await?;
             return Ok(());
         }
         _ => {
             match connections.get(&id) {
-                Some(socket_tx) => socket_tx,
+                Some(connection) => (connection.tx.clone(), connection.admin),
                 None => {
-                    market_speak(format!("Query from unknown id {}.", id), stdout, true);
+                    error!("Query from unknown id {}.", id);
                     return Ok(());
                 }
             }
         }
     };
+    let socket_tx = Replier { tx, req_id };
 
     match query {
-        Query::Connect(_) => {
+        Query::Connect(_) | Query::AdminConnect(_) => {
             unreachable!("Connection should already have been handled.");
         }
+        Query::Numbered(..) => {
+            unreachable!("The number should already have been split off.");
+        }
+        Query::ConnectionDead => {
+            let cancelled = drop_connection(id, connections, market, config);
+            error!("Connection {} stopped reading, cancelled {} orders.", id, cancelled);
+        }
+        Query::Disconnect => {
+            let cancelled = drop_connection(id, connections, market, config);
+            info!("Connection {} disconnected, cancelled {} orders.", id, cancelled);
+        }
         Query::Buy(symbol, order) => {
-            if let Ok(symbol) = Symbol::try_from(&symbol) {
+            let now = market.now();
+            let summary = format!("buy {} {} @ {:.2}", symbol, order.get_quantity(), order.get_price());
+            if let Some(reason) = order_entry_rejection(market, id, config, now) {
+                return reject(market, id, &socket_tx, reason, summary).await;
+            }
+
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                let affordable = market.can_afford(id, symbol, &order);
                 if let Some(stock) = market.get_stock_mut(&symbol) {
-                    stock.add_buy_order(order);
-                    socket_tx.send(QueryResponse::OrderPosted).await?;
+                    if stock.is_halted() {
+                        return reject(market, id, &socket_tx, RejectReason::TradingHalted, summary).await;
+                    }
+                    if let Err(reason) = validate_order(&order, stock, config) {
+                        return reject(market, id, &socket_tx, RejectReason::InvalidOrder(reason), summary).await;
+                    }
+                    if outside_price_band(&order, stock, config) {
+                        return reject(market, id, &socket_tx, RejectReason::PriceOutOfBand, summary).await;
+                    }
+                    if !affordable {
+                        return reject(market, id, &socket_tx, RejectReason::InsufficientFunds, summary).await;
+                    }
+
+                    let warning = order_warning(&order, config);
+                    let tag = order.get_tag();
+                    let order_id = market.add_order(symbol, Side::Buy, order).expect("The stock should still be listed.");
+                    market.get_user_mut(id).record_order_placed(now);
+                    socket_tx.send(posted_response(market, order_id, tag, warning)).await?;
                 } else {
-                    socket_tx.send(QueryResponse::SymbolNotFound).await?;
+                    reject(market, id, &socket_tx, RejectReason::SymbolNotFound, summary).await?;
                 }
             } else {
-                socket_tx.send(QueryResponse::SymbolInvalid).await?;
+                reject(market, id, &socket_tx, RejectReason::SymbolInvalid, summary).await?;
             }
         }
         Query::Sell(symbol, order) => {
-            if let Ok(symbol) = Symbol::try_from(&symbol) {
+            let now = market.now();
+            let summary = format!("sell {} {} @ {:.2}", symbol, order.get_quantity(), order.get_price());
+            if let Some(reason) = order_entry_rejection(market, id, config, now) {
+                return reject(market, id, &socket_tx, reason, summary).await;
+            }
+
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                let deliverable = market.can_deliver(id, symbol, &order);
                 if let Some(stock) = market.get_stock_mut(&symbol) {
-                    stock.add_sell_order(order);
-                    socket_tx.send(QueryResponse::OrderPosted).await?;
+                    if stock.is_halted() {
+                        return reject(market, id, &socket_tx, RejectReason::TradingHalted, summary).await;
+                    }
+                    if let Err(reason) = validate_order(&order, stock, config) {
+                        return reject(market, id, &socket_tx, RejectReason::InvalidOrder(reason), summary).await;
+                    }
+                    if outside_price_band(&order, stock, config) {
+                        return reject(market, id, &socket_tx, RejectReason::PriceOutOfBand, summary).await;
+                    }
+                    if !deliverable {
+                        return reject(market, id, &socket_tx, RejectReason::InsufficientShares, summary).await;
+                    }
+
+                    let warning = order_warning(&order, config);
+                    let tag = order.get_tag();
+                    let order_id = market.add_order(symbol, Side::Sell, order).expect("The stock should still be listed.");
+                    market.get_user_mut(id).record_order_placed(now);
+                    socket_tx.send(posted_response(market, order_id, tag, warning)).await?;
                 } else {
-                    socket_tx.send(QueryResponse::SymbolNotFound).await?;
+                    reject(market, id, &socket_tx, RejectReason::SymbolNotFound, summary).await?;
                 }
             } else {
-                socket_tx.send(QueryResponse::SymbolInvalid).await?;
+                reject(market, id, &socket_tx, RejectReason::SymbolInvalid, summary).await?;
             }
         }
         Query::Ohlc(symbol) => {
-            if let Ok(symbol) = Symbol::try_from(&symbol) {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
                 if let Some(stock) = market.get_stock(&symbol) {
                     let (open, high, low, close) = stock.get_ohlc();
-                    socket_tx.send(QueryResponse::Ohlc(open, high, low, close)).await?;
+                    socket_tx.send(QueryResponse::Ohlc { open, high, low, close }).await?;
                 } else {
-                    socket_tx.send(QueryResponse::SymbolNotFound).await?;
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
                 }
             } else {
-                socket_tx.send(QueryResponse::SymbolInvalid).await?;
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
             }
         }
-        Query::BuyOrders(symbol) => {
-            if let Ok(symbol) = Symbol::try_from(&symbol) {
+        Query::Ticker(symbol) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
                 if let Some(stock) = market.get_stock(&symbol) {
-                    socket_tx.send(QueryResponse::QueriedOrders(stock.get_buy_orders())).await?;
+                    let (last, volume) = stock.get_ticker();
+                    socket_tx.send(QueryResponse::Ticker { last, volume }).await?;
                 } else {
-                    socket_tx.send(QueryResponse::SymbolNotFound).await?;
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
                 }
             } else {
-                socket_tx.send(QueryResponse::SymbolInvalid).await?;
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
             }
         }
-        Query::SellOrders(symbol) => {
-            if let Ok(symbol) = Symbol::try_from(&symbol) {
+        Query::Vwap(symbol) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
                 if let Some(stock) = market.get_stock(&symbol) {
-                    socket_tx.send(QueryResponse::QueriedOrders(stock.get_sell_orders())).await?;
+                    socket_tx.send(QueryResponse::Vwap { vwap: stock.get_vwap() }).await?;
                 } else {
-                    socket_tx.send(QueryResponse::SymbolNotFound).await?;
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
                 }
             } else {
-                socket_tx.send(QueryResponse::SymbolInvalid).await?;
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
             }
         }
-    }
-
-    Ok(())
-}
-
-/// Prints a message to the terminal with a different colour for the market.
-///
-/// This colour is defined by `MARKET_OUTPUT_COLOUR`.
-fn market_speak(message: String, stdout: &mut StandardStream, error: bool) {
-    if let Err(e) = stdout.set_color(ColorSpec::new().set_fg(Some(MARKET_OUTPUT_COLOUR))) {
-        println!(
-            "Could not set terminal color({:#?}). The next statement is probably from the Market:",
-            e
-        );
-    }
-
-    if error {
-        eprintln!("{}", message);
-    } else {
-        println!("{}", message);
-    }
-
-    if let Err(e) = stdout.reset() {
-        println!(
-            "Could not set terminal color({:#?}). Market output ends.",
-            e
-        );
-    }
-}
-
-pub async fn serve(tx: mpsc::Sender<(usize, Query)>, listener_address: String) -> Result<(), std::io::Error> {
-    let mut next_id = 1;
-    let mut connection_future_set = task::JoinSet::new();
-    
-    let listener = TcpListener::bind(listener_address).await?;
-
-    let (shutdown_signal_tx, shutdown_signal_rx) = watch::channel(false);
-        
-    loop {
-        select! {
-            sigint = signal::ctrl_c() => {
-                if let Err(e) = sigint {
-                    eprintln!("Error while waiting for ctrl-c: {:#?}, stopping server.", e);
+        Query::Book(symbol) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    let (bids, asks, truncated) = stock.get_book(config.max_book_orders);
+                    socket_tx.send(QueryResponse::Book { bids, asks, truncated }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
                 }
-                break;
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
             }
-
-            socket_result = listener.accept() => {
-                let (mut socket, _) = match socket_result {
-                    Ok(s) => s,
-                    Err(e) => {
-                        eprintln!("Error while accepting connection: {:#?}", e);
-                        continue;
-                    }
-                };
-        
-                let conn_id: usize = next_id;
-                next_id += 1;
-        
-                let (socket_tx, socket_rx) = mpsc::channel::<QueryResponse>(32);
-        
-                if let Err(e) = tx.send((conn_id, Query::Connect(socket_tx))).await {
-                    eprintln!("Encountered error while sending {:#?}", e);
-                    if let Err(e) = socket.shutdown().await {
-                        eprintln!("Error while shutting down socket: {:#?}", e);
-                    }
-                    continue;
+        }
+        Query::BuyOrders(symbol, levels) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    socket_tx.send(QueryResponse::QueriedOrders { orders: stock.get_buy_orders(levels) }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
                 }
-        
-                connection_future_set.spawn(connection_handler(conn_id, tx.clone(), socket_rx, socket, shutdown_signal_rx.clone()));
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
             }
         }
-    }
-
-    match shutdown_signal_tx.send(true) {
-        Ok(()) => {
-            let results = connection_future_set.join_all().await;
-            for result in results {
-                if let Err((id, e)) = result {
-                    eprintln!("Connection with id {} returned error: {:#?}", id, e);
+        Query::SellOrders(symbol, levels) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    socket_tx.send(QueryResponse::QueriedOrders { orders: stock.get_sell_orders(levels) }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
                 }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
             }
-        },
-        Err(e) => {
-            eprintln!("Error while sending shutdown signal: {:#?}, forcing shutdown on sockets.", e);
-            
-            // I don't see a need to manually shutdown the sockets here.
-            // connection_future_set.shutdown().await;
-        },
-    }
-
-    Ok(())
-}
-
-async fn connection_handler(id: usize, tx: mpsc::Sender<(usize, Query)>, mut rx: mpsc::Receiver<QueryResponse>, mut socket: TcpStream, mut shutdown_signal: watch::Receiver<bool>) -> Result<(), (usize, std::io::Error)> {
-    let mut socket_buffer = [0u8; 1024];
-    loop {
-        select! {
-            query_response = rx.recv() => {
-                let response = match query_response {
-                    Some(r) => r,
-                    None => {
-                        // The market should not be closed before sockets.
-                        socket.write(r#"{"response": "market closed"}"#.as_bytes()).await.map_err(|e| (id, e))?;
-                        socket.shutdown().await.map_err(|e| (id, e))?;
-                        continue;
+        }
+        Query::VolumeProfile(symbol) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    socket_tx.send(QueryResponse::VolumeProfile { levels: stock.get_volume_profile() }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        Query::IndicativePrice(symbol) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    let (price, quantity) = match stock.get_indicative_price() {
+                        Some((price, quantity)) => (Some(price), quantity),
+                        None => (None, 0),
+                    };
+                    socket_tx.send(QueryResponse::IndicativePrice { price, quantity }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        Query::SymbolInfo(symbol) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    socket_tx.send(QueryResponse::SymbolInfo(stock.get_symbol_info())).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        Query::Activity(limit) => {
+            socket_tx.send(QueryResponse::Activity { events: market.get_activity(limit) }).await?;
+        }
+        Query::SpreadHistory(symbol, limit) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    socket_tx.send(QueryResponse::SpreadHistory { candles: stock.get_candles(limit) }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        Query::Candles(symbol, limit) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    socket_tx.send(QueryResponse::Candles { candles: stock.get_candles(limit) }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        Query::Cancel(symbol, order_id) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock_mut(&symbol) {
+                    if stock.cancel_order(order_id, id) {
+                        market.get_user_mut(id).record_order_cancelled();
+                        socket_tx.send(QueryResponse::OrderCancelled { order_id }).await?;
+                    } else {
+                        socket_tx.send(QueryResponse::from(ErrorCode::OrderNotFound)).await?;
                     }
-                };
-        
-                let response = response.to_json();
-                if let Err(e) = socket.write_all(response.as_bytes()).await {
-                    eprintln!("Error while writing to socket: {:#?}", &e);
-                    break Err((id, e));
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
                 }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
             }
-            socket_query = socket.read(&mut socket_buffer) => {
-                let message = String::from_utf8_lossy(&socket_buffer);
-                println!("Received: {}", message);
-                let query = match socket_query {
-                    Ok(0) => {
-                        break Ok(());
+        }
+        Query::CancelOldest(symbol, side) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock_mut(&symbol) {
+                    match stock.cancel_oldest(id, side) {
+                        Some(order) => {
+                            market.get_user_mut(id).record_order_cancelled();
+                            socket_tx.send(QueryResponse::OrderCancelled { order_id: order.get_id() }).await?;
+                        }
+                        None => socket_tx.send(QueryResponse::from(ErrorCode::OrderNotFound)).await?,
                     }
-                    Ok(n) => {
-                        match Query::from_json(&message[0..n], id) {
-                            Some(q) => q,
-                            None => {
-                                socket.write(r#"{"response": "malformed request"}"#.as_bytes()).await.map_err(|e| (id, e))?;
-                                continue;
-                            }
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        Query::CancelNewest(symbol, side) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock_mut(&symbol) {
+                    match stock.cancel_newest(id, side) {
+                        Some(order) => {
+                            market.get_user_mut(id).record_order_cancelled();
+                            socket_tx.send(QueryResponse::OrderCancelled { order_id: order.get_id() }).await?;
                         }
+                        None => socket_tx.send(QueryResponse::from(ErrorCode::OrderNotFound)).await?,
                     }
-                    Err(e) => {
-                        eprintln!("Error while reading from socket: {:#?}", e);
-                        break Err((id, e));
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        Query::QuoteUpdate { symbol, bids, asks } => {
+            let now = market.now();
+            let summary = format!("quote_update {} with {} bids and {} asks", symbol, bids.len(), asks.len());
+            if let Some(reason) = order_entry_rejection(market, id, config, now) {
+                return reject(market, id, &socket_tx, reason, summary).await;
+            }
+
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    // Every leg is checked as a new order would be before anything changes, so a rejected update leaves
+                    // the old quotes in place. The old quotes are cancelled by the update, so they hold nothing back.
+                    if stock.is_halted() {
+                        return reject(market, id, &socket_tx, RejectReason::TradingHalted, summary).await;
                     }
-                };
-        
-                if let Err(e) = tx.send((id, query)).await {
-                    eprintln!("Error while sending query: {:#?}", e);
-                    break Ok(());
+                    let rejection = bids.iter().chain(asks.iter()).find_map(|order| validate_order(order, stock, config).err());
+                    if let Some(reason) = rejection {
+                        return reject(market, id, &socket_tx, RejectReason::InvalidOrder(reason), summary).await;
+                    }
+                    if bids.iter().chain(asks.iter()).any(|order| outside_price_band(order, stock, config)) {
+                        return reject(market, id, &socket_tx, RejectReason::PriceOutOfBand, summary).await;
+                    }
+                    if !market.can_afford_quotes(id, symbol, &bids) {
+                        return reject(market, id, &socket_tx, RejectReason::InsufficientFunds, summary).await;
+                    }
+                    if !market.can_deliver_quotes(id, symbol, &asks) {
+                        return reject(market, id, &socket_tx, RejectReason::InsufficientShares, summary).await;
+                    }
+
+                    let cancelled = market.get_stock_mut(&symbol).expect("The stock should still be listed.").cancel_user_orders(id).len();
+                    let mut add_all = |side, orders: Vec<Order>| -> Vec<u64> {
+                        orders.into_iter().map(|order| market.add_order(symbol, side, order).expect("The stock should still be listed.")).collect()
+                    };
+                    let bid_ids = add_all(Side::Buy, bids);
+                    let ask_ids = add_all(Side::Sell, asks);
+
+                    let user = market.get_user_mut(id);
+                    for _ in 0..cancelled {
+                        user.record_order_cancelled();
+                    }
+                    for _ in 0..(bid_ids.len() + ask_ids.len()) {
+                        user.record_order_placed(now);
+                    }
+                    socket_tx.send(QueryResponse::QuotesReplaced { bid_ids, ask_ids }).await?;
+                } else {
+                    reject(market, id, &socket_tx, RejectReason::SymbolNotFound, summary).await?;
                 }
+            } else {
+                reject(market, id, &socket_tx, RejectReason::SymbolInvalid, summary).await?;
             }
-            _ = shutdown_signal.changed() => {
-                socket.shutdown().await.map_err(|e| (id, e))?;
-                break Ok(());
+        }
+        Query::OrderFlow(symbol, window_secs) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    let window = i64::try_from(window_secs)
+                        .ok()
+                        .and_then(chrono::Duration::try_seconds)
+                        .unwrap_or(chrono::Duration::max_value());
+                    socket_tx.send(QueryResponse::OrderFlow { net_volume: stock.get_order_flow(market.now(), window) }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
             }
         }
-    }
-}
-
+        Query::OrderExists(order_id) => {
+            // Other users' orders are reported as missing so that their existence isn't leaked.
+            let exists = market.get_order(order_id).is_some_and(|order| order.get_creator_id() == id);
+            socket_tx.send(QueryResponse::OrderExists { exists }).await?;
+        }
+        Query::QueueDepth(symbol, side, price) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    let (orders, quantity) = stock.get_queue_depth(side, price);
+                    socket_tx.send(QueryResponse::QueueDepth { orders, quantity }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        Query::Depth(symbol) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    let (total_bid_qty, bid_levels) = stock.get_depth(Side::Buy);
+                    let (total_ask_qty, ask_levels) = stock.get_depth(Side::Sell);
+                    socket_tx.send(QueryResponse::Depth { total_bid_qty, total_ask_qty, bid_levels, ask_levels }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        Query::BookAge(symbol) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock(&symbol) {
+                    let age = market.now() - stock.get_last_book_change();
+                    socket_tx.send(QueryResponse::BookAge { age_ms: age.num_milliseconds().max(0) }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        Query::MarketOverview => {
+            socket_tx.send(QueryResponse::MarketOverview(market.get_overview())).await?;
+        }
+        Query::MyRejections(limit) => {
+            let rejections = market.get_user(id).map(|user| user.get_rejections(limit)).unwrap_or_default();
+            socket_tx.send(QueryResponse::Rejections { rejections }).await?;
+        }
+        Query::Balances => {
+            socket_tx.send(QueryResponse::Balances(market.get_balances(id))).await?;
+        }
+        Query::Symbols => {
+            socket_tx.send(QueryResponse::Symbols { symbols: market.list_stocks() }).await?;
+        }
+        Query::Portfolio => {
+            let balances = market.get_balances(id);
+            let mut positions: Vec<(String, i64)> = balances
+                .get_available_holdings()
+                .iter()
+                .filter(|(_, shares)| **shares != 0)
+                .map(|(symbol, shares)| (symbol.to_string(), *shares))
+                .collect();
+            positions.sort();
+            let cash = balances.get_available_cash_cents() as f64 / 100.0;
+            socket_tx.send(QueryResponse::Portfolio { cash, positions }).await?;
+        }
+        Query::Echo(payload) => {
+            socket_tx.send(QueryResponse::Echo { payload }).await?;
+        }
+        Query::Ping(ts) => {
+            socket_tx.send(QueryResponse::Pong { ts }).await?;
+        }
+        Query::Status => {
+            let status = QueryResponse::Status {
+                stocks: market.stock_count(),
+                users: connections.len(),
+                resting_orders: market.resting_order_count(),
+                uptime_secs: market.uptime().as_secs(),
+                fees_collected_cents: market.fees_collected_cents(),
+            };
+            socket_tx.send(status).await?;
+        }
+        Query::Reservations => {
+            socket_tx.send(QueryResponse::Reservations(market.get_reservations(id))).await?;
+        }
+        Query::Subscribe(symbol) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                if market.get_stock(&symbol).is_some() {
+                    // The connection's handler starts forwarding the stock's prints once it sees this response.
+                    socket_tx.send(QueryResponse::Subscribed { symbol: symbol.to_string() }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        Query::Schema => {
+            socket_tx.send(QueryResponse::Schema { queries: QUERY_SCHEMA }).await?;
+        }
+        Query::OrderStats(user_id) => {
+            let user_id = user_id.unwrap_or(id);
+            if user_id != id && !admin {
+                socket_tx.send(QueryResponse::from(ErrorCode::Unauthorized)).await?;
+                return Ok(());
+            }
+
+            let stats = market.get_user(user_id).map(|user| user.get_stats()).unwrap_or_default();
+            socket_tx.send(QueryResponse::OrderStats { user_id, stats }).await?;
+        }
+        Query::SessionInfo => {
+            let session = market.get_session();
+            let elapsed = session.elapsed(market.now()).num_seconds();
+            socket_tx.send(QueryResponse::SessionInfo { started: session.get_start(), elapsed_secs: elapsed, next_reset: session.next_reset() }).await?;
+        }
+        Query::AdminLogin(token) => {
+            if config.admin_token.as_ref() == Some(&token) {
+                if let Some(connection) = connections.get_mut(&id) {
+                    connection.admin = true;
+                }
+                socket_tx.send(QueryResponse::AdminGranted).await?;
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::Unauthorized)).await?;
+            }
+        }
+        Query::Resume(token) => {
+            // A token can't be shared by two live connections, or one would act on the other's orders.
+            let response = if market.get_token_user(&token).is_some_and(|user_id| user_id != id && connections.contains_key(&user_id)) {
+                QueryResponse::Error { code: ErrorCode::ResumeRefused, message: "token in use".to_string() }
+            } else {
+                match market.resume(&token, id) {
+                    Ok(resumed) => QueryResponse::Resumed { restored: resumed == Resumed::Restored },
+                    Err(()) => QueryResponse::Error { code: ErrorCode::ResumeRefused, message: "connection already has a user".to_string() },
+                }
+            };
+            socket_tx.send(response).await?;
+        }
+        Query::Reduce(order_id, reduce_by) => match market.reduce_order(id, order_id, reduce_by) {
+            Some(remaining) => {
+                if remaining == 0 {
+                    market.get_user_mut(id).record_order_cancelled();
+                }
+                socket_tx.send(QueryResponse::OrderReduced { order_id, remaining }).await?;
+            }
+            None => socket_tx.send(QueryResponse::from(ErrorCode::OrderNotFound)).await?,
+        },
+        Query::Reprice(order_id, price) => {
+            let Some((symbol, side, order)) = market.get_user_order(id, order_id) else {
+                socket_tx.send(QueryResponse::from(ErrorCode::OrderNotFound)).await?;
+                return Ok(());
+            };
+            let repriced = order.repriced(price);
+            if let Some(reason) = replacement_rejection(market, id, symbol, side, &repriced, config) {
+                let summary = format!("reprice {} @ {:.2}", order_id, price);
+                return reject(market, id, &socket_tx, reason, summary).await;
+            }
+
+            let response = QueryResponse::OrderRepriced { order_id, price: repriced.get_price(), quantity: repriced.get_quantity() };
+            market.replace_order(repriced);
+            socket_tx.send(response).await?;
+        }
+        Query::Modify { symbol, order_id, price, quantity } => {
+            let Ok(symbol) = market.parse_symbol(&symbol) else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+                return Ok(());
+            };
+            if market.get_stock(&symbol).is_none() {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                return Ok(());
+            }
+            let (side, modified) = match market.get_user_order(id, order_id) {
+                Some((order_symbol, side, order)) if order_symbol == symbol => (side, order.modified(price, quantity)),
+                _ => {
+                    socket_tx.send(QueryResponse::from(ErrorCode::OrderNotFound)).await?;
+                    return Ok(());
+                }
+            };
+            if let Some(reason) = replacement_rejection(market, id, symbol, side, &modified, config) {
+                let summary = format!("modify {} to {} @ {:.2}", order_id, quantity, price);
+                return reject(market, id, &socket_tx, reason, summary).await;
+            }
+
+            let response = QueryResponse::OrderModified { order_id, price: modified.get_price(), quantity: modified.get_quantity() };
+            if let Some(stock) = market.get_stock_mut(&symbol) {
+                stock.modify_order(modified);
+            }
+            socket_tx.send(response).await?;
+        }
+        Query::Admin(admin_query) => {
+            if !admin {
+                socket_tx.send(QueryResponse::from(ErrorCode::Unauthorized)).await?;
+                return Ok(());
+            }
+
+            resolve_admin_query(id, admin_query, &socket_tx, connections, market, config).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers a query from an admin connection.
+async fn resolve_admin_query(id: usize, query: AdminQuery, socket_tx: &Replier, connections: &HashMap<usize, Connection>, market: &mut Market, config: &Config) -> Result<(), SendError<QueryResponse>> {
+    match query {
+        AdminQuery::ListStock(symbol, name) => {
+            if let Ok(symbol) = market.parse_symbol(&symbol) {
+                match market.add_stock(symbol, new_stock(&name, config)) {
+                    Ok(()) => {
+                        info!("Market says> {} listed as {} by connection {}.", name, symbol, id);
+                        socket_tx.send(QueryResponse::StockAdded).await?;
+                    }
+                    Err(AddStockError::LimitReached) => socket_tx.send(QueryResponse::from(ErrorCode::StockLimitReached)).await?,
+                    Err(AddStockError::AlreadyListed) => socket_tx.send(QueryResponse::from(ErrorCode::StockAlreadyListed)).await?,
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        AdminQuery::Halt(symbol, halted) => {
+            if let Ok(parsed) = market.parse_symbol(&symbol) {
+                if let Some(stock) = market.get_stock_mut(&parsed) {
+                    stock.set_halted(halted);
+                    let state = if halted { "halted" } else { "resumed" };
+                    info!("Market says> Trading in {} {} by connection {}.", parsed, state, id);
+                    socket_tx.send(QueryResponse::SymbolHalted { symbol: parsed.to_string(), halted }).await?;
+                } else {
+                    socket_tx.send(QueryResponse::from(ErrorCode::SymbolNotFound)).await?;
+                }
+            } else {
+                socket_tx.send(QueryResponse::from(ErrorCode::SymbolInvalid)).await?;
+            }
+        }
+        AdminQuery::CancelOrder(order_id) => match market.remove_order(order_id) {
+            Some(order) => {
+                let owner_id = order.get_creator_id();
+                market.get_user_mut(owner_id).record_order_cancelled();
+                if let Some(owner) = connections.get(&owner_id) {
+                    if let Err(e) = owner.tx.send(QueryResponse::OrderCancelled { order_id }).await {
+                        error!("Error while notifying owner of cancelled order: {:#?}", e);
+                    }
+                }
+                socket_tx.send(QueryResponse::OrderCancelled { order_id }).await?;
+            }
+            None => socket_tx.send(QueryResponse::from(ErrorCode::OrderNotFound)).await?,
+        },
+        AdminQuery::CancelEverything => {
+            let cancelled = market.cancel_everything();
+            for order in &cancelled {
+                if let Some(owner) = connections.get(&order.get_creator_id()) {
+                    if let Err(e) = owner.tx.send(QueryResponse::OrderCancelled { order_id: order.get_id() }).await {
+                        error!("Error while notifying owner of cancelled order: {:#?}", e);
+                    }
+                }
+            }
+            socket_tx.send(QueryResponse::CancelledEverything { count: cancelled.len() }).await?;
+        }
+        AdminQuery::OrderEntryHalt(halted) => {
+            market.set_order_entry_halted(halted);
+            let state = if halted { "halted" } else { "resumed" };
+            info!("Market says> Order entry {} by connection {}.", state, id);
+            socket_tx.send(QueryResponse::OrderEntryHalted { halted }).await?;
+        }
+        AdminQuery::Config => {
+            socket_tx.send(QueryResponse::Config { config: config.snapshot() }).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Forgets the connection and, unless configured to keep them, cancels its resting orders. Returns how many were
+/// cancelled.
+fn drop_connection(id: usize, connections: &mut HashMap<usize, Connection>, market: &mut Market, config: &Config) -> usize {
+    connections.remove(&id);
+    if config.keep_orders_on_disconnect {
+        return 0;
+    }
+    let cancelled = market.cancel_user_orders(id);
+    let user = market.get_user_mut(id);
+    for _ in &cancelled {
+        user.record_order_cancelled();
+    }
+    cancelled.len()
+}
+
+/// Resolves every stock's crossing orders, logging each trade, sending it to both counterparties, and publishing it to
+/// the tape if the routing policy calls for one. Counterparties that have disconnected are skipped.
+async fn resolve_trades(market: &mut Market, connections: &HashMap<usize, Connection>, config: &Config, tape: &broadcast::Sender<TapePrint>, trade_log: &mut Option<TradeLog>) {
+    for (symbol, trades) in market.resolve().into_iter() {
+        let symbol = symbol.to_string();
+        for trade in trades.into_iter() {
+            if let Some(Err(e)) = trade_log.as_mut().map(|trade_log| trade_log.record(&symbol, &trade)) {
+                error!("Error while logging trade: {}", e);
+            }
+            if config.fill_routing == FillRouting::Tape {
+                publish_trade(tape, &symbol, &trade);
+            }
+            notify_trade(&symbol, trade, connections).await;
+        }
+    }
+    if let Some(Err(e)) = trade_log.as_mut().map(TradeLog::flush) {
+        error!("Error while writing trade log: {}", e);
+    }
+}
+
+/// Cancels every resting order older than `max_age`, counting each as cancelled by its creator and telling them it
+/// expired.
+async fn expire_orders(market: &mut Market, connections: &HashMap<usize, Connection>, max_age: chrono::Duration) {
+    for order in market.expire_orders(max_age) {
+        market.get_user_mut(order.get_creator_id()).record_order_cancelled();
+        if let Some(creator) = connections.get(&order.get_creator_id()) {
+            if let Err(e) = creator.tx.send(QueryResponse::OrderExpired { order_id: order.get_id() }).await {
+                error!("Error while notifying creator of expired order: {:#?}", e);
+            }
+        }
+    }
+}
+
+/// Sends a trade to both counterparties.
+async fn notify_trade(symbol: &str, trade: Trade, connections: &HashMap<usize, Connection>) {
+    info!(symbol, price = trade.price, quantity = trade.quantity, buyer_id = trade.buyer_id, seller_id = trade.seller_id, "Market says> Trade executed.");
+
+    // Tags, remaining quantities and limit prices are private to the order's creator, so each side only sees its own.
+    if let Some(buyer) = connections.get(&trade.buyer_id) {
+        let aggressor_limit = trade.aggressor_limit.filter(|_| trade.aggressor == Side::Buy);
+        if let Err(e) = buyer.tx.send(QueryResponse::ExecutedTrade(Trade { seller_tag: None, sell_remaining: None, aggressor_limit, ..trade })).await {
+            error!("Error while sending trade to buyer: {:#?}", e);
+        }
+    } else {
+        error!("Buyer with id {} not connected.", trade.buyer_id);
+    }
+
+    if let Some(seller) = connections.get(&trade.seller_id) {
+        let aggressor_limit = trade.aggressor_limit.filter(|_| trade.aggressor == Side::Sell);
+        if let Err(e) = seller.tx.send(QueryResponse::ExecutedTrade(Trade { buyer_tag: None, buy_remaining: None, aggressor_limit, ..trade })).await {
+            error!("Error while sending trade to seller: {:#?}", e);
+        }
+    } else {
+        error!("Seller with id {} not connected.", trade.seller_id);
+    }
+}
+
+/// How each connection handler talks to its client.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionOptions {
+    /// How prices are written.
+    price_format: PriceFormat,
+    /// A write taking longer than this marks the connection as dead.
+    write_timeout: Option<time::Duration>,
+    /// Queries the client can send per second before they are refused.
+    max_queries_per_sec: u32,
+}
+
+impl From<&Config> for ConnectionOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            price_format: config.price_format,
+            write_timeout: config.write_timeout_ms.map(time::Duration::from_millis),
+            max_queries_per_sec: config.max_queries_per_sec,
+        }
+    }
+}
+
+/// Where the server accepts connections, and how many.
+pub struct ListenerOptions {
+    /// Address trading clients connect to.
+    address: String,
+    /// Address admin clients connect to, if admin connections are accepted.
+    admin_address: Option<String>,
+    /// Maximum number of pending connections each listener queues before they are accepted.
+    backlog: u32,
+    /// Live connections the server allows before refusing trading clients.
+    max_connections: Option<usize>,
+    /// ID the first connection is given, with later ones numbered up from it.
+    first_connection_id: usize,
+}
+
+impl From<&Config> for ListenerOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            address: config.listener_address.clone(),
+            admin_address: config.admin_address.clone(),
+            backlog: config.backlog,
+            max_connections: config.max_connections,
+            first_connection_id: 1,
+        }
+    }
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self::from(&Config::default())
+    }
+}
+
+/// A trade as published to connections subscribed to its stock.
+#[derive(Clone, Debug)]
+pub struct TapePrint {
+    symbol: String,
+    price: f64,
+    quantity: usize,
+}
+
+/// Publishes an anonymized print of a trade to the connections subscribed to its stock.
+///
+/// Publishing never waits on subscribers, so a slow one can't hold up the market.
+fn publish_trade(tape: &broadcast::Sender<TapePrint>, symbol: &str, trade: &Trade) {
+    // Sending only fails when no connection is listening, which is fine.
+    let _ = tape.send(TapePrint { symbol: symbol.to_string(), price: trade.price, quantity: trade.quantity });
+}
+
+/// Checks an incoming order against the configured limits, returning why it was rejected.
+///
+/// Market orders have no price, so only limit orders are checked against the price and notional limits.
+fn validate_order(order: &Order, stock: &Stock, config: &Config) -> Result<(), String> {
+    if config.max_quantity.is_some_and(|max_quantity| order.get_quantity() > max_quantity) {
+        return Err("above maximum quantity".to_string());
+    }
+
+    if order.get_kind() == OrderKind::Market {
+        return Ok(());
+    }
+
+    if config.max_price.is_some_and(|max_price| order.get_price() > max_price) {
+        return Err("above maximum price".to_string());
+    }
+
+    if !stock.is_on_tick(order.get_price()) {
+        return Err("price not a multiple of the tick size".to_string());
+    }
+
+    if order.get_value_cents() < money::to_cents(config.min_notional, config.rounding) {
+        return Err("below minimum notional".to_string());
+    }
+
+    Ok(())
+}
+
+/// Returns whether a limit order is priced further from the stock's last trade price than the configured band allows.
+///
+/// The last trade price is the close of the stock's OHLC, so the band only applies once the stock has traded this
+/// session.
+fn outside_price_band(order: &Order, stock: &Stock, config: &Config) -> bool {
+    if order.get_kind() == OrderKind::Market {
+        return false;
+    }
+    match (config.price_band_pct, stock.get_ohlc().3) {
+        (Some(band_pct), Some(close)) => (order.get_price() - close).abs() > close * band_pct / 100.0,
+        _ => false,
+    }
+}
+
+/// Returns why the user can't enter an order right now, if they can't.
+///
+/// Orders are refused while order entry is halted, outside trading hours (unless they are queued instead), and within
+/// `config.order_cooldown_ms` of the user's last order.
+fn order_entry_rejection(market: &Market, id: usize, config: &Config, now: chrono::DateTime<chrono::Utc>) -> Option<RejectReason> {
+    if market.is_order_entry_halted() {
+        return Some(RejectReason::OrderEntryDisabled);
+    }
+    if !market.is_open() && config.closed_order_policy == ClosedOrderPolicy::Reject {
+        return Some(RejectReason::MarketClosed);
+    }
+
+    match (config.order_cooldown_ms, market.get_user(id).and_then(|user| user.get_last_order_time())) {
+        (Some(cooldown_ms), Some(last_order_time)) if now - last_order_time < chrono::Duration::milliseconds(cooldown_ms as i64) => {
+            Some(RejectReason::TooFast)
+        }
+        _ => None,
+    }
+}
+
+/// Returns why a replacement for one of the user's resting orders, on the given side of the stock's book, can't be
+/// entered, if it can't.
+///
+/// A replacement goes through the same checks as a new order, except that funds and shares are measured without the
+/// order being replaced, since the replacement takes its place.
+fn replacement_rejection(market: &Market, id: usize, symbol: Symbol, side: Side, replacement: &Order, config: &Config) -> Option<RejectReason> {
+    if let Some(reason) = order_entry_rejection(market, id, config, market.now()) {
+        return Some(reason);
+    }
+    if let Some(stock) = market.get_stock(&symbol) {
+        if stock.is_halted() {
+            return Some(RejectReason::TradingHalted);
+        }
+        if let Err(reason) = validate_order(replacement, stock, config) {
+            return Some(RejectReason::InvalidOrder(reason));
+        }
+        if outside_price_band(replacement, stock, config) {
+            return Some(RejectReason::PriceOutOfBand);
+        }
+    }
+    match side {
+        Side::Buy if !market.can_afford(id, symbol, replacement) => Some(RejectReason::InsufficientFunds),
+        Side::Sell if !market.can_deliver(id, symbol, replacement) => Some(RejectReason::InsufficientShares),
+        _ => None,
+    }
+}
+
+/// Sends the responses to a query back to the connection that sent it, numbered with the query's `req_id` if it had
+/// one.
+struct Replier {
+    tx: mpsc::Sender<QueryResponse>,
+    req_id: Option<u64>,
+}
+
+impl Replier {
+    async fn send(&self, response: QueryResponse) -> Result<(), SendError<QueryResponse>> {
+        self.tx.send(response.with_req_id(self.req_id)).await
+    }
+}
+
+/// Refuses an order, keeping the rejection in the user's recent rejections so they can look back on it.
+///
+/// `query` is a short description of what was refused.
+async fn reject(market: &mut Market, id: usize, socket_tx: &Replier, reason: RejectReason, query: String) -> Result<(), SendError<QueryResponse>> {
+    let time = market.now();
+    market.get_user_mut(id).record_rejection(Rejection { reason: reason.clone(), query, time });
+    socket_tx.send(QueryResponse::from(reason)).await
+}
+
+/// Returns the response to an order that was added to the book, which depends on whether it can be matched yet.
+fn posted_response(market: &Market, order_id: u64, tag: Option<OrderTag>, warning: Option<String>) -> QueryResponse {
+    if market.is_open() {
+        QueryResponse::OrderPosted { order_id, warning, tag }
+    } else {
+        QueryResponse::OrderQueued { order_id }
+    }
+}
+
+/// Returns a warning for orders that are accepted but worth more than `config.warn_notional`.
+fn order_warning(order: &Order, config: &Config) -> Option<String> {
+    match config.warn_notional {
+        Some(threshold) if order.get_kind() == OrderKind::Limit && order.get_value_cents() > money::to_cents(threshold, config.rounding) => {
+            Some("large order".to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Binds a listener with `SO_REUSEADDR` set, so a restarted server can rebind while old sockets sit in TIME_WAIT.
+///
+/// Falls back to a plain bind if the address isn't a literal socket address or the socket can't be set up.
+async fn bind_listener(listener_address: &str, backlog: u32) -> Result<TcpListener, std::io::Error> {
+    if let Ok(socket_address) = listener_address.parse::<SocketAddr>() {
+        let socket = if socket_address.is_ipv4() { TcpSocket::new_v4() } else { TcpSocket::new_v6() };
+        let listener = socket.and_then(|socket| {
+            socket.set_reuseaddr(true)?;
+            socket.bind(socket_address)?;
+            socket.listen(backlog)
+        });
+
+        match listener {
+            Ok(listener) => return Ok(listener),
+            Err(e) => error!("Error while setting up listener: {:#?}, falling back to a plain bind.", e),
+        }
+    }
+
+    TcpListener::bind(listener_address).await
+}
+
+/// Waits for a connection on either listener, returning whether it came in on the admin one.
+async fn accept_either(listener: &TcpListener, admin_listener: Option<&TcpListener>) -> (std::io::Result<(TcpStream, SocketAddr)>, bool) {
+    match admin_listener {
+        Some(admin_listener) => select! {
+            socket_result = listener.accept() => (socket_result, false),
+            socket_result = admin_listener.accept() => (socket_result, true),
+        },
+        None => (listener.accept().await, false),
+    }
+}
+
+pub async fn serve(tx: mpsc::Sender<(usize, Query)>, tape: broadcast::Sender<TapePrint>, listeners: ListenerOptions, options: ConnectionOptions, market_shutdown: watch::Sender<bool>) -> Result<(), std::io::Error> {
+    let mut next_id = listeners.first_connection_id;
+    let mut connection_future_set = task::JoinSet::new();
+    
+    let listener = bind_listener(&listeners.address, listeners.backlog).await?;
+    let admin_listener = match &listeners.admin_address {
+        Some(admin_address) => Some(bind_listener(admin_address, listeners.backlog).await?),
+        None => None,
+    };
+
+    let (shutdown_signal_tx, shutdown_signal_rx) = watch::channel(false);
+        
+    loop {
+        select! {
+            sigint = signal::ctrl_c() => {
+                if let Err(e) = sigint {
+                    error!("Error while waiting for ctrl-c: {:#?}, stopping server.", e);
+                }
+                break;
+            }
+
+            // Finished connections are collected as they end, so that only live ones count towards the limit.
+            Some(result) = connection_future_set.join_next() => {
+                report_connection_result(result);
+            }
+
+            (socket_result, admin) = accept_either(&listener, admin_listener.as_ref()) => {
+                let (mut socket, _) = match socket_result {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("Error while accepting connection: {:#?}", e);
+                        continue;
+                    }
+                };
+
+                // Admins are let in even when the server is full, so the market can still be managed.
+                if !admin && !should_accept(connection_future_set.len(), listeners.max_connections) {
+                    warn!("Refusing connection, the server is full.");
+                    if let Err(e) = refuse_connection(&mut socket, options.write_timeout).await {
+                        error!("Error while refusing connection: {:#?}", e);
+                    }
+                    continue;
+                }
+        
+                let conn_id: usize = next_id;
+                next_id += 1;
+        
+                let (socket_tx, socket_rx) = mpsc::channel::<QueryResponse>(32);
+        
+                let connect = if admin { Query::AdminConnect(socket_tx) } else { Query::Connect(socket_tx) };
+                if let Err(e) = tx.send((conn_id, connect)).await {
+                    error!("Encountered error while sending {:#?}", e);
+                    if let Err(e) = socket.shutdown().await {
+                        error!("Error while shutting down socket: {:#?}", e);
+                    }
+                    continue;
+                }
+        
+                let handler = connection_handler(conn_id, tx.clone(), socket_rx, tape.subscribe(), socket, shutdown_signal_rx.clone(), options);
+                connection_future_set.spawn(handler.instrument(tracing::info_span!("connection", id = conn_id)));
+            }
+        }
+    }
+
+    // Queries already sent still reach the market, and the trades they make are queued for their connections before
+    // those are closed. A failed send means the market has already closed.
+    if market_shutdown.send(true).is_ok() {
+        market_shutdown.closed().await;
+    }
+
+    // Only the connection handlers should hold receivers, so a failed send means none of them are listening.
+    drop(shutdown_signal_rx);
+    close_connections(&shutdown_signal_tx, connection_future_set).await;
+
+    Ok(())
+}
+
+/// Returns whether a new connection can be accepted while `current` connections are live.
+fn should_accept(current: usize, max_connections: Option<usize>) -> bool {
+    max_connections.is_none_or(|max_connections| current < max_connections)
+}
+
+/// Tells a client the server is full and closes the connection.
+async fn refuse_connection<S>(socket: &mut S, write_timeout: Option<time::Duration>) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    write_frame(socket, &QueryResponse::from(ErrorCode::ServerFull).to_json(), write_timeout).await?;
+    socket.shutdown().await
+}
+
+/// Logs how a finished connection task ended, if it failed.
+fn report_connection_result(result: Result<Result<(), (usize, std::io::Error)>, task::JoinError>) {
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err((id, e))) => error!("Connection with id {} returned error: {:#?}", id, e),
+        Err(e) => error!("Connection task failed: {:#?}", e),
+    }
+}
+
+/// Signals every connection to shut down and waits for them to finish.
+///
+/// If the signal can't be sent, the connection tasks are aborted instead so that their sockets are still dropped.
+async fn close_connections(shutdown_signal_tx: &watch::Sender<bool>, mut connection_future_set: task::JoinSet<Result<(), (usize, std::io::Error)>>) {
+    match shutdown_signal_tx.send(true) {
+        Ok(()) => {
+            let results = connection_future_set.join_all().await;
+            for result in results {
+                if let Err((id, e)) = result {
+                    error!("Connection with id {} returned error: {:#?}", id, e);
+                }
+            }
+        },
+        Err(e) => {
+            error!("Error while sending shutdown signal: {:#?}, forcing shutdown on sockets.", e);
+            connection_future_set.shutdown().await;
+        },
+    }
+}
+
+/// Bytes read from a connection that haven't formed a query yet. Each query is one line of JSON, so a read may hold
+/// several queries, or only part of one.
+#[derive(Default)]
+struct LineBuffer {
+    bytes: Vec<u8>,
+    /// Whether the rest of the current line is being dropped because it is too long.
+    discarding: bool,
+}
+
+impl LineBuffer {
+    fn extend(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete line, without its line ending. Blank lines are skipped.
+    ///
+    /// A line longer than `MAX_FRAME_BYTES` is returned as an error once, and the rest of it is dropped as it arrives.
+    fn next_line(&mut self) -> Option<Result<String, QueryParseError>> {
+        loop {
+            let Some(end) = self.bytes.iter().position(|&byte| byte == b'\n') else {
+                if self.bytes.len() > MAX_FRAME_BYTES {
+                    self.bytes.clear();
+                    if !self.discarding {
+                        self.discarding = true;
+                        return Some(Err(QueryParseError::PayloadTooLarge(MAX_FRAME_BYTES)));
+                    }
+                }
+                return None;
+            };
+
+            let line: Vec<u8> = self.bytes.drain(..=end).collect();
+            if std::mem::take(&mut self.discarding) {
+                continue;
+            }
+            if line.len() > MAX_FRAME_BYTES + 1 {
+                return Some(Err(QueryParseError::PayloadTooLarge(MAX_FRAME_BYTES)));
+            }
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            if !line.is_empty() {
+                return Some(Ok(line));
+            }
+        }
+    }
+}
+
+/// Writes a response to the socket as one line, failing with `TimedOut` if that takes longer than `write_timeout`.
+///
+/// Every response goes out through here, so clients can always split what they read on newlines.
+async fn write_frame<S>(socket: &mut S, frame: &str, write_timeout: Option<time::Duration>) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let line = format!("{}\n", frame);
+    let bytes = line.as_bytes();
+    match write_timeout {
+        Some(write_timeout) => time::timeout(write_timeout, socket.write_all(bytes))
+            .await
+            .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "write to client timed out"))),
+        None => socket.write_all(bytes).await,
+    }
+}
+
+/// Tells the market a connection is dead after a failed write, whether it timed out or the socket broke, so its orders
+/// don't rest for a client that isn't listening. Returns the error for the handler to exit with.
+async fn report_write_error(id: usize, tx: &mpsc::Sender<(usize, Query)>, e: std::io::Error) -> (usize, std::io::Error) {
+    error!("Error while writing to socket: {:#?}", &e);
+    if let Err(e) = tx.send((id, Query::ConnectionDead)).await {
+        error!("Error while reporting dead connection: {:#?}", e);
+    }
+    (id, e)
+}
+
+/// Tells the market the client has gone, so the connection is forgotten and its orders cancelled.
+async fn report_disconnect(id: usize, tx: &mpsc::Sender<(usize, Query)>) {
+    if let Err(e) = tx.send((id, Query::Disconnect)).await {
+        error!("Error while reporting disconnect: {:#?}", e);
+    }
+}
+
+/// Tells the client the market has closed and shuts the socket down, for when the market drops the connection's
+/// response channel.
+async fn close_market_side<S>(socket: &mut S, write_timeout: Option<time::Duration>) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    write_frame(socket, &QueryResponse::from(ErrorCode::MarketShutDown).to_json(), write_timeout).await?;
+    socket.shutdown().await
+}
+
+async fn connection_handler<S>(id: usize, tx: mpsc::Sender<(usize, Query)>, mut rx: mpsc::Receiver<QueryResponse>, mut tape: broadcast::Receiver<TapePrint>, mut socket: S, mut shutdown_signal: watch::Receiver<bool>, options: ConnectionOptions) -> Result<(), (usize, std::io::Error)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ConnectionOptions { price_format, write_timeout, max_queries_per_sec } = options;
+
+    // Nothing is read from the socket until the market has registered this connection, so no query can reach the
+    // market under an ID it doesn't know yet.
+    select! {
+        query_response = rx.recv() => {
+            match query_response {
+                Some(response) => {
+                    if let Err(e) = write_frame(&mut socket, &response.to_json_with(price_format), write_timeout).await {
+                        return Err(report_write_error(id, &tx, e).await);
+                    }
+                }
+                None => return close_market_side(&mut socket, write_timeout).await.map_err(|e| (id, e)),
+            }
+        }
+        _ = shutdown_signal.changed() => {
+            socket.shutdown().await.map_err(|e| (id, e))?;
+            return Ok(());
+        }
+    }
+
+    let mut socket_buffer = [0u8; READ_BUFFER_BYTES];
+    let mut lines = LineBuffer::default();
+    // Stocks whose trade prints are forwarded to the client, and whether the tape is still being published.
+    let mut subscriptions: HashSet<String> = HashSet::new();
+    let mut tape_open = true;
+    let mut rate_limiter = RateLimiter::new(max_queries_per_sec);
+    'connection: loop {
+        select! {
+            query_response = rx.recv() => {
+                let response = match query_response {
+                    Some(r) => r,
+                    None => {
+                        // The market should not be closed before sockets.
+                        break close_market_side(&mut socket, write_timeout).await.map_err(|e| (id, e));
+                    }
+                };
+                if let QueryResponse::Subscribed { symbol } = response.unnumbered() {
+                    subscriptions.insert(symbol.clone());
+                }
+        
+                let response = response.to_json_with(price_format);
+                if let Err(e) = write_frame(&mut socket, &response, write_timeout).await {
+                    break Err(report_write_error(id, &tx, e).await);
+                }
+            }
+            print = tape.recv(), if tape_open => {
+                let print = match print {
+                    Ok(print) if subscriptions.contains(&print.symbol) => print,
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!(missed, "Fell behind and skipped trade prints.");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        tape_open = false;
+                        continue;
+                    }
+                };
+
+                let response = QueryResponse::TradePrint { symbol: print.symbol, price: print.price, quantity: print.quantity };
+                if let Err(e) = write_frame(&mut socket, &response.to_json_with(price_format), write_timeout).await {
+                    break Err(report_write_error(id, &tx, e).await);
+                }
+            }
+            socket_query = socket.read(&mut socket_buffer) => {
+                match socket_query {
+                    Ok(0) => {
+                        report_disconnect(id, &tx).await;
+                        break Ok(());
+                    }
+                    Ok(n) => lines.extend(&socket_buffer[..n]),
+                    Err(e) => {
+                        error!("Error while reading from socket: {:#?}", e);
+                        report_disconnect(id, &tx).await;
+                        break Err((id, e));
+                    }
+                }
+
+                while let Some(line) = lines.next_line() {
+                    // The client is cut off rather than left to fill the buffer with another oversized query.
+                    if let Err(e @ QueryParseError::PayloadTooLarge(_)) = line {
+                        warn!("Closing connection after a query over {} bytes.", MAX_FRAME_BYTES);
+                        report_disconnect(id, &tx).await;
+                        let response = QueryResponse::from(e).to_json();
+                        if let Err(e) = write_frame(&mut socket, &response, write_timeout).await {
+                            break 'connection Err((id, e));
+                        }
+                        break 'connection socket.shutdown().await.map_err(|e| (id, e));
+                    }
+                    if !rate_limiter.try_acquire() {
+                        let req_id = line.as_deref().ok().and_then(Query::peek_req_id);
+                        let response = QueryResponse::from(ErrorCode::RateLimited).with_req_id(req_id).to_json();
+                        if let Err(e) = write_frame(&mut socket, &response, write_timeout).await {
+                            break 'connection Err(report_write_error(id, &tx, e).await);
+                        }
+                        continue;
+                    }
+
+                    let parsed = line.map_err(|e| (e, None)).and_then(|line| {
+                        info!("Received: {}", line);
+                        Query::from_json(&line, id).map_err(|e| (e, Query::peek_req_id(&line)))
+                    });
+                    let query = match parsed.map(Query::split_req_id) {
+                        // Echoes are answered here, so they measure only the connection and never wait on the market
+                        // loop.
+                        Ok((Query::Echo(payload), req_id)) => {
+                            let response = QueryResponse::Echo { payload }.with_req_id(req_id).to_json_with(price_format);
+                            if let Err(e) = write_frame(&mut socket, &response, write_timeout).await {
+                                break 'connection Err(report_write_error(id, &tx, e).await);
+                            }
+                            continue;
+                        }
+                        Ok((q, req_id)) => q.with_req_id(req_id),
+                        Err((e, req_id)) => {
+                            error!("Error while parsing query: {}", e);
+                            let response = QueryResponse::from(e).with_req_id(req_id).to_json();
+                            if let Err(e) = write_frame(&mut socket, &response, write_timeout).await {
+                                break 'connection Err(report_write_error(id, &tx, e).await);
+                            }
+                            continue;
+                        }
+                    };
+
+                    if let Err(e) = tx.send((id, query)).await {
+                        error!("Error while sending query: {:#?}", e);
+                        break 'connection Ok(());
+                    }
+                }
+            }
+            _ = shutdown_signal.changed() => {
+                // The market has closed by now, so anything it sent is still delivered before the socket is.
+                while let Ok(response) = rx.try_recv() {
+                    write_frame(&mut socket, &response.to_json_with(price_format), write_timeout).await.map_err(|e| (id, e))?;
+                }
+                socket.shutdown().await.map_err(|e| (id, e))?;
+                break Ok(());
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+    use tokio::net::TcpStream;
+    use types::Side;
+
+    fn test_market() -> Market {
+        let mut market = Market::new();
+        market.extend_stocks([
+            (Symbol::try_from("V").unwrap(), Stock::new("Vulyenne")),
+            (Symbol::try_from("ORT").unwrap(), Stock::new("Orchard de Rosa et Tulipan")),
+        ]);
+        market
+    }
+
+    /// Reads the next response written to a client, without its newline, or `None` once the connection is closed.
+    async fn next_frame<S: AsyncBufRead + Unpin>(client: &mut S) -> Option<String> {
+        let mut line = String::new();
+        match client.read_line(&mut line).await.unwrap() {
+            0 => None,
+            _ => Some(line.strip_suffix('\n').expect("every response ends with a newline").to_string()),
+        }
+    }
+
+    /// Returns the ID of the order a response reports as posted.
+    fn posted_id(response: Option<QueryResponse>) -> u64 {
+        match response {
+            Some(QueryResponse::OrderPosted { order_id, .. }) => order_id,
+            _ => panic!("expected a posted order"),
+        }
+    }
+
+    /// The test market with its connections and config, so each test only has to state what is specific to it.
+    struct TestMarket {
+        market: Market,
+        connections: HashMap<usize, Connection>,
+        config: Config,
+    }
+
+    /// Returns the default configuration, except that every user starts with shares of each stock to sell.
+    fn stocked_config() -> Config {
+        Config { starting_shares: 1000, ..Config::default() }
+    }
+
+    impl TestMarket {
+        fn new(config: Config) -> Self {
+            let mut market = test_market();
+            configure_market(&mut market, &config);
+            TestMarket { market, connections: HashMap::new(), config }
+        }
+
+        /// Registers a new connection with the market and returns its response receiver.
+        async fn connect(&mut self, id: usize) -> mpsc::Receiver<QueryResponse> {
+            let (tx, mut rx) = mpsc::channel(32);
+            self.query(id, Query::Connect(tx)).await;
+            assert!(matches!(rx.recv().await, Some(QueryResponse::Connected)));
+            rx
+        }
+
+        /// Resolves a query as if it had been sent by connection `id`.
+        async fn query(&mut self, id: usize, query: Query) {
+            resolve_query(id, query, &mut self.connections, &mut self.market, &self.config).await.unwrap();
+        }
+    }
+
+    /// Tests that an admin can clear every book and that each order's owner is notified.
+    #[tokio::test]
+    async fn test_cancel_everything() {
+        let mut harness = TestMarket::new(Config { admin_token: Some("hunter2".to_string()), ..stocked_config() });
+
+        let mut alice = harness.connect(1).await;
+        let mut bob = harness.connect(2).await;
+        let mut admin = harness.connect(3).await;
+
+        for (id, query) in [
+            (1, Query::Buy("V".to_string(), Order::new(1, 100.0, 10))),
+            (2, Query::Sell("ORT".to_string(), Order::new(2, 120.0, 5))),
+        ] {
+            harness.query(id, query).await;
+        }
+        assert!(matches!(alice.recv().await, Some(QueryResponse::OrderPosted { .. })));
+        assert!(matches!(bob.recv().await, Some(QueryResponse::OrderPosted { .. })));
+
+        // Without logging in, the admin query is refused and nothing is cancelled.
+        harness.query(3, Query::Admin(AdminQuery::CancelEverything)).await;
+        assert!(matches!(admin.recv().await, Some(QueryResponse::Error { code: ErrorCode::Unauthorized, .. })));
+
+        harness.query(3, Query::AdminLogin("hunter2".to_string())).await;
+        assert!(matches!(admin.recv().await, Some(QueryResponse::AdminGranted)));
+        harness.query(3, Query::Admin(AdminQuery::CancelEverything)).await;
+        assert!(matches!(admin.recv().await, Some(QueryResponse::CancelledEverything { count: 2 })));
+
+        assert!(matches!(alice.recv().await, Some(QueryResponse::OrderCancelled { .. })));
+        assert!(matches!(bob.recv().await, Some(QueryResponse::OrderCancelled { .. })));
+        for symbol in ["V", "ORT"] {
+            let stock = harness.market.get_stock(&Symbol::try_from(symbol).unwrap()).unwrap();
+            assert!(stock.get_buy_orders(5).is_empty());
+            assert!(stock.get_sell_orders(5).is_empty());
+        }
+    }
+
+    /// Tests that orders above the notional threshold are accepted with a warning.
+    #[tokio::test]
+    async fn test_warn_notional() {
+        let mut harness = TestMarket::new(Config { warn_notional: Some(1000.0), ..stocked_config() });
+        let mut rx = harness.connect(1).await;
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { warning: None, .. })));
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 20))).await;
+        match rx.recv().await {
+            Some(QueryResponse::OrderPosted { warning: Some(warning), .. }) => assert_eq!(warning, "large order"),
+            _ => panic!("expected a posted order with a warning"),
+        }
+
+        let stock = harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap();
+        assert_eq!(stock.get_buy_orders(5), vec![(100.0, 25)]);
+    }
+
+    /// Tests that the address can be bound again straight after a listener with live connections is dropped.
+    #[tokio::test]
+    async fn test_rebind_after_shutdown() {
+        let listener = bind_listener("127.0.0.1:0", 16).await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = TcpStream::connect(address).await.unwrap();
+        let (mut server_side, _) = listener.accept().await.unwrap();
+        // Closing from the server side first leaves the server's socket in TIME_WAIT.
+        server_side.shutdown().await.unwrap();
+        drop(server_side);
+        drop(client);
+        drop(listener);
+
+        assert!(bind_listener(&address.to_string(), 16).await.is_ok());
+    }
+
+    /// Tests that a query written straight after connecting only reaches the market once the connection is registered.
+    #[tokio::test]
+    async fn test_query_after_connect_is_never_unknown() {
+        let mut harness = TestMarket::new(stocked_config());
+
+        let (tx, mut market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (mut client, server_side) = tokio::io::duplex(1024);
+
+        tx.send((1, Query::Connect(socket_tx))).await.unwrap();
+        let handler = task::spawn(connection_handler(1, tx.clone(), socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, ConnectionOptions::default()));
+        client.write_all(concat!(r#"{"type": "buy", "symbol": "V", "price": 100.0, "quantity": 1}"#, "\n").as_bytes()).await.unwrap();
+
+        let (id, query) = market_rx.recv().await.unwrap();
+        assert!(matches!(query, Query::Connect(_)));
+        time::sleep(time::Duration::from_millis(20)).await;
+        assert!(market_rx.try_recv().is_err(), "the order must wait for the connection to be registered");
+        harness.query(id, query).await;
+
+        let (id, query) = market_rx.recv().await.unwrap();
+        assert!(matches!(query, Query::Buy(..)));
+        harness.query(id, query).await;
+
+        let mut client = BufReader::new(client);
+        assert_eq!(next_frame(&mut client).await.unwrap(), QueryResponse::Connected.to_json());
+        assert!(next_frame(&mut client).await.unwrap().contains("order_posted"));
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    /// Tests that a stock's metadata survives the trip through the protocol.
+    #[tokio::test]
+    async fn test_symbol_info() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx = harness.connect(1).await;
+
+        harness.query(1, Query::SymbolInfo("ORT".to_string())).await;
+        let json: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap().to_json()).unwrap();
+        let info = harness.market.get_stock(&Symbol::try_from("ORT").unwrap()).unwrap().get_symbol_info();
+        assert_eq!(json["response"], "symbol_info");
+        assert_eq!(json["tick_size"].as_f64(), Some(info.tick_size));
+        assert_eq!(json["lot_size"].as_u64(), Some(info.lot_size as u64));
+        assert_eq!(json["decimals"].as_u64(), Some(info.decimals as u64));
+        assert_eq!(json["currency"].as_str(), Some(info.currency.as_str()));
+        assert_eq!(json["halted"].as_bool(), Some(info.halted));
+
+        harness.query(1, Query::SymbolInfo("NOPE".to_string())).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::SymbolNotFound, .. })));
+    }
+
+    /// Tests that orders below the minimum notional are rejected while orders at the minimum are posted.
+    #[tokio::test]
+    async fn test_min_notional() {
+        let mut harness = TestMarket::new(Config { min_notional: 500.0, ..stocked_config() });
+        let mut rx = harness.connect(1).await;
+
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 99.99, 5))).await;
+        match rx.recv().await {
+            Some(QueryResponse::Error { code: ErrorCode::InvalidOrder, message }) => assert_eq!(message, "below minimum notional"),
+            _ => panic!("expected the order to be rejected"),
+        }
+
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 100.0, 5))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+        assert_eq!(harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap().get_sell_orders(5), vec![(100.0, 5)]);
+    }
+
+    /// Tests that adding a stock past the limit is refused while existing stocks keep trading.
+    #[tokio::test]
+    async fn test_max_stocks() {
+        let mut harness = TestMarket::new(Config { admin_token: Some("hunter2".to_string()), ..stocked_config() });
+        harness.market.set_max_stocks(Some(2));
+        let mut rx = harness.connect(1).await;
+
+        harness.query(1, Query::AdminLogin("hunter2".to_string())).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::AdminGranted)));
+
+        harness.query(1, Query::Admin(AdminQuery::ListStock("X".to_string(), "Xanthe".to_string()))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::StockLimitReached, .. })));
+        assert!(harness.market.get_stock(&Symbol::try_from("X").unwrap()).is_none());
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+    }
+
+    /// Tests that orders priced above the maximum are rejected without resting in the book.
+    #[tokio::test]
+    async fn test_max_price() {
+        let mut harness = TestMarket::new(Config { max_price: Some(1000.0), ..stocked_config() });
+        let mut rx = harness.connect(1).await;
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 1000.01, 1))).await;
+        match rx.recv().await {
+            Some(QueryResponse::Error { code: ErrorCode::InvalidOrder, message }) => assert_eq!(message, "above maximum price"),
+            _ => panic!("expected the order to be rejected"),
+        }
+        assert!(harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap().get_buy_orders(5).is_empty());
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 1000.0, 1))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+    }
+
+    /// Tests that orders for more shares than the maximum are rejected, market orders included.
+    #[tokio::test]
+    async fn test_max_quantity() {
+        let mut harness = TestMarket::new(Config { max_quantity: Some(1000), ..stocked_config() });
+        let mut rx = harness.connect(1).await;
+
+        for order in [Order::new(1, 100.0, 1001), Order::market(1, Side::Buy, 1001)] {
+            harness.query(1, Query::Buy("V".to_string(), order)).await;
+            match rx.recv().await {
+                Some(QueryResponse::Error { code: ErrorCode::InvalidOrder, message }) => assert_eq!(message, "above maximum quantity"),
+                _ => panic!("expected the order to be rejected"),
+            }
+        }
+        assert!(harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap().get_buy_orders(5).is_empty());
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 1000))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+    }
+
+    /// Tests that posting and cancelling orders without trading gives a high order-to-trade ratio.
+    #[tokio::test]
+    async fn test_order_stats() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx = harness.connect(1).await;
+
+        for _ in 0..10 {
+            harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 1))).await;
+            assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+            harness.query(1, Query::CancelNewest("V".to_string(), Side::Buy)).await;
+            assert!(matches!(rx.recv().await, Some(QueryResponse::OrderCancelled { .. })));
+        }
+
+        harness.query(1, Query::OrderStats(None)).await;
+        match rx.recv().await {
+            Some(QueryResponse::OrderStats { user_id: 1, stats }) => {
+                assert_eq!((stats.placed, stats.cancelled, stats.traded), (10, 10, 0));
+                assert_eq!(stats.order_to_trade_ratio(), 10.0);
+            }
+            _ => panic!("expected the connection's order stats"),
+        }
+
+        // Other users' stats are only visible to admins.
+        harness.query(1, Query::OrderStats(Some(2))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::Unauthorized, .. })));
+    }
+
+    /// Tests that a quote update replaces the maker's old quotes and leaves other users' orders alone.
+    #[tokio::test]
+    async fn test_quote_update() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut maker = harness.connect(1).await;
+        let mut other = harness.connect(2).await;
+
+        for (id, query) in [
+            (1, Query::Buy("V".to_string(), Order::new(1, 98.0, 10))),
+            (1, Query::Sell("V".to_string(), Order::new(1, 102.0, 10))),
+            (2, Query::Buy("V".to_string(), Order::new(2, 97.0, 5))),
+        ] {
+            harness.query(id, query).await;
+        }
+        maker.recv().await.unwrap();
+        maker.recv().await.unwrap();
+        other.recv().await.unwrap();
+
+        let bids = vec![Order::new(1, 99.0, 20), Order::new(1, 98.5, 30)];
+        let asks = vec![Order::new(1, 101.0, 20)];
+        let query = Query::QuoteUpdate { symbol: "V".to_string(), bids, asks };
+        harness.query(1, query).await;
+        match maker.recv().await {
+            Some(QueryResponse::QuotesReplaced { bid_ids, ask_ids }) => {
+                let prices = |ids: Vec<u64>| -> Vec<f64> { ids.into_iter().map(|id| harness.market.get_order(id).unwrap().get_price()).collect() };
+                assert_eq!(prices(bid_ids), vec![99.0, 98.5]);
+                assert_eq!(prices(ask_ids), vec![101.0]);
+            }
+            _ => panic!("expected the quotes to be replaced"),
+        }
+
+        let stock = harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap();
+        assert_eq!(stock.get_buy_orders(5), vec![(99.0, 20), (98.5, 30), (97.0, 5)]);
+        assert_eq!(stock.get_sell_orders(5), vec![(101.0, 20)]);
+    }
+
+    /// Tests that every leg of a quote update is checked like a new order, with the quotes it replaces holding nothing
+    /// back, and that a failing leg rejects the whole update and leaves the old quotes in place.
+    #[tokio::test]
+    async fn test_quote_update_entry_checks() {
+        let mut harness = TestMarket::new(Config { starting_cash: 10_000.0, starting_shares: 50, ..Config::default() });
+        let mut maker = harness.connect(1).await;
+        let symbol = Symbol::try_from("V").unwrap();
+        let quotes = |bids: Vec<Order>, asks: Vec<Order>| Query::QuoteUpdate { symbol: "V".to_string(), bids, asks };
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 98.0, 100))).await;
+        maker.recv().await.unwrap();
+        harness.query(1, quotes(vec![Order::new(1, 99.0, 100)], vec![Order::new(1, 101.0, 50)])).await;
+        assert!(matches!(maker.recv().await, Some(QueryResponse::QuotesReplaced { .. })));
+
+        let unaffordable = quotes(vec![Order::new(1, 99.0, 60), Order::new(1, 98.0, 50)], vec![]);
+        harness.query(1, unaffordable).await;
+        assert!(matches!(maker.recv().await, Some(QueryResponse::Error { code: ErrorCode::InsufficientFunds, .. })));
+        harness.query(1, quotes(vec![], vec![Order::new(1, 101.0, 30), Order::new(1, 102.0, 30)])).await;
+        assert!(matches!(maker.recv().await, Some(QueryResponse::Error { code: ErrorCode::InsufficientShares, .. })));
+
+        harness.market.get_stock_mut(&symbol).unwrap().set_halted(true);
+        harness.query(1, quotes(vec![Order::new(1, 99.5, 10)], vec![])).await;
+        assert!(matches!(maker.recv().await, Some(QueryResponse::Error { code: ErrorCode::TradingHalted, .. })));
+
+        let stock = harness.market.get_stock(&symbol).unwrap();
+        assert_eq!(stock.get_buy_orders(5), vec![(99.0, 100)]);
+        assert_eq!(stock.get_sell_orders(5), vec![(101.0, 50)]);
+    }
+
+    /// Tests that a second order within the cooldown is rejected, while other users are unaffected.
+    #[tokio::test]
+    async fn test_order_cooldown() {
+        let mut harness = TestMarket::new(Config { order_cooldown_ms: Some(60_000), ..stocked_config() });
+        let mut rx_1 = harness.connect(1).await;
+        let mut rx_2 = harness.connect(2).await;
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        assert!(matches!(rx_1.recv().await, Some(QueryResponse::OrderPosted { .. })));
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 110.0, 5))).await;
+        assert!(matches!(rx_1.recv().await, Some(QueryResponse::Error { code: ErrorCode::TooFast, .. })));
+        harness.query(2, Query::Sell("V".to_string(), Order::new(2, 110.0, 5))).await;
+        assert!(matches!(rx_2.recv().await, Some(QueryResponse::OrderPosted { .. })));
+
+        assert_eq!(harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap().get_sell_orders(5), vec![(110.0, 5)]);
+    }
+
+    /// Tests that connections are still torn down when nothing is listening for the shutdown signal.
+    #[tokio::test]
+    async fn test_close_connections_without_receivers() {
+        let (shutdown_signal_tx, shutdown_signal_rx) = watch::channel(false);
+        drop(shutdown_signal_rx);
+
+        let (socket, mut client) = tokio::io::duplex(64);
+        let mut connection_future_set = task::JoinSet::new();
+        connection_future_set.spawn(async move {
+            // Holds the socket open until the task is aborted.
+            let _socket = socket;
+            std::future::pending::<Result<(), (usize, std::io::Error)>>().await
+        });
+
+        time::timeout(time::Duration::from_secs(1), close_connections(&shutdown_signal_tx, connection_future_set))
+            .await
+            .expect("connections should be aborted when the shutdown signal can't be sent");
+        // The socket was dropped along with the task.
+        assert_eq!(client.read(&mut [0; 8]).await.unwrap(), 0);
+    }
+
+    /// Tests that only the creator's working orders are reported as existing.
+    #[tokio::test]
+    async fn test_order_exists() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx_1 = harness.connect(1).await;
+        let mut rx_2 = harness.connect(2).await;
+
+        let mut ids = Vec::new();
+        for query in [
+            Query::Buy("V".to_string(), Order::new(1, 90.0, 5)),
+            Query::Buy("V".to_string(), Order::new(1, 95.0, 5)),
+            Query::Sell("V".to_string(), Order::new(1, 110.0, 5)),
+        ] {
+            harness.query(1, query).await;
+            ids.push(posted_id(rx_1.recv().await));
+        }
+
+        harness.query(1, Query::CancelNewest("V".to_string(), Side::Buy)).await;
+        rx_1.recv().await.unwrap();
+        harness.query(2, Query::Buy("V".to_string(), Order::new(2, 110.0, 5))).await;
+        rx_2.recv().await.unwrap();
+        harness.market.resolve();
+
+        for (&order_id, expected) in ids.iter().zip([true, false, false]) {
+            harness.query(1, Query::OrderExists(order_id)).await;
+            assert!(matches!(rx_1.recv().await, Some(QueryResponse::OrderExists { exists }) if exists == expected));
+        }
+
+        harness.query(2, Query::OrderExists(ids[0])).await;
+        assert!(matches!(rx_2.recv().await, Some(QueryResponse::OrderExists { exists: false })));
+    }
+
+    /// Tests that orders are rejected outside trading hours by default, and that queued orders only match once the
+    /// market opens.
+    #[tokio::test]
+    async fn test_trading_schedule() {
+        use chrono::{NaiveTime, TimeZone, Utc};
+        use types::MockClock;
+
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2024, 1, 1, 8, 0, 0).unwrap());
+        let schedule = TradingSchedule::new(NaiveTime::from_hms_opt(9, 30, 0).unwrap(), NaiveTime::from_hms_opt(16, 0, 0).unwrap());
+        let mut harness = TestMarket::new(stocked_config());
+        harness.market.set_clock(Box::new(clock.clone()));
+        harness.market.set_schedule(Some(schedule));
+
+        let mut rx = harness.connect(1).await;
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::MarketClosed, .. })));
+
+        harness.config = Config { closed_order_policy: ClosedOrderPolicy::Queue, ..Config::default() };
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderQueued { .. })));
+        let mut seller = harness.connect(2).await;
+        harness.query(2, Query::Sell("V".to_string(), Order::new(2, 100.0, 5))).await;
+        assert!(matches!(seller.recv().await, Some(QueryResponse::OrderQueued { .. })));
+        assert!(harness.market.resolve().is_empty());
+
+        clock.set(Utc.with_ymd_and_hms(2024, 1, 1, 9, 30, 0).unwrap());
+        let trades: usize = harness.market.resolve().iter().map(|(_, trades)| trades.len()).sum();
+        assert_eq!(trades, 1);
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+    }
+
+    /// Tests that orders rest for as long as the market's clock says they have, that expired ones count as cancelled by
+    /// their creator, and that the book's age is measured on the same clock.
+    #[tokio::test]
+    async fn test_expire_orders() {
+        use chrono::{TimeZone, Utc};
+        use types::MockClock;
+
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap());
+        let mut harness = TestMarket::new(stocked_config());
+        harness.market.set_clock(Box::new(clock.clone()));
+        let mut rx = harness.connect(1).await;
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        let old_id = posted_id(rx.recv().await);
+        clock.set(Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 30).unwrap());
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 101.0, 5))).await;
+        let new_id = posted_id(rx.recv().await);
+
+        clock.set(Utc.with_ymd_and_hms(2024, 1, 1, 10, 1, 10).unwrap());
+        expire_orders(&mut harness.market, &harness.connections, chrono::Duration::seconds(60)).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderExpired { order_id }) if order_id == old_id));
+        assert!(harness.market.get_order(old_id).is_none() && harness.market.get_order(new_id).is_some());
+        assert_eq!(harness.market.get_user(1).unwrap().get_stats().cancelled, 1);
+
+        clock.set(Utc.with_ymd_and_hms(2024, 1, 1, 10, 1, 15).unwrap());
+        harness.query(1, Query::BookAge("V".to_string())).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::BookAge { age_ms: 5000 })));
+    }
+
+    /// Tests that the session is timed by the market's clock.
+    #[tokio::test]
+    async fn test_session_info() {
+        use chrono::{TimeZone, Utc};
+        use types::MockClock;
+
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+        let clock = MockClock::new(start);
+        let mut harness = TestMarket::new(stocked_config());
+        harness.market.set_clock(Box::new(clock.clone()));
+        let mut rx = harness.connect(1).await;
+
+        clock.set(Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 45).unwrap());
+        harness.query(1, Query::SessionInfo).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::SessionInfo { started, elapsed_secs: 45, .. }) if started == start));
+    }
+
+    /// Tests that admins can see the active limits but never the admin token.
+    #[tokio::test]
+    async fn test_config_snapshot() {
+        let mut harness = TestMarket::new(Config { admin_token: Some("hunter2".to_string()), max_price: Some(500.0), ..stocked_config() });
+        let mut rx = harness.connect(1).await;
+
+        harness.query(1, Query::Admin(AdminQuery::Config)).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::Unauthorized, .. })));
+
+        harness.query(1, Query::AdminLogin("hunter2".to_string())).await;
+        rx.recv().await.unwrap();
+        harness.query(1, Query::Admin(AdminQuery::Config)).await;
+        let json = rx.recv().await.unwrap().to_json();
+        assert!(!json.contains("hunter2"));
+
+        let response: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let snapshot = &response["config"];
+        assert_eq!(snapshot["admin_token"], "<redacted>");
+        assert_eq!(snapshot["max_price"], 500.0);
+        assert_eq!(snapshot["tick_interval_ms"], 10);
+        assert_eq!(snapshot["rounding"], "half_up");
+        assert_eq!(snapshot["listener_address"], "127.0.0.1:8080");
+    }
+
+    /// Tests that privileged queries are answered on the admin port and for connections that logged in with the admin
+    /// token, and refused for other trading connections.
+    #[tokio::test]
+    async fn test_admin_port() {
+        let mut harness = TestMarket::new(Config { admin_token: Some("hunter2".to_string()), ..stocked_config() });
+        let mut trader = harness.connect(1).await;
+        let (tx, mut admin) = mpsc::channel(32);
+        harness.query(2, Query::AdminConnect(tx)).await;
+        assert!(matches!(admin.recv().await, Some(QueryResponse::Connected)));
+        let mut logged_in = harness.connect(3).await;
+        harness.query(3, Query::AdminLogin("hunter2".to_string())).await;
+        assert!(matches!(logged_in.recv().await, Some(QueryResponse::AdminGranted)));
+
+        let halt = || Query::Admin(AdminQuery::Halt("V".to_string(), true));
+        harness.query(1, halt()).await;
+        assert!(matches!(trader.recv().await, Some(QueryResponse::Error { code: ErrorCode::Unauthorized, .. })));
+        harness.query(2, halt()).await;
+        assert!(matches!(admin.recv().await, Some(QueryResponse::SymbolHalted { symbol, halted: true }) if symbol == "V"));
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        assert!(matches!(trader.recv().await, Some(QueryResponse::Error { code: ErrorCode::TradingHalted, .. })));
+
+        let list = |symbol: &str| Query::Admin(AdminQuery::ListStock(symbol.to_string(), "Xanthe".to_string()));
+        harness.query(1, list("X")).await;
+        assert!(matches!(trader.recv().await, Some(QueryResponse::Error { code: ErrorCode::Unauthorized, .. })));
+        harness.query(2, list("X")).await;
+        assert!(matches!(admin.recv().await, Some(QueryResponse::StockAdded)));
+        harness.query(3, list("XX")).await;
+        assert!(matches!(logged_in.recv().await, Some(QueryResponse::StockAdded)));
+
+        for (admin_id, rx) in [(2, &mut admin), (3, &mut logged_in)] {
+            harness.query(1, Query::Sell("ORT".to_string(), Order::new(1, 120.0, 5))).await;
+            let order_id = posted_id(trader.recv().await);
+            harness.query(1, Query::Admin(AdminQuery::CancelOrder(order_id))).await;
+            assert!(matches!(trader.recv().await, Some(QueryResponse::Error { code: ErrorCode::Unauthorized, .. })));
+            harness.query(admin_id, Query::Admin(AdminQuery::CancelOrder(order_id))).await;
+            assert!(matches!(trader.recv().await, Some(QueryResponse::OrderCancelled { order_id: cancelled }) if cancelled == order_id));
+            assert!(matches!(rx.recv().await, Some(QueryResponse::OrderCancelled { order_id: cancelled }) if cancelled == order_id));
+            assert!(harness.market.get_order(order_id).is_none());
+        }
+
+        harness.query(2, Query::Admin(AdminQuery::Config)).await;
+        assert!(matches!(admin.recv().await, Some(QueryResponse::Config { .. })));
+        harness.query(2, Query::Admin(AdminQuery::OrderEntryHalt(true))).await;
+        assert!(matches!(admin.recv().await, Some(QueryResponse::OrderEntryHalted { halted: true })));
+    }
+
+    /// Tests that orders placed while a symbol is halted are rejected, and resting orders only match once it resumes.
+    #[tokio::test]
+    async fn test_trading_halt() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut buyer = harness.connect(1).await;
+        let mut seller = harness.connect(2).await;
+        let (tx, mut admin) = mpsc::channel(32);
+        harness.query(3, Query::AdminConnect(tx)).await;
+        admin.recv().await.unwrap();
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        buyer.recv().await.unwrap();
+        harness.query(2, Query::Sell("V".to_string(), Order::new(2, 100.0, 5))).await;
+        seller.recv().await.unwrap();
+
+        let halt = Query::from_json(r#"{"type": "halt", "symbol": "V"}"#, 3).unwrap();
+        harness.query(3, halt).await;
+        assert!(matches!(admin.recv().await, Some(QueryResponse::SymbolHalted { halted: true, .. })));
+        harness.query(2, Query::Sell("V".to_string(), Order::new(2, 99.0, 5))).await;
+        assert!(matches!(seller.recv().await, Some(QueryResponse::Error { code: ErrorCode::TradingHalted, .. })));
+        assert!(harness.market.resolve().iter().all(|(_, trades)| trades.is_empty()));
+        let symbol = harness.market.parse_symbol("V").unwrap();
+        assert_eq!(harness.market.get_stock(&symbol).unwrap().get_resting_order_count(), 2);
+
+        let resume = Query::from_json(r#"{"type": "resume", "symbol": "V"}"#, 3).unwrap();
+        harness.query(3, resume).await;
+        assert!(matches!(admin.recv().await, Some(QueryResponse::SymbolHalted { halted: false, .. })));
+        let trades: usize = harness.market.resolve().iter().map(|(_, trades)| trades.len()).sum();
+        assert_eq!(trades, 1);
+    }
+
+    /// Tests that the book query lists every resting order with its owner and time, up to the configured limit.
+    #[tokio::test]
+    async fn test_book() {
+        let mut harness = TestMarket::new(Config { max_book_orders: 2, ..stocked_config() });
+        let mut rx = harness.connect(1).await;
+
+        for query in [
+            Query::Buy("V".to_string(), Order::new(1, 99.0, 5)),
+            Query::Sell("V".to_string(), Order::new(1, 101.0, 7)),
+        ] {
+            harness.query(1, query).await;
+            rx.recv().await.unwrap();
+        }
+        harness.query(1, Query::Book("V".to_string())).await;
+        let response = rx.recv().await.unwrap();
+        let json: serde_json::Value = serde_json::from_str(&response.to_json()).unwrap();
+        assert_eq!(json["bids"][0]["creator_id"], 1);
+        assert_eq!(json["bids"][0]["price"], 99.0);
+        assert_eq!(json["asks"][0]["quantity"], 7);
+        assert!(json["asks"][0]["time"].is_string());
+        assert_eq!(json["truncated"], false);
+
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 102.0, 1))).await;
+        rx.recv().await.unwrap();
+        harness.query(1, Query::Book("V".to_string())).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Book { bids, asks, truncated: true }) if bids.len() + asks.len() == 2));
+    }
+
+    /// Tests that halting order entry refuses new orders but still serves queries and cancels.
+    #[tokio::test]
+    async fn test_order_entry_halt() {
+        let mut harness = TestMarket::new(Config { admin_token: Some("hunter2".to_string()), ..stocked_config() });
+        let mut rx = harness.connect(1).await;
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        rx.recv().await.unwrap();
+        harness.query(1, Query::Admin(AdminQuery::OrderEntryHalt(true))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::Unauthorized, .. })));
+        harness.query(1, Query::AdminLogin("hunter2".to_string())).await;
+        rx.recv().await.unwrap();
+        harness.query(1, Query::Admin(AdminQuery::OrderEntryHalt(true))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderEntryHalted { halted: true })));
+
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 110.0, 5))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::OrderEntryDisabled, .. })));
+        harness.query(1, Query::BuyOrders("V".to_string(), 5)).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::QueriedOrders { orders }) if orders == vec![(100.0, 5)]));
+        harness.query(1, Query::CancelOldest("V".to_string(), Side::Buy)).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderCancelled { .. })));
+
+        harness.query(1, Query::Admin(AdminQuery::OrderEntryHalt(false))).await;
+        rx.recv().await.unwrap();
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 110.0, 5))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+    }
+
+    /// Tests that a repriced order moves to its new level and queues behind orders already there.
+    #[tokio::test]
+    async fn test_reprice() {
+        let mut harness = TestMarket::new(Config { max_price: Some(200.0), ..stocked_config() });
+        let mut rx_1 = harness.connect(1).await;
+        let mut rx_2 = harness.connect(2).await;
+
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 100.0, 5))).await;
+        let order_id = posted_id(rx_1.recv().await);
+        harness.query(2, Query::Sell("V".to_string(), Order::new(2, 101.0, 5))).await;
+        rx_2.recv().await.unwrap();
+
+        harness.query(2, Query::Reprice(order_id, 101.0)).await;
+        assert!(matches!(rx_2.recv().await, Some(QueryResponse::Error { code: ErrorCode::OrderNotFound, .. })));
+        harness.query(1, Query::Reprice(order_id, 250.0)).await;
+        assert!(matches!(rx_1.recv().await, Some(QueryResponse::Error { code: ErrorCode::InvalidOrder, .. })));
+        harness.query(1, Query::Reprice(order_id, 101.0)).await;
+        assert!(matches!(
+            rx_1.recv().await,
+            Some(QueryResponse::OrderRepriced { order_id: repriced_id, price, quantity: 5 }) if repriced_id == order_id && price == 101.0
+        ));
+
+        let symbol = Symbol::try_from("V").unwrap();
+        assert_eq!(harness.market.get_stock(&symbol).unwrap().get_sell_orders(5), vec![(101.0, 10)]);
+        let stock = harness.market.get_stock_mut(&symbol).unwrap();
+        stock.add_buy_order(Order::new(3, 101.0, 5));
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].seller_id, 2);
+    }
+
+    /// Tests that counterparties get full fills either way, and that prints are only published to the tape when the
+    /// routing policy calls for it.
+    #[tokio::test]
+    async fn test_fill_routing() {
+        let mut harness = TestMarket::new(Config { fill_routing: FillRouting::Tape, ..stocked_config() });
+        let (tape_tx, mut tape_rx) = broadcast::channel(TAPE_CAPACITY);
+        let mut buyer = harness.connect(1).await;
+        let mut seller = harness.connect(2).await;
+        let mut bystander = harness.connect(3).await;
+
+        for routing in [FillRouting::Tape, FillRouting::Counterparties] {
+            harness.config.fill_routing = routing;
+            harness.query(2, Query::Sell("V".to_string(), Order::new(2, 100.0, 5))).await;
+            seller.recv().await.unwrap();
+            harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+            buyer.recv().await.unwrap();
+            resolve_trades(&mut harness.market, &harness.connections, &harness.config, &tape_tx, &mut None).await;
+
+            for rx in [&mut buyer, &mut seller] {
+                assert!(matches!(rx.recv().await, Some(QueryResponse::ExecutedTrade(trade)) if trade.buyer_id == 1 && trade.seller_id == 2));
+            }
+            match routing {
+                FillRouting::Tape => {
+                    let print = tape_rx.try_recv().unwrap();
+                    assert_eq!((print.symbol.as_str(), print.price, print.quantity), ("V", 100.0, 5));
+                }
+                FillRouting::Counterparties => assert!(tape_rx.try_recv().is_err()),
+            }
+            assert!(bystander.try_recv().is_err());
+        }
+    }
+
+    /// Tests that placing orders reserves cash and shares, and cancelling them releases it.
+    #[tokio::test]
+    async fn test_reservations() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx = harness.connect(1).await;
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        let buy_id = posted_id(rx.recv().await);
+        harness.query(1, Query::Sell("ORT".to_string(), Order::new(1, 20.0, 3))).await;
+        rx.recv().await.unwrap();
+
+        harness.query(1, Query::Reservations).await;
+        let reservations: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap().to_json()).unwrap();
+        assert_eq!(reservations["cash"], 500.0);
+        assert_eq!(reservations["shares"]["ORT"], 3);
+        assert_eq!(reservations["orders"][0]["order_id"], buy_id);
+        assert_eq!(reservations["orders"][0]["cash"], 500.0);
+
+        harness.query(1, Query::CancelOldest("V".to_string(), Side::Buy)).await;
+        rx.recv().await.unwrap();
+        harness.query(1, Query::Reservations).await;
+        let reservations: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap().to_json()).unwrap();
+        assert_eq!(reservations["cash"], 0.0);
+        assert_eq!(reservations["orders"].as_array().unwrap().len(), 1);
+    }
+
+    /// Tests that an echo round-trips unchanged, unicode included, without reaching the market.
+    #[tokio::test]
+    async fn test_echo() {
+        let (tx, mut market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (mut client, server_side) = tokio::io::duplex(1024);
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, ConnectionOptions::default()));
+        socket_tx.send(QueryResponse::Connected).await.unwrap();
+
+        let payload = "héllo, 世界 🚀 \"quoted\"";
+        let query = format!("{}\n", serde_json::json!({"type": "echo", "payload": payload}));
+        client.write_all(query.as_bytes()).await.unwrap();
+
+        let mut client = BufReader::new(client);
+        assert_eq!(next_frame(&mut client).await.unwrap(), QueryResponse::Connected.to_json());
+        let echoed: serde_json::Value = serde_json::from_str(&next_frame(&mut client).await.unwrap()).unwrap();
+        assert_eq!(echoed["payload"], payload);
+        assert!(market_rx.try_recv().is_err(), "echoes must not reach the market");
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    /// Tests that a rejected order shows up in its user's rejections, and not in anyone else's.
+    #[tokio::test]
+    async fn test_my_rejections() {
+        let mut harness = TestMarket::new(Config { max_price: Some(500.0), ..stocked_config() });
+        let mut rx_1 = harness.connect(1).await;
+        let mut rx_2 = harness.connect(2).await;
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        rx_1.recv().await.unwrap();
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 600.0, 5))).await;
+        assert!(matches!(rx_1.recv().await, Some(QueryResponse::Error { code: ErrorCode::InvalidOrder, .. })));
+
+        harness.query(1, Query::MyRejections(10)).await;
+        match rx_1.recv().await {
+            Some(QueryResponse::Rejections { rejections }) => {
+                assert_eq!(rejections.len(), 1);
+                assert_eq!(rejections[0].reason, RejectReason::InvalidOrder("above maximum price".to_string()));
+                assert_eq!(rejections[0].query, "buy V 5 @ 600.00");
+            }
+            _ => panic!("expected the user's rejections"),
+        }
+
+        harness.query(2, Query::MyRejections(10)).await;
+        assert!(matches!(rx_2.recv().await, Some(QueryResponse::Rejections { rejections }) if rejections.is_empty()));
+    }
+
+    /// Tests that a write to a client that stopped reading times out, and the market then drops the connection and its
+    /// orders.
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let (tx, mut market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        // The client never reads, so writes stall once this buffer is full.
+        let (_client, server_side) = tokio::io::duplex(64);
+        let write_timeout = Some(time::Duration::from_millis(50));
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, ConnectionOptions { write_timeout, ..ConnectionOptions::default() }));
+        socket_tx.send(QueryResponse::Connected).await.unwrap();
+        socket_tx.send(QueryResponse::Echo { payload: "x".repeat(256) }).await.unwrap();
+
+        let (id, e) = time::timeout(time::Duration::from_secs(1), handler)
+            .await
+            .expect("a stuck write should end the handler")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!((id, e.kind()), (1, std::io::ErrorKind::TimedOut));
+        let (id, query) = market_rx.recv().await.unwrap();
+        assert!(matches!(query, Query::ConnectionDead));
+
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx = harness.connect(id).await;
+        harness.query(id, Query::Buy("V".to_string(), Order::new(id, 100.0, 5))).await;
+        rx.recv().await.unwrap();
+
+        harness.query(id, Query::ConnectionDead).await;
+        assert!(!harness.connections.contains_key(&id));
+        assert!(harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap().get_buy_orders(5).is_empty());
+    }
+
+    /// Tests that telling a client which stopped reading that the market has closed still gives up after the write
+    /// timeout.
+    #[tokio::test]
+    async fn test_market_closed_write_timeout() {
+        let (tx, _market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (_client, server_side) = tokio::io::duplex(32);
+        let write_timeout = Some(time::Duration::from_millis(50));
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, ConnectionOptions { write_timeout, ..ConnectionOptions::default() }));
+        socket_tx.send(QueryResponse::Connected).await.unwrap();
+        drop(socket_tx);
+
+        let (id, e) = time::timeout(time::Duration::from_secs(1), handler)
+            .await
+            .expect("a stuck write should end the handler")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!((id, e.kind()), (1, std::io::ErrorKind::TimedOut));
+    }
+
+    /// Tests that a write to a client that has already gone is reported to the market, just like a timed out one.
+    #[tokio::test]
+    async fn test_write_broken_pipe() {
+        let (tx, mut market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (client, server_side) = tokio::io::duplex(1024);
+        drop(client);
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, ConnectionOptions::default()));
+        socket_tx.send(QueryResponse::Connected).await.unwrap();
+
+        let (id, e) = handler.await.unwrap().unwrap_err();
+        assert_eq!((id, e.kind()), (1, std::io::ErrorKind::BrokenPipe));
+        assert!(matches!(market_rx.recv().await, Some((1, Query::ConnectionDead))));
+    }
+
+    /// Tests that an order's tag is echoed when it is posted and on each of its fills, but not to its counterparties.
+    #[tokio::test]
+    async fn test_order_tag() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut seller = harness.connect(1).await;
+        let mut buyers = [harness.connect(2).await, harness.connect(3).await];
+
+        let sell = Query::from_json(r#"{"type": "sell", "symbol": "V", "price": 100.0, "quantity": 10, "tag": "strat-a"}"#, 1).unwrap();
+        harness.query(1, sell).await;
+        let posted = seller.recv().await.unwrap().to_json();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&posted).unwrap()["tag"], "strat-a");
+
+        for (buyer_id, quantity) in [(2, 4), (3, 6)] {
+            let rx = &mut buyers[buyer_id - 2];
+            harness.query(buyer_id, Query::Buy("V".to_string(), Order::new(buyer_id, 100.0, quantity))).await;
+            rx.recv().await.unwrap();
+            for (symbol, trades) in harness.market.resolve() {
+                for trade in trades {
+                    notify_trade(&symbol.to_string(), trade, &harness.connections).await;
+                }
+            }
+
+            let fill: serde_json::Value = serde_json::from_str(&seller.recv().await.unwrap().to_json()).unwrap();
+            assert_eq!((fill["quantity"].as_u64(), &fill["seller_tag"]), (Some(quantity as u64), &serde_json::json!("strat-a")));
+            let fill = rx.recv().await.unwrap().to_json();
+            assert!(!fill.contains("strat-a"));
+        }
+    }
+
+    /// Tests that only an order's creator can cancel it by ID.
+    #[tokio::test]
+    async fn test_cancel() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx_1 = harness.connect(1).await;
+        let mut rx_2 = harness.connect(2).await;
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        let order_id = posted_id(rx_1.recv().await);
+
+        harness.query(2, Query::Cancel("V".to_string(), order_id)).await;
+        assert!(matches!(rx_2.recv().await, Some(QueryResponse::Error { code: ErrorCode::OrderNotFound, .. })));
+        harness.query(1, Query::Cancel("V".to_string(), order_id)).await;
+        assert!(matches!(rx_1.recv().await, Some(QueryResponse::OrderCancelled { order_id: cancelled }) if cancelled == order_id));
+        assert!(harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap().get_buy_orders(5).is_empty());
+    }
+
+    /// Tests that each posted order is told a distinct ID, increasing with each post.
+    #[tokio::test]
+    async fn test_order_ids() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx = harness.connect(1).await;
+
+        let mut order_ids = Vec::new();
+        for price in [90.0, 91.0, 92.0, 93.0] {
+            harness.query(1, Query::Buy("V".to_string(), Order::new(1, price, 1))).await;
+            let posted: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap().to_json()).unwrap();
+            order_ids.push(posted["order_id"].as_u64().unwrap());
+        }
+        assert!(order_ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    /// Tests that a short message read after a longer one isn't mixed up with what is left of the longer one.
+    #[tokio::test]
+    async fn test_read_after_longer_message() {
+        let (tx, mut market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (client, server_side) = tokio::io::duplex(1024);
+        let mut client = BufReader::new(client);
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, ConnectionOptions::default()));
+        socket_tx.send(QueryResponse::Connected).await.unwrap();
+
+        let long = format!(r#"{{"type": "echo", "payload": "{}"}}"#, "é".repeat(100)) + "\n";
+        client.write_all(long.as_bytes()).await.unwrap();
+        assert_eq!(next_frame(&mut client).await.unwrap(), QueryResponse::Connected.to_json());
+        assert!(next_frame(&mut client).await.unwrap().contains("é"));
+
+        // The stray byte decodes to a longer replacement character, so the decoded message is longer than the read.
+        let mut short = br#"{"type": "schema", "note": ""#.to_vec();
+        short.push(0xff);
+        short.extend_from_slice(b"\"}\n");
+        client.write_all(&short).await.unwrap();
+        let (_, query) = time::timeout(time::Duration::from_secs(1), market_rx.recv()).await.unwrap().unwrap();
+        assert!(matches!(query, Query::Schema));
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    /// Tests that several queries sent in one write are each parsed, in order.
+    #[tokio::test]
+    async fn test_pipelined_queries() {
+        let (tx, mut market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (mut client, server_side) = tokio::io::duplex(1024);
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, ConnectionOptions::default()));
+        socket_tx.send(QueryResponse::Connected).await.unwrap();
+
+        let queries = concat!(
+            r#"{"type": "buy", "symbol": "V", "price": 100.0, "quantity": 1}"#, "\n",
+            "\n",
+            r#"{"type": "sell", "symbol": "V", "price": 101.0, "quantity": 2}"#, "\r\n",
+            r#"{"type": "schema"}"#, "\n",
+        );
+        client.write_all(queries.as_bytes()).await.unwrap();
+
+        assert!(matches!(market_rx.recv().await, Some((1, Query::Buy(..)))));
+        assert!(matches!(market_rx.recv().await, Some((1, Query::Sell(..)))));
+        assert!(matches!(market_rx.recv().await, Some((1, Query::Schema))));
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+        assert!(matches!(market_rx.try_recv(), Ok((1, Query::Disconnect))));
+        assert!(market_rx.try_recv().is_err());
+    }
+
+    /// Tests that a query split across two reads is held until its newline arrives.
+    #[tokio::test]
+    async fn test_query_split_across_reads() {
+        let (tx, mut market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (mut client, server_side) = tokio::io::duplex(1024);
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, ConnectionOptions::default()));
+        socket_tx.send(QueryResponse::Connected).await.unwrap();
+
+        client.write_all(br#"{"type": "buy", "symbol": "V", "#).await.unwrap();
+        time::sleep(time::Duration::from_millis(20)).await;
+        assert!(market_rx.try_recv().is_err(), "half a query must not be parsed");
+
+        client.write_all(concat!(r#""price": 100.0, "quantity": 3}"#, "\n").as_bytes()).await.unwrap();
+        let (_, query) = time::timeout(time::Duration::from_secs(1), market_rx.recv()).await.unwrap().unwrap();
+        let Query::Buy(_, order) = query else { panic!("expected a buy") };
+        assert_eq!(order.get_quantity(), 3);
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    /// Tests that a query just under the frame limit is read over several reads, and one over it closes the connection
+    /// with an error instead of being parsed.
+    #[tokio::test]
+    async fn test_frame_limit() {
+        let (tx, mut market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (mut client, server_side) = tokio::io::duplex(1024);
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, ConnectionOptions::default()));
+        socket_tx.send(QueryResponse::Connected).await.unwrap();
+        let padded = |len: usize| {
+            let query = r#"{"type": "buy", "symbol": "V", "price": 100.0, "quantity": 3}"#;
+            format!("{}{}\n", query, " ".repeat(len - query.len()))
+        };
+
+        client.write_all(padded(MAX_FRAME_BYTES).as_bytes()).await.unwrap();
+        let (_, query) = time::timeout(time::Duration::from_secs(1), market_rx.recv()).await.unwrap().unwrap();
+        assert!(matches!(query, Query::Buy(_, order) if order.get_quantity() == 3));
+
+        client.write_all(padded(MAX_FRAME_BYTES + 1).as_bytes()).await.unwrap();
+        handler.await.unwrap().unwrap();
+        assert!(matches!(market_rx.try_recv(), Ok((1, Query::Disconnect))));
+        assert!(market_rx.try_recv().is_err());
+
+        let mut client = BufReader::new(client);
+        assert_eq!(next_frame(&mut client).await.unwrap(), QueryResponse::Connected.to_json());
+        let response: serde_json::Value = serde_json::from_str(&next_frame(&mut client).await.unwrap()).unwrap();
+        assert_eq!(response["code"], ErrorCode::PayloadTooLarge.code());
+        assert!(next_frame(&mut client).await.is_none());
+    }
+
+    /// Tests that a line longer than a frame is rejected once and the query after it still parses.
+    #[test]
+    fn test_line_too_long() {
+        let mut lines = LineBuffer::default();
+        lines.extend(&[b'x'; MAX_FRAME_BYTES + 1]);
+        assert!(matches!(lines.next_line(), Some(Err(QueryParseError::PayloadTooLarge(MAX_FRAME_BYTES)))));
+        lines.extend(&[b'x'; MAX_FRAME_BYTES]);
+        assert!(lines.next_line().is_none());
+
+        lines.extend(b"xx\n{}\n");
+        assert_eq!(lines.next_line().unwrap().unwrap(), "{}");
+        assert!(lines.next_line().is_none());
+    }
+
+    /// Tests that buys are checked against cash out of the box, and refused once they and the user's resting buys cost
+    /// more than it.
+    #[tokio::test]
+    async fn test_insufficient_funds() {
+        let mut harness = TestMarket::new(Config::default());
+        let mut rx = harness.connect(1).await;
+
+        // Users start with 100000.00 by default.
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 150.0, 1000))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::InsufficientFunds, .. })));
+
+        for _ in 0..2 {
+            harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 500))).await;
+            assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+        }
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 1))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::InsufficientFunds, .. })));
+        assert_eq!(harness.market.get_user(1).unwrap().get_rejections(10).len(), 2);
+    }
+
+    /// Tests that a market buy is costed at the asks it would sweep, and refused if that is more than the user's cash.
+    #[tokio::test]
+    async fn test_market_buy_insufficient_funds() {
+        let mut harness = TestMarket::new(stocked_config());
+        harness.market.set_starting_cash_cents(100_000);
+        let mut rx = harness.connect(1).await;
+        let stock = harness.market.get_stock_mut(&Symbol::try_from("V").unwrap()).unwrap();
+        stock.add_sell_order(Order::new(2, 100.0, 5));
+        stock.add_sell_order(Order::new(2, 150.0, 5));
+
+        // Five at 100.00 and two at 150.00 come to 800.00 of the 1000.00 the buyer has.
+        harness.query(1, Query::Buy("V".to_string(), Order::market(1, Side::Buy, 7))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+        harness.market.resolve();
+
+        harness.query(1, Query::Buy("V".to_string(), Order::market(1, Side::Buy, 2))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::InsufficientFunds, .. })));
+        harness.query(1, Query::Buy("V".to_string(), Order::market(1, Side::Buy, 1))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+    }
+
+    /// Tests that short sells are refused out of the box, as users start with no shares, and that a sell is allowed once
+    /// the user holds the shares to deliver.
+    #[tokio::test]
+    async fn test_insufficient_shares() {
+        let mut harness = TestMarket::new(Config::default());
+        let mut buyer = harness.connect(1).await;
+        let mut seller = harness.connect(2).await;
+
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 100.0, 5))).await;
+        assert!(matches!(buyer.recv().await, Some(QueryResponse::Error { code: ErrorCode::InsufficientShares, .. })));
+
+        let symbol = Symbol::try_from("V").unwrap();
+        harness.market.get_user_mut(2).get_balances_mut().set_available_shares(symbol, 5);
+        harness.query(2, Query::Sell("V".to_string(), Order::new(2, 100.0, 5))).await;
+        assert!(matches!(seller.recv().await, Some(QueryResponse::OrderPosted { .. })));
+        harness.query(2, Query::Sell("V".to_string(), Order::new(2, 100.0, 1))).await;
+        assert!(matches!(seller.recv().await, Some(QueryResponse::Error { code: ErrorCode::InsufficientShares, .. })));
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 5))).await;
+        buyer.recv().await.unwrap();
+        harness.market.resolve();
+        harness.market.start_tick();
+        assert_eq!(harness.market.get_user(1).unwrap().get_balances().get_available_holdings()[&symbol], 5);
+        assert_eq!(harness.market.get_user(2).unwrap().get_balances().get_available_holdings()[&symbol], 0);
+
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 100.0, 5))).await;
+        assert!(matches!(buyer.recv().await, Some(QueryResponse::OrderPosted { .. })));
+    }
+
+    /// Tests that a new user's portfolio is their starting cash and positions, and that fills move cash and shares
+    /// between users.
+    #[tokio::test]
+    async fn test_portfolio() {
+        let mut harness = TestMarket::new(Config { starting_cash: 1000.0, starting_positions: vec![("V".to_string(), 4)], ..Config::default() });
+        let mut buyer = harness.connect(1).await;
+        let mut seller = harness.connect(2).await;
+
+        harness.query(1, Query::Portfolio).await;
+        assert!(matches!(buyer.recv().await, Some(QueryResponse::Portfolio { cash, positions }) if cash == 1000.0 && positions == vec![("V".to_string(), 4)]));
+
+        for price in [100.0, 101.0] {
+            harness.query(2, Query::Sell("V".to_string(), Order::new(2, price, 2))).await;
+            seller.recv().await.unwrap();
+        }
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 101.0, 4))).await;
+        buyer.recv().await.unwrap();
+        assert_eq!(harness.market.resolve().into_iter().map(|(_, trades)| trades.len()).sum::<usize>(), 2);
+
+        harness.query(1, Query::Portfolio).await;
+        let portfolio: serde_json::Value = serde_json::from_str(&buyer.recv().await.unwrap().to_json()).unwrap();
+        assert_eq!(portfolio["cash"], 598.0);
+        assert_eq!(portfolio["positions"], serde_json::json!([{"symbol": "V", "shares": 8}]));
+
+        harness.query(2, Query::Portfolio).await;
+        let portfolio: serde_json::Value = serde_json::from_str(&seller.recv().await.unwrap().to_json()).unwrap();
+        assert_eq!(portfolio["cash"], 1402.0);
+        assert_eq!(portfolio["positions"], serde_json::json!([]));
+    }
+
+    /// Tests that every listed stock is returned with its name.
+    #[tokio::test]
+    async fn test_symbols() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx = harness.connect(1).await;
+
+        harness.query(1, Query::from_json(r#"{"type": "symbols"}"#, 1).unwrap()).await;
+        let symbols: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap().to_json()).unwrap();
+        assert_eq!(
+            symbols["symbols"],
+            serde_json::json!([
+                {"symbol": "ORT", "name": "Orchard de Rosa et Tulipan"},
+                {"symbol": "V", "name": "Vulyenne"},
+            ])
+        );
+    }
+
+    /// Tests that an admin can list a new stock and trade it, but not list a symbol twice.
+    #[tokio::test]
+    async fn test_add_stock() {
+        let mut harness = TestMarket::new(Config { admin_token: Some("hunter2".to_string()), ..stocked_config() });
+        let mut rx = harness.connect(1).await;
+
+        let add_stock = Query::from_json(r#"{"type": "add_stock", "symbol": "X", "name": "Xanthe"}"#, 1).unwrap();
+        harness.query(1, add_stock).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::Unauthorized, .. })));
+
+        harness.query(1, Query::AdminLogin("hunter2".to_string())).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::AdminGranted)));
+        harness.query(1, Query::Admin(AdminQuery::ListStock("X".to_string(), "Xanthe".to_string()))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::StockAdded)));
+        harness.query(1, Query::Buy("X".to_string(), Order::new(1, 10.0, 5))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+
+        harness.query(1, Query::Admin(AdminQuery::ListStock("X".to_string(), "Xanthe again".to_string()))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::StockAlreadyListed, .. })));
+        let stock = harness.market.get_stock(&Symbol::try_from("X").unwrap()).unwrap();
+        assert_eq!((stock.get_name(), stock.get_buy_orders(5)), ("Xanthe", vec![(10.0, 5)]));
+    }
+
+    /// Tests that `-t` sets the tick interval, and that a missing or invalid value falls back to the default.
+    #[test]
+    fn test_parse_tick_interval() {
+        let parse = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string()));
+
+        assert_eq!(parse(&["d1x", "-t", "50"]).tick_interval_ms, 50);
+        assert_eq!(parse(&["d1x", "-t", "fast"]).tick_interval_ms, 10);
+        assert_eq!(parse(&["d1x", "-t", "-5"]).tick_interval_ms, 10);
+        assert_eq!(parse(&["d1x", "-t", "0"]).tick_interval_ms, 10);
+        assert_eq!(parse(&["d1x", "-t"]).tick_interval_ms, 10);
+
+        let config = parse(&["d1x", "-p", "0.0.0.0:9000", "-t", "25"]);
+        assert_eq!(config.listener_address, "0.0.0.0:9000");
+        assert_eq!(config.tick_interval_ms, 25);
+    }
+
+    /// Tests that each side of a partial fill is told how much of its own order is left, and nothing of the other's.
+    #[tokio::test]
+    async fn test_trade_remaining_quantity() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut buyer = harness.connect(1).await;
+        let mut seller = harness.connect(2).await;
+
+        harness.query(2, Query::Sell("V".to_string(), Order::new(2, 100.0, 40))).await;
+        seller.recv().await.unwrap();
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 100))).await;
+        buyer.recv().await.unwrap();
+        for (symbol, trades) in harness.market.resolve() {
+            for trade in trades {
+                notify_trade(&symbol.to_string(), trade, &harness.connections).await;
+            }
+        }
+
+        let Some(QueryResponse::ExecutedTrade(trade)) = buyer.recv().await else { panic!("buyer should be sent the trade") };
+        assert_eq!((trade.quantity, trade.buy_remaining, trade.sell_remaining), (40, Some(60), None));
+        let json: serde_json::Value = serde_json::from_str(&QueryResponse::ExecutedTrade(trade).to_json()).unwrap();
+        assert_eq!(json["buy_remaining"], 60);
+        assert!(json.get("sell_remaining").is_none());
+
+        let Some(QueryResponse::ExecutedTrade(trade)) = seller.recv().await else { panic!("seller should be sent the trade") };
+        assert_eq!((trade.buy_remaining, trade.sell_remaining), (None, Some(0)));
+    }
+
+    /// Tests that a connection whose market side goes away is told in full that the market closed, and then ends
+    /// instead of waiting on the closed channel.
+    #[tokio::test]
+    async fn test_market_closed_ends_connection() {
+        let (tx, _market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (client, server_side) = tokio::io::duplex(1024);
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, ConnectionOptions::default()));
+        socket_tx.send(QueryResponse::Connected).await.unwrap();
+        drop(socket_tx);
+
+        time::timeout(time::Duration::from_secs(1), handler).await.unwrap().unwrap().unwrap();
+        let mut client = BufReader::new(client);
+        assert_eq!(next_frame(&mut client).await.unwrap(), QueryResponse::Connected.to_json());
+        assert_eq!(next_frame(&mut client).await.unwrap(), QueryResponse::from(ErrorCode::MarketShutDown).to_json());
+        assert!(next_frame(&mut client).await.is_none());
+    }
+
+    /// Tests that a client closing its connection is reported to the market, which forgets the connection and cancels
+    /// its orders.
+    #[tokio::test]
+    async fn test_disconnect() {
+        let (tx, mut market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (client, server_side) = tokio::io::duplex(1024);
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, ConnectionOptions::default()));
+        socket_tx.send(QueryResponse::Connected).await.unwrap();
+        let mut client = BufReader::new(client);
+        assert_eq!(next_frame(&mut client).await.unwrap(), QueryResponse::Connected.to_json());
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+        let (id, query) = market_rx.recv().await.unwrap();
+        assert!(matches!(query, Query::Disconnect));
+
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx = harness.connect(id).await;
+        harness.query(id, Query::Sell("V".to_string(), Order::new(id, 100.0, 5))).await;
+        rx.recv().await.unwrap();
+
+        harness.query(id, query).await;
+        assert!(!harness.connections.contains_key(&id));
+        assert!(harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap().get_sell_orders(5).is_empty());
+        assert_eq!(harness.market.get_user(id).unwrap().get_stats().cancelled, 1);
+    }
+
+    /// Tests that a disconnected connection's orders stay in the book when configured to keep them.
+    #[tokio::test]
+    async fn test_keep_orders_on_disconnect() {
+        let mut harness = TestMarket::new(parse_args(["--starting-shares".to_string(), "1000".to_string(), "--keep-orders-on-disconnect".to_string()].into_iter()));
+        let mut rx = harness.connect(1).await;
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 100.0, 5))).await;
+        rx.recv().await.unwrap();
+
+        harness.query(1, Query::Disconnect).await;
+        assert!(!harness.connections.contains_key(&1));
+        assert_eq!(harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap().get_sell_orders(5), vec![(100.0, 5)]);
+    }
+
+    /// Tests that responses to a numbered query carry its `req_id`, and that other responses don't.
+    #[tokio::test]
+    async fn test_req_id() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx = harness.connect(1).await;
+
+        let mut respond = async |json: &str| {
+            harness.query(1, Query::from_json(json, 1).unwrap()).await;
+            serde_json::from_str::<serde_json::Value>(&rx.recv().await.unwrap().to_json()).unwrap()
+        };
+
+        let symbols = respond(r#"{"type": "symbols", "req_id": 7}"#).await;
+        assert_eq!(symbols["response"], "symbols");
+        assert_eq!(symbols["req_id"], 7);
+
+        let error = respond(r#"{"type": "ohlc", "symbol": "NOPE", "req_id": 8}"#).await;
+        assert_eq!(error["response"], "error");
+        assert_eq!(error["req_id"], 8);
+
+        let unnumbered = respond(r#"{"type": "symbols"}"#).await;
+        assert!(unnumbered.get("req_id").is_none());
+
+        assert!(matches!(
+            Query::from_json(r#"{"type": "symbols", "req_id": "seven"}"#, 1),
+            Err(QueryParseError::MissingField("req_id"))
+        ));
+    }
+
+    /// Tests that a ping is answered through the market loop with the client's timestamp.
+    #[tokio::test]
+    async fn test_ping() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx = harness.connect(1).await;
+
+        let ping = Query::from_json(r#"{"type": "ping", "ts": 1712345678901}"#, 1).unwrap();
+        harness.query(1, ping).await;
+        let pong = rx.recv().await.unwrap();
+        assert!(matches!(pong, QueryResponse::Pong { ts: 1712345678901 }));
+        assert_eq!(pong.to_json(), r#"{"response":"pong","ts":1712345678901}"#);
+
+        // Queries from connections the market doesn't know about are still ignored.
+        harness.query(2, Query::Ping(1)).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    /// Tests that the depth query sums every level of each side of the book.
+    #[tokio::test]
+    async fn test_depth() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx = harness.connect(1).await;
+
+        let stock = harness.market.get_stock_mut(&Symbol::try_from("V").unwrap()).unwrap();
+        for level in 1..=6 {
+            stock.add_buy_order(Order::new(2, 100.0 - level as f64, level));
+        }
+        stock.add_buy_order(Order::new(3, 99.0, 4));
+        stock.add_sell_order(Order::new(2, 101.0, 8));
+
+        harness.query(1, Query::from_json(r#"{"type": "depth", "symbol": "V"}"#, 1).unwrap()).await;
+        let depth: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap().to_json()).unwrap();
+        assert_eq!(
+            depth,
+            serde_json::json!({"response": "depth", "total_bid_qty": 25, "total_ask_qty": 8, "bid_levels": 6, "ask_levels": 1})
+        );
+
+        harness.query(1, Query::Depth("NOPE".to_string())).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::SymbolNotFound, .. })));
+    }
+
+    /// Tests that a subscriber is sent prints of trades between other connections, only in the stocks it subscribed to.
+    #[tokio::test]
+    async fn test_subscribe() {
+        let mut harness = TestMarket::new(stocked_config());
+        let (tape_tx, _) = broadcast::channel(TAPE_CAPACITY);
+
+        let (tx, mut market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (client, server_side) = tokio::io::duplex(1024);
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, tape_tx.subscribe(), server_side, shutdown_rx, ConnectionOptions::default()));
+        harness.query(1, Query::Connect(socket_tx)).await;
+
+        let mut client = BufReader::new(client);
+        client.write_all(concat!(r#"{"type": "subscribe", "symbol": "V"}"#, "\n").as_bytes()).await.unwrap();
+        let (id, query) = market_rx.recv().await.unwrap();
+        harness.query(id, query).await;
+        assert_eq!(next_frame(&mut client).await.unwrap(), QueryResponse::Connected.to_json());
+        assert_eq!(next_frame(&mut client).await.unwrap(), QueryResponse::Subscribed { symbol: "V".to_string() }.to_json());
+
+        let _rx_2 = harness.connect(2).await;
+        let _rx_3 = harness.connect(3).await;
+        for symbol in ["ORT", "V"] {
+            harness.query(2, Query::Sell(symbol.to_string(), Order::new(2, 100.0, 5))).await;
+            harness.query(3, Query::Buy(symbol.to_string(), Order::new(3, 100.0, 5))).await;
+            for (symbol, trades) in harness.market.resolve() {
+                for trade in trades {
+                    publish_trade(&tape_tx, &symbol.to_string(), &trade);
+                }
+            }
+        }
+
+        // Only the print for V is sent, so it is the next response; the ORT print was never subscribed to.
+        let print = QueryResponse::TradePrint { symbol: "V".to_string(), price: 100.0, quantity: 5 }.to_json();
+        assert_eq!(next_frame(&mut client).await.unwrap(), print);
+        assert!(!print.contains("buyer_id"));
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    /// Tests that queries past a connection's rate limit are refused without reaching the market.
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let (tx, mut market_rx) = mpsc::channel(32);
+        let (socket_tx, socket_rx) = mpsc::channel(32);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (mut client, server_side) = tokio::io::duplex(1024);
+        let options = ConnectionOptions { max_queries_per_sec: 3, ..ConnectionOptions::default() };
+        let handler = task::spawn(connection_handler(1, tx, socket_rx, broadcast::channel(1).1, server_side, shutdown_rx, options));
+        socket_tx.send(QueryResponse::Connected).await.unwrap();
+
+        client.write_all(concat!(r#"{"type": "symbols"}"#, "\n").repeat(4).as_bytes()).await.unwrap();
+        client.write_all(concat!(r#"{"type": "symbols", "req_id": 9}"#, "\n").as_bytes()).await.unwrap();
+        let rate_limited = QueryResponse::from(ErrorCode::RateLimited);
+        let mut client = BufReader::new(client);
+        assert_eq!(next_frame(&mut client).await.unwrap(), QueryResponse::Connected.to_json());
+        assert_eq!(next_frame(&mut client).await.unwrap(), rate_limited.to_json());
+        assert!(rate_limited.to_json().contains(r#""code":401"#));
+        assert_eq!(next_frame(&mut client).await.unwrap(), rate_limited.with_req_id(Some(9)).to_json());
+
+        for _ in 0..3 {
+            assert!(matches!(market_rx.recv().await, Some((1, Query::Symbols))));
+        }
+        assert!(market_rx.try_recv().is_err());
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    /// Tests that connections are accepted up to the limit, and that a full server tells the client before closing.
+    #[tokio::test]
+    async fn test_connection_limit() {
+        assert!(should_accept(1000, None));
+        assert!(should_accept(0, Some(2)));
+        assert!(should_accept(1, Some(2)));
+        assert!(!should_accept(2, Some(2)));
+        assert!(!should_accept(0, Some(0)));
+
+        let (client, mut server_side) = tokio::io::duplex(1024);
+        refuse_connection(&mut server_side, None).await.unwrap();
+        let mut client = BufReader::new(client);
+        let refusal: serde_json::Value = serde_json::from_str(&next_frame(&mut client).await.unwrap()).unwrap();
+        assert_eq!((&refusal["response"], &refusal["code"]), (&serde_json::json!("error"), &serde_json::json!(402)));
+    }
+
+    /// Tests that every stock in a stocks file is listed with its starting orders in the configured tick size, that a
+    /// stock with a starting order off the tick grid is skipped, and that the default stock is listed instead if the file
+    /// can't be read.
+    #[test]
+    fn test_initial_stocks() {
+        let path = std::env::temp_dir().join(format!("d1x-stocks-{}.json", std::process::id()));
+        std::fs::write(&path, r#"[
+            {"symbol": "V", "name": "Vulyenne", "orders": [
+                {"side": "buy", "price": 99.0, "quantity": 5},
+                {"side": "sell", "price": 101.0, "quantity": 3}
+            ]},
+            {"symbol": "ORT", "name": "Orchard de Rosa et Tulipan"},
+            {"symbol": "OFF", "name": "Off Grid", "orders": [{"side": "buy", "price": 99.03, "quantity": 1}]}
+        ]"#).unwrap();
+        let config = Config { stocks_path: Some(path.to_string_lossy().into_owned()), tick_size: 0.05, ..Config::default() };
+
+        let mut market = Market::new();
+        market.extend_stocks(initial_stocks(&config));
+        std::fs::remove_file(&path).unwrap();
+
+        let v = market.get_stock(&Symbol::try_from("V").unwrap()).unwrap();
+        assert_eq!(v.get_name(), "Vulyenne");
+        assert_eq!(v.get_buy_orders(5), vec![(99.0, 5)]);
+        assert_eq!(v.get_sell_orders(5), vec![(101.0, 3)]);
+        let ort = market.get_stock(&Symbol::try_from("ORT").unwrap()).unwrap();
+        assert_eq!(ort.get_name(), "Orchard de Rosa et Tulipan");
+        assert!(ort.get_buy_orders(5).is_empty() && ort.get_sell_orders(5).is_empty());
+        assert_eq!(ort.get_symbol_info().tick_size, 0.05);
+        assert!(market.get_stock(&Symbol::try_from("OFF").unwrap()).is_none());
+
+        // The file is gone now, so only the default stock is listed.
+        let stocks = initial_stocks(&config);
+        assert_eq!(stocks.len(), 1);
+        assert_eq!(stocks[0].1.get_name(), "Vulyenne");
+    }
+
+    /// Tests that the last resolve before the market closes still executes crossing orders and notifies whoever is
+    /// connected, even if the other counterparty's socket has already gone.
+    #[tokio::test]
+    async fn test_final_resolve() {
+        let mut harness = TestMarket::new(stocked_config());
+        let (tape_tx, _) = broadcast::channel(TAPE_CAPACITY);
+        let mut seller_rx = harness.connect(1).await;
+        let mut buyer_rx = harness.connect(2).await;
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 100.0, 5))).await;
+        harness.query(2, Query::Buy("V".to_string(), Order::new(2, 100.0, 5))).await;
+        seller_rx.recv().await.unwrap();
+        buyer_rx.recv().await.unwrap();
+        drop(buyer_rx);
+
+        resolve_trades(&mut harness.market, &harness.connections, &harness.config, &tape_tx, &mut None).await;
+        match seller_rx.try_recv() {
+            Ok(QueryResponse::ExecutedTrade(trade)) => assert_eq!((trade.seller_id, trade.buyer_id, trade.quantity), (1, 2, 5)),
+            _ => panic!("Expected the seller to be sent the trade."),
+        }
+        let stock = harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap();
+        assert!(stock.get_buy_orders(5).is_empty() && stock.get_sell_orders(5).is_empty());
+    }
+
+    /// Tests that limit orders priced off the stock's tick grid are rejected, that stocks are listed with the configured
+    /// tick size but each keeps its own, and that the symbol info reports the stock's tick size.
+    #[tokio::test]
+    async fn test_tick_size() {
+        let mut harness = TestMarket::new(parse_args(["--starting-shares".to_string(), "1000".to_string(), "--tick-size".to_string(), "0.05".to_string()].into_iter()));
+        assert_eq!(harness.config.tick_size, 0.05);
+        harness.market = Market::new();
+        harness.market.extend_stocks(initial_stocks(&harness.config));
+        configure_market(&mut harness.market, &harness.config);
+        harness.market.add_stock(Symbol::try_from("ORT").unwrap(), Stock::new("Orchard de Rosa et Tulipan")).unwrap();
+        let mut rx = harness.connect(1).await;
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.03, 1))).await;
+        match rx.recv().await {
+            Some(QueryResponse::Error { code: ErrorCode::InvalidOrder, message }) => assert_eq!(message, "price not a multiple of the tick size"),
+            _ => panic!("expected the order to be rejected"),
+        }
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 100.05, 1))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+        assert_eq!(harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap().get_sell_orders(5), vec![(100.05, 1)]);
+
+        harness.query(1, Query::SymbolInfo("V".to_string())).await;
+        match rx.recv().await {
+            Some(QueryResponse::SymbolInfo(info)) => assert_eq!(info.tick_size, 0.05),
+            _ => panic!("expected symbol info"),
+        }
+
+        harness.query(1, Query::Sell("ORT".to_string(), Order::new(1, 100.03, 1))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+        harness.query(1, Query::SymbolInfo("ORT".to_string())).await;
+        match rx.recv().await {
+            Some(QueryResponse::SymbolInfo(info)) => assert_eq!(info.tick_size, 0.01),
+            _ => panic!("expected symbol info"),
+        }
+
+        let config = parse_args(["--tick-size".to_string(), "0.001".to_string()].into_iter());
+        assert_eq!(config.tick_size, 0.01);
+    }
+
+    /// Tests that once a stock has traded, limit orders priced outside the band around the last trade are rejected,
+    /// and that any price is accepted before then.
+    #[tokio::test]
+    async fn test_price_band() {
+        let mut harness = TestMarket::new(parse_args(["--starting-shares".to_string(), "1000".to_string(), "--price-band".to_string(), "10".to_string()].into_iter()));
+        let mut rx = harness.connect(1).await;
+
+        // Nothing has traded yet, so there's no price to measure the band from.
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 100.0, 1))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+        let mut buyer_rx = harness.connect(2).await;
+        harness.query(2, Query::Buy("V".to_string(), Order::new(2, 1000.0, 1))).await;
+        assert!(matches!(buyer_rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+        harness.market.resolve();
+        let close = harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap().get_ohlc().3;
+        assert_eq!(close, Some(100.0));
+
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 109.0, 1))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderPosted { .. })));
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 111.0, 1))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::PriceOutOfBand, .. })));
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 85.0, 1))).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::PriceOutOfBand, .. })));
+        assert_eq!(harness.market.get_stock(&Symbol::try_from("V").unwrap()).unwrap().get_buy_orders(5), vec![(109.0, 1)]);
+    }
+
+    /// Tests that only the aggressor is told its limit price along with the execution price.
+    #[tokio::test]
+    async fn test_aggressor_limit_private() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut seller = harness.connect(1).await;
+        let mut buyer = harness.connect(2).await;
+        harness.query(1, Query::Sell("V".to_string(), Order::new(1, 150.0, 5))).await;
+        seller.recv().await.unwrap();
+        harness.query(2, Query::Buy("V".to_string(), Order::new(2, 151.0, 5))).await;
+        buyer.recv().await.unwrap();
+        for (symbol, trades) in harness.market.resolve() {
+            for trade in trades {
+                notify_trade(&symbol.to_string(), trade, &harness.connections).await;
+            }
+        }
+
+        let buyer_json: serde_json::Value = serde_json::from_str(&buyer.recv().await.unwrap().to_json()).unwrap();
+        assert_eq!((&buyer_json["price"], &buyer_json["aggressor_limit"]), (&serde_json::json!(150.0), &serde_json::json!(151.0)));
+        let seller_json: serde_json::Value = serde_json::from_str(&seller.recv().await.unwrap().to_json()).unwrap();
+        assert_eq!(seller_json["price"], 150.0);
+        assert!(seller_json.get("aggressor_limit").is_none());
+    }
+
+    /// Tests that the status query reports the market's listed stocks, open connections and resting orders.
+    #[tokio::test]
+    async fn test_status() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx = harness.connect(1).await;
+        let _rx_2 = harness.connect(2).await;
+        for price in [99.0, 98.0] {
+            harness.query(1, Query::Buy("V".to_string(), Order::new(1, price, 1))).await;
+            rx.recv().await.unwrap();
+        }
+
+        harness.query(1, Query::from_json(r#"{"type": "status"}"#, 1).unwrap()).await;
+        let status: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap().to_json()).unwrap();
+        assert_eq!(status["response"], "status");
+        assert_eq!((&status["stocks"], &status["users"], &status["resting_orders"]), (&serde_json::json!(2), &serde_json::json!(2), &serde_json::json!(2)));
+        assert!(status["uptime_secs"].is_u64());
+        assert_eq!(status["fees_collected"], 0.0);
+    }
+
+    /// Tests that a reconnect token can't be resumed while the connection using it is still open, and can once it
+    /// closes.
+    #[tokio::test]
+    async fn test_resume_token_in_use() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut first = harness.connect(1).await;
+        let mut second = harness.connect(2).await;
+        let resume = || Query::from_json(r#"{"type": "resume", "token": "alice"}"#, 1).unwrap();
+
+        harness.query(1, resume()).await;
+        assert!(matches!(first.recv().await, Some(QueryResponse::Resumed { restored: false })));
+        harness.query(2, resume()).await;
+        assert!(matches!(second.recv().await, Some(QueryResponse::Error { code: ErrorCode::ResumeRefused, .. })));
+
+        harness.query(1, Query::Disconnect).await;
+        harness.query(2, resume()).await;
+        assert!(matches!(second.recv().await, Some(QueryResponse::Resumed { restored: true })));
+    }
+
+    /// Tests that a new user starts with exactly the cash and positions given on the command line.
+    #[test]
+    fn test_starting_funding() {
+        let args = ["-b", "100000", "--starting-position", "V:500", "--starting-position", "nonsense"];
+        let config = parse_args(args.into_iter().map(String::from));
+        assert_eq!(config.starting_cash, 100000.0);
+        assert_eq!(config.starting_positions, vec![("V".to_string(), 500)]);
+
+        let mut market = test_market();
+        configure_market(&mut market, &config);
+        market.get_user_mut(7);
+        let balances = market.get_user(7).unwrap().get_balances();
+        assert_eq!(balances.get_available_cash_cents(), 10_000_000);
+        let (v, ort) = (Symbol::try_from("V").unwrap(), Symbol::try_from("ORT").unwrap());
+        assert_eq!(balances.get_available_holdings().get(&v), Some(&500));
+        assert_eq!(balances.get_available_holdings().get(&ort), Some(&0));
+
+        // Stocks without a position are held at the starting shares, which is none by default, so can't be sold short.
+        assert!(market.can_deliver(7, v, &Order::new(7, 100.0, 500)));
+        assert!(!market.can_deliver(7, v, &Order::new(7, 100.0, 501)));
+        assert!(!market.can_deliver(7, ort, &Order::new(7, 100.0, 1)));
+    }
+
+    /// Tests that modifying an order down in quantity keeps its place in the queue, that changing its price moves it
+    /// to the back, and that only its creator can modify it.
+    #[tokio::test]
+    async fn test_modify_order() {
+        let mut harness = TestMarket::new(stocked_config());
+        let mut rx_1 = harness.connect(1).await;
+        let mut rx_2 = harness.connect(2).await;
+        let symbol = Symbol::try_from("V").unwrap();
+        let first_id = harness.market.add_order(symbol, Side::Sell, Order::new(1, 100.0, 5)).unwrap();
+        harness.market.add_order(symbol, Side::Sell, Order::new(2, 100.0, 5));
+        let modify = |order_id, price, quantity| Query::Modify { symbol: "V".to_string(), order_id, price, quantity };
+
+        harness.query(2, modify(first_id, 100.0, 3)).await;
+        assert!(matches!(rx_2.recv().await, Some(QueryResponse::Error { code: ErrorCode::OrderNotFound, .. })));
+        harness.query(1, modify(first_id, 100.0, 3)).await;
+        assert!(matches!(
+            rx_1.recv().await,
+            Some(QueryResponse::OrderModified { order_id, price, quantity: 3 }) if order_id == first_id && price == 100.0
+        ));
+        let stock = harness.market.get_stock_mut(&symbol).unwrap();
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 8)]);
+        stock.add_buy_order(Order::new(3, 100.0, 3));
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(trades.iter().map(|trade| (trade.seller_id, trade.quantity)).collect::<Vec<_>>(), vec![(1, 3)]);
+
+        let first_id = harness.market.add_order(symbol, Side::Sell, Order::new(1, 101.0, 5)).unwrap();
+        harness.query(1, modify(first_id, 100.0, 5)).await;
+        assert!(matches!(rx_1.recv().await, Some(QueryResponse::OrderModified { .. })));
+        let stock = harness.market.get_stock_mut(&symbol).unwrap();
+        stock.add_buy_order(Order::new(3, 100.0, 5));
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(trades.iter().map(|trade| (trade.seller_id, trade.quantity)).collect::<Vec<_>>(), vec![(2, 5)]);
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 5)]);
+    }
+
+    /// Tests that modifying or repricing an order goes through the same checks as entering it, with funds measured net
+    /// of the order being replaced.
+    #[tokio::test]
+    async fn test_modify_entry_checks() {
+        let mut harness = TestMarket::new(stocked_config());
+        harness.market.set_starting_cash_cents(100_000);
+        let mut rx = harness.connect(1).await;
+        harness.query(1, Query::Buy("V".to_string(), Order::new(1, 100.0, 8))).await;
+        let order_id = posted_id(rx.recv().await);
+        let modify = |price, quantity| Query::Modify { symbol: "V".to_string(), order_id, price, quantity };
+
+        // The 800.00 already held back by the order is available to its replacement, but 1100.00 is too much.
+        harness.query(1, modify(100.0, 10)).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::OrderModified { quantity: 10, .. })));
+        harness.query(1, modify(100.0, 11)).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::InsufficientFunds, .. })));
+        harness.query(1, Query::Reprice(order_id, 110.0)).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::InsufficientFunds, .. })));
+
+        harness.market.get_stock_mut(&Symbol::try_from("V").unwrap()).unwrap().set_halted(true);
+        harness.query(1, modify(90.0, 10)).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::TradingHalted, .. })));
+        harness.query(1, Query::Reprice(order_id, 90.0)).await;
+        assert!(matches!(rx.recv().await, Some(QueryResponse::Error { code: ErrorCode::TradingHalted, .. })));
+        assert_eq!(harness.market.get_order(order_id).unwrap().get_price(), 100.0);
+    }
+}
@@ -0,0 +1,58 @@
+use tokio::time::Instant;
+
+/// A token bucket limiting how often a connection can send queries.
+///
+/// The bucket holds up to a second's worth of queries, so a client can burst up to the rate after being idle, and
+/// refills continuously at the rate.
+pub struct RateLimiter {
+    /// Queries allowed per second.
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a full bucket allowing `per_sec` queries per second.
+    pub fn new(per_sec: u32) -> Self {
+        Self { rate: per_sec as f64, tokens: per_sec as f64, last_refill: Instant::now() }
+    }
+
+    /// Takes a token for one query, returning `false` if there are none left.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    /// Tests that a full bucket allows a burst of a second's worth of queries, then refills at the rate.
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(10);
+        let start = limiter.last_refill;
+        assert_eq!((0..15).filter(|_| limiter.try_acquire_at(start)).count(), 10);
+
+        assert!(limiter.try_acquire_at(start + Duration::from_millis(100)));
+        assert!(!limiter.try_acquire_at(start + Duration::from_millis(100)));
+
+        // Idling longer than a second doesn't let more than a second's worth build up.
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..15).filter(|_| limiter.try_acquire_at(later)).count(), 10);
+    }
+}
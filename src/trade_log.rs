@@ -0,0 +1,81 @@
+use crate::types::Trade;
+use chrono::SecondsFormat;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// An append-only record of every executed trade, kept for auditing.
+///
+/// Each trade is written as one line of JSON. Writes are buffered until `flush` is called.
+pub struct TradeLog {
+    writer: BufWriter<File>,
+}
+
+impl TradeLog {
+    /// Opens the log at the given path, creating it if it doesn't exist. Trades are added after any already logged.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { writer: BufWriter::new(file) })
+    }
+
+    /// Adds a trade in the given stock to the log.
+    pub fn record(&mut self, symbol: &str, trade: &Trade) -> io::Result<()> {
+        let line = serde_json::json!({
+            "time": trade.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "symbol": symbol,
+            "buyer_id": trade.buyer_id,
+            "seller_id": trade.seller_id,
+            "price": trade.price,
+            "quantity": trade.quantity,
+        });
+        writeln!(self.writer, "{}", line)
+    }
+
+    /// Writes every trade recorded so far to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Order, SelfTradePrevention, Stock};
+
+    /// Tests that each trade is logged as one line of JSON with its details, after whatever was logged before.
+    #[test]
+    fn test_trade_log() {
+        let path = std::env::temp_dir().join(format!("d1x-trades-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut stock = Stock::new("Vulyenne");
+        for seller_id in 1..=3 {
+            stock.add_sell_order(Order::new(seller_id, 100.0 + seller_id as f64, 1));
+        }
+        stock.add_buy_order(Order::new(4, 110.0, 3));
+        let trades = stock.resolve(0, None, SelfTradePrevention::default());
+        assert_eq!(trades.len(), 3);
+
+        let mut log = TradeLog::open(&path).unwrap();
+        log.record("V", &trades[0]).unwrap();
+        log.flush().unwrap();
+        let mut log = TradeLog::open(&path).unwrap();
+        for trade in &trades[1..] {
+            log.record("V", trade).unwrap();
+        }
+        log.flush().unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        for (line, seller_id) in lines.iter().zip(1..) {
+            assert_eq!(line["symbol"], "V");
+            assert_eq!(line["buyer_id"], 4);
+            assert_eq!(line["seller_id"], seller_id);
+            assert_eq!(line["price"], 100.0 + seller_id as f64);
+            assert_eq!(line["quantity"], 1);
+            assert!(line["time"].as_str().is_some_and(|time| chrono::DateTime::parse_from_rfc3339(time).is_ok()));
+        }
+    }
+}
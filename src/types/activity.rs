@@ -0,0 +1,80 @@
+use super::{json, Symbol};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Number of events kept in the activity feed.
+const ACTIVITY_CAPACITY: usize = 256;
+
+/// Something that happened in the market, as shown in the activity feed.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MarketEvent {
+    /// A trade was executed.
+    Trade {
+        symbol: Symbol,
+        #[serde(serialize_with = "json::price")]
+        price: f64,
+        quantity: usize,
+        #[serde(serialize_with = "json::time")]
+        time: DateTime<Utc>,
+    },
+    /// The best bid or ask of a stock changed. `None` means that side of the book is empty.
+    TopOfBook {
+        symbol: Symbol,
+        #[serde(serialize_with = "json::optional_price")]
+        bid: Option<f64>,
+        #[serde(serialize_with = "json::optional_price")]
+        ask: Option<f64>,
+        #[serde(serialize_with = "json::time")]
+        time: DateTime<Utc>,
+    },
+}
+
+/// A bounded feed of the most recent market events across all stocks.
+///
+/// Once full, the oldest event is dropped for every new one.
+#[derive(Default)]
+pub struct ActivityLog {
+    events: VecDeque<MarketEvent>,
+}
+
+impl ActivityLog {
+    /// Appends an event to the feed.
+    pub fn push(&mut self, event: MarketEvent) {
+        if self.events.len() >= ACTIVITY_CAPACITY {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Returns up to `limit` of the most recent events, oldest first.
+    pub fn recent(&self, limit: usize) -> Vec<MarketEvent> {
+        let skip = self.events.len().saturating_sub(limit);
+        self.events.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that the feed keeps only the most recent events, in order.
+    #[test]
+    fn test_activity_log_is_bounded() {
+        let symbol = Symbol::try_from("V").unwrap();
+        let time = Utc::now();
+        let mut log = ActivityLog::default();
+        for quantity in 0..ACTIVITY_CAPACITY + 10 {
+            log.push(MarketEvent::Trade { symbol, price: 1.0, quantity, time });
+        }
+
+        let recent = log.recent(usize::MAX);
+        assert_eq!(recent.len(), ACTIVITY_CAPACITY);
+        assert_eq!(recent[0], MarketEvent::Trade { symbol, price: 1.0, quantity: 10, time });
+        assert_eq!(
+            log.recent(1),
+            vec![MarketEvent::Trade { symbol, price: 1.0, quantity: ACTIVITY_CAPACITY + 9, time }]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use chrono::{DateTime, Utc};
+
+/// Statistics for a stock over one completed window of time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candle {
+    /// When the window started.
+    pub start: DateTime<Utc>,
+    /// When the window ended.
+    pub end: DateTime<Utc>,
+    /// Time-weighted average spread.
+    ///
+    /// Only the time during which both sides of the book were populated counts, and it is `None` if that never
+    /// happened during the window.
+    pub avg_spread: Option<f64>,
+    /// Price of the first trade in the window, or `None` if nothing traded.
+    pub open: Option<f64>,
+    /// Highest trade price in the window.
+    pub high: Option<f64>,
+    /// Lowest trade price in the window.
+    pub low: Option<f64>,
+    /// Price of the last trade in the window.
+    pub close: Option<f64>,
+}
+
+/// Accumulates a time-weighted average of the spread between samples.
+///
+/// Each sample's spread is assumed to hold until the next sample.
+#[derive(Default)]
+pub struct SpreadSampler {
+    /// The time and spread of the most recent sample.
+    last: Option<(DateTime<Utc>, Option<usize>)>,
+    /// Sum of spread multiplied by the milliseconds it held for.
+    weighted_sum: f64,
+    /// Milliseconds during which there was a spread.
+    weight_ms: i64,
+}
+
+impl SpreadSampler {
+    /// Records the spread at the given time. `None` means one side of the book was empty.
+    pub fn sample(&mut self, now: DateTime<Utc>, spread: Option<usize>) {
+        if let Some((then, Some(last_spread))) = self.last {
+            let elapsed_ms = (now - then).num_milliseconds().max(0);
+            self.weighted_sum += last_spread as f64 * elapsed_ms as f64;
+            self.weight_ms += elapsed_ms;
+        }
+        self.last = Some((now, spread));
+    }
+
+    /// Closes the window at the given time, returning the average spread and starting a new window.
+    ///
+    /// The latest spread carries over into the new window.
+    pub fn finish(&mut self, now: DateTime<Utc>) -> Option<f64> {
+        let spread = self.last.and_then(|(_, spread)| spread);
+        self.sample(now, spread);
+
+        let average = if self.weight_ms > 0 {
+            Some(self.weighted_sum / self.weight_ms as f64)
+        } else {
+            None
+        };
+        self.weighted_sum = 0.0;
+        self.weight_ms = 0;
+        average
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    /// Tests the time weighting, and that windows without a two-sided book average to `None`.
+    #[test]
+    fn test_spread_sampler() {
+        let start = Utc::now();
+        let mut sampler = SpreadSampler::default();
+        sampler.sample(start, Some(200));
+        sampler.sample(start + Duration::seconds(45), Some(100));
+        assert_eq!(sampler.finish(start + Duration::seconds(60)), Some(175.0));
+
+        // The last spread carries over.
+        assert_eq!(sampler.finish(start + Duration::seconds(120)), Some(100.0));
+
+        sampler.sample(start + Duration::seconds(120), None);
+        assert_eq!(sampler.finish(start + Duration::seconds(180)), None);
+    }
+}
@@ -0,0 +1,117 @@
+use serde::{Serialize, Serializer};
+
+/// What went wrong with a query, sent to clients as a number that stays fixed across releases.
+///
+/// Codes are grouped by the hundred: 1xx for requests that couldn't be parsed, 2xx for orders and queries the market
+/// refused, 3xx for admin queries, and 4xx for the connection itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request was not valid JSON.
+    MalformedRequest,
+    /// The request's `type` does not name a known query.
+    UnknownType,
+    /// The request is missing a field its query type needs.
+    MissingField,
+    /// A field of the request is too long.
+    PayloadTooLarge,
+    /// The order can't be placed as described.
+    InvalidOrder,
+    /// The symbol provided was not found.
+    SymbolNotFound,
+    /// The symbol provided was not valid.
+    SymbolInvalid,
+    /// No matching order was found.
+    OrderNotFound,
+    /// Orders aren't accepted outside trading hours.
+    MarketClosed,
+    /// Order entry has been halted by an admin.
+    OrderEntryDisabled,
+    /// The user posted an order too recently to post another.
+    TooFast,
+    /// The user doesn't have the cash to pay for the order, on top of what their resting buy orders hold back.
+    InsufficientFunds,
+    /// The user doesn't hold the shares to deliver for the order, on top of what their resting sell orders hold back.
+    InsufficientShares,
+    /// The order's price is too far from the stock's last trade price.
+    PriceOutOfBand,
+    /// Trading in the stock has been halted by an admin.
+    TradingHalted,
+    /// The query requires admin privileges.
+    Unauthorized,
+    /// The market already lists as many stocks as it is allowed to.
+    StockLimitReached,
+    /// A stock with the requested symbol is already listed.
+    StockAlreadyListed,
+    /// The market has shut down, and the connection is being closed.
+    MarketShutDown,
+    /// The connection sent queries faster than it is allowed to, and the query was dropped.
+    RateLimited,
+    /// The server already has as many connections as it allows, and the new one is being closed.
+    ServerFull,
+    /// The connection can't take on the user its reconnect token identifies.
+    ResumeRefused,
+}
+
+impl ErrorCode {
+    /// Returns the number sent to clients.
+    pub fn code(&self) -> u32 {
+        match self {
+            ErrorCode::MalformedRequest => 100,
+            ErrorCode::UnknownType => 101,
+            ErrorCode::MissingField => 102,
+            ErrorCode::PayloadTooLarge => 103,
+            ErrorCode::InvalidOrder => 200,
+            ErrorCode::SymbolNotFound => 201,
+            ErrorCode::SymbolInvalid => 202,
+            ErrorCode::OrderNotFound => 203,
+            ErrorCode::MarketClosed => 204,
+            ErrorCode::OrderEntryDisabled => 205,
+            ErrorCode::TooFast => 206,
+            ErrorCode::InsufficientFunds => 207,
+            ErrorCode::InsufficientShares => 208,
+            ErrorCode::PriceOutOfBand => 209,
+            ErrorCode::TradingHalted => 210,
+            ErrorCode::Unauthorized => 300,
+            ErrorCode::StockLimitReached => 301,
+            ErrorCode::StockAlreadyListed => 302,
+            ErrorCode::MarketShutDown => 400,
+            ErrorCode::RateLimited => 401,
+            ErrorCode::ServerFull => 402,
+            ErrorCode::ResumeRefused => 403,
+        }
+    }
+
+    /// Returns a description of the error for people, used when there are no details to add.
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::MalformedRequest => "malformed request",
+            ErrorCode::UnknownType => "unknown query type",
+            ErrorCode::MissingField => "missing field",
+            ErrorCode::PayloadTooLarge => "payload too large",
+            ErrorCode::InvalidOrder => "invalid order",
+            ErrorCode::SymbolNotFound => "symbol not found",
+            ErrorCode::SymbolInvalid => "symbol invalid",
+            ErrorCode::OrderNotFound => "order not found",
+            ErrorCode::MarketClosed => "market closed",
+            ErrorCode::OrderEntryDisabled => "order entry disabled",
+            ErrorCode::TooFast => "too fast",
+            ErrorCode::InsufficientFunds => "insufficient funds",
+            ErrorCode::InsufficientShares => "insufficient shares",
+            ErrorCode::PriceOutOfBand => "price out of band",
+            ErrorCode::TradingHalted => "trading halted",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::StockLimitReached => "stock limit reached",
+            ErrorCode::StockAlreadyListed => "stock already listed",
+            ErrorCode::MarketShutDown => "market shut down",
+            ErrorCode::RateLimited => "rate limited",
+            ErrorCode::ServerFull => "server full",
+            ErrorCode::ResumeRefused => "resume refused",
+        }
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.code())
+    }
+}
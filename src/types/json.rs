@@ -0,0 +1,64 @@
+use super::{money, PriceFormat};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serializer;
+use std::cell::Cell;
+
+thread_local! {
+    /// How prices are written by the response being serialized on this thread. Set by `QueryResponse::to_json_with`.
+    pub static PRICE_FORMAT: Cell<PriceFormat> = const { Cell::new(PriceFormat::Number) };
+}
+
+/// Serializes a price in the current `PRICE_FORMAT`.
+pub fn price<S: Serializer>(price: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    match PRICE_FORMAT.get() {
+        PriceFormat::Number => serializer.serialize_f64(*price),
+        PriceFormat::String => serializer.collect_str(&fmt_opt_price(Some(*price))),
+    }
+}
+
+/// Formats a price that may be missing as text, to two decimals, or as `null` if it is missing.
+///
+/// This is how every price-bearing response writes its prices when they are sent as strings, so that a missing one
+/// reads the same in all of them.
+pub fn fmt_opt_price(price: Option<f64>) -> String {
+    match price {
+        Some(price) => format!("{:.2}", price),
+        None => "null".to_string(),
+    }
+}
+
+/// Serializes a price that may be missing, using `null` if it is.
+pub fn optional_price<S: Serializer>(price: &Option<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match price {
+        Some(price) => self::price(price, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serializes an amount of cents as a price.
+pub fn cents<S: Serializer>(cents: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    price(&money::from_cents(*cents), serializer)
+}
+
+/// Serializes an amount of cents that may be negative as a price.
+pub fn signed_cents<S: Serializer>(cents: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    price(&(*cents as f64 / 100.0), serializer)
+}
+
+/// Serializes a time as RFC 3339, to the millisecond.
+pub fn time<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+/// Serializes a time that may be missing, using `null` if it is.
+pub fn optional_time<S: Serializer>(time: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => self::time(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serializes a value as the string it displays as.
+pub fn display<T: std::fmt::Display, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
@@ -1,44 +1,718 @@
+mod activity;
+mod candle;
+mod clock;
+mod error;
+mod json;
+pub mod money;
 mod stock;
 mod query;
+mod rejection;
+mod reservation;
+mod schedule;
+mod schema;
+mod session;
+mod settlement;
+mod snapshot;
 mod user;
 
+pub use activity::*;
+pub use candle::*;
+pub use clock::*;
+pub use error::*;
+pub use money::RoundingMode;
 pub use stock::*;
 pub use query::*;
+pub use rejection::*;
+pub use reservation::*;
+pub use schedule::*;
+pub use schema::*;
+pub use session::*;
+pub use settlement::*;
+pub use snapshot::*;
 pub use user::*;
 
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
 
 pub struct Market {
     stocks: HashMap<Symbol, Stock>,
-    users: HashMap<usize, User>
+    users: HashMap<usize, User>,
+    /// The ID of the user each reconnect token identifies, which is the ID of the connection that last resumed it.
+    tokens: HashMap<String, usize>,
+    session: Session,
+    /// Recent trades and top-of-book changes across all stocks.
+    activity: ActivityLog,
+    /// The best bid and ask of each stock as of the last resolve, used to spot top-of-book changes.
+    top_of_book: HashMap<Symbol, (Option<f64>, Option<f64>)>,
+    /// Maximum number of stocks `add_stock` will list, if limited.
+    max_stocks: Option<usize>,
+    /// How long each candle lasts.
+    candle_interval: Duration,
+    /// How the case of symbols from clients is treated.
+    symbol_case: SymbolCase,
+    /// Resting orders with less than this quantity left are not matched.
+    min_fill_qty: usize,
+    /// What happens when two of a user's orders would trade with each other.
+    self_trade: SelfTradePrevention,
+    /// Daily hours during which orders are matched. The market is always open if this isn't set.
+    schedule: Option<TradingSchedule>,
+    /// Whether new orders are being refused.
+    order_entry_halted: bool,
+    /// Maximum number of trades each stock makes per tick, if limited.
+    max_matches_per_tick: Option<usize>,
+    /// Trades each stock has made so far this tick.
+    matches_this_tick: HashMap<Symbol, usize>,
+    /// Ticks started so far.
+    tick: u64,
+    /// Ticks after a trade before what each side receives becomes available.
+    settle_ticks: u64,
+    /// Cash, in cents, each user starts with.
+    starting_cash_cents: u64,
+    /// Shares of each stock each user starts with.
+    starting_shares: usize,
+    /// Shares of particular stocks each user starts with, overriding `starting_shares` for those stocks.
+    starting_positions: HashMap<Symbol, usize>,
+    /// Fee, in basis points of the notional, charged to the taker of each trade.
+    taker_fee_bps: u32,
+    /// Rebate, in basis points of the notional, paid to the maker of each trade out of the fees collected.
+    maker_rebate_bps: u32,
+    /// Fees collected by the exchange, less rebates paid, in cents.
+    fees_collected_cents: i64,
+    /// How amounts of money that fall between two cents are rounded.
+    rounding: RoundingMode,
+    /// ID the next order added to the market is numbered with, so that an ID identifies an order market-wide.
+    next_order_id: u64,
+    /// Lowest ID no restored user, reconnect token or resting order belongs to, which new connections are numbered from.
+    first_free_user_id: usize,
+    /// Tells the market's time. Every listed stock shares it, so the times in its book agree with the market's.
+    clock: Arc<dyn Clock>,
+    /// When the market was created. Unlike the clock, this always follows real time.
+    started: Instant,
+}
+
+/// Totals across every stock in the market, for a dashboard overview.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct MarketOverview {
+    /// Quantity traded this session.
+    pub volume: usize,
+    /// Trades made this session.
+    pub trades: usize,
+    /// Value, in cents, of every resting order.
+    #[serde(rename = "resting_notional", serialize_with = "json::cents")]
+    pub resting_notional_cents: u64,
+    /// Stocks that have traded this session or have orders resting.
+    pub active_symbols: usize,
+}
+
+/// How a connection took on the user a reconnect token identifies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resumed {
+    /// The token was new, so the connection's own user is now known by it.
+    New,
+    /// The token's user, with their balances and resting orders, was moved to the connection.
+    Restored,
+}
+
+/// Why a stock could not be added to the market.
+#[derive(Debug, PartialEq)]
+pub enum AddStockError {
+    /// The market already lists as many stocks as it is allowed to.
+    LimitReached,
+    /// A stock with the same symbol is already listed.
+    AlreadyListed,
 }
 
 impl Market {
     pub fn new() -> Self {
-        Self { stocks: HashMap::new(), users: HashMap::new() }
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Self {
+            stocks: HashMap::new(),
+            users: HashMap::new(),
+            tokens: HashMap::new(),
+            session: Session::new(clock.now(), None),
+            activity: ActivityLog::default(),
+            top_of_book: HashMap::new(),
+            max_stocks: None,
+            candle_interval: Duration::seconds(60),
+            symbol_case: SymbolCase::default(),
+            min_fill_qty: 0,
+            self_trade: SelfTradePrevention::default(),
+            schedule: None,
+            order_entry_halted: false,
+            max_matches_per_tick: None,
+            matches_this_tick: HashMap::new(),
+            tick: 0,
+            settle_ticks: 0,
+            starting_cash_cents: 0,
+            starting_shares: 0,
+            starting_positions: HashMap::new(),
+            taker_fee_bps: 0,
+            maker_rebate_bps: 0,
+            fees_collected_cents: 0,
+            rounding: RoundingMode::default(),
+            next_order_id: 1,
+            first_free_user_id: 1,
+            clock,
+            started: Instant::now(),
+        }
+    }
+
+    /// Sets how the case of symbols from clients is treated.
+    pub fn set_symbol_case(&mut self, symbol_case: SymbolCase) {
+        self.symbol_case = symbol_case;
+    }
+
+    /// Parses a symbol from a client, normalising its case so that it is stored and looked up consistently.
+    pub fn parse_symbol(&self, symbol: &str) -> Result<Symbol, ()> {
+        self.symbol_case.parse(symbol)
+    }
+
+    /// Sets the quantity below which resting orders are skipped by incoming orders. Skipped orders stay in the book.
+    pub fn set_min_fill_qty(&mut self, min_fill_qty: usize) {
+        self.min_fill_qty = min_fill_qty;
+    }
+
+    /// Sets what happens when two of a user's orders would trade with each other.
+    pub fn set_self_trade_prevention(&mut self, self_trade: SelfTradePrevention) {
+        self.self_trade = self_trade;
+    }
+
+    /// Replaces the clock the market, and every stock it lists, uses to tell the time. The session restarts at the new
+    /// clock's time.
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = Arc::from(clock);
+        self.session.restart(self.clock.now());
+        for stock in self.stocks.values_mut() {
+            stock.set_clock(self.clock.clone());
+        }
+    }
+
+    /// Returns the current time according to the market's clock.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Sets the daily hours during which orders are matched. `None` keeps the market open at all times.
+    pub fn set_schedule(&mut self, schedule: Option<TradingSchedule>) {
+        self.schedule = schedule;
+    }
+
+    /// Returns whether the market is currently within its trading hours.
+    pub fn is_open(&self) -> bool {
+        self.schedule.is_none_or(|schedule| schedule.is_open(self.now()))
+    }
+
+    /// Sets whether new orders are refused. Queries and cancels are unaffected.
+    pub fn set_order_entry_halted(&mut self, halted: bool) {
+        self.order_entry_halted = halted;
+    }
+
+    /// Returns whether new orders are being refused.
+    pub fn is_order_entry_halted(&self) -> bool {
+        self.order_entry_halted
     }
 
-    pub fn add_stock(&mut self, symbol: Symbol, stock: Stock) {
+    /// Sets the maximum number of trades each stock makes per tick. `None` removes the limit.
+    ///
+    /// The cap is per stock, so one large crossing can't hold up matching in other stocks. Orders left crossing when a
+    /// stock hits the cap are matched on later ticks, so orders arriving in the meantime at a better price can trade
+    /// ahead of them.
+    pub fn set_max_matches_per_tick(&mut self, max_matches_per_tick: Option<usize>) {
+        self.max_matches_per_tick = max_matches_per_tick;
+    }
+
+    /// Sets how many ticks after a trade sale proceeds can be spent and bought shares sold. 0 settles immediately.
+    pub fn set_settle_ticks(&mut self, settle_ticks: u64) {
+        self.settle_ticks = settle_ticks;
+    }
+
+    /// Sets the fee charged to the taker, and the rebate paid to the maker, of each trade, in basis points of its
+    /// notional.
+    pub fn set_fees(&mut self, taker_fee_bps: u32, maker_rebate_bps: u32) {
+        self.taker_fee_bps = taker_fee_bps;
+        self.maker_rebate_bps = maker_rebate_bps;
+    }
+
+    /// Returns the fees the exchange has collected, less the rebates it has paid, in cents.
+    pub fn fees_collected_cents(&self) -> i64 {
+        self.fees_collected_cents
+    }
+
+    /// Sets how amounts of money that fall between two cents, like fees, are rounded.
+    pub fn set_rounding(&mut self, rounding: RoundingMode) {
+        self.rounding = rounding;
+    }
+
+    /// Sets the cash, in cents, each new user starts with.
+    pub fn set_starting_cash_cents(&mut self, starting_cash_cents: u64) {
+        self.starting_cash_cents = starting_cash_cents;
+    }
+
+    /// Returns whether the user has the cash to pay for a buy order, on top of what their resting buy orders already
+    /// hold back.
+    ///
+    /// A market order has no price, so it is costed at what it would take to sweep the stock's asks for its quantity
+    /// right now. Any of the orders could take liquidity when it trades, so the taker fee on all of them must be covered
+    /// too. A resting order with the same ID is being replaced by this one, so what it holds back isn't counted.
+    pub fn can_afford(&self, user_id: usize, symbol: Symbol, order: &Order) -> bool {
+        self.has_cash_for(user_id, self.cost_cents(symbol, order), |reservation| reservation.order_id == order.get_id())
+    }
+
+    /// Returns whether the user has the cash to pay for every bid in a quote update for the stock, which replaces all of
+    /// their resting orders in it, so what those hold back isn't counted.
+    pub fn can_afford_quotes(&self, user_id: usize, symbol: Symbol, bids: &[Order]) -> bool {
+        let cost = bids.iter().fold(0u64, |cost, bid| cost.saturating_add(self.cost_cents(symbol, bid)));
+        self.has_cash_for(user_id, cost, |reservation| reservation.symbol == symbol)
+    }
+
+    /// Returns what a buy order in the stock costs, in cents, before fees.
+    fn cost_cents(&self, symbol: Symbol, order: &Order) -> u64 {
+        match order.get_kind() {
+            OrderKind::Limit => order.get_value_cents(),
+            OrderKind::Market => self.stocks.get(&symbol).map_or(0, |stock| stock.get_sweep_cost_cents(order.get_quantity())),
+        }
+    }
+
+    /// Returns whether the user's cash covers `cost`, plus what their resting buy orders hold back, other than those
+    /// being replaced, and the taker fee on all of it.
+    fn has_cash_for(&self, user_id: usize, cost: u64, replaced: impl Fn(&Reservation) -> bool) -> bool {
+        let available = self
+            .users
+            .get(&user_id)
+            .map_or(self.starting_cash_cents as i64, |user| user.get_balances().get_available_cash_cents());
+        let reserved: u64 = self
+            .get_reservations(user_id)
+            .iter()
+            .filter(|reservation| !replaced(reservation))
+            .map(|reservation| reservation.cash_cents)
+            .sum();
+        let notional = reserved.saturating_add(cost);
+        let needed = notional.saturating_add(money::apply_bps(notional, self.taker_fee_bps, self.rounding));
+        available >= 0 && available as u64 >= needed
+    }
+
+    /// Sets the shares of each stock each new user starts with.
+    pub fn set_starting_shares(&mut self, starting_shares: usize) {
+        self.starting_shares = starting_shares;
+    }
+
+    /// Sets how many shares of particular stocks each user starts with, overriding the starting shares for those stocks.
+    pub fn set_starting_positions(&mut self, starting_positions: HashMap<Symbol, usize>) {
+        self.starting_positions = starting_positions;
+    }
+
+    /// Returns how many shares of the stock each user starts with.
+    fn starting_shares_of(&self, symbol: Symbol) -> usize {
+        self.starting_positions.get(&symbol).copied().unwrap_or(self.starting_shares)
+    }
+
+    /// Returns whether the user holds the shares to deliver for a sell order, on top of what their resting sell orders
+    /// in the stock already hold back.
+    ///
+    /// Nobody can sell short, so a user without shares of the stock can't sell it at all. A resting order with the same
+    /// ID is being replaced by this one, so what it holds back isn't counted.
+    pub fn can_deliver(&self, user_id: usize, symbol: Symbol, order: &Order) -> bool {
+        self.has_shares_for(user_id, symbol, order.get_quantity(), |reservation| reservation.order_id == order.get_id())
+    }
+
+    /// Returns whether the user holds the shares to deliver for every ask in a quote update for the stock, which
+    /// replaces all of their resting orders in it, so what those hold back isn't counted.
+    pub fn can_deliver_quotes(&self, user_id: usize, symbol: Symbol, asks: &[Order]) -> bool {
+        let quantity = asks.iter().fold(0usize, |quantity, ask| quantity.saturating_add(ask.get_quantity()));
+        self.has_shares_for(user_id, symbol, quantity, |_| true)
+    }
+
+    /// Returns whether the user holds `quantity` shares of the stock, plus what their resting sell orders in it hold
+    /// back, other than those being replaced.
+    fn has_shares_for(&self, user_id: usize, symbol: Symbol, quantity: usize, replaced: impl Fn(&Reservation) -> bool) -> bool {
+        let held = match self.users.get(&user_id) {
+            Some(user) => user.get_balances().get_available_holdings().get(&symbol).copied().unwrap_or(0),
+            None => self.starting_shares_of(symbol) as i64,
+        };
+        let reserved: usize = self
+            .get_reservations(user_id)
+            .iter()
+            .filter(|reservation| reservation.symbol == symbol && reservation.side == Side::Sell && !replaced(reservation))
+            .map(|reservation| reservation.quantity)
+            .sum();
+        held >= 0 && held as usize >= reserved.saturating_add(quantity)
+    }
+
+    /// Returns the user's balances, or what they would start with if they haven't done anything yet.
+    pub fn get_balances(&self, user_id: usize) -> Balances {
+        match self.users.get(&user_id) {
+            Some(user) => user.get_balances().clone(),
+            None => self.starting_balances(),
+        }
+    }
+
+    /// Returns the balances a new user starts with.
+    fn starting_balances(&self) -> Balances {
+        let mut balances = Balances::default();
+        balances.set_available_cash_cents(self.starting_cash_cents as i64);
+        for symbol in self.stocks.keys() {
+            balances.set_available_shares(*symbol, self.starting_shares_of(*symbol) as i64);
+        }
+        balances
+    }
+
+    /// Starts a new tick, resetting each stock's count of trades towards the per-tick cap and settling trades that are
+    /// due.
+    pub fn start_tick(&mut self) {
+        self.matches_this_tick.clear();
+        self.tick += 1;
+        for user in self.users.values_mut() {
+            user.get_balances_mut().settle(self.tick);
+        }
+    }
+
+    /// Sets the maximum number of stocks `add_stock` will list. `None` removes the limit.
+    pub fn set_max_stocks(&mut self, max_stocks: Option<usize>) {
+        self.max_stocks = max_stocks;
+    }
+
+    /// Lists a stock, unless the symbol is already listed or the market already lists the maximum number of stocks.
+    pub fn add_stock(&mut self, symbol: Symbol, mut stock: Stock) -> Result<(), AddStockError> {
+        if self.stocks.contains_key(&symbol) {
+            return Err(AddStockError::AlreadyListed);
+        }
+        if self.max_stocks.is_some_and(|max_stocks| self.stocks.len() >= max_stocks) {
+            return Err(AddStockError::LimitReached);
+        }
+
+        let shares = self.starting_shares_of(symbol);
+        for user in self.users.values_mut() {
+            user.get_balances_mut().set_available_shares(symbol, shares as i64);
+        }
+        stock.number_orders(&mut self.next_order_id);
+        stock.set_clock(self.clock.clone());
         self.stocks.insert(symbol, stock);
+        Ok(())
     }
 
     pub fn extend_stocks<I>(&mut self, stocks: I)
     where
         I: IntoIterator<Item = (Symbol, Stock)>
     {
-        self.stocks.extend(stocks);        
+        for (symbol, mut stock) in stocks {
+            stock.number_orders(&mut self.next_order_id);
+            stock.set_clock(self.clock.clone());
+            self.stocks.insert(symbol, stock);
+        }
     }
 
-    pub fn resolve(&mut self) -> Vec<(String, Vec<Trade>)> {
+    /// Numbers the order, stamps it with the market's time, and adds it to the stock's book on the given side. Returns
+    /// the order's ID, or `None` if the stock isn't listed.
+    pub fn add_order(&mut self, symbol: Symbol, side: Side, order: Order) -> Option<u64> {
+        let now = self.now();
+        let stock = self.stocks.get_mut(&symbol)?;
+        let order_id = self.next_order_id;
+        self.next_order_id += 1;
+        let order = order.with_id(order_id).with_time(now);
+        match side {
+            Side::Buy => stock.add_buy_order(order),
+            Side::Sell => stock.add_sell_order(order),
+        }
+        Some(order_id)
+    }
+
+    /// Resolves trades in every stock, recording them and any top-of-book changes in the activity feed. Returns each
+    /// stock's trades with its symbol.
+    ///
+    /// Nothing is matched outside trading hours.
+    pub fn resolve(&mut self) -> Vec<(Symbol, Vec<Trade>)> {
+        if !self.is_open() {
+            return Vec::new();
+        }
+
         let mut executed_trades = Vec::new();
+        let now = self.now();
+        let starting = self.starting_balances();
         
-        for stock in self.stocks.values_mut() {
-            executed_trades.push((stock.get_name().to_string(), stock.resolve()))
+        for (symbol, stock) in self.stocks.iter_mut() {
+            let matched = self.matches_this_tick.entry(*symbol).or_insert(0);
+            let remaining = self.max_matches_per_tick.map(|max_matches| max_matches.saturating_sub(*matched));
+            let trades = stock.resolve(self.min_fill_qty, remaining, self.self_trade);
+            *matched += trades.len();
+            for trade in &trades {
+                for user_id in [trade.buyer_id, trade.seller_id] {
+                    self.users.entry(user_id).or_insert_with(|| User::with_balances(starting.clone())).record_trade();
+                }
+                let notional = money::notional(money::to_cents(trade.price, self.rounding) as usize, trade.quantity);
+                let settles_at = self.tick + self.settle_ticks;
+                // The aggressor takes liquidity and pays the fee. The resting order's owner made it, and gets the rebate.
+                let taker_fee = money::apply_bps(notional, self.taker_fee_bps, self.rounding) as i64;
+                let maker_rebate = money::apply_bps(notional, self.maker_rebate_bps, self.rounding) as i64;
+                self.fees_collected_cents += taker_fee - maker_rebate;
+                for (user_id, buying) in [(trade.buyer_id, true), (trade.seller_id, false)] {
+                    let balances = self.users.entry(user_id).or_insert_with(|| User::with_balances(starting.clone())).get_balances_mut();
+                    balances.record_trade(*symbol, buying, notional, trade.quantity, self.tick, settles_at);
+                    let taker = buying == (trade.aggressor == Side::Buy);
+                    balances.record_fee(if taker { taker_fee } else { -maker_rebate });
+                }
+                self.activity.push(MarketEvent::Trade { symbol: *symbol, price: trade.price, quantity: trade.quantity, time: now });
+            }
+
+            let top = (stock.get_best_bid(), stock.get_best_ask());
+            if self.top_of_book.get(symbol) != Some(&top) {
+                self.top_of_book.insert(*symbol, top);
+                self.activity.push(MarketEvent::TopOfBook { symbol: *symbol, bid: top.0, ask: top.1, time: now });
+            }
+
+            executed_trades.push((*symbol, trades))
         }
 
         executed_trades
     }
 
+    /// Returns the number of listed stocks.
+    pub fn stock_count(&self) -> usize {
+        self.stocks.len()
+    }
+
+    /// Returns the number of orders resting across every stock's book.
+    pub fn resting_order_count(&self) -> usize {
+        self.stocks.values().map(Stock::get_resting_order_count).sum()
+    }
+
+    /// Returns how long the market has been running.
+    pub fn uptime(&self) -> std::time::Duration {
+        self.started.elapsed()
+    }
+
+    /// Returns session and book totals across every stock.
+    pub fn get_overview(&self) -> MarketOverview {
+        let mut overview = MarketOverview::default();
+        for stock in self.stocks.values() {
+            let trades = stock.get_session_trades();
+            overview.volume += stock.get_session_volume();
+            overview.trades += trades;
+            overview.resting_notional_cents += stock.get_resting_notional_cents();
+            let has_resting = stock.get_best_bid().is_some() || stock.get_best_ask().is_some();
+            if trades > 0 || has_resting {
+                overview.active_symbols += 1;
+            }
+        }
+        overview
+    }
+
+    /// Returns the ID the user a reconnect token identifies goes by now, if the token has been used.
+    pub fn get_token_user(&self, token: &str) -> Option<usize> {
+        self.tokens.get(token).copied()
+    }
+
+    /// Makes connection `id` the user a reconnect token identifies.
+    ///
+    /// A new token is given to the connection's own user. A known token's user keeps their balances and resting
+    /// orders, which move to the connection's ID. Fails if the connection has already acted as a user of its own, or
+    /// already has a token.
+    pub fn resume(&mut self, token: &str, id: usize) -> Result<Resumed, ()> {
+        if self.users.contains_key(&id) || self.tokens.values().any(|&user_id| user_id == id) {
+            return Err(());
+        }
+
+        match self.tokens.insert(token.to_string(), id) {
+            None => Ok(Resumed::New),
+            Some(previous_id) => {
+                if let Some(user) = self.users.remove(&previous_id) {
+                    self.users.insert(id, user);
+                }
+                for stock in self.stocks.values_mut() {
+                    stock.reassign_orders(previous_id, id);
+                }
+                Ok(Resumed::Restored)
+            }
+        }
+    }
+
+    /// Returns the user with the given ID, if they have done anything yet.
+    pub fn get_user(&self, id: usize) -> Option<&User> {
+        self.users.get(&id)
+    }
+
+    /// Returns the user with the given ID, creating them if they don't exist yet.
+    pub fn get_user_mut(&mut self, id: usize) -> &mut User {
+        if !self.users.contains_key(&id) {
+            let user = User::with_balances(self.starting_balances());
+            self.users.insert(id, user);
+        }
+        self.users.get_mut(&id).expect("The user was just created if they didn't exist.")
+    }
+
+    /// Returns up to `limit` of the most recent market events, oldest first.
+    pub fn get_activity(&self, limit: usize) -> Vec<MarketEvent> {
+        self.activity.recent(limit)
+    }
+
+    /// Returns what each of the user's resting orders holds back, ordered by order ID.
+    pub fn get_reservations(&self, user_id: usize) -> Vec<Reservation> {
+        let mut reservations: Vec<Reservation> = self
+            .stocks
+            .iter()
+            .flat_map(|(symbol, stock)| {
+                stock.get_user_orders(user_id).into_iter().map(|(side, order)| Reservation {
+                    order_id: order.get_id(),
+                    symbol: *symbol,
+                    side,
+                    quantity: order.get_quantity(),
+                    cash_cents: match (side, order.get_kind()) {
+                        (Side::Buy, OrderKind::Limit) => order.get_value_cents(),
+                        // A market buy waiting for the next resolve holds back what it would cost at the asks.
+                        (Side::Buy, OrderKind::Market) => stock.get_sweep_cost_cents(order.get_quantity()),
+                        (Side::Sell, _) => 0,
+                    },
+                })
+            })
+            .collect();
+        reservations.sort_by_key(|reservation| reservation.order_id);
+        reservations
+    }
+
+    /// Returns the resting order with the given ID, in whichever stock it is.
+    pub fn get_order(&self, order_id: u64) -> Option<&Order> {
+        self.stocks.values().find_map(|stock| stock.get_order(order_id))
+    }
+
+    /// Returns the user's resting order with the given ID, with the symbol and side of the book it rests on.
+    pub fn get_user_order(&self, user_id: usize, order_id: u64) -> Option<(Symbol, Side, &Order)> {
+        self.stocks.iter().find_map(|(symbol, stock)| {
+            let (side, order) = stock.get_user_orders(user_id).into_iter().find(|(_, order)| order.get_id() == order_id)?;
+            Some((*symbol, side, order))
+        })
+    }
+
+    /// Replaces the resting order with the same ID, in whichever stock it is. Returns whether it was found.
+    pub fn replace_order(&mut self, order: Order) -> bool {
+        match self.stocks.values_mut().find(|stock| stock.get_order(order.get_id()).is_some()) {
+            Some(stock) => stock.replace_order(order),
+            None => false,
+        }
+    }
+
+    /// Removes the resting order with the given ID, in whichever stock it is and whoever placed it, returning it.
+    pub fn remove_order(&mut self, order_id: u64) -> Option<Order> {
+        self.stocks.values_mut().find_map(|stock| stock.remove_order(order_id))
+    }
+
+    /// Reduces the remaining quantity of the creator's resting order, in whichever stock it is, returning what is left.
+    pub fn reduce_order(&mut self, creator_id: usize, order_id: u64, reduce_by: usize) -> Option<usize> {
+        self.stocks.values_mut().find_map(|stock| stock.reduce_order(creator_id, order_id, reduce_by))
+    }
+
+    /// Removes every resting order in the market that has rested for longer than `max_age`, returning them.
+    pub fn expire_orders(&mut self, max_age: Duration) -> Vec<Order> {
+        let Some(cutoff) = self.now().checked_sub_signed(max_age) else {
+            return Vec::new();
+        };
+        self.stocks.values_mut().flat_map(|stock| stock.expire_orders(cutoff)).collect()
+    }
+
+    /// Cancels every resting order the user has in any stock, returning the cancelled orders.
+    pub fn cancel_user_orders(&mut self, user_id: usize) -> Vec<Order> {
+        self.stocks.values_mut().flat_map(|stock| stock.cancel_user_orders(user_id)).collect()
+    }
+
+    /// Clears every order book in the market, returning all cancelled orders.
+    ///
+    /// Unlike a session reset, the OHLC of each stock is left untouched.
+    pub fn cancel_everything(&mut self) -> Vec<Order> {
+        self.stocks.values_mut().flat_map(|stock| stock.cancel_all()).collect()
+    }
+
+    pub fn get_session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Sets how often the session resets. `None` disables resets.
+    pub fn set_session_reset_interval(&mut self, reset_interval: Option<Duration>) {
+        self.session.set_reset_interval(reset_interval);
+    }
+
+    /// Resets the OHLC of every stock if the session reset is due, returning whether it was.
+    pub fn roll_session(&mut self, now: DateTime<Utc>) -> bool {
+        if !self.session.roll(now) {
+            return false;
+        }
+
+        for stock in self.stocks.values_mut() {
+            stock.reset_session();
+        }
+        true
+    }
+
+    /// Sets how long each candle lasts.
+    pub fn set_candle_interval(&mut self, candle_interval: Duration) {
+        self.candle_interval = candle_interval;
+    }
+
+    /// Samples each stock's book into its running candle, completing candles that have lasted the candle interval.
+    pub fn update_candles(&mut self, now: DateTime<Utc>) {
+        for stock in self.stocks.values_mut() {
+            stock.sample_spread(now);
+            if now - stock.get_candle_start() >= self.candle_interval {
+                stock.roll_candle(now);
+            }
+        }
+    }
+
+    /// Returns the symbol and name of every listed stock, ordered by symbol.
+    pub fn list_stocks(&self) -> Vec<(String, String)> {
+        let mut stocks: Vec<(String, String)> = self
+            .stocks
+            .iter()
+            .map(|(symbol, stock)| (symbol.to_string(), stock.get_name().to_string()))
+            .collect();
+        stocks.sort();
+        stocks
+    }
+
+    /// Returns a snapshot of every stock, with its resting orders, and every user.
+    pub fn snapshot(&self) -> MarketSnapshot {
+        let mut stocks: Vec<_> = self.stocks.iter().map(|(symbol, stock)| stock.to_snapshot(*symbol)).collect();
+        stocks.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+        let mut users: Vec<_> = self.users.iter().map(|(id, user)| user.to_snapshot(*id, self.tick)).collect();
+        users.sort_by_key(|user| user.id);
+        let tokens = self.tokens.iter().map(|(token, id)| (token.clone(), *id)).collect();
+        MarketSnapshot { stocks, users, tokens, fees_collected_cents: self.fees_collected_cents }
+    }
+
+    /// Replaces every stock, user and reconnect token with those in the snapshot. Stocks with symbols that are no longer
+    /// valid are dropped.
+    ///
+    /// The market's settings are left as they are.
+    pub fn restore(&mut self, snapshot: MarketSnapshot) {
+        // Orders added from now on are numbered after every restored order.
+        let orders = || snapshot.stocks.iter().flat_map(|stock| stock.buy_orders.iter().chain(&stock.sell_orders));
+        let last_id = orders().map(|order| order.id).max();
+        self.next_order_id = self.next_order_id.max(last_id.map_or(1, |id| id.saturating_add(1)));
+        // Connections from now on are numbered after every restored user, so none takes over another's orders or
+        // balances without its token.
+        let last_user_id = snapshot.users.iter().map(|user| user.id)
+            .chain(snapshot.tokens.values().copied())
+            .chain(orders().map(|order| order.creator_id))
+            .max();
+        self.first_free_user_id = self.first_free_user_id.max(last_user_id.map_or(1, |id| id.saturating_add(1)));
+        self.tokens = snapshot.tokens.into_iter().collect();
+        self.stocks = snapshot.stocks.into_iter()
+            .filter_map(|stock| Some((Symbol::try_from(stock.symbol.as_str()).ok()?, Stock::from_snapshot(stock))))
+            .collect();
+        for stock in self.stocks.values_mut() {
+            stock.set_clock(self.clock.clone());
+        }
+        self.users = snapshot.users.into_iter().map(|user| (user.id, User::from_snapshot(user, self.tick))).collect();
+        self.fees_collected_cents = snapshot.fees_collected_cents;
+        self.top_of_book.clear();
+    }
+
+    /// Returns the lowest ID that no restored user, reconnect token or resting order belongs to.
+    pub fn get_first_free_user_id(&self) -> usize {
+        self.first_free_user_id
+    }
+
     pub fn get_stock(&self, symbol: &Symbol) -> Option<&Stock> {
         self.stocks.get(symbol)
     }
@@ -47,3 +721,425 @@ impl Market {
         self.stocks.get_mut(symbol)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /// Tests that the per-tick cap holds across several resolves within a tick, and resets with the next tick.
+    #[test]
+    fn test_max_matches_per_tick() {
+        let symbol = Symbol::try_from("V").unwrap();
+        let mut market = Market::new();
+        market.add_stock(symbol, Stock::new("Vulyenne")).unwrap();
+        market.set_max_matches_per_tick(Some(2));
+        let stock = market.get_stock_mut(&symbol).unwrap();
+        for seller_id in 1..=5 {
+            stock.add_sell_order(Order::new(seller_id, 100.0, 1));
+        }
+        stock.add_buy_order(Order::new(6, 100.0, 5));
+
+        let count = |market: &mut Market| -> usize { market.resolve().iter().map(|(_, trades)| trades.len()).sum() };
+        market.start_tick();
+        assert_eq!(count(&mut market), 2);
+        assert_eq!(count(&mut market), 0);
+        market.start_tick();
+        assert_eq!(count(&mut market), 2);
+        market.start_tick();
+        assert_eq!(count(&mut market), 1);
+    }
+
+    /// Tests that the overview sums session activity and resting orders across stocks.
+    #[test]
+    fn test_overview() {
+        let mut market = Market::new();
+        let v = Symbol::try_from("V").unwrap();
+        let ort = Symbol::try_from("ORT").unwrap();
+        let idle = Symbol::try_from("IDLE").unwrap();
+        market.add_stock(v, Stock::new("Vulyenne")).unwrap();
+        market.add_stock(ort, Stock::new("Orchard de Rosa et Tulipan")).unwrap();
+        market.add_stock(idle, Stock::new("Idle")).unwrap();
+
+        let stock = market.get_stock_mut(&v).unwrap();
+        stock.add_sell_order(Order::new(1, 100.0, 5));
+        stock.add_buy_order(Order::new(2, 100.0, 3));
+        let stock = market.get_stock_mut(&ort).unwrap();
+        stock.add_sell_order(Order::new(1, 20.0, 2));
+        stock.add_sell_order(Order::new(1, 21.0, 2));
+        stock.add_buy_order(Order::new(2, 21.0, 4));
+        stock.add_buy_order(Order::new(2, 10.0, 1));
+        market.resolve();
+
+        assert_eq!(
+            market.get_overview(),
+            MarketOverview { volume: 7, trades: 3, resting_notional_cents: 2 * 10000 + 1000, active_symbols: 2 }
+        );
+    }
+
+    /// Tests that funds from a sale can't be spent until the settlement delay has elapsed.
+    #[test]
+    fn test_settlement_delay() {
+        let symbol = Symbol::try_from("V").unwrap();
+        let mut market = Market::new();
+        market.add_stock(symbol, Stock::new("Vulyenne")).unwrap();
+        market.set_settle_ticks(2);
+        market.set_starting_cash_cents(100_000);
+        market.set_starting_shares(5);
+        let stock = market.get_stock_mut(&symbol).unwrap();
+        stock.add_sell_order(Order::new(1, 100.0, 5));
+        stock.add_buy_order(Order::new(2, 100.0, 5));
+
+        let cash = |market: &Market, user_id| market.get_user(user_id).unwrap().get_balances().get_available_cash_cents();
+        let shares = |market: &Market, user_id| market.get_user(user_id).unwrap().get_balances().get_available_holdings()[&symbol];
+        market.start_tick();
+        market.resolve();
+        assert_eq!(cash(&market, 1), 100_000);
+        assert_eq!(cash(&market, 2), 50_000);
+        assert_eq!(shares(&market, 2), 5);
+        market.start_tick();
+        assert_eq!(cash(&market, 1), 100_000);
+        market.start_tick();
+        assert_eq!(cash(&market, 1), 150_000);
+        assert_eq!(shares(&market, 2), 10);
+    }
+
+    /// Tests that the taker pays the fee on each trade, the maker gets the rebate, and the exchange keeps the difference.
+    #[test]
+    fn test_taker_fee() {
+        let symbol = Symbol::try_from("V").unwrap();
+        let mut market = Market::new();
+        market.add_stock(symbol, Stock::new("Vulyenne")).unwrap();
+        market.set_starting_cash_cents(2_000_000);
+        market.set_fees(10, 2);
+        let stock = market.get_stock_mut(&symbol).unwrap();
+        stock.add_sell_order(Order::new(1, 150.0, 100));
+        stock.add_buy_order(Order::new(2, 150.0, 100));
+
+        market.resolve();
+        let cash = |user_id| market.get_user(user_id).unwrap().get_balances().get_available_cash_cents();
+        // The buy came in second, so the buyer took liquidity: 10bps of 15000.00 is 15.00.
+        assert_eq!(cash(2), 2_000_000 - 1_500_000 - 1_500);
+        // The seller made it, and gets 2bps of 15000.00 back.
+        assert_eq!(cash(1), 2_000_000 + 1_500_000 + 300);
+        assert_eq!(market.fees_collected_cents(), 1_200);
+    }
+
+    /// Tests that a buy is only affordable if the cash also covers the taker fee it may pay.
+    #[test]
+    fn test_can_afford_taker_fee() {
+        let symbol = Symbol::try_from("V").unwrap();
+        let mut market = Market::new();
+        market.add_stock(symbol, Stock::new("Vulyenne")).unwrap();
+        market.set_starting_cash_cents(100_000);
+        assert!(market.can_afford(1, symbol, &Order::new(1, 100.0, 10)));
+
+        // 1% of 1000.00 is 10.00 more than the buyer has, but 1% of 990.00 fits in the 10.00 left over.
+        market.set_fees(100, 0);
+        assert!(!market.can_afford(1, symbol, &Order::new(1, 100.0, 10)));
+        assert!(market.can_afford(1, symbol, &Order::new(1, 99.0, 10)));
+    }
+
+    /// Tests that the amounts settled on each trade are rounded with the market's rounding mode.
+    #[test]
+    fn test_resolve_rounding() {
+        let symbol = Symbol::try_from("V").unwrap();
+        // 1% of 2.50 is 2.5 cents.
+        for (rounding, fee) in [(RoundingMode::HalfUp, 3), (RoundingMode::HalfEven, 2), (RoundingMode::Floor, 2)] {
+            let mut market = Market::new();
+            market.add_stock(symbol, Stock::new("Vulyenne")).unwrap();
+            market.set_rounding(rounding);
+            market.set_fees(100, 0);
+            let stock = market.get_stock_mut(&symbol).unwrap();
+            stock.add_sell_order(Order::new(1, 2.5, 1));
+            stock.add_buy_order(Order::new(2, 2.5, 1));
+
+            market.resolve();
+            assert_eq!(market.fees_collected_cents(), fee);
+            assert_eq!(market.get_user(2).unwrap().get_balances().get_available_cash_cents(), -250 - fee);
+        }
+    }
+
+    /// Tests that trades and top-of-book changes land in the activity feed in the order they happened.
+    #[test]
+    fn test_activity_feed() {
+        let symbol = Symbol::try_from("V").unwrap();
+        let mut market = Market::new();
+        market.add_stock(symbol, Stock::new("Vulyenne")).unwrap();
+
+        let stock = market.get_stock_mut(&symbol).unwrap();
+        stock.add_sell_order(Order::new(1, 100.0, 5));
+        stock.add_sell_order(Order::new(1, 101.0, 5));
+        market.resolve();
+        market.get_stock_mut(&symbol).unwrap().add_buy_order(Order::new(2, 101.0, 7));
+        market.resolve();
+
+        let activity = market.get_activity(10);
+        let summary: Vec<String> = activity
+            .iter()
+            .map(|event| match event {
+                MarketEvent::Trade { price, quantity, .. } => format!("trade {} x {}", price, quantity),
+                MarketEvent::TopOfBook { bid, ask, .. } => format!("top {:?} / {:?}", bid, ask),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                "top None / Some(100.0)",
+                "trade 100 x 5",
+                "trade 101 x 2",
+                "top None / Some(101.0)",
+            ]
+        );
+        assert_eq!(market.get_activity(1).len(), 1);
+    }
+
+    /// Tests that symbols are normalised under the upper-case policy and left alone under the preserve policy.
+    #[test]
+    fn test_symbol_case() {
+        let mut market = Market::new();
+        let symbol = market.parse_symbol("ort").unwrap();
+        market.add_stock(symbol, Stock::new("Orchard de Rosa et Tulipan")).unwrap();
+        assert_eq!(symbol.to_string(), "ORT");
+        assert!(market.get_stock(&market.parse_symbol("Ort").unwrap()).is_some());
+
+        market.set_symbol_case(SymbolCase::Preserve);
+        assert!(market.get_stock(&market.parse_symbol("ort").unwrap()).is_none());
+        assert!(market.get_stock(&market.parse_symbol("ORT").unwrap()).is_some());
+    }
+
+    /// Tests that both counterparties of a trade have it counted.
+    #[test]
+    fn test_trades_counted_per_user() {
+        let symbol = Symbol::try_from("V").unwrap();
+        let mut market = Market::new();
+        market.add_stock(symbol, Stock::new("Vulyenne")).unwrap();
+        let stock = market.get_stock_mut(&symbol).unwrap();
+        stock.add_sell_order(Order::new(1, 100.0, 5));
+        stock.add_sell_order(Order::new(1, 101.0, 5));
+        stock.add_buy_order(Order::new(2, 101.0, 10));
+        market.resolve();
+
+        assert_eq!(market.get_user(1).unwrap().get_stats().traded, 2);
+        assert_eq!(market.get_user(2).unwrap().get_stats().traded, 2);
+        assert!(market.get_user(3).is_none());
+    }
+
+    /// Tests that stocks can't be added past the limit.
+    #[test]
+    fn test_max_stocks() {
+        let mut market = Market::new();
+        market.set_max_stocks(Some(1));
+        assert_eq!(market.add_stock(Symbol::try_from("V").unwrap(), Stock::new("Vulyenne")), Ok(()));
+        assert_eq!(
+            market.add_stock(Symbol::try_from("ORT").unwrap(), Stock::new("Orchard de Rosa et Tulipan")),
+            Err(AddStockError::LimitReached)
+        );
+        assert!(market.get_stock(&Symbol::try_from("ORT").unwrap()).is_none());
+    }
+
+    /// Tests that listing a symbol again is refused and leaves the listed stock's book alone.
+    #[test]
+    fn test_add_stock_already_listed() {
+        let symbol = Symbol::try_from("V").unwrap();
+        let mut market = Market::new();
+        assert_eq!(market.add_stock(symbol, Stock::new("Vulyenne")), Ok(()));
+        market.get_stock_mut(&symbol).unwrap().add_buy_order(Order::new(1, 100.0, 5));
+
+        assert_eq!(market.add_stock(symbol, Stock::new("Impostor")), Err(AddStockError::AlreadyListed));
+        let stock = market.get_stock(&symbol).unwrap();
+        assert_eq!(stock.get_name(), "Vulyenne");
+        assert_eq!(stock.get_buy_orders(5), vec![(100.0, 5)]);
+    }
+
+    /// Tests that a user's orders are cleared from every stock's book, and no one else's are.
+    #[test]
+    fn test_cancel_user_orders() {
+        let (v, ort) = (Symbol::try_from("V").unwrap(), Symbol::try_from("ORT").unwrap());
+        let mut market = Market::new();
+        market.add_stock(v, Stock::new("Vulyenne")).unwrap();
+        market.add_stock(ort, Stock::new("Orchard de Rosa et Tulipan")).unwrap();
+        for (symbol, creator_id, price) in [(v, 1, 100.0), (v, 2, 101.0), (ort, 1, 50.0), (ort, 2, 49.0)] {
+            let stock = market.get_stock_mut(&symbol).unwrap();
+            stock.add_buy_order(Order::new(creator_id, price - 10.0, 1));
+            stock.add_sell_order(Order::new(creator_id, price + 10.0, 1));
+        }
+
+        let cancelled = market.cancel_user_orders(1);
+        assert_eq!(cancelled.len(), 4);
+        assert!(cancelled.iter().all(|order| order.get_creator_id() == 1));
+        for symbol in [v, ort] {
+            let stock = market.get_stock(&symbol).unwrap();
+            assert!(stock.get_user_orders(1).is_empty());
+            assert_eq!(stock.get_user_orders(2).len(), 2);
+        }
+        assert_eq!(market.get_stock(&v).unwrap().get_buy_orders(5), vec![(91.0, 1)]);
+        assert_eq!(market.get_stock(&ort).unwrap().get_sell_orders(5), vec![(59.0, 1)]);
+    }
+
+    /// Tests that a market written to JSON and read back has the same stocks, resting orders and users.
+    #[test]
+    fn test_snapshot_round_trip() {
+        let (v, ort) = (Symbol::try_from("V").unwrap(), Symbol::try_from("ORT").unwrap());
+        let mut market = Market::new();
+        market.set_settle_ticks(2);
+        market.set_fees(10, 0);
+        market.add_stock(v, Stock::new("Vulyenne")).unwrap();
+        market.add_stock(ort, Stock::new("Orchard de Rosa et Tulipan")).unwrap();
+        market.add_order(v, Side::Sell, Order::new(1, 100.0, 3));
+        market.add_order(v, Side::Buy, Order::new(2, 100.0, 1));
+        market.add_order(v, Side::Buy, Order::new(2, 99.0, 4).with_tag(OrderTag::try_from("hedge").ok()));
+        market.add_order(v, Side::Buy, Order::new(3, 99.0, 2));
+        market.add_order(ort, Side::Sell, Order::market(1, Side::Sell, 7).with_time_in_force(TimeInForce::Ioc));
+        market.start_tick();
+        assert_eq!(market.resolve().iter().map(|(_, trades)| trades.len()).sum::<usize>(), 1);
+        market.get_user_mut(1).record_order_placed(Utc::now());
+
+        let json = serde_json::to_string(&market.snapshot()).unwrap();
+        let mut restored = Market::new();
+        restored.restore(serde_json::from_str(&json).unwrap());
+        assert_eq!(restored.snapshot(), market.snapshot());
+        assert_eq!(restored.fees_collected_cents(), 10);
+
+        let stock = restored.get_stock(&v).unwrap();
+        assert_eq!(stock.get_name(), "Vulyenne");
+        assert_eq!(stock.get_buy_orders(5), vec![(99.0, 6)]);
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 2)]);
+        assert_eq!(stock.get_ticker(), (Some(100.0), 1));
+        let hedge = stock.get_user_orders(2)[0].1;
+        assert_eq!(hedge.get_tag().unwrap().as_str(), "hedge");
+        let hedge_id = hedge.get_id();
+        let next_id = restored.add_order(v, Side::Buy, Order::new(1, 1.0, 1)).unwrap();
+        assert!(next_id > hedge_id);
+        restored.remove_order(next_id);
+
+        let seller = restored.get_user(1).unwrap();
+        assert_eq!(seller.get_stats().placed, 1);
+        assert_eq!(seller.get_balances().get_available_cash_cents(), 0);
+        assert_eq!(seller.get_balances().get_pending().len(), 1);
+        restored.start_tick();
+        restored.start_tick();
+        assert_eq!(restored.get_user(1).unwrap().get_balances().get_available_cash_cents(), 10000);
+    }
+
+    /// Tests that restoring an order with the largest possible ID doesn't overflow the market's next order ID.
+    #[test]
+    fn test_restore_largest_order_id() {
+        let v = Symbol::try_from("V").unwrap();
+        let mut market = Market::new();
+        market.add_stock(v, Stock::new("Vulyenne")).unwrap();
+        market.add_order(v, Side::Buy, Order::new(1, 100.0, 5));
+        let mut snapshot = market.snapshot();
+        snapshot.stocks[0].buy_orders[0].id = u64::MAX;
+
+        let mut restored = Market::new();
+        restored.restore(snapshot);
+        assert_eq!(restored.get_order(u64::MAX).unwrap().get_quantity(), 5);
+    }
+
+    /// Tests that reconnect tokens survive a restore, and that new connections are numbered after every restored user
+    /// so they don't take over one without its token.
+    #[test]
+    fn test_restore_tokens() {
+        let v = Symbol::try_from("V").unwrap();
+        let mut market = Market::new();
+        market.add_stock(v, Stock::new("Vulyenne")).unwrap();
+        assert_eq!(market.resume("abc", 1), Ok(Resumed::New));
+        market.add_order(v, Side::Buy, Order::new(1, 100.0, 5));
+        market.get_user_mut(1).record_order_placed(Utc::now());
+        market.add_order(v, Side::Sell, Order::new(3, 101.0, 2));
+
+        let mut restored = Market::new();
+        restored.restore(market.snapshot());
+        let new_id = restored.get_first_free_user_id();
+        assert_eq!(new_id, 4);
+        assert!(restored.get_user(new_id).is_none());
+        assert!(restored.get_stock(&v).unwrap().get_user_orders(new_id).is_empty());
+
+        assert_eq!(restored.get_token_user("abc"), Some(1));
+        assert_eq!(restored.resume("abc", new_id), Ok(Resumed::Restored));
+        assert_eq!(restored.get_user(new_id).unwrap().get_stats().placed, 1);
+        assert_eq!(restored.get_stock(&v).unwrap().get_user_orders(new_id).len(), 1);
+    }
+
+    /// Tests that a missing or corrupt snapshot file can't be loaded.
+    #[test]
+    fn test_snapshot_load_errors() {
+        let path = std::env::temp_dir().join(format!("d1x-snapshot-{}.json", std::process::id()));
+        assert_eq!(MarketSnapshot::load(&path).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        std::fs::write(&path, "{\"stocks\": [").unwrap();
+        assert_eq!(MarketSnapshot::load(&path).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        Market::new().snapshot().save(&path).unwrap();
+        assert_eq!(MarketSnapshot::load(&path).unwrap(), MarketSnapshot { stocks: vec![], users: vec![], tokens: BTreeMap::new(), fees_collected_cents: 0 });
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that trades are reported under the stock's symbol, not its name.
+    #[test]
+    fn test_resolve_reports_symbol() {
+        let mut market = Market::new();
+        let symbol = Symbol::try_from("ORT").unwrap();
+        market.extend_stocks([(symbol, Stock::new("Orchard de Rosa et Tulipan"))]);
+        let stock = market.get_stock_mut(&symbol).unwrap();
+        stock.add_sell_order(Order::new(1, 100.0, 5));
+        stock.add_buy_order(Order::new(2, 100.0, 5));
+
+        let resolved = market.resolve();
+        assert_eq!(resolved.len(), 1);
+        let (reported, trades) = &resolved[0];
+        assert_eq!(*reported, symbol);
+        assert_eq!(reported.to_string(), "ORT");
+        assert_eq!(trades.len(), 1);
+    }
+
+    /// Tests the stock and resting order counts across the market.
+    #[test]
+    fn test_market_counts() {
+        let mut market = Market::new();
+        assert_eq!((market.stock_count(), market.resting_order_count()), (0, 0));
+
+        let (v, ort) = (Symbol::try_from("V").unwrap(), Symbol::try_from("ORT").unwrap());
+        market.extend_stocks([(v, Stock::new("Vulyenne")), (ort, Stock::new("Orchard de Rosa et Tulipan"))]);
+        let stock = market.get_stock_mut(&v).unwrap();
+        stock.add_buy_order(Order::new(1, 99.0, 5));
+        stock.add_buy_order(Order::new(2, 99.0, 5));
+        stock.add_sell_order(Order::new(3, 101.0, 5));
+        market.get_stock_mut(&ort).unwrap().add_sell_order(Order::new(1, 50.0, 1));
+        assert_eq!((market.stock_count(), market.resting_order_count()), (2, 4));
+
+        // Orders that trade out of the book no longer count.
+        market.get_stock_mut(&ort).unwrap().add_buy_order(Order::new(2, 50.0, 1));
+        market.resolve();
+        assert_eq!(market.resting_order_count(), 3);
+    }
+
+    /// Tests that a new reconnect token is given to the connection's user, and that resuming a known token moves that
+    /// user's state and resting orders to the new connection.
+    #[test]
+    fn test_resume() {
+        let mut market = Market::new();
+        let v = Symbol::try_from("V").unwrap();
+        market.extend_stocks([(v, Stock::new("Vulyenne"))]);
+
+        assert_eq!(market.resume("alice", 1), Ok(Resumed::New));
+        assert_eq!(market.get_token_user("alice"), Some(1));
+        let now = market.now();
+        market.get_user_mut(1).record_order_placed(now);
+        market.get_stock_mut(&v).unwrap().add_buy_order(Order::new(1, 99.0, 5));
+
+        assert_eq!(market.resume("alice", 2), Ok(Resumed::Restored));
+        assert_eq!(market.get_token_user("alice"), Some(2));
+        assert!(market.get_user(1).is_none());
+        assert_eq!(market.get_user(2).unwrap().get_stats().placed, 1);
+        assert!(market.cancel_user_orders(1).is_empty());
+        assert_eq!(market.get_reservations(2).len(), 1);
+
+        // A connection that already has a user, or a token, can't take on another.
+        assert_eq!(market.resume("bob", 2), Err(()));
+        market.get_user_mut(3);
+        assert_eq!(market.resume("bob", 3), Err(()));
+        assert_eq!(market.get_token_user("bob"), None);
+    }
+}
@@ -0,0 +1,125 @@
+//! Money arithmetic on integer cents.
+//!
+//! Every amount of money the market computes goes through these helpers so that the same rounding policy is applied
+//! everywhere, and a user is never charged a fraction of a cent differently in two code paths.
+
+/// How an amount that falls between two cents is rounded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to the nearest cent, with halves rounded up.
+    #[default]
+    HalfUp,
+    /// Round to the nearest cent, with halves rounded to the even cent (banker's rounding).
+    HalfEven,
+    /// Always round down.
+    Floor,
+}
+
+impl TryFrom<&str> for RoundingMode {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, ()> {
+        match s {
+            "half_up" => Ok(RoundingMode::HalfUp),
+            "half_even" => Ok(RoundingMode::HalfEven),
+            "floor" => Ok(RoundingMode::Floor),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for RoundingMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoundingMode::HalfUp => write!(f, "half_up"),
+            RoundingMode::HalfEven => write!(f, "half_even"),
+            RoundingMode::Floor => write!(f, "floor"),
+        }
+    }
+}
+
+/// Divides `numerator` by `denominator`, rounding the quotient according to `mode`.
+pub fn div_round(numerator: u128, denominator: u128, mode: RoundingMode) -> u128 {
+    let quotient = numerator / denominator;
+    let remainder = numerator % denominator;
+
+    let round_up = match mode {
+        RoundingMode::Floor => false,
+        RoundingMode::HalfUp => remainder * 2 >= denominator,
+        RoundingMode::HalfEven => {
+            remainder * 2 > denominator || (remainder * 2 == denominator && quotient % 2 == 1)
+        }
+    };
+
+    if round_up {
+        quotient + 1
+    } else {
+        quotient
+    }
+}
+
+/// Converts an amount in currency units to cents.
+///
+/// The amount is first snapped to a tenth of a cent so that float noise (`1.005` being stored as `1.00499...`) does not
+/// decide which way a half cent rounds.
+pub fn to_cents(amount: f64, mode: RoundingMode) -> u64 {
+    let mills = (amount.max(0.0) * 1000.0).round() as u128;
+    div_round(mills, 10, mode) as u64
+}
+
+/// Converts cents to currency units.
+pub fn from_cents(cents: u64) -> f64 {
+    cents as f64 / 100.0
+}
+
+/// Returns the value in cents of `quantity` units priced at `price_cents` each.
+///
+/// This is exact, so no rounding is needed.
+pub fn notional(price_cents: usize, quantity: usize) -> u64 {
+    (price_cents as u64).saturating_mul(quantity as u64)
+}
+
+/// Returns `bps` basis points of an amount of cents, rounded according to `mode`.
+pub fn apply_bps(amount_cents: u64, bps: u32, mode: RoundingMode) -> u64 {
+    div_round(amount_cents as u128 * bps as u128, 10_000, mode) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests each rounding mode on amounts that end exactly on half a cent.
+    #[test]
+    fn test_half_cent_boundary() {
+        assert_eq!(to_cents(1.005, RoundingMode::HalfUp), 101);
+        assert_eq!(to_cents(1.005, RoundingMode::HalfEven), 100);
+        assert_eq!(to_cents(1.015, RoundingMode::HalfEven), 102);
+        assert_eq!(to_cents(1.005, RoundingMode::Floor), 100);
+        assert_eq!(to_cents(1.009, RoundingMode::Floor), 100);
+
+        // 45 / 10 is 4.5 cents.
+        assert_eq!(div_round(45, 10, RoundingMode::HalfUp), 5);
+        assert_eq!(div_round(45, 10, RoundingMode::HalfEven), 4);
+        assert_eq!(div_round(45, 10, RoundingMode::Floor), 4);
+    }
+
+    /// Tests taking basis points of an amount, including a fraction of a cent.
+    #[test]
+    fn test_apply_bps() {
+        assert_eq!(apply_bps(1_500_000, 10, RoundingMode::HalfUp), 1_500);
+        // 2.5 bps of 1.00 is 0.025 cents.
+        assert_eq!(apply_bps(100, 25, RoundingMode::HalfUp), 0);
+        assert_eq!(apply_bps(2_000, 25, RoundingMode::HalfUp), 5);
+        assert_eq!(apply_bps(2_000, 25, RoundingMode::HalfEven), 5);
+        assert_eq!(apply_bps(1_800, 25, RoundingMode::Floor), 4);
+    }
+
+    /// Tests that the rounding mode names parse.
+    #[test]
+    fn test_parse_rounding_mode() {
+        assert_eq!(RoundingMode::try_from("half_up"), Ok(RoundingMode::HalfUp));
+        assert_eq!(RoundingMode::try_from("half_even"), Ok(RoundingMode::HalfEven));
+        assert_eq!(RoundingMode::try_from("floor"), Ok(RoundingMode::Floor));
+        assert!(RoundingMode::try_from("ceiling").is_err());
+    }
+}
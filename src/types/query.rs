@@ -1,6 +1,21 @@
-use super::{Order, Trade};
+use super::{json, schema, serialize_reservations, Balances, BookEntry, Candle, ErrorCode, MarketEvent, MarketOverview, Order, OrderStats, OrderTag, MAX_TAG_LEN, QueryDef, Rejection, Reservation, Side, SymbolInfo, TimeInForce, Trade};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
 use tokio::sync::mpsc;
 
+/// Number of events returned by an activity query that doesn't set a limit.
+const DEFAULT_ACTIVITY_LIMIT: usize = 20;
+/// Number of candles returned by a candle-based query that doesn't set a limit.
+const DEFAULT_CANDLE_LIMIT: usize = 20;
+/// Number of rejections returned by a rejections query that doesn't set a limit.
+const DEFAULT_REJECTION_LIMIT: usize = 20;
+/// Number of price levels returned by an order book query that doesn't set how many.
+const DEFAULT_LEVELS: usize = 5;
+/// Most price levels an order book query can ask for.
+pub const MAX_LEVELS: usize = 50;
+/// Largest message, in bytes, a connection reads as one query. A client sending a longer one is disconnected.
+pub const MAX_FRAME_BYTES: usize = 64 * 1024;
+
 /// A query to the market.
 pub enum Query {
     /// Post a buy order for the stock.
@@ -9,95 +24,1094 @@ pub enum Query {
     Sell(String, Order),
     /// Query the OHLC prices for the stock.
     Ohlc(String),
-    /// Query the pending buy orders for the stock.
-    BuyOrders(String),
-    /// Query the pending sell orders for the stock.
-    SellOrders(String),
+    /// Query the last trade price and total traded quantity of the stock.
+    Ticker(String),
+    /// The volume-weighted average price of a stock's trades in the current candle.
+    Vwap(String),
+    /// Every resting order of a stock, up to the configured limit.
+    Book(String),
+    /// Query the pending buy orders for the stock, at up to this many of the best price levels.
+    BuyOrders(String, usize),
+    /// Query the pending sell orders for the stock, at up to this many of the best price levels.
+    SellOrders(String, usize),
     /// New connection
     Connect(mpsc::Sender<QueryResponse>),
+    /// The connection stopped accepting writes. It is removed and its orders are cancelled.
+    ConnectionDead,
+    /// The client closed the connection, or reading from it failed. It is removed and its orders are cancelled, as
+    /// there is no one left to tell about their fills.
+    Disconnect,
+    /// Grant admin privileges to the connection if the token matches the configured admin token, as if it had come in on
+    /// the admin port.
+    AdminLogin(String),
+    /// Act as the user the reconnect token identifies, restoring their balances and resting orders if the token has been
+    /// used before. Only accepted as the connection's first query that touches its user.
+    Resume(String),
+    /// Query the session start, elapsed time and next reset.
+    SessionInfo,
+    /// Query the quantity traded this session at each price for the stock.
+    VolumeProfile(String),
+    /// Query the price at which the stock's book would clear in an auction right now.
+    IndicativePrice(String),
+    /// Query the tick size, lot size, precision, currency and halt status of the stock.
+    SymbolInfo(String),
+    /// Query up to this many of the most recent market events across all stocks.
+    Activity(usize),
+    /// Query the average spread of up to this many of the stock's most recent candles.
+    SpreadHistory(String, usize),
+    /// Query the open, high, low and close prices of up to this many of the stock's most recent candles.
+    Candles(String, usize),
+    /// Cancel the connection's resting order in the stock with this ID.
+    Cancel(String, u64),
+    /// Cancel the connection's earliest resting order on the given side of the stock.
+    CancelOldest(String, Side),
+    /// Cancel the connection's latest resting order on the given side of the stock.
+    CancelNewest(String, Side),
+    /// Query the order activity counts of the connection, or of another user (admin only).
+    OrderStats(Option<usize>),
+    /// Replace all of the connection's resting orders in the stock with the given bids and asks, in one step.
+    QuoteUpdate { symbol: String, bids: Vec<Order>, asks: Vec<Order> },
+    /// Query the type and fields of every supported query.
+    Schema,
+    /// Query the stock's buy-initiated minus sell-initiated volume over this many of the most recent seconds.
+    OrderFlow(String, u64),
+    /// Query whether the connection's order with this ID is still resting.
+    OrderExists(u64),
+    /// Reduce the remaining quantity of the connection's resting order with this ID by this much.
+    Reduce(u64, usize),
+    /// Query how long it has been since the stock's book last changed.
+    BookAge(String),
+    /// Move the connection's resting order with this ID to a new price, behind orders already at that price.
+    Reprice(u64, f64),
+    /// Change the price and remaining quantity of the connection's resting order in the stock with this ID. The order
+    /// only keeps its place in the queue if its quantity is reduced at the same price.
+    Modify { symbol: String, order_id: u64, price: f64, quantity: usize },
+    /// Query how many orders, and how much quantity, rest at exactly this price on the given side of the stock.
+    QueueDepth(String, Side, f64),
+    /// Query the total quantity resting on each side of the stock's book, and how many price levels it rests at.
+    Depth(String),
+    /// Receive a print of every trade in the stock, without user IDs, if the market publishes a tape.
+    Subscribe(String),
+    /// Query the cash and shares held back by the connection's resting orders.
+    Reservations,
+    /// Query up to this many of the connection's most recently refused orders.
+    MyRejections(usize),
+    /// Query session volume, trade count and resting value summed across every stock, and how many stocks are active.
+    MarketOverview,
+    /// Query the connection's spendable cash and sellable holdings, and what it is still waiting to receive from trades.
+    Balances,
+    /// Query the connection's spendable cash and the shares it holds of each stock.
+    Portfolio,
+    /// Query the symbol and name of every listed stock.
+    Symbols,
+    /// Reply with the same payload, without touching the market. For testing connectivity and encoding.
+    Echo(String),
+    /// Reply with the same timestamp, without touching the market. Unlike an echo, the ping goes through the market loop,
+    /// so its round trip includes the time spent queued for the market.
+    Ping(u64),
+    /// Query the server's health: how many stocks are listed, connections are open and orders are resting, and how long
+    /// the market has been running.
+    Status,
+    /// A query the client numbered with a `req_id`. It is answered like the query itself, and each response to it carries
+    /// the same number.
+    Numbered(u64, Box<Query>),
+    /// A connection was accepted on the admin port. Like `Query::Connect`, this is never sent by clients.
+    AdminConnect(mpsc::Sender<QueryResponse>),
+    /// A privileged query, only answered for admin connections.
+    Admin(AdminQuery),
+}
+
+/// Queries that change the market for everyone or reveal how it is run, so are only answered for admin connections:
+/// those on the admin port, and those that logged in with the admin token.
+pub enum AdminQuery {
+    /// List a new stock with the given symbol and name.
+    ListStock(String, String),
+    /// Halt or resume trading in the stock with the symbol.
+    Halt(String, bool),
+    /// Cancel the resting order with this ID, whoever placed it.
+    CancelOrder(u64),
+    /// Cancel every resting order in the market.
+    CancelEverything,
+    /// Stop (`true`) or resume (`false`) accepting new orders, while still serving queries and cancels.
+    OrderEntryHalt(bool),
+    /// Query the server's effective configuration.
+    Config,
 }
 
 impl Query {
-    pub fn from_json(json: &str, id: usize) -> Option<Self> {
-        let query: serde_json::Value = match serde_json::from_str(json) {
-            Ok(q) => q,
-            Err(e) => {
-                println!("Error parsing JSON: {}", e);
-                return None;
+    /// Parses a query from a client's message.
+    ///
+    /// Any query can carry a `req_id` alongside its own fields, in which case it is parsed as a `Query::Numbered`.
+    pub fn from_json(json: &str, id: usize) -> Result<Self, QueryParseError> {
+        let query: serde_json::Value =
+            serde_json::from_str(json).map_err(|e| QueryParseError::InvalidJson(e.to_string()))?;
+        let req_id = match &query["req_id"] {
+            serde_json::Value::Null => None,
+            req_id => Some(req_id.as_u64().ok_or(QueryParseError::MissingField("req_id"))?),
+        };
+        let query_type = query["type"].as_str().ok_or(QueryParseError::MissingField("type"))?;
+        let def = schema::lookup(query_type).ok_or_else(|| QueryParseError::UnknownType(query_type.to_string()))?;
+        if let Some(field) = def.fields.iter().find(|field| field.required && !field.kind.matches(&query[field.name])) {
+            // A fractional, negative or oversized quantity was sent, just not one that can be ordered.
+            if field.name == "quantity" && query[field.name].is_number() {
+                return Err(QueryParseError::InvalidOrder("quantity must be a positive integer".to_string()));
+            }
+            return Err(QueryParseError::MissingField(field.name));
+        }
+
+        let symbol = || {
+            query["symbol"]
+                .as_str()
+                .map(str::to_string)
+                .ok_or(QueryParseError::MissingField("symbol"))
+        };
+        let price = || {
+            let price = query["price"].as_f64().ok_or(QueryParseError::MissingField("price"))?;
+            if !Order::is_price_representable(price) {
+                return Err(QueryParseError::InvalidOrder("price out of range".to_string()));
+            }
+            Ok(price)
+        };
+        let quantity = || match query["quantity"].as_u64() {
+            Some(0) => Err(QueryParseError::InvalidOrder("quantity must be a positive integer".to_string())),
+            quantity => quantity.ok_or(QueryParseError::MissingField("quantity")),
+        };
+        let levels = |field: &'static str| -> Result<Vec<Order>, QueryParseError> {
+            let levels = query[field].as_array().ok_or(QueryParseError::MissingField(field))?;
+            levels
+                .iter()
+                .map(|level| {
+                    let price = level[0].as_f64().ok_or(QueryParseError::MissingField(field))?;
+                    let quantity = level[1].as_u64().ok_or(QueryParseError::MissingField(field))?;
+                    if !Order::is_price_representable(price) {
+                        return Err(QueryParseError::InvalidOrder("price out of range".to_string()));
+                    }
+                    Ok(Order::new(id, price, quantity as usize))
+                })
+                .collect()
+        };
+        let tag = || match &query["tag"] {
+            serde_json::Value::Null => Ok(None),
+            tag => tag
+                .as_str()
+                .and_then(|tag| OrderTag::try_from(tag).ok())
+                .map(Some)
+                .ok_or_else(|| QueryParseError::InvalidOrder(format!("tag must be a string of at most {} bytes", MAX_TAG_LEN))),
+        };
+        let time_in_force = || match &query["tif"] {
+            serde_json::Value::Null => Ok(TimeInForce::Gtc),
+            tif => tif
+                .as_str()
+                .and_then(|tif| TimeInForce::try_from(tif).ok())
+                .ok_or_else(|| QueryParseError::InvalidOrder("tif must be one of gtc, ioc or fok".to_string())),
+        };
+        let display_qty = || match &query["display"] {
+            serde_json::Value::Null => Ok(None),
+            display => match display.as_u64() {
+                Some(display) if display > 0 => Ok(Some(display as usize)),
+                _ => Err(QueryParseError::InvalidOrder("display must be a positive integer".to_string())),
             },
         };
-        let query_type = query["type"].as_str()?;
-        let symbol = query["symbol"].as_str();
-        println!("symbol: {:#?}", symbol);
-        let price = query["price"].as_f64();
-        let quantity = query["quantity"].as_u64();
-
-        match query_type {
-            "buy" => Some(Query::Buy(symbol?.to_string(), Order::new(id, price?, quantity? as usize))),
-            "sell" => Some(Query::Sell(symbol?.to_string(), Order::new(id, price?, quantity? as usize))),
-            "ohlc" => Some(Query::Ohlc(symbol?.to_string())),
-            "buy_orders" => Some(Query::BuyOrders(symbol?.to_string())),
-            "sell_orders" => Some(Query::SellOrders(symbol?.to_string())),
-            _ => None,
+        // Orders without a price are market orders.
+        let order = |side: Side| -> Result<Order, QueryParseError> {
+            let quantity = quantity()? as usize;
+            let order = match query["price"] {
+                serde_json::Value::Null => Order::market(id, side, quantity),
+                _ => Order::new(id, price()?, quantity),
+            };
+            Ok(order.with_tag(tag()?).with_time_in_force(time_in_force()?).with_display_qty(display_qty()?))
+        };
+        let level_count = || query["levels"].as_u64().map_or(DEFAULT_LEVELS, |levels| (levels as usize).min(MAX_LEVELS));
+        let side = || {
+            query["side"]
+                .as_str()
+                .and_then(|side| Side::try_from(side).ok())
+                .ok_or(QueryParseError::MissingField("side"))
+        };
+
+        let parsed = match query_type {
+            "buy" => Ok(Query::Buy(symbol()?, order(Side::Buy)?)),
+            "sell" => Ok(Query::Sell(symbol()?, order(Side::Sell)?)),
+            "ohlc" => Ok(Query::Ohlc(symbol()?)),
+            "ticker" => Ok(Query::Ticker(symbol()?)),
+            "vwap" => Ok(Query::Vwap(symbol()?)),
+            "book" => Ok(Query::Book(symbol()?)),
+            "buy_orders" => Ok(Query::BuyOrders(symbol()?, level_count())),
+            "sell_orders" => Ok(Query::SellOrders(symbol()?, level_count())),
+            "admin_login" => {
+                let token = query["token"].as_str().ok_or(QueryParseError::MissingField("token"))?;
+                Ok(Query::AdminLogin(token.to_string()))
+            }
+            // With a symbol, this resumes trading in the stock, otherwise it resumes a user with their token.
+            "resume" if !query["symbol"].is_null() => Ok(Query::Admin(AdminQuery::Halt(symbol()?, false))),
+            "resume" => {
+                let token = query["token"].as_str().ok_or(QueryParseError::MissingField("token"))?;
+                Ok(Query::Resume(token.to_string()))
+            }
+            "halt" => Ok(Query::Admin(AdminQuery::Halt(symbol()?, true))),
+            "cancel_everything" => Ok(Query::Admin(AdminQuery::CancelEverything)),
+            "session_info" => Ok(Query::SessionInfo),
+            "volume_profile" => Ok(Query::VolumeProfile(symbol()?)),
+            "indicative_price" => Ok(Query::IndicativePrice(symbol()?)),
+            "symbol_info" => Ok(Query::SymbolInfo(symbol()?)),
+            "spread_history" => {
+                let limit = query["limit"].as_u64().map_or(DEFAULT_CANDLE_LIMIT, |limit| limit as usize);
+                Ok(Query::SpreadHistory(symbol()?, limit))
+            }
+            "candles" => {
+                let limit = query["limit"].as_u64().map_or(DEFAULT_CANDLE_LIMIT, |limit| limit as usize);
+                Ok(Query::Candles(symbol()?, limit))
+            }
+            "cancel" => {
+                let order_id = query["order_id"].as_u64().ok_or(QueryParseError::MissingField("order_id"))?;
+                Ok(Query::Cancel(symbol()?, order_id))
+            }
+            "cancel_oldest" => Ok(Query::CancelOldest(symbol()?, side()?)),
+            "cancel_newest" => Ok(Query::CancelNewest(symbol()?, side()?)),
+            "order_stats" => Ok(Query::OrderStats(query["user_id"].as_u64().map(|user_id| user_id as usize))),
+            "quote_update" => Ok(Query::QuoteUpdate { symbol: symbol()?, bids: levels("bids")?, asks: levels("asks")? }),
+            "add_stock" => {
+                let name = query["name"].as_str().ok_or(QueryParseError::MissingField("name"))?;
+                Ok(Query::Admin(AdminQuery::ListStock(symbol()?, name.to_string())))
+            }
+            "activity" => {
+                let limit = query["limit"].as_u64().map_or(DEFAULT_ACTIVITY_LIMIT, |limit| limit as usize);
+                Ok(Query::Activity(limit))
+            }
+            "schema" => Ok(Query::Schema),
+            "config" => Ok(Query::Admin(AdminQuery::Config)),
+            "subscribe" => Ok(Query::Subscribe(symbol()?)),
+            "reservations" => Ok(Query::Reservations),
+            "balances" => Ok(Query::Balances),
+            "portfolio" => Ok(Query::Portfolio),
+            "symbols" => Ok(Query::Symbols),
+            "market_overview" => Ok(Query::MarketOverview),
+            "my_rejections" => {
+                let limit = query["limit"].as_u64().map_or(DEFAULT_REJECTION_LIMIT, |limit| limit as usize);
+                Ok(Query::MyRejections(limit))
+            }
+            "echo" => {
+                let payload = query["payload"].as_str().ok_or(QueryParseError::MissingField("payload"))?;
+                if payload.len() > MAX_FRAME_BYTES {
+                    return Err(QueryParseError::PayloadTooLarge(MAX_FRAME_BYTES));
+                }
+                Ok(Query::Echo(payload.to_string()))
+            }
+            "ping" => Ok(Query::Ping(query["ts"].as_u64().ok_or(QueryParseError::MissingField("ts"))?)),
+            "status" => Ok(Query::Status),
+            "book_age" => Ok(Query::BookAge(symbol()?)),
+            "queue_depth" => Ok(Query::QueueDepth(symbol()?, side()?, price()?)),
+            "depth" => Ok(Query::Depth(symbol()?)),
+            "reprice" => {
+                let order_id = query["order_id"].as_u64().ok_or(QueryParseError::MissingField("order_id"))?;
+                Ok(Query::Reprice(order_id, price()?))
+            }
+            "modify" => {
+                let order_id = query["order_id"].as_u64().ok_or(QueryParseError::MissingField("order_id"))?;
+                Ok(Query::Modify { symbol: symbol()?, order_id, price: price()?, quantity: quantity()? as usize })
+            }
+            "order_entry_halt" => {
+                let halted = query["halted"].as_bool().ok_or(QueryParseError::MissingField("halted"))?;
+                Ok(Query::Admin(AdminQuery::OrderEntryHalt(halted)))
+            }
+            "reduce" => {
+                let order_id = query["order_id"].as_u64().ok_or(QueryParseError::MissingField("order_id"))?;
+                let reduce_by = query["reduce_by"].as_u64().ok_or(QueryParseError::MissingField("reduce_by"))?;
+                Ok(Query::Reduce(order_id, reduce_by as usize))
+            }
+            "order_flow" => {
+                let window_secs = query["window_secs"].as_u64().ok_or(QueryParseError::MissingField("window_secs"))?;
+                Ok(Query::OrderFlow(symbol()?, window_secs))
+            }
+            "list_stock" => {
+                let name = query["name"].as_str().ok_or(QueryParseError::MissingField("name"))?;
+                Ok(Query::Admin(AdminQuery::ListStock(symbol()?, name.to_string())))
+            }
+            "halt_symbol" => {
+                let halted = query["halted"].as_bool().ok_or(QueryParseError::MissingField("halted"))?;
+                Ok(Query::Admin(AdminQuery::Halt(symbol()?, halted)))
+            }
+            "force_cancel" => {
+                let order_id = query["order_id"].as_u64().ok_or(QueryParseError::MissingField("order_id"))?;
+                Ok(Query::Admin(AdminQuery::CancelOrder(order_id)))
+            }
+            "order_exists" => {
+                let order_id = query["order_id"].as_u64().ok_or(QueryParseError::MissingField("order_id"))?;
+                Ok(Query::OrderExists(order_id))
+            }
+            unknown => Err(QueryParseError::UnknownType(unknown.to_string())),
+        }?;
+        Ok(parsed.with_req_id(req_id))
+    }
+
+    /// Returns the `req_id` of a message, if it is JSON with one, so that even a query that can't be parsed can be
+    /// answered with its number.
+    pub fn peek_req_id(json: &str) -> Option<u64> {
+        serde_json::from_str::<serde_json::Value>(json).ok()?["req_id"].as_u64()
+    }
+
+    /// Numbers the query with `req_id`, if there is one.
+    pub fn with_req_id(self, req_id: Option<u64>) -> Self {
+        match req_id {
+            Some(req_id) => Query::Numbered(req_id, Box::new(self)),
+            None => self,
+        }
+    }
+
+    /// Separates a numbered query into the query itself and its number.
+    pub fn split_req_id(self) -> (Self, Option<u64>) {
+        match self {
+            Query::Numbered(req_id, query) => (*query, Some(req_id)),
+            query => (query, None),
+        }
+    }
+}
+
+/// Why a message from a client could not be parsed into a `Query`.
+#[derive(Debug, PartialEq)]
+pub enum QueryParseError {
+    /// The message is not valid JSON.
+    InvalidJson(String),
+    /// The `type` field names a query that doesn't exist.
+    UnknownType(String),
+    /// A field the query needs is absent or has the wrong type.
+    MissingField(&'static str),
+    /// The fields are present but describe an order that can't be placed.
+    InvalidOrder(String),
+    /// A field is longer than this many bytes.
+    PayloadTooLarge(usize),
+}
+
+impl std::fmt::Display for QueryParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryParseError::InvalidJson(e) => write!(f, "invalid JSON: {}", e),
+            QueryParseError::UnknownType(query_type) => write!(f, "unknown query type `{}`", query_type),
+            QueryParseError::MissingField(field) => write!(f, "missing field `{}`", field),
+            QueryParseError::InvalidOrder(reason) => write!(f, "invalid order: {}", reason),
+            QueryParseError::PayloadTooLarge(limit) => write!(f, "payload larger than {} bytes", limit),
+        }
+    }
+}
+
+impl QueryParseError {
+    /// Returns the code the error is reported to the client with.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            QueryParseError::InvalidJson(_) => ErrorCode::MalformedRequest,
+            QueryParseError::UnknownType(_) => ErrorCode::UnknownType,
+            QueryParseError::MissingField(_) => ErrorCode::MissingField,
+            QueryParseError::InvalidOrder(_) => ErrorCode::InvalidOrder,
+            QueryParseError::PayloadTooLarge(_) => ErrorCode::PayloadTooLarge,
         }
     }
 }
 
+impl From<QueryParseError> for QueryResponse {
+    fn from(error: QueryParseError) -> Self {
+        let message = match &error {
+            QueryParseError::InvalidOrder(reason) => reason.clone(),
+            error => error.to_string(),
+        };
+        QueryResponse::Error { code: error.code(), message }
+    }
+}
+
 /// A response from the market to a query.
+///
+/// Responses are serialized as JSON objects whose `response` field names the variant.
+#[derive(Serialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
 pub enum QueryResponse {
     // Successes
     /// Socket tx stored.
     Connected,
-    /// The order was successfully posted.
-    OrderPosted,
+    /// The order with this ID was successfully posted.
+    ///
+    /// A warning is attached if the order was accepted but looks suspicious, e.g. its value is unusually large. The
+    /// order's tag is echoed if it has one.
+    OrderPosted {
+        order_id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        warning: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tag: Option<OrderTag>,
+    },
+    /// The order with this ID was added to the book outside trading hours, and won't be matched until the market opens.
+    OrderQueued { order_id: u64 },
     /// A vector of pending orders for the stock.
     ///
-    /// It contains a limited number of unique prices and their quantities. The number of unique prices is defined by `NO_OF_PRICES_QUERIED`.
-    QueriedOrders(Vec<(f64, usize)>),
+    /// It contains a limited number of unique prices and their quantities. The number of unique prices is set by the query, up to `MAX_LEVELS`.
+    QueriedOrders {
+        #[serde(serialize_with = "serialize_order_levels")]
+        orders: Vec<(f64, usize)>,
+    },
     /// The open, high, low, close prices for the stock.
-    Ohlc(Option<f64>, Option<f64>, Option<f64>, Option<f64>),
+    Ohlc {
+        #[serde(serialize_with = "json::optional_price")]
+        open: Option<f64>,
+        #[serde(serialize_with = "json::optional_price")]
+        high: Option<f64>,
+        #[serde(serialize_with = "json::optional_price")]
+        low: Option<f64>,
+        #[serde(serialize_with = "json::optional_price")]
+        close: Option<f64>,
+    },
+    /// The last trade price of a stock, `None` if it has never traded, and the total quantity traded in it.
+    Ticker {
+        #[serde(serialize_with = "json::optional_price")]
+        last: Option<f64>,
+        volume: usize,
+    },
+    /// The volume-weighted average price of a stock's trades in the current candle, `None` if there were none.
+    Vwap {
+        #[serde(serialize_with = "json::optional_price")]
+        vwap: Option<f64>,
+    },
+    /// A stock's resting orders, each side in priority order. `truncated` is set if the book held more orders than were
+    /// returned.
+    Book { bids: Vec<BookEntry>, asks: Vec<BookEntry>, truncated: bool },
     /// Receipt of a completed trade.
     ExecutedTrade(Trade),
+    /// A trade in the stock, as published on the public tape. The counterparties aren't identified.
+    TradePrint {
+        symbol: String,
+        #[serde(serialize_with = "json::price")]
+        price: f64,
+        quantity: usize,
+    },
+    /// The connection will receive prints of the stock's trades.
+    Subscribed { symbol: String },
+    /// Admin privileges were granted to the connection.
+    AdminGranted,
+    /// The connection now acts as the user its reconnect token identifies, whose earlier state was restored if they
+    /// had connected before.
+    Resumed { restored: bool },
+    /// The order with this ID was removed from the book without being filled.
+    OrderCancelled { order_id: u64 },
+    /// The order with this ID was moved to a new price, and has this quantity left.
+    OrderRepriced {
+        order_id: u64,
+        #[serde(serialize_with = "json::price")]
+        price: f64,
+        quantity: usize,
+    },
+    /// The order with this ID now has this price and remaining quantity.
+    OrderModified {
+        order_id: u64,
+        #[serde(serialize_with = "json::price")]
+        price: f64,
+        quantity: usize,
+    },
+    /// The order with this ID rested for longer than the market allows, and was removed from the book.
+    OrderExpired { order_id: u64 },
+    /// The order with this ID was reduced, and has this quantity left. It is no longer in the book if none is left.
+    OrderReduced { order_id: u64, remaining: usize },
+    /// Whether order entry is now halted.
+    OrderEntryHalted { halted: bool },
+    /// Whether trading in the stock is now halted.
+    SymbolHalted { symbol: String, halted: bool },
+    /// The number of orders removed by `AdminQuery::CancelEverything`.
+    CancelledEverything { count: usize },
+    /// When the session started, how many seconds it has run, and when it next resets (if resets are configured).
+    SessionInfo {
+        #[serde(serialize_with = "json::time")]
+        started: DateTime<Utc>,
+        elapsed_secs: i64,
+        #[serde(serialize_with = "json::optional_time")]
+        next_reset: Option<DateTime<Utc>>,
+    },
+    /// Traded quantity per price for the stock, sorted in ascending order of price.
+    VolumeProfile {
+        #[serde(serialize_with = "serialize_volume_levels")]
+        levels: Vec<(f64, usize)>,
+    },
+    /// The auction clearing price and the quantity that would trade there. The price is `None`, and the quantity 0, if
+    /// the book doesn't cross.
+    IndicativePrice {
+        #[serde(serialize_with = "json::optional_price")]
+        price: Option<f64>,
+        quantity: usize,
+    },
+    /// Instrument metadata for the stock.
+    SymbolInfo(SymbolInfo),
+    /// Recent market events, oldest first.
+    Activity { events: Vec<MarketEvent> },
+    /// The stock was listed.
+    StockAdded,
+    /// The connection's quotes were replaced. Holds the IDs of the new bid and ask orders, in the order given.
+    QuotesReplaced { bid_ids: Vec<u64>, ask_ids: Vec<u64> },
+    /// Totals across every stock.
+    MarketOverview(MarketOverview),
+    /// The connection's most recently refused orders, oldest first.
+    Rejections { rejections: Vec<Rejection> },
+    /// The connection's settled and pending cash and holdings.
+    Balances(Balances),
+    /// The connection's spendable cash and its holding in each stock it has a position in, ordered by symbol.
+    Portfolio {
+        #[serde(serialize_with = "json::price")]
+        cash: f64,
+        #[serde(serialize_with = "serialize_positions")]
+        positions: Vec<(String, i64)>,
+    },
+    /// The symbol and name of every listed stock, ordered by symbol.
+    Symbols {
+        #[serde(serialize_with = "serialize_symbols")]
+        symbols: Vec<(String, String)>,
+    },
+    /// The payload of an echo query, unchanged.
+    Echo { payload: String },
+    /// The client's timestamp from a ping, unchanged.
+    Pong { ts: u64 },
+    /// The server's health, and the fees the exchange has collected less the rebates it has paid.
+    Status {
+        stocks: usize,
+        users: usize,
+        resting_orders: usize,
+        uptime_secs: u64,
+        #[serde(rename = "fees_collected", serialize_with = "json::signed_cents")]
+        fees_collected_cents: i64,
+    },
+    /// What each of the connection's resting orders holds back.
+    #[serde(serialize_with = "serialize_reservations")]
+    Reservations(Vec<Reservation>),
+    /// The number of orders and their total quantity resting at the requested price.
+    QueueDepth { orders: usize, quantity: usize },
+    /// The total quantity resting on each side of the book, and the number of price levels on each side.
+    Depth { total_bid_qty: usize, total_ask_qty: usize, bid_levels: usize, ask_levels: usize },
+    /// Milliseconds since the stock's book last changed.
+    BookAge { age_ms: i64 },
+    /// Buy-initiated minus sell-initiated volume over the requested window.
+    OrderFlow { net_volume: i64 },
+    /// Whether the order is still resting. Always false for orders placed by someone else.
+    OrderExists { exists: bool },
+    /// The server's effective configuration, with secrets redacted.
+    Config { config: serde_json::Value },
+    /// Every supported query type and its fields.
+    Schema { queries: &'static [QueryDef] },
+    /// Order activity counts of the user with the given ID.
+    OrderStats {
+        user_id: usize,
+        #[serde(flatten)]
+        stats: OrderStats,
+    },
+    /// The average spread of recent candles, oldest first.
+    SpreadHistory {
+        #[serde(serialize_with = "serialize_spreads")]
+        candles: Vec<Candle>,
+    },
+    /// The open, high, low and close prices of recent candles, oldest first.
+    Candles {
+        #[serde(serialize_with = "serialize_candles")]
+        candles: Vec<Candle>,
+    },
+
+    /// A response to a numbered query. It is written as the response itself with the query's `req_id` added.
+    #[serde(skip)]
+    Numbered { req_id: u64, response: Box<QueryResponse> },
 
     // Errors
-    /// The symbol provided was not found.
-    SymbolNotFound,
-    /// The symbol provided was not valid: 1 <= len(symbol) <= 4
-    SymbolInvalid
+    /// The query failed. Every failure is reported this way, with a code for programs and a message for people.
+    Error { code: ErrorCode, message: String },
+}
+
+impl From<ErrorCode> for QueryResponse {
+    /// Returns an error response with the code's own message.
+    fn from(code: ErrorCode) -> Self {
+        QueryResponse::Error { code, message: code.message().to_string() }
+    }
+}
+
+/// How prices are written in responses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PriceFormat {
+    /// JSON numbers, e.g. `150.0`.
+    #[default]
+    Number,
+    /// Strings with a fixed number of decimals, e.g. `"150.00"`, for clients that can't parse floats reliably.
+    String,
 }
 
 impl QueryResponse {
+    /// Serializes the response with prices as JSON numbers.
     pub fn to_json(&self) -> String {
+        self.to_json_with(PriceFormat::default())
+    }
+
+    /// Serializes the response, writing prices in the given format.
+    pub fn to_json_with(&self, price_format: PriceFormat) -> String {
+        json::PRICE_FORMAT.set(price_format);
         match self {
-            QueryResponse::Connected => r#"{"response": "connected"}"#.to_string(),
-            QueryResponse::OrderPosted => r#"{"response": "order_posted"}"#.to_string(),
-            QueryResponse::QueriedOrders(orders) => {
-                let orders: Vec<String> = orders
-                    .iter()
-                    .map(|(price, quantity)| {
-                        format!(
-                            r#"{{"price": {:.2}, "quantity": {}}}"#,
-                            price, quantity
-                        )
-                    })
-                    .collect();
-                format!(r#"{{"response": "queried_orders", "orders": [{}]}}"#, orders.join(","))
-            }
-            QueryResponse::Ohlc(open, high, low, close) => {
-                format!(
-                    r#"{{"response": "ohlc", "open": {:?}, "high": {:?}, "low": {:?}, "close": {:?}}}"#,
-                    open, high, low, close
-                )
-            }
-            QueryResponse::ExecutedTrade(trade) => {
-                format!(
-                    r#"{{"response": "executed_trade", "buyer_id": {}, "seller_id": {}, "price": {:.2}, "quantity": {}}}"#,
-                    trade.buyer_id, trade.seller_id, trade.price, trade.quantity
-                )
-            }
-            QueryResponse::SymbolNotFound => r#"{"response": "symbol_not_found"}"#.to_string(),
-            QueryResponse::SymbolInvalid => r#"{"response": "symbol_invalid"}"#.to_string(),
+            QueryResponse::Numbered { req_id, response } => {
+                let mut json = serde_json::to_value(response.as_ref()).expect("Responses should always serialize.");
+                json["req_id"] = (*req_id).into();
+                json.to_string()
+            }
+            response => serde_json::to_string(response).expect("Responses should always serialize."),
+        }
+    }
+
+    /// Numbers the response with the `req_id` of the query it answers, if the query had one.
+    pub fn with_req_id(self, req_id: Option<u64>) -> Self {
+        match req_id {
+            Some(req_id) => QueryResponse::Numbered { req_id, response: Box::new(self) },
+            None => self,
+        }
+    }
+
+    /// Returns the response, without the number of the query it answers if it has one.
+    pub fn unnumbered(&self) -> &Self {
+        match self {
+            QueryResponse::Numbered { response, .. } => response,
+            response => response,
         }
     }
 }
 
+/// Serializes price and quantity pairs as `{"price", "quantity"}` objects.
+fn serialize_order_levels<S: Serializer>(levels: &[(f64, usize)], serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Level {
+        #[serde(serialize_with = "json::price")]
+        price: f64,
+        quantity: usize,
+    }
+
+    serializer.collect_seq(levels.iter().map(|&(price, quantity)| Level { price, quantity }))
+}
+
+/// Serializes price and volume pairs as `{"price", "volume"}` objects.
+fn serialize_volume_levels<S: Serializer>(levels: &[(f64, usize)], serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct VolumeLevel {
+        #[serde(serialize_with = "json::price")]
+        price: f64,
+        volume: usize,
+    }
+
+    serializer.collect_seq(levels.iter().map(|&(price, volume)| VolumeLevel { price, volume }))
+}
+
+/// Serializes symbol and share count pairs as `{"symbol", "shares"}` objects.
+fn serialize_positions<S: Serializer>(positions: &[(String, i64)], serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Position<'a> {
+        symbol: &'a str,
+        shares: i64,
+    }
+
+    serializer.collect_seq(positions.iter().map(|(symbol, shares)| Position { symbol, shares: *shares }))
+}
+
+/// Serializes symbol and name pairs as `{"symbol", "name"}` objects.
+fn serialize_symbols<S: Serializer>(symbols: &[(String, String)], serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Listing<'a> {
+        symbol: &'a str,
+        name: &'a str,
+    }
+
+    serializer.collect_seq(symbols.iter().map(|(symbol, name)| Listing { symbol, name }))
+}
+
+/// Serializes the window and average spread of each candle, with the spread rounded to four decimals.
+fn serialize_spreads<S: Serializer>(candles: &[Candle], serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Spread {
+        #[serde(serialize_with = "json::time")]
+        start: DateTime<Utc>,
+        #[serde(serialize_with = "json::time")]
+        end: DateTime<Utc>,
+        avg_spread: Option<f64>,
+    }
+
+    serializer.collect_seq(candles.iter().map(|candle| Spread {
+        start: candle.start,
+        end: candle.end,
+        avg_spread: candle.avg_spread.map(|spread| (spread * 1e4).round() / 1e4),
+    }))
+}
+
+/// Serializes the window and trade prices of each candle.
+fn serialize_candles<S: Serializer>(candles: &[Candle], serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Prices {
+        #[serde(serialize_with = "json::time")]
+        start: DateTime<Utc>,
+        #[serde(serialize_with = "json::time")]
+        end: DateTime<Utc>,
+        #[serde(serialize_with = "json::optional_price")]
+        open: Option<f64>,
+        #[serde(serialize_with = "json::optional_price")]
+        high: Option<f64>,
+        #[serde(serialize_with = "json::optional_price")]
+        low: Option<f64>,
+        #[serde(serialize_with = "json::optional_price")]
+        close: Option<f64>,
+    }
+
+    serializer.collect_seq(candles.iter().map(|candle| Prices {
+        start: candle.start,
+        end: candle.end,
+        open: candle.open,
+        high: candle.high,
+        low: candle.low,
+        close: candle.close,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::schema::FieldKind;
+    use super::super::OrderKind;
+
+    /// Tests that each kind of unparseable request gets its own error.
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(Query::from_json(r#"{"type": "buy""#, 1), Err(QueryParseError::InvalidJson(_))));
+        assert!(matches!(
+            Query::from_json(r#"{"type": "short"}"#, 1),
+            Err(QueryParseError::UnknownType(query_type)) if query_type == "short"
+        ));
+        assert!(matches!(Query::from_json(r#"{"symbol": "V"}"#, 1), Err(QueryParseError::MissingField("type"))));
+        assert!(matches!(
+            Query::from_json(r#"{"type": "buy", "symbol": "V", "price": 100.0}"#, 1),
+            Err(QueryParseError::MissingField("quantity"))
+        ));
+        assert!(matches!(
+            Query::from_json(r#"{"type": "buy", "symbol": "V", "price": 100.0, "quantity": 5}"#, 1),
+            Ok(Query::Buy(..))
+        ));
+    }
+
+    /// Tests that every query type in the schema can be parsed from a message built from its field definitions.
+    #[test]
+    fn test_schema_covers_parser() {
+        for def in schema::QUERY_SCHEMA {
+            let mut message = serde_json::json!({ "type": def.name });
+            for field in def.fields {
+                message[field.name] = match field.kind {
+                    FieldKind::String => serde_json::json!("V"),
+                    FieldKind::Number => serde_json::json!(100.0),
+                    FieldKind::Integer => serde_json::json!(1),
+                    FieldKind::Boolean => serde_json::json!(true),
+                    FieldKind::Side => serde_json::json!("buy"),
+                    FieldKind::TimeInForce => serde_json::json!("ioc"),
+                    FieldKind::Levels => serde_json::json!([[100.0, 1]]),
+                };
+            }
+            assert!(Query::from_json(&message.to_string(), 1).is_ok(), "`{}` failed to parse", def.name);
+        }
+
+        let schema: serde_json::Value =
+            serde_json::from_str(&QueryResponse::Schema { queries: schema::QUERY_SCHEMA }.to_json()).unwrap();
+        assert_eq!(schema["queries"].as_array().unwrap().len(), schema::QUERY_SCHEMA.len());
+    }
+
+    /// Tests that prices can be written as fixed-decimal strings, and are numbers by default.
+    #[test]
+    fn test_price_format() {
+        let orders = QueryResponse::QueriedOrders { orders: vec![(150.0, 5)] };
+        let parse = |json: String| serde_json::from_str::<serde_json::Value>(&json).unwrap();
+        assert_eq!(
+            parse(orders.to_json()),
+            serde_json::json!({"response": "queried_orders", "orders": [{"price": 150.0, "quantity": 5}]})
+        );
+        assert_eq!(
+            parse(orders.to_json_with(PriceFormat::String)),
+            serde_json::json!({"response": "queried_orders", "orders": [{"price": "150.00", "quantity": 5}]})
+        );
+
+        let ohlc: serde_json::Value = serde_json::from_str(
+            &QueryResponse::Ohlc { open: Some(150.0), high: Some(151.5), low: None, close: None }.to_json_with(PriceFormat::String),
+        )
+        .unwrap();
+        assert_eq!(ohlc["open"], "150.00");
+        assert_eq!(ohlc["high"], "151.50");
+        assert!(ohlc["low"].is_null());
+
+        let events = QueryResponse::Activity { events: vec![MarketEvent::TopOfBook {
+            symbol: crate::types::Symbol::try_from("V").unwrap(),
+            bid: Some(99.5),
+            ask: None,
+            time: Utc::now(),
+        }] };
+        let events: serde_json::Value = serde_json::from_str(&events.to_json_with(PriceFormat::String)).unwrap();
+        assert_eq!(events["events"][0]["bid"], "99.50");
+        assert!(events["events"][0]["ask"].is_null());
+    }
+
+    /// Tests that quote updates parse both sides as price and quantity pairs.
+    #[test]
+    fn test_parse_quote_update() {
+        let json = r#"{"type": "quote_update", "symbol": "V", "bids": [[99.5, 10], [99.0, 20]], "asks": [[100.5, 10]]}"#;
+        match Query::from_json(json, 7) {
+            Ok(Query::QuoteUpdate { symbol, bids, asks }) => {
+                assert_eq!(symbol, "V");
+                let levels: Vec<_> = bids.iter().map(|order| (order.get_price(), order.get_quantity())).collect();
+                assert_eq!(levels, vec![(99.5, 10), (99.0, 20)]);
+                assert_eq!(asks.len(), 1);
+                assert!(bids.iter().chain(asks.iter()).all(|order| order.get_creator_id() == 7));
+            }
+            _ => panic!("expected a quote update"),
+        }
+        assert!(matches!(
+            Query::from_json(r#"{"type": "quote_update", "symbol": "V", "bids": []}"#, 7),
+            Err(QueryParseError::MissingField("asks"))
+        ));
+    }
+
+    /// Tests that echo payloads longer than a frame are rejected.
+    #[test]
+    fn test_parse_echo_limit() {
+        let echo = |payload: &str| format!(r#"{{"type": "echo", "payload": "{}"}}"#, payload);
+        assert!(matches!(
+            Query::from_json(&echo(&"a".repeat(MAX_FRAME_BYTES)), 1),
+            Ok(Query::Echo(payload)) if payload.len() == MAX_FRAME_BYTES
+        ));
+        assert!(matches!(
+            Query::from_json(&echo(&"a".repeat(MAX_FRAME_BYTES + 1)), 1),
+            Err(QueryParseError::PayloadTooLarge(MAX_FRAME_BYTES))
+        ));
+    }
+
+    /// Tests that order book queries default to five levels and are capped at `MAX_LEVELS`.
+    #[test]
+    fn test_parse_levels() {
+        let levels = |json: &str| match Query::from_json(json, 1) {
+            Ok(Query::BuyOrders(_, levels) | Query::SellOrders(_, levels)) => levels,
+            _ => panic!("expected an order book query"),
+        };
+        assert_eq!(levels(r#"{"type": "buy_orders", "symbol": "V"}"#), 5);
+        assert_eq!(levels(r#"{"type": "sell_orders", "symbol": "V", "levels": 20}"#), 20);
+        assert_eq!(levels(r#"{"type": "buy_orders", "symbol": "V", "levels": 1000000}"#), MAX_LEVELS);
+    }
+
+    /// Tests that orders without a price are parsed as market orders.
+    #[test]
+    fn test_parse_market_order() {
+        match Query::from_json(r#"{"type": "buy", "symbol": "V", "quantity": 10}"#, 1) {
+            Ok(Query::Buy(_, order)) => assert_eq!((order.get_kind(), order.get_quantity()), (OrderKind::Market, 10)),
+            _ => panic!("expected a market buy"),
+        }
+        assert!(matches!(
+            Query::from_json(r#"{"type": "sell", "symbol": "V", "price": 100.0, "quantity": 10}"#, 1),
+            Ok(Query::Sell(_, order)) if order.get_kind() == OrderKind::Limit
+        ));
+    }
+
+    /// Tests that quantities that aren't whole numbers that fit are rejected as invalid, not missing.
+    #[test]
+    fn test_parse_invalid_quantity() {
+        let buy = |quantity: &str| format!(r#"{{"type": "buy", "symbol": "V", "price": 100.0, "quantity": {}}}"#, quantity);
+        for quantity in ["0", "10.5", "-5", "18446744073709551616", "1e30"] {
+            assert!(
+                matches!(
+                    Query::from_json(&buy(quantity), 1),
+                    Err(QueryParseError::InvalidOrder(reason)) if reason == "quantity must be a positive integer"
+                ),
+                "quantity {} should be invalid",
+                quantity
+            );
+        }
+        assert!(matches!(
+            Query::from_json(r#"{"type": "buy", "symbol": "V", "price": 100.0}"#, 1),
+            Err(QueryParseError::MissingField("quantity"))
+        ));
+        assert!(matches!(
+            Query::from_json(&buy(r#""5""#), 1),
+            Err(QueryParseError::MissingField("quantity"))
+        ));
+    }
+
+    /// Tests that the time in force defaults to good-till-cancelled and that unknown values are rejected.
+    #[test]
+    fn test_parse_time_in_force() {
+        let buy = |tif: &str| format!(r#"{{"type": "buy", "symbol": "V", "price": 100.0, "quantity": 5{}}}"#, tif);
+        assert!(matches!(
+            Query::from_json(&buy(""), 1),
+            Ok(Query::Buy(_, order)) if order.get_time_in_force() == TimeInForce::Gtc
+        ));
+        assert!(matches!(
+            Query::from_json(&buy(r#", "tif": "fok""#), 1),
+            Ok(Query::Buy(_, order)) if order.get_time_in_force() == TimeInForce::Fok
+        ));
+        assert!(matches!(
+            Query::from_json(&buy(r#", "tif": "day""#), 1),
+            Err(QueryParseError::InvalidOrder(reason)) if reason == "tif must be one of gtc, ioc or fok"
+        ));
+    }
+
+    /// Tests that tags longer than the limit, or that aren't strings, are rejected.
+    #[test]
+    fn test_parse_tag() {
+        let sell = |tag: &str| format!(r#"{{"type": "sell", "symbol": "V", "price": 100.0, "quantity": 5, "tag": {}}}"#, tag);
+        assert!(matches!(
+            Query::from_json(&sell(&format!(r#""{}""#, "a".repeat(MAX_TAG_LEN))), 1),
+            Ok(Query::Sell(_, order)) if order.get_tag().is_some_and(|tag| tag.as_str().len() == MAX_TAG_LEN)
+        ));
+        for tag in [format!(r#""{}""#, "a".repeat(MAX_TAG_LEN + 1)), "7".to_string()] {
+            assert!(matches!(Query::from_json(&sell(&tag), 1), Err(QueryParseError::InvalidOrder(_))));
+        }
+    }
+
+    /// Tests that a negative price is rejected rather than stored as zero.
+    #[test]
+    fn test_parse_negative_price() {
+        assert!(matches!(
+            Query::from_json(r#"{"type": "sell", "symbol": "V", "price": -5.0, "quantity": 5}"#, 1),
+            Err(QueryParseError::InvalidOrder(reason)) if reason == "price out of range"
+        ));
+    }
+
+    /// Tests that a price too large to store is rejected before an order is made from it.
+    #[test]
+    fn test_parse_huge_price() {
+        let json = format!(r#"{{"type": "buy", "symbol": "V", "price": {:e}, "quantity": 5}}"#, f64::MAX);
+        assert!(matches!(
+            Query::from_json(&json, 1),
+            Err(QueryParseError::InvalidOrder(reason)) if reason == "price out of range"
+        ));
+        assert!(Query::from_json(r#"{"type": "buy", "symbol": "V", "price": 1e400, "quantity": 5}"#, 1).is_err());
+    }
+
+    /// Tests that parse errors map onto distinct responses.
+    #[test]
+    fn test_parse_error_responses() {
+        let json = |error| serde_json::from_str::<serde_json::Value>(&QueryResponse::from(error).to_json()).unwrap();
+        assert_eq!(
+            json(QueryParseError::UnknownType("short".to_string())),
+            serde_json::json!({"response": "error", "code": 101, "message": "unknown query type `short`"})
+        );
+        assert_eq!(
+            json(QueryParseError::MissingField("symbol")),
+            serde_json::json!({"response": "error", "code": 102, "message": "missing field `symbol`"})
+        );
+        assert_eq!(
+            json(QueryParseError::InvalidOrder("price out of range".to_string())),
+            serde_json::json!({"response": "error", "code": 200, "message": "price out of range"})
+        );
+        assert_eq!(json(QueryParseError::InvalidJson(String::new()))["code"], 100);
+
+        let Err(missing_type) = Query::from_json(r#"{"symbol": "V"}"#, 1) else { panic!("a query without a type should fail") };
+        assert_eq!(json(missing_type), serde_json::json!({"response": "error", "code": 102, "message": "missing field `type`"}));
+    }
+
+    /// Tests that OHLC responses are valid JSON, with missing prices as `null`.
+    #[test]
+    fn test_ohlc_json() {
+        let ohlc = QueryResponse::Ohlc { open: Some(150.0), high: Some(151.5), low: None, close: Some(149.25) }.to_json();
+        let ohlc: serde_json::Value = serde_json::from_str(&ohlc).unwrap();
+        assert_eq!(
+            ohlc,
+            serde_json::json!({"response": "ohlc", "open": 150.0, "high": 151.5, "low": null, "close": 149.25})
+        );
+    }
+
+    /// Tests the exact OHLC output for a stock that has traded and one that never has.
+    #[test]
+    fn test_ohlc_exact_json() {
+        let traded = QueryResponse::Ohlc { open: Some(150.0), high: Some(151.5), low: Some(149.0), close: Some(150.25) };
+        assert_eq!(traded.to_json(), r#"{"response":"ohlc","open":150.0,"high":151.5,"low":149.0,"close":150.25}"#);
+
+        let untraded = QueryResponse::Ohlc { open: None, high: None, low: None, close: None };
+        assert_eq!(untraded.to_json(), r#"{"response":"ohlc","open":null,"high":null,"low":null,"close":null}"#);
+        assert_eq!(
+            untraded.to_json_with(PriceFormat::String),
+            r#"{"response":"ohlc","open":null,"high":null,"low":null,"close":null}"#
+        );
+    }
+
+    /// Tests that every price-bearing response writes all of its prices, whether all, some or none of them are missing.
+    #[test]
+    fn test_optional_prices() {
+        let json = |response: QueryResponse, price_format| serde_json::from_str::<serde_json::Value>(&response.to_json_with(price_format)).unwrap();
+        let ohlc = |open, high, low, close| QueryResponse::Ohlc { open, high, low, close };
+
+        assert_eq!(
+            json(ohlc(None, None, None, None), PriceFormat::String),
+            serde_json::json!({"response": "ohlc", "open": null, "high": null, "low": null, "close": null})
+        );
+        assert_eq!(
+            json(ohlc(Some(150.0), Some(151.5), Some(149.0), Some(150.25)), PriceFormat::String),
+            serde_json::json!({"response": "ohlc", "open": "150.00", "high": "151.50", "low": "149.00", "close": "150.25"})
+        );
+        assert_eq!(
+            json(ohlc(Some(150.0), None, None, None), PriceFormat::String),
+            serde_json::json!({"response": "ohlc", "open": "150.00", "high": null, "low": null, "close": null})
+        );
+        assert_eq!(
+            json(ohlc(Some(150.0), None, None, None), PriceFormat::Number),
+            serde_json::json!({"response": "ohlc", "open": 150.0, "high": null, "low": null, "close": null})
+        );
+
+        assert_eq!(
+            json(QueryResponse::Ticker { last: None, volume: 0 }, PriceFormat::String),
+            serde_json::json!({"response": "ticker", "last": null, "volume": 0})
+        );
+        assert_eq!(
+            json(QueryResponse::IndicativePrice { price: Some(99.5), quantity: 3 }, PriceFormat::String),
+            serde_json::json!({"response": "indicative_price", "price": "99.50", "quantity": 3})
+        );
+        assert_eq!(
+            json(QueryResponse::Vwap { vwap: Some(100.3) }, PriceFormat::String),
+            serde_json::json!({"response": "vwap", "vwap": "100.30"})
+        );
+
+        assert_eq!(json::fmt_opt_price(Some(7.125)), "7.12");
+        assert_eq!(json::fmt_opt_price(None), "null");
+    }
+
+    /// Tests parsing an order's display quantity, which must be positive if given.
+    #[test]
+    fn test_parse_display() {
+        let buy = |display: &str| format!(r#"{{"type": "buy", "symbol": "V", "price": 100.0, "quantity": 50{}}}"#, display);
+        assert!(matches!(
+            Query::from_json(&buy(""), 1),
+            Ok(Query::Buy(_, order)) if order.get_visible_quantity() == 50
+        ));
+        assert!(matches!(
+            Query::from_json(&buy(r#", "display": 10"#), 1),
+            Ok(Query::Buy(_, order)) if order.get_visible_quantity() == 10 && order.get_quantity() == 50
+        ));
+        for display in [r#", "display": 0"#, r#", "display": "ten""#, r#", "display": -1"#] {
+            assert!(matches!(Query::from_json(&buy(display), 1), Err(QueryParseError::InvalidOrder(_))));
+        }
+    }
+
+    /// Tests that admin queries parse into their own enum.
+    #[test]
+    fn test_parse_admin_queries() {
+        assert!(matches!(
+            Query::from_json(r#"{"type": "list_stock", "symbol": "X", "name": "Xanthe"}"#, 1),
+            Ok(Query::Admin(AdminQuery::ListStock(symbol, name))) if symbol == "X" && name == "Xanthe"
+        ));
+        assert!(matches!(
+            Query::from_json(r#"{"type": "halt_symbol", "symbol": "V", "halted": true}"#, 1),
+            Ok(Query::Admin(AdminQuery::Halt(symbol, true))) if symbol == "V"
+        ));
+        assert!(matches!(Query::from_json(r#"{"type": "force_cancel", "order_id": 7}"#, 1), Ok(Query::Admin(AdminQuery::CancelOrder(7)))));
+        assert!(matches!(
+            Query::from_json(r#"{"type": "add_stock", "symbol": "X", "name": "Xanthe"}"#, 1),
+            Ok(Query::Admin(AdminQuery::ListStock(symbol, name))) if symbol == "X" && name == "Xanthe"
+        ));
+        assert!(matches!(Query::from_json(r#"{"type": "cancel_everything"}"#, 1), Ok(Query::Admin(AdminQuery::CancelEverything))));
+        assert!(matches!(Query::from_json(r#"{"type": "order_entry_halt", "halted": true}"#, 1), Ok(Query::Admin(AdminQuery::OrderEntryHalt(true)))));
+        assert!(matches!(Query::from_json(r#"{"type": "config"}"#, 1), Ok(Query::Admin(AdminQuery::Config))));
+        assert!(matches!(
+            Query::from_json(r#"{"type": "halt", "symbol": "V"}"#, 1),
+            Ok(Query::Admin(AdminQuery::Halt(symbol, true))) if symbol == "V"
+        ));
+        assert!(matches!(
+            Query::from_json(r#"{"type": "resume", "symbol": "V"}"#, 1),
+            Ok(Query::Admin(AdminQuery::Halt(symbol, false))) if symbol == "V"
+        ));
+        assert!(matches!(Query::from_json(r#"{"type": "resume", "token": "abc"}"#, 1), Ok(Query::Resume(token)) if token == "abc"));
+        assert!(matches!(Query::from_json(r#"{"type": "resume"}"#, 1), Err(QueryParseError::MissingField("token"))));
+        assert!(matches!(
+            Query::from_json(r#"{"type": "halt_symbol", "symbol": "V"}"#, 1),
+            Err(QueryParseError::MissingField("halted"))
+        ));
+    }
+}
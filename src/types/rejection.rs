@@ -0,0 +1,111 @@
+use super::{json, ErrorCode, QueryResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Number of rejections kept for each user.
+const REJECTION_CAPACITY: usize = 64;
+
+/// Why an order was refused.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RejectReason {
+    /// Order entry has been halted by an admin.
+    OrderEntryDisabled,
+    /// The order arrived outside trading hours.
+    MarketClosed,
+    /// The user posted an order too recently to post another.
+    TooFast,
+    /// The user doesn't have the cash to pay for the order.
+    InsufficientFunds,
+    /// The user doesn't hold the shares to deliver for the order.
+    InsufficientShares,
+    /// The order's price was too far from the stock's last trade price.
+    PriceOutOfBand,
+    /// Trading in the stock has been halted by an admin.
+    TradingHalted,
+    /// The symbol was not listed.
+    SymbolNotFound,
+    /// The symbol was not valid.
+    SymbolInvalid,
+    /// The order broke one of the configured limits, described by the string.
+    InvalidOrder(String),
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::OrderEntryDisabled => write!(f, "order_entry_disabled"),
+            RejectReason::MarketClosed => write!(f, "market_closed"),
+            RejectReason::TooFast => write!(f, "too_fast"),
+            RejectReason::InsufficientFunds => write!(f, "insufficient_funds"),
+            RejectReason::InsufficientShares => write!(f, "insufficient_shares"),
+            RejectReason::PriceOutOfBand => write!(f, "price_out_of_band"),
+            RejectReason::TradingHalted => write!(f, "trading_halted"),
+            RejectReason::SymbolNotFound => write!(f, "symbol_not_found"),
+            RejectReason::SymbolInvalid => write!(f, "symbol_invalid"),
+            RejectReason::InvalidOrder(reason) => write!(f, "invalid_order: {}", reason),
+        }
+    }
+}
+
+impl RejectReason {
+    /// Returns the code the rejection is reported to the client with.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RejectReason::OrderEntryDisabled => ErrorCode::OrderEntryDisabled,
+            RejectReason::MarketClosed => ErrorCode::MarketClosed,
+            RejectReason::TooFast => ErrorCode::TooFast,
+            RejectReason::InsufficientFunds => ErrorCode::InsufficientFunds,
+            RejectReason::InsufficientShares => ErrorCode::InsufficientShares,
+            RejectReason::PriceOutOfBand => ErrorCode::PriceOutOfBand,
+            RejectReason::TradingHalted => ErrorCode::TradingHalted,
+            RejectReason::SymbolNotFound => ErrorCode::SymbolNotFound,
+            RejectReason::SymbolInvalid => ErrorCode::SymbolInvalid,
+            RejectReason::InvalidOrder(_) => ErrorCode::InvalidOrder,
+        }
+    }
+}
+
+impl From<RejectReason> for QueryResponse {
+    fn from(reason: RejectReason) -> Self {
+        match reason {
+            RejectReason::InvalidOrder(message) => QueryResponse::Error { code: ErrorCode::InvalidOrder, message },
+            reason => reason.code().into(),
+        }
+    }
+}
+
+/// A refused order, as kept for its user to look back on.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Rejection {
+    #[serde(serialize_with = "json::display")]
+    pub reason: RejectReason,
+    /// A short description of the refused query, e.g. `buy V 5 @ 100.00`.
+    pub query: String,
+    #[serde(serialize_with = "json::time")]
+    pub time: DateTime<Utc>,
+}
+
+/// A bounded list of a user's most recent rejections.
+///
+/// Once full, the oldest rejection is dropped for every new one.
+#[derive(Default)]
+pub struct RejectionLog {
+    rejections: VecDeque<Rejection>,
+}
+
+impl RejectionLog {
+    /// Appends a rejection to the list.
+    pub fn push(&mut self, rejection: Rejection) {
+        if self.rejections.len() >= REJECTION_CAPACITY {
+            self.rejections.pop_front();
+        }
+        self.rejections.push_back(rejection);
+    }
+
+    /// Returns up to `limit` of the most recent rejections, oldest first.
+    pub fn recent(&self, limit: usize) -> Vec<Rejection> {
+        let skip = self.rejections.len().saturating_sub(limit);
+        self.rejections.iter().skip(skip).cloned().collect()
+    }
+}
@@ -0,0 +1,36 @@
+use super::{json, Side, Symbol};
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+
+/// What one of a user's resting orders holds back until it fills or is cancelled.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Reservation {
+    pub order_id: u64,
+    pub symbol: Symbol,
+    #[serde(serialize_with = "json::display")]
+    pub side: Side,
+    /// Shares still to be bought or sold.
+    pub quantity: usize,
+    /// Cash held to pay for a buy order, in cents. Sell orders hold shares rather than cash, so this is 0 for them.
+    #[serde(rename = "cash", serialize_with = "json::cents")]
+    pub cash_cents: u64,
+}
+
+/// Serializes a user's reservations as the total cash and the shares of each stock they hold back, along with the
+/// orders holding them.
+pub fn serialize_reservations<S: Serializer>(reservations: &[Reservation], serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Wire<'a> {
+        #[serde(serialize_with = "json::cents")]
+        cash: u64,
+        shares: HashMap<Symbol, usize>,
+        orders: &'a [Reservation],
+    }
+
+    let mut shares = HashMap::new();
+    for reservation in reservations.iter().filter(|reservation| reservation.side == Side::Sell) {
+        *shares.entry(reservation.symbol).or_insert(0) += reservation.quantity;
+    }
+    Wire { cash: reservations.iter().map(|reservation| reservation.cash_cents).sum(), shares, orders: reservations }
+        .serialize(serializer)
+}
@@ -0,0 +1,92 @@
+use chrono::{DateTime, NaiveTime, Utc};
+
+/// Daily hours, in UTC, during which the market matches orders.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TradingSchedule {
+    open: NaiveTime,
+    close: NaiveTime,
+}
+
+impl TradingSchedule {
+    /// Creates a schedule that opens and closes at the given times each day.
+    ///
+    /// If `close` is earlier than `open`, the market trades overnight.
+    pub fn new(open: NaiveTime, close: NaiveTime) -> Self {
+        Self { open, close }
+    }
+
+    /// Returns the daily opening time.
+    pub fn get_open(&self) -> NaiveTime {
+        self.open
+    }
+
+    /// Returns the daily closing time.
+    pub fn get_close(&self) -> NaiveTime {
+        self.close
+    }
+
+    /// Returns whether the market is open at the given time. The open time is inclusive and the close time exclusive.
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        let time = now.time();
+        if self.open <= self.close {
+            self.open <= time && time < self.close
+        } else {
+            time >= self.open || time < self.close
+        }
+    }
+}
+
+/// What happens to orders that arrive while the market is closed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClosedOrderPolicy {
+    /// The order is rejected.
+    #[default]
+    Reject,
+    /// The order rests in the book and is matched once the market opens.
+    Queue,
+}
+
+impl TryFrom<&str> for ClosedOrderPolicy {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, ()> {
+        match s {
+            "reject" => Ok(ClosedOrderPolicy::Reject),
+            "queue" => Ok(ClosedOrderPolicy::Queue),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for ClosedOrderPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClosedOrderPolicy::Reject => write!(f, "reject"),
+            ClosedOrderPolicy::Queue => write!(f, "queue"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// Tests day and overnight schedules at and around their boundaries.
+    #[test]
+    fn test_is_open() {
+        let at = |hour, min| Utc.with_ymd_and_hms(2024, 1, 1, hour, min, 0).unwrap();
+        let time = |hour, min| NaiveTime::from_hms_opt(hour, min, 0).unwrap();
+
+        let day = TradingSchedule::new(time(9, 30), time(16, 0));
+        assert!(!day.is_open(at(9, 29)));
+        assert!(day.is_open(at(9, 30)));
+        assert!(day.is_open(at(15, 59)));
+        assert!(!day.is_open(at(16, 0)));
+
+        let overnight = TradingSchedule::new(time(22, 0), time(2, 0));
+        assert!(overnight.is_open(at(23, 0)));
+        assert!(overnight.is_open(at(1, 0)));
+        assert!(!overnight.is_open(at(12, 0)));
+    }
+}
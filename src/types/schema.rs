@@ -0,0 +1,185 @@
+use super::{Side, TimeInForce};
+use serde::{Serialize, Serializer};
+
+/// The JSON type of a query field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldKind {
+    String,
+    /// Any JSON number, e.g. a price.
+    Number,
+    /// A non-negative whole number, e.g. a quantity.
+    Integer,
+    /// `true` or `false`.
+    Boolean,
+    /// `"buy"` or `"sell"`.
+    Side,
+    /// `"gtc"`, `"ioc"` or `"fok"`.
+    TimeInForce,
+    /// An array of `[price, quantity]` pairs.
+    Levels,
+}
+
+impl FieldKind {
+    /// Returns the name of the kind as shown in the schema.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FieldKind::String => "string",
+            FieldKind::Number => "number",
+            FieldKind::Integer => "integer",
+            FieldKind::Boolean => "boolean",
+            FieldKind::Side => "side",
+            FieldKind::TimeInForce => "time_in_force",
+            FieldKind::Levels => "levels",
+        }
+    }
+
+    /// Returns whether the value is of this kind.
+    pub fn matches(&self, value: &serde_json::Value) -> bool {
+        match self {
+            FieldKind::String => value.is_string(),
+            FieldKind::Number => value.is_number(),
+            FieldKind::Integer => value.is_u64(),
+            FieldKind::Boolean => value.is_boolean(),
+            FieldKind::Side => value.as_str().is_some_and(|side| Side::try_from(side).is_ok()),
+            FieldKind::TimeInForce => value.as_str().is_some_and(|tif| TimeInForce::try_from(tif).is_ok()),
+            FieldKind::Levels => value.as_array().is_some_and(|levels| {
+                levels.iter().all(|level| level[0].is_number() && level[1].is_u64())
+            }),
+        }
+    }
+}
+
+impl Serialize for FieldKind {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// A field of a query.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FieldDef {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub kind: FieldKind,
+    /// Whether the query is rejected without this field. Optional fields fall back to a default.
+    pub required: bool,
+}
+
+/// A query type clients can send, identified by the `type` field of the message.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct QueryDef {
+    #[serde(rename = "type")]
+    pub name: &'static str,
+    pub fields: &'static [FieldDef],
+}
+
+const fn required(name: &'static str, kind: FieldKind) -> FieldDef {
+    FieldDef { name, kind, required: true }
+}
+
+const fn optional(name: &'static str, kind: FieldKind) -> FieldDef {
+    FieldDef { name, kind, required: false }
+}
+
+const SYMBOL: FieldDef = required("symbol", FieldKind::String);
+
+/// Every query type the parser accepts, along with its fields.
+///
+/// `Query::from_json` rejects any type not listed here, and checks required fields against it before building the
+/// query, so this is the single place a new query type needs to be declared.
+pub const QUERY_SCHEMA: &[QueryDef] = &[
+    QueryDef {
+        name: "buy",
+        fields: &[
+            SYMBOL,
+            optional("price", FieldKind::Number),
+            required("quantity", FieldKind::Integer),
+            optional("tag", FieldKind::String),
+            optional("tif", FieldKind::TimeInForce),
+            optional("display", FieldKind::Integer),
+        ],
+    },
+    QueryDef {
+        name: "sell",
+        fields: &[
+            SYMBOL,
+            optional("price", FieldKind::Number),
+            required("quantity", FieldKind::Integer),
+            optional("tag", FieldKind::String),
+            optional("tif", FieldKind::TimeInForce),
+            optional("display", FieldKind::Integer),
+        ],
+    },
+    QueryDef { name: "ohlc", fields: &[SYMBOL] },
+    QueryDef { name: "ticker", fields: &[SYMBOL] },
+    QueryDef { name: "vwap", fields: &[SYMBOL] },
+    QueryDef { name: "book", fields: &[SYMBOL] },
+    QueryDef { name: "buy_orders", fields: &[SYMBOL, optional("levels", FieldKind::Integer)] },
+    QueryDef { name: "sell_orders", fields: &[SYMBOL, optional("levels", FieldKind::Integer)] },
+    QueryDef { name: "admin_login", fields: &[required("token", FieldKind::String)] },
+    QueryDef { name: "resume", fields: &[optional("token", FieldKind::String), optional("symbol", FieldKind::String)] },
+    QueryDef { name: "halt", fields: &[SYMBOL] },
+    QueryDef { name: "cancel_everything", fields: &[] },
+    QueryDef { name: "session_info", fields: &[] },
+    QueryDef { name: "volume_profile", fields: &[SYMBOL] },
+    QueryDef { name: "indicative_price", fields: &[SYMBOL] },
+    QueryDef { name: "symbol_info", fields: &[SYMBOL] },
+    QueryDef { name: "spread_history", fields: &[SYMBOL, optional("limit", FieldKind::Integer)] },
+    QueryDef { name: "candles", fields: &[SYMBOL, optional("limit", FieldKind::Integer)] },
+    QueryDef { name: "cancel", fields: &[SYMBOL, required("order_id", FieldKind::Integer)] },
+    QueryDef { name: "cancel_oldest", fields: &[SYMBOL, required("side", FieldKind::Side)] },
+    QueryDef { name: "cancel_newest", fields: &[SYMBOL, required("side", FieldKind::Side)] },
+    QueryDef { name: "order_stats", fields: &[optional("user_id", FieldKind::Integer)] },
+    QueryDef {
+        name: "quote_update",
+        fields: &[SYMBOL, required("bids", FieldKind::Levels), required("asks", FieldKind::Levels)],
+    },
+    QueryDef { name: "add_stock", fields: &[SYMBOL, required("name", FieldKind::String)] },
+    QueryDef { name: "activity", fields: &[optional("limit", FieldKind::Integer)] },
+    QueryDef { name: "schema", fields: &[] },
+    QueryDef { name: "order_flow", fields: &[SYMBOL, required("window_secs", FieldKind::Integer)] },
+    QueryDef { name: "config", fields: &[] },
+    QueryDef { name: "subscribe", fields: &[SYMBOL] },
+    QueryDef { name: "reservations", fields: &[] },
+    QueryDef { name: "balances", fields: &[] },
+    QueryDef { name: "portfolio", fields: &[] },
+    QueryDef { name: "symbols", fields: &[] },
+    QueryDef { name: "market_overview", fields: &[] },
+    QueryDef { name: "my_rejections", fields: &[optional("limit", FieldKind::Integer)] },
+    QueryDef { name: "echo", fields: &[required("payload", FieldKind::String)] },
+    QueryDef { name: "ping", fields: &[required("ts", FieldKind::Integer)] },
+    QueryDef { name: "status", fields: &[] },
+    QueryDef { name: "book_age", fields: &[SYMBOL] },
+    QueryDef { name: "depth", fields: &[SYMBOL] },
+    QueryDef {
+        name: "queue_depth",
+        fields: &[SYMBOL, required("side", FieldKind::Side), required("price", FieldKind::Number)],
+    },
+    QueryDef {
+        name: "reprice",
+        fields: &[required("order_id", FieldKind::Integer), required("price", FieldKind::Number)],
+    },
+    QueryDef {
+        name: "modify",
+        fields: &[
+            SYMBOL,
+            required("order_id", FieldKind::Integer),
+            required("price", FieldKind::Number),
+            required("quantity", FieldKind::Integer),
+        ],
+    },
+    QueryDef { name: "order_entry_halt", fields: &[required("halted", FieldKind::Boolean)] },
+    QueryDef { name: "list_stock", fields: &[SYMBOL, required("name", FieldKind::String)] },
+    QueryDef { name: "halt_symbol", fields: &[SYMBOL, required("halted", FieldKind::Boolean)] },
+    QueryDef { name: "force_cancel", fields: &[required("order_id", FieldKind::Integer)] },
+    QueryDef { name: "order_exists", fields: &[required("order_id", FieldKind::Integer)] },
+    QueryDef {
+        name: "reduce",
+        fields: &[required("order_id", FieldKind::Integer), required("reduce_by", FieldKind::Integer)],
+    },
+];
+
+/// Returns the definition of the query type with the given name, if it exists.
+pub fn lookup(name: &str) -> Option<&'static QueryDef> {
+    QUERY_SCHEMA.iter().find(|def| def.name == name)
+}
@@ -0,0 +1,84 @@
+use chrono::{DateTime, Duration, Utc};
+
+/// Timing of the trading session.
+///
+/// The session starts with the market loop. If a reset interval is set, the OHLC of every stock is cleared each time
+/// the interval elapses.
+pub struct Session {
+    /// When the session started.
+    started: DateTime<Utc>,
+    /// How often the session resets, if at all.
+    reset_interval: Option<Duration>,
+    /// When the session last reset (or started, if it hasn't reset yet).
+    last_reset: DateTime<Utc>,
+}
+
+impl Session {
+    /// Starts a new session at the given time.
+    pub fn new(started: DateTime<Utc>, reset_interval: Option<Duration>) -> Self {
+        Self {
+            started,
+            reset_interval,
+            last_reset: started,
+        }
+    }
+
+    /// Starts the session again at the given time, keeping its reset interval.
+    #[cfg(test)]
+    pub fn restart(&mut self, started: DateTime<Utc>) {
+        self.started = started;
+        self.last_reset = started;
+    }
+
+    /// Returns when the session started.
+    pub fn get_start(&self) -> DateTime<Utc> {
+        self.started
+    }
+
+    /// Returns how long the session has been running.
+    pub fn elapsed(&self, now: DateTime<Utc>) -> Duration {
+        now - self.started
+    }
+
+    /// Returns when the session will next reset, or `None` if no reset interval is configured.
+    pub fn next_reset(&self) -> Option<DateTime<Utc>> {
+        self.reset_interval.map(|interval| self.last_reset + interval)
+    }
+
+    /// Sets how often the session resets.
+    pub fn set_reset_interval(&mut self, reset_interval: Option<Duration>) {
+        self.reset_interval = reset_interval;
+    }
+
+    /// Marks the session as reset if the next reset is due, returning whether it was.
+    pub fn roll(&mut self, now: DateTime<Utc>) -> bool {
+        match self.next_reset() {
+            Some(next_reset) if now >= next_reset => {
+                self.last_reset = now;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that elapsed time advances and the next reset is only reported when configured.
+    #[test]
+    fn test_session_info() {
+        let start = Utc::now();
+        let session = Session::new(start, None);
+        assert_eq!(session.elapsed(start + Duration::seconds(5)).num_seconds(), 5);
+        assert_eq!(session.elapsed(start + Duration::seconds(9)).num_seconds(), 9);
+        assert!(session.next_reset().is_none());
+
+        let mut session = Session::new(start, Some(Duration::seconds(60)));
+        assert_eq!(session.next_reset(), Some(start + Duration::seconds(60)));
+        assert!(!session.roll(start + Duration::seconds(30)));
+        assert!(session.roll(start + Duration::seconds(61)));
+        assert_eq!(session.next_reset(), Some(start + Duration::seconds(121)));
+    }
+}
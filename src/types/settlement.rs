@@ -0,0 +1,136 @@
+//! Trade settlement.
+//!
+//! Each trade has two legs for each side: the buyer pays cash and receives shares, and the seller gives up shares and
+//! receives cash. What a user gives up leaves their available balance as soon as the trade happens, but what they
+//! receive only becomes available once the trade settles, a configured number of ticks later (T+N). Until then, sale
+//! proceeds can't be spent and bought shares can't be sold.
+//!
+//! Users start with the market's starting cash and shares, if it has any. Unless the market checks orders against
+//! them, balances go negative as users spend more than they have or sell short.
+
+use super::{json, Symbol};
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+
+/// What a user receives from a trade, once it settles.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct PendingSettlement {
+    /// The tick on which the trade settles.
+    #[serde(rename = "settles_at_tick")]
+    pub settles_at: u64,
+    pub symbol: Symbol,
+    /// Cash received, in cents.
+    #[serde(rename = "cash", serialize_with = "json::cents")]
+    pub cash_cents: u64,
+    /// Shares received.
+    pub shares: usize,
+}
+
+/// A user's settled cash and holdings, and what they are still waiting to receive.
+#[derive(Clone, Debug, Default)]
+pub struct Balances {
+    cash_cents: i64,
+    holdings: HashMap<Symbol, i64>,
+    pending: Vec<PendingSettlement>,
+}
+
+impl Balances {
+    /// Returns the cash, in cents, the user can spend.
+    pub fn get_available_cash_cents(&self) -> i64 {
+        self.cash_cents
+    }
+
+    /// Sets the cash, in cents, the user can spend.
+    pub fn set_available_cash_cents(&mut self, cash_cents: i64) {
+        self.cash_cents = cash_cents;
+    }
+
+    /// Returns the shares of each stock the user can sell.
+    pub fn get_available_holdings(&self) -> &HashMap<Symbol, i64> {
+        &self.holdings
+    }
+
+    /// Sets the shares of the stock the user can sell.
+    pub fn set_available_shares(&mut self, symbol: Symbol, shares: i64) {
+        self.holdings.insert(symbol, shares);
+    }
+
+    /// Adds something for the user to receive when it settles.
+    pub fn add_pending(&mut self, settlement: PendingSettlement) {
+        self.pending.push(settlement);
+    }
+
+    /// Returns what the user is still waiting to receive, in the order the trades happened.
+    pub fn get_pending(&self) -> &[PendingSettlement] {
+        &self.pending
+    }
+
+    /// Records the user's side of a trade of `shares` for `cash_cents`, made on tick `now` and settling on tick
+    /// `settles_at`.
+    pub fn record_trade(&mut self, symbol: Symbol, buying: bool, cash_cents: u64, shares: usize, now: u64, settles_at: u64) {
+        let (cash_delta, shares_delta) = (cash_cents as i64, shares as i64);
+        if buying {
+            self.cash_cents -= cash_delta;
+            self.pending.push(PendingSettlement { settles_at, symbol, cash_cents: 0, shares });
+        } else {
+            *self.holdings.entry(symbol).or_insert(0) -= shares_delta;
+            self.pending.push(PendingSettlement { settles_at, symbol, cash_cents, shares: 0 });
+        }
+        self.settle(now);
+    }
+
+    /// Takes a fee, in cents, from the user's available cash. A negative fee is a rebate, and is paid in instead.
+    pub fn record_fee(&mut self, fee_cents: i64) {
+        self.cash_cents -= fee_cents;
+    }
+
+    /// Makes everything due to settle by tick `now` available.
+    pub fn settle(&mut self, now: u64) {
+        let (due, pending) = self.pending.drain(..).partition(|settlement| settlement.settles_at <= now);
+        self.pending = pending;
+        for settlement in due {
+            self.cash_cents += settlement.cash_cents as i64;
+            *self.holdings.entry(settlement.symbol).or_insert(0) += settlement.shares as i64;
+        }
+    }
+}
+
+impl Serialize for Balances {
+    /// Serializes the available cash and holdings, and what is still to settle.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Wire<'a> {
+            #[serde(serialize_with = "json::signed_cents")]
+            cash: i64,
+            holdings: &'a HashMap<Symbol, i64>,
+            pending: &'a [PendingSettlement],
+        }
+
+        Wire { cash: self.cash_cents, holdings: &self.holdings, pending: &self.pending }.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that what is given up leaves at once, and what is received arrives on the settlement tick.
+    #[test]
+    fn test_settle() {
+        let symbol = Symbol::try_from("V").unwrap();
+        let mut seller = Balances::default();
+        seller.record_trade(symbol, false, 50000, 5, 0, 2);
+        assert_eq!(seller.get_available_holdings()[&symbol], -5);
+        assert_eq!(seller.get_available_cash_cents(), 0);
+        seller.settle(1);
+        assert_eq!(seller.get_available_cash_cents(), 0);
+        seller.settle(2);
+        assert_eq!(seller.get_available_cash_cents(), 50000);
+        assert!(seller.get_pending().is_empty());
+
+        let mut buyer = Balances::default();
+        buyer.record_trade(symbol, true, 50000, 5, 3, 3);
+        assert_eq!(buyer.get_available_cash_cents(), -50000);
+        assert_eq!(buyer.get_available_holdings()[&symbol], 5);
+    }
+}
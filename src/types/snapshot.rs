@@ -0,0 +1,97 @@
+use super::{OrderKind, TimeInForce};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// The state of the market that is kept across restarts: every stock with its resting orders, every user's balances,
+/// and the reconnect tokens that identify them.
+///
+/// Anything that only describes the current session, like OHLC, candles and the activity feed, starts fresh instead.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MarketSnapshot {
+    pub stocks: Vec<StockSnapshot>,
+    pub users: Vec<UserSnapshot>,
+    /// The ID of the user each reconnect token identifies. Missing from snapshots taken before tokens were kept.
+    #[serde(default)]
+    pub tokens: BTreeMap<String, usize>,
+    /// Fees collected by the exchange, less rebates paid, in cents. Missing from snapshots taken before fees were
+    /// charged.
+    #[serde(default)]
+    pub fees_collected_cents: i64,
+}
+
+impl MarketSnapshot {
+    /// Reads a snapshot from a JSON file.
+    ///
+    /// A file that isn't a valid snapshot is reported as `InvalidData`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the snapshot to a JSON file, replacing it if it exists.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let json = serde_json::to_string(self).expect("Snapshots should always serialize.");
+        std::fs::write(path, json)
+    }
+}
+
+/// A listed stock and the orders resting in its book.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StockSnapshot {
+    pub symbol: String,
+    pub name: String,
+    /// Smallest price increment, unadjusted.
+    pub tick_size: usize,
+    pub lot_size: usize,
+    pub currency: String,
+    pub halted: bool,
+    pub last_price: Option<f64>,
+    pub total_volume: usize,
+    /// Resting buy orders, in priority order.
+    pub buy_orders: Vec<OrderSnapshot>,
+    /// Resting sell orders, in priority order.
+    pub sell_orders: Vec<OrderSnapshot>,
+}
+
+/// An order resting in a book.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OrderSnapshot {
+    pub id: u64,
+    pub creator_id: usize,
+    /// Price per stock, unadjusted.
+    pub price: usize,
+    pub quantity: usize,
+    /// When the order was created, in milliseconds since the Unix epoch.
+    pub time_ms: i64,
+    pub tag: Option<String>,
+    pub kind: OrderKind,
+    pub time_in_force: TimeInForce,
+    /// How much of an iceberg order is shown at a time. Missing from snapshots of orders that show all of it.
+    #[serde(default)]
+    pub display_qty: Option<usize>,
+}
+
+/// A user's order counts and balances.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserSnapshot {
+    pub id: usize,
+    pub placed: usize,
+    pub cancelled: usize,
+    pub traded: usize,
+    pub cash_cents: i64,
+    pub holdings: Vec<(String, i64)>,
+    pub pending: Vec<PendingSnapshot>,
+}
+
+/// Something a user is still waiting to receive from a trade.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingSnapshot {
+    /// Ticks left until it settles. Ticks are counted from the start of each run, so they are stored relative to the
+    /// tick the snapshot was taken on.
+    pub ticks_left: u64,
+    pub symbol: String,
+    pub cash_cents: u64,
+    pub shares: usize,
+}
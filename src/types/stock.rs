@@ -1,10 +1,33 @@
-use chrono::{DateTime, Utc};
-use std::collections::HashMap;
+use super::{json, money, Candle, Clock, OrderSnapshot, SpreadSampler, StockSnapshot, SystemClock};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
+/// Number of decimals to keep for prices.
+const PRICE_DECIMALS: u32 = 2;
 // 10 raised to the number of decimals to keep for prices.
 const PRICE_PRECISION_FACTOR: f64 = 1e2;
-/// Number of unique prices that are checked for in the order book.
-const NO_OF_PRICES_QUERIED: usize = 5;
+/// Number of completed candles kept per stock.
+const MAX_CANDLES: usize = 1000;
+/// Number of recent trades kept per stock for order flow.
+const MAX_RECENT_TRADES: usize = 1000;
+/// Currency that stocks are quoted in unless configured otherwise.
+const DEFAULT_CURRENCY: &str = "USD";
+/// Maximum length of an order tag, in bytes.
+pub const MAX_TAG_LEN: usize = 32;
+
+/// Converts a price to the integer form orders store it in, rounding to the nearest tick of precision.
+fn unadjust_price(price: f64) -> usize {
+    (price * PRICE_PRECISION_FACTOR).round() as usize
+}
+
+/// Returns whether a price is a whole number of ticks. Both are rounded to the precision prices are kept to first, and
+/// a tick that rounds to nothing allows no price.
+pub fn is_valid_tick(price: f64, tick: f64) -> bool {
+    let tick = unadjust_price(tick);
+    tick != 0 && unadjust_price(price).is_multiple_of(tick)
+}
 
 #[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
 pub struct Symbol {
@@ -24,13 +47,19 @@ impl std::fmt::Display for Symbol {
     }
 }
 
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 impl PartialEq<&str> for Symbol {
     fn eq(&self, other: &&str) -> bool {
         let mut l = self.chars.iter();
         let mut r = other.chars();
         
         loop {
-            let lc = l.next().map(|c| *c);
+            let lc = l.next().copied();
             let rc = r.next();
             if lc.is_none() && rc.is_none() {
                 break true;
@@ -45,19 +74,15 @@ impl TryFrom<&str> for Symbol {
     type Error = ();
     
     fn try_from(s: &str) -> Result<Self, ()> {
-        if (s.len() < 1) || (s.len() > 4) {
+        if s.is_empty() || (s.len() > 4) {
             Err(())
         } else {
             let mut chars = [' '; 4];
-            for i in 0..4 {
-                if let Some(c) = s.chars().nth(i) {
-                    if c == ' ' {
-                        break;
-                    }
-                    chars[i] = c;
-                } else {
+            for (slot, c) in chars.iter_mut().zip(s.chars()) {
+                if c == ' ' {
                     break;
                 }
+                *slot = c;
             }
 
             Ok(Self {
@@ -67,6 +92,90 @@ impl TryFrom<&str> for Symbol {
     }
 }
 
+/// A client's label for an order, e.g. the strategy that placed it, echoed back on its fills.
+///
+/// Tags are stored inline, like symbols, so orders and trades stay cheap to copy.
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct OrderTag {
+    bytes: [u8; MAX_TAG_LEN],
+    len: usize,
+}
+
+impl OrderTag {
+    /// Returns the tag as a string.
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len]).expect("Tags are only made from strings.")
+    }
+}
+
+impl std::fmt::Display for OrderTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Serialize for OrderTag {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl TryFrom<&str> for OrderTag {
+    type Error = ();
+
+    /// Fails if the tag is longer than `MAX_TAG_LEN` bytes.
+    fn try_from(s: &str) -> Result<Self, ()> {
+        if s.len() > MAX_TAG_LEN {
+            return Err(());
+        }
+
+        let mut bytes = [0; MAX_TAG_LEN];
+        bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Self { bytes, len: s.len() })
+    }
+}
+
+/// How the case of a symbol is treated when stocks are listed and looked up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymbolCase {
+    /// Symbols are used exactly as given, so "v" and "V" are different stocks.
+    Preserve,
+    /// Symbols are converted to upper case, so "v" and "V" are the same stock.
+    #[default]
+    Upper,
+}
+
+impl SymbolCase {
+    /// Parses a symbol, normalising its case according to the policy.
+    pub fn parse(&self, s: &str) -> Result<Symbol, ()> {
+        match self {
+            SymbolCase::Preserve => Symbol::try_from(s),
+            SymbolCase::Upper => Symbol::try_from(s.to_uppercase().as_str()),
+        }
+    }
+}
+
+impl TryFrom<&str> for SymbolCase {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, ()> {
+        match s {
+            "preserve" => Ok(SymbolCase::Preserve),
+            "upper" => Ok(SymbolCase::Upper),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for SymbolCase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SymbolCase::Preserve => write!(f, "preserve"),
+            SymbolCase::Upper => write!(f, "upper"),
+        }
+    }
+}
+
 impl TryFrom<&String> for Symbol {
     type Error = ();
     
@@ -75,16 +184,122 @@ impl TryFrom<&String> for Symbol {
     }
 }
 
+/// A side of the order book.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Side::Buy => write!(f, "buy"),
+            Side::Sell => write!(f, "sell"),
+        }
+    }
+}
+
+impl TryFrom<&str> for Side {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, ()> {
+        match s {
+            "buy" => Ok(Side::Buy),
+            "sell" => Ok(Side::Sell),
+            _ => Err(()),
+        }
+    }
+}
+
 /// Holds details for a stock and its orders.
 pub struct Stock {
     /// The full name of the stock (e.g., "Orchard de Rosa et Tulipan")
     name: String,
     /// Buy orders for the stock.
-    buy_orders: Vec<Order>,
+    buy_orders: BookSide,
     /// Sell orders for the stock.
-    sell_orders: Vec<Order>,
+    sell_orders: BookSide,
     /// Open, high, low, close prices for the stock.
     ohlc: Ohlc,
+    /// Quantity traded this session at each (unadjusted) price.
+    volume_profile: HashMap<usize, usize>,
+    /// Number of trades this session.
+    session_trades: usize,
+    /// Price of the most recent trade, kept across sessions.
+    last_price: Option<f64>,
+    /// Total quantity traded since the stock was listed.
+    total_volume: usize,
+    /// Smallest price increment, unadjusted.
+    tick_size: usize,
+    /// Order quantities must be a multiple of this.
+    lot_size: usize,
+    /// Currency the stock is quoted in.
+    currency: String,
+    /// Whether trading in the stock is halted.
+    halted: bool,
+    /// When the current candle started.
+    candle_start: DateTime<Utc>,
+    /// Trade prices over the current candle.
+    candle_ohlc: Ohlc,
+    /// Sum of unadjusted price times quantity over the trades of the current candle, for its VWAP.
+    candle_notional: u128,
+    /// Quantity traded over the current candle.
+    candle_volume: usize,
+    /// Tracks the spread over the current candle.
+    spread_sampler: SpreadSampler,
+    /// Completed candles, oldest first.
+    candles: VecDeque<Candle>,
+    /// The most recent trades and when they happened, oldest first.
+    recent_trades: VecDeque<(DateTime<Utc>, Trade)>,
+    /// When an order was last added to, removed from, or changed in the book.
+    last_book_change: DateTime<Utc>,
+    /// Sequence number given to the next order added to the book.
+    next_sequence: u64,
+    /// Tells the time for book changes and replaced orders.
+    clock: Arc<dyn Clock>,
+}
+
+/// A resting order as listed in a full book query. Only the shown part of an iceberg order is listed.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BookEntry {
+    pub order_id: u64,
+    pub creator_id: usize,
+    #[serde(serialize_with = "json::price")]
+    pub price: f64,
+    pub quantity: usize,
+    /// When the order was created.
+    #[serde(serialize_with = "json::time")]
+    pub time: DateTime<Utc>,
+}
+
+impl From<&Order> for BookEntry {
+    fn from(order: &Order) -> Self {
+        Self {
+            order_id: order.id,
+            creator_id: order.creator_id,
+            price: order.get_price(),
+            quantity: order.get_visible_quantity(),
+            time: order.time,
+        }
+    }
+}
+
+/// Instrument metadata that clients need to format and validate orders.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SymbolInfo {
+    /// Smallest price increment.
+    #[serde(serialize_with = "json::price")]
+    pub tick_size: f64,
+    /// Order quantities must be a multiple of this.
+    pub lot_size: usize,
+    /// Number of decimals prices are kept to.
+    pub decimals: u32,
+    /// Currency the stock is quoted in.
+    pub currency: String,
+    /// Whether trading in the stock is halted.
+    pub halted: bool,
 }
 
 impl Stock {
@@ -92,9 +307,65 @@ impl Stock {
     pub fn new(name: &str) -> Self {
         Self {
             name: name.to_string(),
-            buy_orders: Vec::new(),
-            sell_orders: Vec::new(),
+            buy_orders: BookSide::new(Side::Buy),
+            sell_orders: BookSide::new(Side::Sell),
             ohlc: Ohlc::new(),
+            volume_profile: HashMap::new(),
+            session_trades: 0,
+            last_price: None,
+            total_volume: 0,
+            tick_size: 1,
+            lot_size: 1,
+            currency: DEFAULT_CURRENCY.to_string(),
+            halted: false,
+            candle_start: Utc::now(),
+            candle_ohlc: Ohlc::new(),
+            candle_notional: 0,
+            candle_volume: 0,
+            spread_sampler: SpreadSampler::default(),
+            candles: VecDeque::new(),
+            recent_trades: VecDeque::new(),
+            last_book_change: Utc::now(),
+            next_sequence: 0,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Recreates a stock from a snapshot, with its resting orders back in the book in the same priority order.
+    ///
+    /// Session statistics, like OHLC and candles, start fresh.
+    pub fn from_snapshot(snapshot: StockSnapshot) -> Self {
+        let mut stock = Self {
+            tick_size: snapshot.tick_size,
+            lot_size: snapshot.lot_size,
+            currency: snapshot.currency,
+            halted: snapshot.halted,
+            last_price: snapshot.last_price,
+            total_volume: snapshot.total_volume,
+            ..Self::new(&snapshot.name)
+        };
+        for order in snapshot.buy_orders {
+            stock.add_buy_order(Order::from_snapshot(order));
+        }
+        for order in snapshot.sell_orders {
+            stock.add_sell_order(Order::from_snapshot(order));
+        }
+        stock
+    }
+
+    /// Returns a snapshot of the stock, listed under the given symbol, and its resting orders.
+    pub fn to_snapshot(&self, symbol: Symbol) -> StockSnapshot {
+        StockSnapshot {
+            symbol: symbol.to_string(),
+            name: self.name.clone(),
+            tick_size: self.tick_size,
+            lot_size: self.lot_size,
+            currency: self.currency.clone(),
+            halted: self.halted,
+            last_price: self.last_price,
+            total_volume: self.total_volume,
+            buy_orders: self.buy_orders.iter().map(Order::to_snapshot).collect(),
+            sell_orders: self.sell_orders.iter().map(Order::to_snapshot).collect(),
         }
     }
 
@@ -103,114 +374,206 @@ impl Stock {
         &self.name
     }
 
+    /// Returns whether trading in the stock is halted.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Halts or resumes trading in the stock. A halted stock takes no new orders, and its book isn't matched.
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+
+    /// Sets the smallest price increment the stock trades in. It's rounded to the precision prices are kept to.
+    pub fn set_tick_size(&mut self, tick_size: f64) {
+        self.tick_size = unadjust_price(tick_size);
+    }
+
+    /// Returns whether a price is a whole number of the stock's ticks.
+    pub fn is_on_tick(&self, price: f64) -> bool {
+        self.tick_size != 0 && unadjust_price(price).is_multiple_of(self.tick_size)
+    }
+
+    /// Returns the stock's instrument metadata.
+    pub fn get_symbol_info(&self) -> SymbolInfo {
+        SymbolInfo {
+            tick_size: (self.tick_size as f64) / PRICE_PRECISION_FACTOR,
+            lot_size: self.lot_size,
+            decimals: PRICE_DECIMALS,
+            currency: self.currency.clone(),
+            halted: self.halted,
+        }
+    }
+
     /// Adds a buy order to the stock.
     pub fn add_buy_order(&mut self, order: Order) {
+        let order = self.sequenced(order);
         self.buy_orders.push(order);
-        self.sort_orders();
+        self.touch();
     }
 
     /// Adds a sell order to the stock.
     pub fn add_sell_order(&mut self, order: Order) {
+        let order = self.sequenced(order);
         self.sell_orders.push(order);
-        self.sort_orders();
+        self.touch();
     }
 
-    /// Returns pending buy orders for the stock, sorted in descending order of price.
-    pub fn get_buy_orders(&self) -> Vec<(f64, usize)> {
-        let mut pricelist = HashMap::<usize, usize>::new();
-
-        for order in &self.buy_orders {
-            let price = order.get_unadjusted_price();
-            let quantity = order.get_quantity();
-
-            if let Some(existing_price) = pricelist.get(&price) {
-                pricelist.insert(price, existing_price + quantity);
-            } else {
-                if pricelist.len() >= NO_OF_PRICES_QUERIED {
-                    break;
-                }
-                pricelist.insert(price, quantity);
+    /// Gives each order in the book that hasn't been numbered yet the next ID from `next_id`, for a stock joining the
+    /// market with its starting orders.
+    pub fn number_orders(&mut self, next_id: &mut u64) {
+        for order in self.buy_orders.iter_mut().chain(self.sell_orders.iter_mut()) {
+            if order.id == 0 {
+                order.id = *next_id;
+                *next_id += 1;
             }
         }
+    }
 
-        let mut pricelist: Vec<(f64, usize)> = pricelist
-            .iter()
-            .map(|(price, quantity)| ((*price as f64) / PRICE_PRECISION_FACTOR, *quantity))
-            .collect();
-        pricelist.sort_by(|a, b| {
-            b.0.partial_cmp(&a.0)
-                .expect("prices are f64s and should be comparable.")
-        });
-        pricelist
+    /// Returns the order numbered after every order already added to the book, so that it is the taker against them.
+    fn sequenced(&mut self, order: Order) -> Order {
+        self.next_sequence += 1;
+        Order { sequence: self.next_sequence, ..order }
     }
 
-    /// Returns pending sell orders for the stock, sorted in ascending order of price.
-    pub fn get_sell_orders(&self) -> Vec<(f64, usize)> {
-        let mut pricelist = HashMap::<usize, usize>::new();
+    /// Returns when an order was last added to, removed from, or changed in the book.
+    pub fn get_last_book_change(&self) -> DateTime<Utc> {
+        self.last_book_change
+    }
 
-        for order in &self.sell_orders {
-            let price = order.get_unadjusted_price();
-            let quantity = order.get_quantity();
+    /// Records that the book changed just now.
+    fn touch(&mut self) {
+        self.last_book_change = self.clock.now();
+    }
 
-            if let Some(existing_price) = pricelist.get(&price) {
-                pricelist.insert(price, existing_price + quantity);
-            } else {
-                if pricelist.len() >= NO_OF_PRICES_QUERIED {
-                    break;
-                }
-                pricelist.insert(price, quantity);
-            }
-        }
+    /// Replaces the clock the stock stamps book changes and replaced orders with. The market gives each stock it lists
+    /// its own clock.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
 
-        let mut pricelist: Vec<(f64, usize)> = pricelist
-            .iter()
-            .map(|(price, quantity)| ((*price as f64) / PRICE_PRECISION_FACTOR, *quantity))
-            .collect();
-        pricelist.sort_by(|a, b| {
-            a.0.partial_cmp(&b.0)
-                .expect("prices are f64s and should be comparable.")
-        });
-        pricelist
+    /// Returns up to `levels` of the best price levels of pending buy orders, with the quantity resting at each,
+    /// sorted in descending order of price.
+    pub fn get_buy_orders(&self, levels: usize) -> Vec<(f64, usize)> {
+        self.buy_orders.top_levels(levels)
+    }
+
+    /// Returns up to `levels` of the best price levels of pending sell orders, with the quantity resting at each,
+    /// sorted in ascending order of price.
+    pub fn get_sell_orders(&self, levels: usize) -> Vec<(f64, usize)> {
+        self.sell_orders.top_levels(levels)
     }
 
     /// Resolves trades between buy and sell orders.
-    pub fn resolve(&mut self) -> Vec<Trade> {
+    ///
+    /// Market orders sweep the other side of the book from its best price, and whatever is left of them is cancelled, as
+    /// is whatever is left of immediate-or-cancel orders. Fill-or-kill orders that the other side can't fill completely,
+    /// after the orders ahead of them and within `max_matches`, are cancelled before anything is matched.
+    /// A resting order with less than `min_fill_qty` left is skipped rather than matched against the incoming order,
+    /// so dust can be left resting in the book indefinitely. At most `max_matches` trades are made, if set, leaving the
+    /// rest of the crossing orders to be matched by a later call. A user's orders never trade with each other; what
+    /// happens instead is set by `self_trade`.
+    pub fn resolve(&mut self, min_fill_qty: usize, max_matches: Option<usize>, self_trade: SelfTradePrevention) -> Vec<Trade> {
+        // A halted stock's orders wait in the book, untouched, until trading resumes.
+        if self.halted {
+            return Vec::new();
+        }
+
         let mut trades = Vec::new();
+        let mut capped = false;
+        let now = self.clock.now();
+        let resting = self.buy_orders.len() + self.sell_orders.len();
 
-        for buy_order in &mut self.buy_orders {
-            if let Some(lowest_sell_offer) = self.sell_orders.first() {
+        let unfillable_buys = self.unfillable_orders(Side::Buy, min_fill_qty, max_matches);
+        let unfillable_sells = self.unfillable_orders(Side::Sell, min_fill_qty, max_matches);
+        self.buy_orders.retain(|order| !unfillable_buys.contains(&order.id));
+        self.sell_orders.retain(|order| !unfillable_sells.contains(&order.id));
+
+        // Sell orders before the cursor have been filled or cancelled, so each buy order starts where the previous
+        // one left off instead of walking the whole sell side again.
+        let mut cursor = (0, 0);
+        'matching: for buy_order in self.buy_orders.iter_mut() {
+            if buy_order.get_quantity() == 0 {
+                // Cancelled to prevent a self-trade.
+                continue;
+            }
+            let Some(next) = self.sell_orders.skip_filled(cursor) else {
+                // No sell orders left
+                break;
+            };
+            cursor = next;
+            if let Some(lowest_sell_offer) = self.sell_orders.get(cursor) {
                 if buy_order.get_price() < lowest_sell_offer.get_price() {
                     // Highest buy bid is less than lowest sell offer
                     break;
                 }
-            } else {
-                // No sell orders left
-                break;
             }
 
-            for sell_order in &mut self.sell_orders {
+            for sell_order in self.sell_orders.iter_mut_from(cursor) {
                 if sell_order.get_quantity() == 0 {
                     // These might be left over after being resolved.
                     continue;
                 }
 
                 if buy_order.get_price() >= sell_order.get_price() {
-                    let price = if sell_order.get_time() < buy_order.get_time() {
-                        sell_order.get_price()
+                    if max_matches.is_some_and(|max_matches| trades.len() >= max_matches) {
+                        capped = true;
+                        break 'matching;
+                    }
+
+                    // Trades happen at the maker's price, the order that was added to the book first. A market order
+                    // always takes liquidity, so it trades at the limit order's price. Two market orders have no price to
+                    // trade at.
+                    let sell_is_resting = match (buy_order.kind, sell_order.kind) {
+                        (OrderKind::Market, OrderKind::Market) => continue,
+                        (OrderKind::Market, OrderKind::Limit) => true,
+                        (OrderKind::Limit, OrderKind::Market) => false,
+                        (OrderKind::Limit, OrderKind::Limit) => sell_order.sequence < buy_order.sequence,
+                    };
+                    if buy_order.creator_id == sell_order.creator_id {
+                        let cancel_sell = match self_trade {
+                            SelfTradePrevention::Skip => continue,
+                            SelfTradePrevention::CancelResting => sell_is_resting,
+                            SelfTradePrevention::CancelIncoming => !sell_is_resting,
+                        };
+                        if cancel_sell {
+                            sell_order.quantity = 0;
+                            continue;
+                        }
+                        buy_order.quantity = 0;
+                        break;
+                    }
+                    if sell_is_resting && sell_order.get_quantity() < min_fill_qty {
+                        continue;
+                    } else if !sell_is_resting && buy_order.get_quantity() < min_fill_qty {
+                        break;
+                    }
+
+                    let unadjusted_price = if sell_is_resting {
+                        sell_order.get_unadjusted_price()
                     } else {
-                        buy_order.get_price()
+                        buy_order.get_unadjusted_price()
                     };
+                    let price = unadjusted_price as f64 / PRICE_PRECISION_FACTOR;
                     let quantity = buy_order.get_quantity().min(sell_order.get_quantity());
 
                     buy_order.resolve(quantity);
                     sell_order.resolve(quantity);
-                    trades.push(Trade::new(
-                        buy_order.creator_id,
-                        sell_order.creator_id,
-                        price,
-                        quantity,
-                    ));
+                    let aggressor = if sell_is_resting { Side::Buy } else { Side::Sell };
+                    let trade = Trade::new(buy_order, sell_order, price, quantity, aggressor, now);
+                    trades.push(trade);
+                    if self.recent_trades.len() >= MAX_RECENT_TRADES {
+                        self.recent_trades.pop_front();
+                    }
+                    self.recent_trades.push_back((now, trade));
                     self.ohlc.update(price);
+                    self.candle_ohlc.update(price);
+                    self.candle_notional += unadjusted_price as u128 * quantity as u128;
+                    self.candle_volume += quantity;
+                    *self.volume_profile.entry(unadjusted_price).or_insert(0) += quantity;
+                    self.session_trades += 1;
+                    self.last_price = Some(price);
+                    self.total_volume += quantity;
 
                     if buy_order.get_quantity() == 0 {
                         break;
@@ -221,168 +584,1049 @@ impl Stock {
             }
         }
 
-        self.buy_orders.retain(|order| order.get_quantity() > 0);
-        self.sell_orders.retain(|order| order.get_quantity() > 0);
+        // Market and immediate-or-cancel orders left over once nothing more can be matched are cancelled. If the cap cut
+        // matching short, they get another go on the next call instead, unless they are immediate-or-cancel or
+        // fill-or-kill orders that have already traded, which would otherwise keep filling on a later tick.
+        let traded: HashSet<u64> = trades.iter().flat_map(|trade| [trade.buy_order_id, trade.sell_order_id]).collect();
+        let keep = |order: &Order| {
+            let resumable = order.time_in_force == TimeInForce::Gtc || !traded.contains(&order.id);
+            order.get_quantity() > 0
+                && ((capped && resumable) || (order.kind == OrderKind::Limit && order.time_in_force == TimeInForce::Gtc))
+        };
+        self.buy_orders.retain(keep);
+        self.sell_orders.retain(keep);
+        if !trades.is_empty() || self.buy_orders.len() + self.sell_orders.len() != resting {
+            self.touch();
+        }
 
         trades
     }
 
-    /// Sorts buy and sell orders by price.
-    fn sort_orders(&mut self) {
-        self.buy_orders.sort_by(|a, b| {
-            b.price
-                .partial_cmp(&a.price)
-                .expect("prices are f64s and should be comparable.")
-        });
-        self.sell_orders.sort_by(|a, b| {
-            a.price
-                .partial_cmp(&b.price)
-                .expect("prices are f64s and should be comparable.")
-        });
-    }
-
-    /// Returns the open, high, low, close prices for the stock.
-    pub fn get_ohlc(&self) -> (Option<f64>, Option<f64>, Option<f64>, Option<f64>) {
-        self.ohlc.get()
-    }
-}
-
-/// An order to buy or sell a stock.
-pub struct Order {
-    /// The ID of the creator of the order.
-    creator_id: usize,
-    /// The price per stock.
-    price: usize,
-    /// The quantity of the order.
-    quantity: usize,
-    /// The time the order was created.
+    /// Returns the IDs of the fill-or-kill orders on the side that the other side of the book can't fill completely.
     ///
-    /// The price listed on the order that was created earlier is considered while resolving orders.
-    time: DateTime<Utc>,
-}
+    /// Orders ahead of each one on its own side are filled first, so only what they leave counts, and if `max_matches`
+    /// is set, the trades they make count towards it too: an order that would run out of matches before it is filled
+    /// can't be filled either. Resting orders that `min_fill_qty` would skip, and the creator's own orders, don't count
+    /// towards filling them.
+    fn unfillable_orders(&self, side: Side, min_fill_qty: usize, max_matches: Option<usize>) -> Vec<u64> {
+        let (orders, other_side) = match side {
+            Side::Buy => (&self.buy_orders, &self.sell_orders),
+            Side::Sell => (&self.sell_orders, &self.buy_orders),
+        };
 
-impl Order {
-    /// Creates a new order with the given creator ID, price, and quantity.
-    pub fn new(creator_id: usize, price: f64, quantity: usize) -> Self {
-        let price = (price * PRICE_PRECISION_FACTOR) as usize;
+        // Only the orders up to the last fill-or-kill one need walking.
+        let mut remaining = orders.iter().filter(|order| order.time_in_force == TimeInForce::Fok).count();
+        let mut ahead = 0;
+        let mut matches = 0;
+        let mut unfillable = Vec::new();
+        for order in orders.iter() {
+            if remaining == 0 {
+                break;
+            }
+            if order.time_in_force == TimeInForce::Fok {
+                remaining -= 1;
+            }
+            let liquidity = other_side.iter().filter(|other| {
+                let crosses = match side {
+                    Side::Buy => order.price >= other.price,
+                    Side::Sell => other.price >= order.price,
+                };
+                let priced = order.kind == OrderKind::Limit || other.kind == OrderKind::Limit;
+                crosses && priced && other.quantity >= min_fill_qty && other.creator_id != order.creator_id
+            });
 
-        Self {
-            creator_id,
-            price,
-            quantity,
-            time: Utc::now(),
+            // Walk the liquidity past what the orders ahead take, counting the orders this one would trade with.
+            let (mut start, mut filled, mut touched) = (0, 0, 0);
+            for quantity in liquidity.map(Order::get_quantity) {
+                let end = start + quantity;
+                let taken = end.min(ahead + order.quantity).saturating_sub(start.max(ahead));
+                start = end;
+                if taken > 0 {
+                    filled += taken;
+                    touched += 1;
+                } else if end > ahead {
+                    break;
+                }
+            }
+
+            let out_of_matches = max_matches.is_some_and(|max_matches| matches + touched > max_matches);
+            if order.time_in_force == TimeInForce::Fok && (filled < order.quantity || out_of_matches) {
+                unfillable.push(order.id);
+                continue;
+            }
+            ahead += filled;
+            matches += touched;
         }
+        unfillable
     }
 
-    /// Returns the total value of the order.
-    pub fn get_value(&self) -> f64 {
-        (self.price as f64) * (self.quantity as f64) / PRICE_PRECISION_FACTOR
+    /// Returns the highest price a buyer is bidding, if any.
+    pub fn get_best_bid(&self) -> Option<f64> {
+        self.buy_orders.first_limit().map(|order| order.get_price())
     }
 
-    /// Returns the price per stock of the order.
-    pub fn get_price(&self) -> f64 {
-        self.price as f64 / PRICE_PRECISION_FACTOR
+    /// Returns the lowest price a seller is asking, if any.
+    pub fn get_best_ask(&self) -> Option<f64> {
+        self.sell_orders.first_limit().map(|order| order.get_price())
     }
 
-    /// Returns the price per stock WITHOUT adjusting for the precision factor.
-    fn get_unadjusted_price(&self) -> usize {
-        self.price
+    /// Returns the difference between the best ask and best bid, unadjusted, if both sides have orders.
+    fn get_unadjusted_spread(&self) -> Option<usize> {
+        let bid = self.buy_orders.first_limit()?.get_unadjusted_price();
+        let ask = self.sell_orders.first_limit()?.get_unadjusted_price();
+        Some(ask.saturating_sub(bid))
     }
 
-    /// Returns the quantity of the order.
-    pub fn get_quantity(&self) -> usize {
-        self.quantity
+    /// Samples the current spread into the running candle.
+    pub fn sample_spread(&mut self, now: DateTime<Utc>) {
+        let spread = self.get_unadjusted_spread();
+        self.spread_sampler.sample(now, spread);
     }
 
-    /// Returns the time the order was created.
-    pub fn get_time(&self) -> DateTime<Utc> {
-        self.time
+    /// Returns when the current candle started.
+    pub fn get_candle_start(&self) -> DateTime<Utc> {
+        self.candle_start
     }
 
-    /// Reduces the quantity of the order by the given amount.
-    pub fn resolve(&mut self, quantity: usize) {
-        self.quantity -= quantity;
+    /// Completes the current candle at the given time and starts a new one.
+    pub fn roll_candle(&mut self, now: DateTime<Utc>) {
+        let avg_spread = self.spread_sampler.finish(now).map(|spread| spread / PRICE_PRECISION_FACTOR);
+        let (open, high, low, close) = std::mem::replace(&mut self.candle_ohlc, Ohlc::new()).get();
+        if self.candles.len() >= MAX_CANDLES {
+            self.candles.pop_front();
+        }
+        self.candles.push_back(Candle {
+            start: self.candle_start,
+            end: now,
+            avg_spread,
+            open,
+            high,
+            low,
+            close,
+        });
+        self.candle_start = now;
+        self.candle_notional = 0;
+        self.candle_volume = 0;
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-/// A log of a resolved trade between a buyer and a seller.
-pub struct Trade {
-    /// The ID of the buyer.
-    pub buyer_id: usize,
-    /// The ID of the seller.
-    pub seller_id: usize,
-    /// The price per stock.
-    pub price: f64,
-    /// The quantity of the trade.
-    pub quantity: usize,
-}
+    /// Returns the resting limit orders on each side in priority order, and whether any were left out to keep to
+    /// `max_orders` in total.
+    ///
+    /// When both sides are deep, the limit is split evenly between them.
+    pub fn get_book(&self, max_orders: usize) -> (Vec<BookEntry>, Vec<BookEntry>, bool) {
+        let limits = |side: &BookSide| side.limit_orders().count();
+        let (buys, sells) = (limits(&self.buy_orders), limits(&self.sell_orders));
+        let sells_taken = sells.min(max_orders - buys.min(max_orders.div_ceil(2)));
+        let buys_taken = buys.min(max_orders - sells_taken);
 
-impl Trade {
-    /// Creates a new trade with the given buyer ID, seller ID, price, and quantity.
-    fn new(buyer_id: usize, seller_id: usize, price: f64, quantity: usize) -> Self {
-        Self {
-            buyer_id,
-            seller_id,
-            price,
-            quantity,
+        let entries = |side: &BookSide, n: usize| -> Vec<BookEntry> {
+            side.limit_orders().take(n).map(BookEntry::from).collect()
+        };
+        (entries(&self.buy_orders, buys_taken), entries(&self.sell_orders, sells_taken), buys_taken + sells_taken < buys + sells)
+    }
+
+    /// Returns the volume-weighted average price of the trades in the current candle, or `None` if there were none.
+    ///
+    /// It resets with the candles, so it is an intraday figure rather than one for the whole session.
+    pub fn get_vwap(&self) -> Option<f64> {
+        if self.candle_volume == 0 {
+            return None;
         }
+        Some(self.candle_notional as f64 / self.candle_volume as f64 / PRICE_PRECISION_FACTOR)
     }
-}
 
-/// Open, high, low, close prices for a stock.
-pub struct Ohlc {
-    open: Option<f64>,
-    high: Option<f64>,
-    low: Option<f64>,
-    close: Option<f64>,
-}
+    /// Returns up to `limit` of the most recent completed candles, oldest first.
+    pub fn get_candles(&self, limit: usize) -> Vec<Candle> {
+        let skip = self.candles.len().saturating_sub(limit);
+        self.candles.iter().skip(skip).cloned().collect()
+    }
 
-impl Ohlc {
-    /// Creates a blank OHLC struct.
+    /// Returns the buy-initiated minus the sell-initiated volume traded in the `window` before `now`.
     ///
-    /// Values are all set to `None` until the first update.
-    fn new() -> Self {
-        Self {
-            open: None,
-            high: None,
-            low: None,
-            close: None,
-        }
+    /// Only the most recent trades are kept, so a long window on a busy stock may not see all of its trades.
+    pub fn get_order_flow(&self, now: DateTime<Utc>, window: Duration) -> i64 {
+        // A window reaching back past the earliest representable time covers every trade.
+        let since = now.checked_sub_signed(window);
+        self.recent_trades
+            .iter()
+            .filter(|(time, _)| since.is_none_or(|since| *time >= since))
+            .map(|(_, trade)| match trade.aggressor {
+                Side::Buy => trade.quantity as i64,
+                Side::Sell => -(trade.quantity as i64),
+            })
+            .sum()
     }
 
-    /// Updates the OHLC values according to the latest trade price provided.
-    fn update(&mut self, latest_price: f64) {
-        if self.open.is_none() {
-            self.open = Some(latest_price);
+    /// Returns the price at which the current book would clear in an auction, and the quantity that would trade.
+    ///
+    /// This is the price that maximises matched volume, with ties broken by the smallest imbalance between the two sides
+    /// and then by the lower price. Nothing is executed. Returns `None` if the book doesn't cross.
+    pub fn get_indicative_price(&self) -> Option<(f64, usize)> {
+        let mut candidates: Vec<usize> = self
+            .buy_orders
+            .iter()
+            .chain(self.sell_orders.iter())
+            .map(|order| order.get_unadjusted_price())
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        let mut best: Option<(usize, usize, usize)> = None; // (price, matched, imbalance)
+        for price in candidates {
+            let demand: usize = self
+                .buy_orders
+                .iter()
+                .filter(|order| order.get_unadjusted_price() >= price)
+                .map(|order| order.get_quantity())
+                .sum();
+            let supply: usize = self
+                .sell_orders
+                .iter()
+                .filter(|order| order.get_unadjusted_price() <= price)
+                .map(|order| order.get_quantity())
+                .sum();
+            let matched = demand.min(supply);
+            let imbalance = demand.abs_diff(supply);
+
+            let better = match best {
+                None => true,
+                Some((_, best_matched, best_imbalance)) => {
+                    matched > best_matched || (matched == best_matched && imbalance < best_imbalance)
+                }
+            };
+            if better {
+                best = Some((price, matched, imbalance));
+            }
         }
 
-        self.high = Some(self.high.unwrap_or(latest_price).max(latest_price));
-        self.low = Some(self.low.unwrap_or(latest_price).min(latest_price));
-        self.close = Some(latest_price);
+        match best {
+            Some((price, matched, _)) if matched > 0 => Some(((price as f64) / PRICE_PRECISION_FACTOR, matched)),
+            _ => None,
+        }
     }
 
-    /// Returns the open, high, low, close prices.
-    pub fn get(&self) -> (Option<f64>, Option<f64>, Option<f64>, Option<f64>) {
-        (self.open, self.high, self.low, self.close)
+    /// Clears the per-session statistics of the stock.
+    pub fn reset_session(&mut self) {
+        self.ohlc = Ohlc::new();
+        self.volume_profile.clear();
+        self.session_trades = 0;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Returns the total quantity traded this session.
+    pub fn get_session_volume(&self) -> usize {
+        self.volume_profile.values().sum()
+    }
 
-    /// Tests trade resolution, checking the returned logs and stored pending orders.
-    #[test]
-    fn test_resolve_trade() {
-        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+    /// Returns the number of trades this session.
+    pub fn get_session_trades(&self) -> usize {
+        self.session_trades
+    }
+
+    /// Returns the price of the most recent trade, if any, and the total quantity traded since the stock was listed.
+    pub fn get_ticker(&self) -> (Option<f64>, usize) {
+        (self.last_price, self.total_volume)
+    }
+
+    /// Returns the number of orders resting on both sides of the book.
+    pub fn get_resting_order_count(&self) -> usize {
+        self.buy_orders.len() + self.sell_orders.len()
+    }
+
+    /// Returns the total value, in cents, of the orders resting on both sides of the book.
+    pub fn get_resting_notional_cents(&self) -> u64 {
+        self.buy_orders.iter().chain(self.sell_orders.iter()).map(Order::get_value_cents).sum()
+    }
+
+    /// Returns what buying `quantity` shares from the best asks would cost, in cents, counting only as much as the book
+    /// can fill.
+    pub fn get_sweep_cost_cents(&self, quantity: usize) -> u64 {
+        let mut remaining = quantity;
+        let mut cost = 0u64;
+        for order in self.sell_orders.limit_orders() {
+            if remaining == 0 {
+                break;
+            }
+            let filled = remaining.min(order.get_quantity());
+            cost = cost.saturating_add(money::notional(order.price, filled));
+            remaining -= filled;
+        }
+        cost
+    }
+
+    /// Returns the quantity traded this session at each price, sorted in ascending order of price.
+    ///
+    /// This is executed volume, not the quantity resting in the book.
+    pub fn get_volume_profile(&self) -> Vec<(f64, usize)> {
+        let mut profile: Vec<(usize, usize)> = self.volume_profile.iter().map(|(price, quantity)| (*price, *quantity)).collect();
+        profile.sort_unstable_by_key(|(price, _)| *price);
+        profile
+            .into_iter()
+            .map(|(price, quantity)| ((price as f64) / PRICE_PRECISION_FACTOR, quantity))
+            .collect()
+    }
+
+    /// Returns the orders resting on the given side of the book.
+    fn orders(&self, side: Side) -> &BookSide {
+        match side {
+            Side::Buy => &self.buy_orders,
+            Side::Sell => &self.sell_orders,
+        }
+    }
+
+    /// Returns the number of orders and their total visible quantity resting at exactly this price on the given side.
+    ///
+    /// A new order at this price would queue behind all of them.
+    pub fn get_queue_depth(&self, side: Side, price: f64) -> (usize, usize) {
+        let price = unadjust_price(price);
+        self.orders(side)
+            .level(price)
+            .fold((0, 0), |(count, quantity), order| (count + 1, quantity + order.get_visible_quantity()))
+    }
+
+    /// Returns the total quantity resting on the given side of the book, and the number of price levels it rests at.
+    pub fn get_depth(&self, side: Side) -> (usize, usize) {
+        self.orders(side).depth()
+    }
+
+    fn orders_mut(&mut self, side: Side) -> &mut BookSide {
+        match side {
+            Side::Buy => &mut self.buy_orders,
+            Side::Sell => &mut self.sell_orders,
+        }
+    }
+
+    /// Replaces the resting order with the same ID, placing the new order at the back of its price level.
+    ///
+    /// Returns `false`, leaving the book unchanged, if there is no such order.
+    pub fn replace_order(&mut self, order: Order) -> bool {
+        let Some(orders) = [&mut self.buy_orders, &mut self.sell_orders]
+            .into_iter()
+            .find(|orders| orders.iter().any(|resting| resting.id == order.id))
+        else {
+            return false;
+        };
+
+        orders.remove(order.id);
+        self.next_sequence += 1;
+        orders.push(Order { sequence: self.next_sequence, time: self.clock.now(), ..order });
+        self.touch();
+        true
+    }
+
+    /// Replaces the resting order with the same ID as `order`, from `Order::modified`. Returns whether there was one.
+    ///
+    /// Reducing a limit order's quantity at the same price keeps its place in the queue. Any other change moves it to
+    /// the back of the queue at its new price, as if it had just been placed.
+    pub fn modify_order(&mut self, order: Order) -> bool {
+        let keeps_priority = match self.get_order(order.id) {
+            Some(resting) => resting.kind == order.kind && resting.price == order.price && order.quantity <= resting.quantity,
+            None => return false,
+        };
+        if !keeps_priority {
+            return self.replace_order(order);
+        }
+
+        for orders in [&mut self.buy_orders, &mut self.sell_orders] {
+            if let Some(resting) = orders.iter_mut().find(|resting| resting.id == order.id) {
+                resting.quantity = order.quantity;
+            }
+        }
+        self.touch();
+        true
+    }
+
+    /// Reduces the remaining quantity of the creator's resting order by up to `reduce_by`, returning what is left.
+    ///
+    /// The order keeps its place in the queue, unless nothing is left, in which case it is removed from the book.
+    /// Returns `None` if the creator has no resting order with that ID.
+    pub fn reduce_order(&mut self, creator_id: usize, order_id: u64, reduce_by: usize) -> Option<usize> {
+        let mut remaining = None;
+        for orders in [&mut self.buy_orders, &mut self.sell_orders] {
+            let Some(order) = orders.iter_mut().find(|order| order.id == order_id && order.creator_id == creator_id) else {
+                continue;
+            };
+            order.resolve(reduce_by.min(order.get_quantity()));
+            let left = order.get_quantity();
+            if left == 0 {
+                orders.remove(order_id);
+            }
+            remaining = Some(left);
+            break;
+        }
+
+        if remaining.is_some() {
+            self.touch();
+        }
+        remaining
+    }
+
+    /// Cancels the creator's earliest resting order on the given side, returning it.
+    pub fn cancel_oldest(&mut self, creator_id: usize, side: Side) -> Option<Order> {
+        let orders = self.orders_mut(side);
+        let order_id = orders.iter().filter(|order| order.creator_id == creator_id).map(Order::get_id).min()?;
+        let order = orders.remove(order_id);
+        self.touch();
+        order
+    }
+
+    /// Cancels the creator's latest resting order on the given side, returning it.
+    pub fn cancel_newest(&mut self, creator_id: usize, side: Side) -> Option<Order> {
+        let orders = self.orders_mut(side);
+        let order_id = orders.iter().filter(|order| order.creator_id == creator_id).map(Order::get_id).max()?;
+        let order = orders.remove(order_id);
+        self.touch();
+        order
+    }
+
+    /// Returns the creator's resting orders, along with the side of the book each is on.
+    pub fn get_user_orders(&self, creator_id: usize) -> Vec<(Side, &Order)> {
+        let buys = self.buy_orders.iter().map(|order| (Side::Buy, order));
+        let sells = self.sell_orders.iter().map(|order| (Side::Sell, order));
+        buys.chain(sells).filter(|(_, order)| order.creator_id == creator_id).collect()
+    }
+
+    /// Returns the resting order with the given ID, if it is in this stock's book.
+    pub fn get_order(&self, order_id: u64) -> Option<&Order> {
+        self.buy_orders.iter().chain(self.sell_orders.iter()).find(|order| order.id == order_id)
+    }
+
+    /// Removes the resting order with the given ID, whoever placed it, returning it.
+    pub fn remove_order(&mut self, order_id: u64) -> Option<Order> {
+        let order = self.buy_orders.remove(order_id).or_else(|| self.sell_orders.remove(order_id));
+        if order.is_some() {
+            self.touch();
+        }
+        order
+    }
+
+    /// Cancels the resting order with the given ID, if the creator placed it. Returns whether it was cancelled.
+    pub fn cancel_order(&mut self, order_id: u64, creator_id: usize) -> bool {
+        let mut cancelled = false;
+        for orders in [&mut self.buy_orders, &mut self.sell_orders] {
+            if orders.iter().any(|order| order.id == order_id && order.creator_id == creator_id) {
+                orders.remove(order_id);
+                cancelled = true;
+                break;
+            }
+        }
+        if cancelled {
+            self.touch();
+        }
+        cancelled
+    }
+
+    /// Cancels every resting order placed by the creator on both sides of the book, returning them.
+    pub fn cancel_user_orders(&mut self, creator_id: usize) -> Vec<Order> {
+        let mut cancelled = Vec::new();
+        for orders in [&mut self.buy_orders, &mut self.sell_orders] {
+            cancelled.extend(orders.extract(|order| order.creator_id == creator_id));
+        }
+        if !cancelled.is_empty() {
+            self.touch();
+        }
+        cancelled
+    }
+
+    /// Moves every resting order placed by one creator to another, keeping their place in the book.
+    pub fn reassign_orders(&mut self, from: usize, to: usize) {
+        for orders in [&mut self.buy_orders, &mut self.sell_orders] {
+            for order in orders.iter_mut().filter(|order| order.creator_id == from) {
+                order.creator_id = to;
+            }
+        }
+    }
+
+    /// Removes every resting order placed before `cutoff`, returning them.
+    pub fn expire_orders(&mut self, cutoff: DateTime<Utc>) -> Vec<Order> {
+        let mut expired = Vec::new();
+        for orders in [&mut self.buy_orders, &mut self.sell_orders] {
+            expired.extend(orders.extract(|order| order.time < cutoff));
+        }
+        if !expired.is_empty() {
+            self.touch();
+        }
+        expired
+    }
+
+    /// Removes every resting order on both sides of the book, returning them.
+    pub fn cancel_all(&mut self) -> Vec<Order> {
+        let mut cancelled = self.buy_orders.extract(|_| true);
+        cancelled.extend(self.sell_orders.extract(|_| true));
+        if !cancelled.is_empty() {
+            self.touch();
+        }
+        cancelled
+    }
+
+    /// Returns the open, high, low, close prices for the stock.
+    pub fn get_ohlc(&self) -> (Option<f64>, Option<f64>, Option<f64>, Option<f64>) {
+        self.ohlc.get()
+    }
+}
+
+/// One side of a stock's order book, with its orders grouped into price levels.
+///
+/// Levels are keyed so that ascending key order is priority order: highest price first for buys, lowest first for
+/// sells. Within a level, orders queue in the order they were added. Adding an order is O(log n) in the number of
+/// levels, and the book never needs re-sorting.
+struct BookSide {
+    side: Side,
+    levels: BTreeMap<usize, VecDeque<Order>>,
+}
+
+impl BookSide {
+    fn new(side: Side) -> Self {
+        Self { side, levels: BTreeMap::new() }
+    }
+
+    /// Returns the key of the level for the (unadjusted) price.
+    fn key(&self, price: usize) -> usize {
+        match self.side {
+            Side::Buy => usize::MAX - price,
+            Side::Sell => price,
+        }
+    }
+
+    /// Adds an order to the back of its price level.
+    fn push(&mut self, order: Order) {
+        let key = self.key(order.price);
+        self.levels.entry(key).or_default().push_back(order);
+    }
+
+    /// Returns the limit order with the highest priority, if any.
+    fn first_limit(&self) -> Option<&Order> {
+        self.limit_orders().next()
+    }
+
+    /// Returns the orders in priority order.
+    fn iter(&self) -> impl Iterator<Item = &Order> {
+        self.levels.values().flatten()
+    }
+
+    /// Returns the limit orders in priority order. Market orders only rest here until the next resolve, at sentinel
+    /// prices that aren't real levels, so they are left out of every view of the book.
+    fn limit_orders(&self) -> impl Iterator<Item = &Order> {
+        self.iter().filter(|order| order.kind == OrderKind::Limit)
+    }
+
+    /// Returns the orders in priority order, for updating.
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Order> {
+        self.levels.values_mut().flatten()
+    }
+
+    /// Returns the order at a position, given as a level key and an index into that level.
+    fn get(&self, (key, index): (usize, usize)) -> Option<&Order> {
+        self.levels.get(&key)?.get(index)
+    }
+
+    /// Returns the position of the first order at or after `cursor` that still has quantity left, if any.
+    fn skip_filled(&self, (key, index): (usize, usize)) -> Option<(usize, usize)> {
+        self.levels.range(key..).find_map(|(&level_key, orders)| {
+            let start = if level_key == key { index } else { 0 };
+            let offset = orders.iter().skip(start).position(|order| order.get_quantity() > 0)?;
+            Some((level_key, start + offset))
+        })
+    }
+
+    /// Returns the orders from `cursor` onwards in priority order, for updating.
+    fn iter_mut_from(&mut self, (key, index): (usize, usize)) -> impl Iterator<Item = &mut Order> {
+        self.levels
+            .range_mut(key..)
+            .flat_map(move |(&level_key, orders)| orders.iter_mut().skip(if level_key == key { index } else { 0 }))
+    }
+
+    /// Returns the best `n` price levels in priority order, each with the total quantity resting at it.
+    fn top_levels(&self, n: usize) -> Vec<(f64, usize)> {
+        self.levels
+            .values()
+            .filter_map(|orders| {
+                let mut limits = orders.iter().filter(|order| order.kind == OrderKind::Limit).peekable();
+                let price = limits.peek()?.get_unadjusted_price();
+                Some(((price as f64) / PRICE_PRECISION_FACTOR, limits.map(Order::get_visible_quantity).sum()))
+            })
+            .take(n)
+            .collect()
+    }
+
+    /// Returns the total quantity shown on this side and the number of price levels it rests at.
+    fn depth(&self) -> (usize, usize) {
+        let levels = self.levels.values().filter(|orders| orders.iter().any(|order| order.kind == OrderKind::Limit)).count();
+        (self.limit_orders().map(Order::get_visible_quantity).sum(), levels)
+    }
+
+    /// Returns the number of resting orders.
+    fn len(&self) -> usize {
+        self.levels.values().map(VecDeque::len).sum()
+    }
+
+    /// Returns the limit orders at exactly the (unadjusted) price, in queue order.
+    fn level(&self, price: usize) -> impl Iterator<Item = &Order> {
+        self.levels.get(&self.key(price)).into_iter().flatten().filter(|order| order.kind == OrderKind::Limit)
+    }
+
+    /// Keeps only the orders for which `keep` returns `true`.
+    fn retain(&mut self, mut keep: impl FnMut(&Order) -> bool) {
+        for orders in self.levels.values_mut() {
+            orders.retain(&mut keep);
+        }
+        self.levels.retain(|_, orders| !orders.is_empty());
+    }
+
+    /// Removes the orders for which `remove` returns `true`, returning them in priority order.
+    fn extract(&mut self, mut remove: impl FnMut(&Order) -> bool) -> Vec<Order> {
+        let mut removed = Vec::new();
+        for orders in self.levels.values_mut() {
+            let (taken, kept): (VecDeque<Order>, VecDeque<Order>) = std::mem::take(orders).into_iter().partition(&mut remove);
+            *orders = kept;
+            removed.extend(taken);
+        }
+        self.levels.retain(|_, orders| !orders.is_empty());
+        removed
+    }
+
+    /// Removes the order with the given ID, returning it.
+    fn remove(&mut self, order_id: u64) -> Option<Order> {
+        let (&key, orders) = self.levels.iter_mut().find(|(_, orders)| orders.iter().any(|order| order.id == order_id))?;
+        let index = orders.iter().position(|order| order.id == order_id)?;
+        let order = orders.remove(index);
+        if orders.is_empty() {
+            self.levels.remove(&key);
+        }
+        order
+    }
+}
+
+/// Whether an order has a price limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderKind {
+    /// The order trades at its price or better, and rests in the book until it does.
+    #[default]
+    Limit,
+    /// The order trades at whatever prices the other side of the book offers. Whatever can't be filled straight away is
+    /// cancelled rather than left resting.
+    Market,
+}
+
+/// What happens when two of a user's orders would trade with each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SelfTradePrevention {
+    /// Neither order is matched against the other. Both stay in the book, and may still trade with other users.
+    #[default]
+    Skip,
+    /// The order that was in the book first is cancelled.
+    CancelResting,
+    /// The order that arrived later is cancelled.
+    CancelIncoming,
+}
+
+impl TryFrom<&str> for SelfTradePrevention {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, ()> {
+        match s {
+            "skip" => Ok(SelfTradePrevention::Skip),
+            "cancel_resting" => Ok(SelfTradePrevention::CancelResting),
+            "cancel_incoming" => Ok(SelfTradePrevention::CancelIncoming),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for SelfTradePrevention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelfTradePrevention::Skip => write!(f, "skip"),
+            SelfTradePrevention::CancelResting => write!(f, "cancel_resting"),
+            SelfTradePrevention::CancelIncoming => write!(f, "cancel_incoming"),
+        }
+    }
+}
+
+/// How long an order may stay in the book.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    /// Good till cancelled. Whatever isn't filled rests in the book.
+    #[default]
+    Gtc,
+    /// Immediate or cancel. Whatever isn't filled on the tick the order arrives is cancelled.
+    Ioc,
+    /// Fill or kill. The order is cancelled without trading unless it can be filled completely on the tick it arrives,
+    /// within what is left of `max_matches_per_tick`.
+    Fok,
+}
+
+impl TryFrom<&str> for TimeInForce {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, ()> {
+        match s {
+            "gtc" => Ok(TimeInForce::Gtc),
+            "ioc" => Ok(TimeInForce::Ioc),
+            "fok" => Ok(TimeInForce::Fok),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for TimeInForce {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeInForce::Gtc => write!(f, "gtc"),
+            TimeInForce::Ioc => write!(f, "ioc"),
+            TimeInForce::Fok => write!(f, "fok"),
+        }
+    }
+}
+
+/// An order to buy or sell a stock.
+pub struct Order {
+    /// The unique ID of the order.
+    id: u64,
+    /// The ID of the creator of the order.
+    creator_id: usize,
+    /// The price per stock.
+    price: usize,
+    /// The quantity of the order.
+    quantity: usize,
+    /// The time the order was created.
+    time: DateTime<Utc>,
+    /// The order's place in the sequence of orders added to its stock's book, set when it is added. Of two crossing
+    /// orders, the one added earlier is the maker and sets the trade price.
+    sequence: u64,
+    /// The creator's label for the order. It is echoed on fills and plays no part in matching.
+    tag: Option<OrderTag>,
+    /// Whether the order has a price limit. Market orders store the most aggressive price possible, so that they sort
+    /// ahead of, and cross, every limit order on the other side.
+    kind: OrderKind,
+    /// How long the order may stay in the book.
+    time_in_force: TimeInForce,
+    /// For an iceberg order, how much of its quantity is shown in the book at a time. All of it still matches.
+    display_qty: Option<usize>,
+}
+
+impl Order {
+    /// Creates a new order with the given creator ID, price, and quantity.
+    ///
+    /// The order has no ID until the market numbers it, and is timestamped by the system clock until the market stamps it
+    /// with its own, when it is added.
+    pub fn new(creator_id: usize, price: f64, quantity: usize) -> Self {
+        let price = unadjust_price(price);
+
+        Self {
+            id: 0,
+            creator_id,
+            price,
+            quantity,
+            time: Utc::now(),
+            sequence: 0,
+            tag: None,
+            kind: OrderKind::Limit,
+            time_in_force: TimeInForce::Gtc,
+            display_qty: None,
+        }
+    }
+
+    /// Creates a new market order for the given side with the given creator ID and quantity.
+    ///
+    /// The order has no ID until the market numbers it, and is timestamped by the system clock until the market stamps it
+    /// with its own, when it is added.
+    pub fn market(creator_id: usize, side: Side, quantity: usize) -> Self {
+        let price = match side {
+            Side::Buy => usize::MAX,
+            Side::Sell => 0,
+        };
+
+        Self {
+            id: 0,
+            creator_id,
+            price,
+            quantity,
+            time: Utc::now(),
+            sequence: 0,
+            tag: None,
+            kind: OrderKind::Market,
+            time_in_force: TimeInForce::Gtc,
+            display_qty: None,
+        }
+    }
+
+    /// Recreates an order from a snapshot, keeping its ID.
+    ///
+    /// A tag that is no longer valid is dropped.
+    pub fn from_snapshot(snapshot: OrderSnapshot) -> Self {
+        Self {
+            id: snapshot.id,
+            creator_id: snapshot.creator_id,
+            price: snapshot.price,
+            quantity: snapshot.quantity,
+            time: DateTime::from_timestamp_millis(snapshot.time_ms).unwrap_or_else(Utc::now),
+            sequence: 0,
+            tag: snapshot.tag.and_then(|tag| OrderTag::try_from(tag.as_str()).ok()),
+            kind: snapshot.kind,
+            time_in_force: snapshot.time_in_force,
+            display_qty: snapshot.display_qty,
+        }
+    }
+
+    /// Returns a snapshot of the order.
+    pub fn to_snapshot(&self) -> OrderSnapshot {
+        OrderSnapshot {
+            id: self.id,
+            creator_id: self.creator_id,
+            price: self.price,
+            quantity: self.quantity,
+            time_ms: self.time.timestamp_millis(),
+            tag: self.tag.map(|tag| tag.to_string()),
+            kind: self.kind,
+            time_in_force: self.time_in_force,
+            display_qty: self.display_qty,
+        }
+    }
+
+    /// Returns the order with the given ID.
+    pub fn with_id(self, id: u64) -> Self {
+        Self { id, ..self }
+    }
+
+    /// Returns the order stamped with the given creation time.
+    pub fn with_time(self, time: DateTime<Utc>) -> Self {
+        Self { time, ..self }
+    }
+
+    /// Returns the order with the given tag.
+    pub fn with_tag(self, tag: Option<OrderTag>) -> Self {
+        Self { tag, ..self }
+    }
+
+    /// Returns the order with the given time in force.
+    pub fn with_time_in_force(self, time_in_force: TimeInForce) -> Self {
+        Self { time_in_force, ..self }
+    }
+
+    /// Returns the order showing at most `display_qty` of its quantity in the book at a time, or all of it if `None`.
+    pub fn with_display_qty(self, display_qty: Option<usize>) -> Self {
+        Self { display_qty, ..self }
+    }
+
+    /// Returns a copy of the order at a new price, which queues behind orders already at that price once it replaces the
+    /// original.
+    ///
+    /// The copy keeps the order's ID, so it can replace the original with `Stock::replace_order`, which stamps it with
+    /// the time it was replaced. It is always a good-till-cancelled limit order.
+    pub fn repriced(&self, price: f64) -> Self {
+        Self {
+            id: self.id,
+            creator_id: self.creator_id,
+            price: unadjust_price(price),
+            quantity: self.quantity,
+            time: self.time,
+            sequence: 0,
+            tag: self.tag,
+            kind: OrderKind::Limit,
+            time_in_force: TimeInForce::Gtc,
+            display_qty: self.display_qty,
+        }
+    }
+
+    /// Returns a copy of the order at a new price and remaining quantity, to replace the original with
+    /// `Stock::modify_order`. Like `repriced`, it is always a good-till-cancelled limit order.
+    pub fn modified(&self, price: f64, quantity: usize) -> Self {
+        Self { quantity, ..self.repriced(price) }
+    }
+
+    /// Returns whether the price can be stored on an order, i.e. is not negative and doesn't overflow.
+    pub fn is_price_representable(price: f64) -> bool {
+        price.is_finite() && price >= 0.0 && price * PRICE_PRECISION_FACTOR <= usize::MAX as f64
+    }
+
+    /// Returns whether the order has a price limit.
+    pub fn get_kind(&self) -> OrderKind {
+        self.kind
+    }
+
+    /// Returns how long the order may stay in the book.
+    pub fn get_time_in_force(&self) -> TimeInForce {
+        self.time_in_force
+    }
+
+    /// Returns the creator's label for the order, if it has one.
+    pub fn get_tag(&self) -> Option<OrderTag> {
+        self.tag
+    }
+
+    /// Returns the unique ID of the order.
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the ID of the creator of the order.
+    pub fn get_creator_id(&self) -> usize {
+        self.creator_id
+    }
+
+    /// Returns the total value of the order.
+    pub fn get_value(&self) -> f64 {
+        money::from_cents(self.get_value_cents())
+    }
+
+    /// Returns the total value of the order in cents.
+    pub fn get_value_cents(&self) -> u64 {
+        money::notional(self.price, self.quantity)
+    }
+
+    /// Returns the price per stock of the order.
+    pub fn get_price(&self) -> f64 {
+        self.price as f64 / PRICE_PRECISION_FACTOR
+    }
+
+    /// Returns the price per stock WITHOUT adjusting for the precision factor.
+    fn get_unadjusted_price(&self) -> usize {
+        self.price
+    }
+
+    /// Returns the quantity of the order.
+    pub fn get_quantity(&self) -> usize {
+        self.quantity
+    }
+
+    /// Returns the quantity shown in the book. An iceberg order shows its next slice, and refills it as slices fill.
+    pub fn get_visible_quantity(&self) -> usize {
+        self.display_qty.map_or(self.quantity, |display_qty| display_qty.min(self.quantity))
+    }
+
+    /// Returns the time the order was created.
+    pub fn get_time(&self) -> DateTime<Utc> {
+        self.time
+    }
+
+    /// Reduces the quantity of the order by the given amount.
+    pub fn resolve(&mut self, quantity: usize) {
+        self.quantity -= quantity;
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+/// A log of a resolved trade between a buyer and a seller.
+pub struct Trade {
+    /// The ID of the buyer.
+    pub buyer_id: usize,
+    /// The ID of the seller.
+    pub seller_id: usize,
+    /// The price per stock.
+    #[serde(serialize_with = "json::price")]
+    pub price: f64,
+    /// The quantity of the trade.
+    pub quantity: usize,
+    /// The limit price of the order that took liquidity, so its price improvement can be worked out. `None` if it was a
+    /// market order.
+    #[serde(serialize_with = "json::optional_price", skip_serializing_if = "Option::is_none")]
+    pub aggressor_limit: Option<f64>,
+    /// The side of the incoming order that took liquidity from the resting one.
+    #[serde(skip)]
+    pub aggressor: Side,
+    /// The ID of the buy order.
+    pub buy_order_id: u64,
+    /// The ID of the sell order.
+    pub sell_order_id: u64,
+    /// The quantity of the buy order left after the trade.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buy_remaining: Option<usize>,
+    /// The quantity of the sell order left after the trade.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sell_remaining: Option<usize>,
+    /// The tag of the buy order.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buyer_tag: Option<OrderTag>,
+    /// The tag of the sell order.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seller_tag: Option<OrderTag>,
+    /// When the trade was executed.
+    #[serde(serialize_with = "json::time")]
+    pub time: DateTime<Utc>,
+}
+
+impl Trade {
+    /// Creates a new trade between the buy and sell orders at the given price and quantity, with the given aggressor
+    /// side, executed at `time`. The orders should already have been filled by the trade, so that their remaining
+    /// quantities are recorded.
+    fn new(buy_order: &Order, sell_order: &Order, price: f64, quantity: usize, aggressor: Side, time: DateTime<Utc>) -> Self {
+        let aggressor_order = match aggressor {
+            Side::Buy => buy_order,
+            Side::Sell => sell_order,
+        };
+        Self {
+            buyer_id: buy_order.creator_id,
+            seller_id: sell_order.creator_id,
+            price,
+            quantity,
+            aggressor_limit: (aggressor_order.kind == OrderKind::Limit).then(|| aggressor_order.get_price()),
+            aggressor,
+            buy_order_id: buy_order.id,
+            sell_order_id: sell_order.id,
+            buy_remaining: Some(buy_order.get_quantity()),
+            sell_remaining: Some(sell_order.get_quantity()),
+            buyer_tag: buy_order.tag,
+            seller_tag: sell_order.tag,
+            time,
+        }
+    }
+}
+
+/// Open, high, low, close prices for a stock.
+pub struct Ohlc {
+    open: Option<f64>,
+    high: Option<f64>,
+    low: Option<f64>,
+    close: Option<f64>,
+}
+
+impl Ohlc {
+    /// Creates a blank OHLC struct.
+    ///
+    /// Values are all set to `None` until the first update.
+    fn new() -> Self {
+        Self {
+            open: None,
+            high: None,
+            low: None,
+            close: None,
+        }
+    }
+
+    /// Updates the OHLC values according to the latest trade price provided.
+    fn update(&mut self, latest_price: f64) {
+        if self.open.is_none() {
+            self.open = Some(latest_price);
+        }
+
+        self.high = Some(self.high.unwrap_or(latest_price).max(latest_price));
+        self.low = Some(self.low.unwrap_or(latest_price).min(latest_price));
+        self.close = Some(latest_price);
+    }
+
+    /// Returns the open, high, low, close prices.
+    pub fn get(&self) -> (Option<f64>, Option<f64>, Option<f64>, Option<f64>) {
+        (self.open, self.high, self.low, self.close)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests trade resolution, checking the returned logs and stored pending orders.
+    #[test]
+    fn test_resolve_trade() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
         let buy_order = Order::new(1, 150.5, 10);
         let sell_order = Order::new(2, 150.0, 5);
 
         stock.add_buy_order(buy_order);
         stock.add_sell_order(sell_order);
 
-        let trades = stock.resolve();
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
         assert_eq!(trades.len(), 1);
         assert_eq!(trades[0].buyer_id, 1);
         assert_eq!(trades[0].seller_id, 2);
@@ -390,8 +1634,8 @@ mod tests {
         assert_eq!(trades[0].quantity, 5);
 
         // Verify remaining orders
-        assert_eq!(stock.get_buy_orders()[0].1, 5);
-        assert!(stock.get_sell_orders().is_empty());
+        assert_eq!(stock.get_buy_orders(5)[0].1, 5);
+        assert!(stock.get_sell_orders(5).is_empty());
     }
 
     /// Tests whether OHLC is updated correctly.
@@ -418,10 +1662,20 @@ mod tests {
         stock.add_buy_order(Order::new(2, 155.0, 5));
         stock.add_buy_order(Order::new(3, 150.0, 15));
 
-        let buy_orders = stock.get_buy_orders();
+        let buy_orders = stock.get_buy_orders(5);
         assert_eq!(buy_orders.len(), 2); // Only unique prices are kept
         assert_eq!(buy_orders[0], (155.0, 5)); // Highest price first
         assert_eq!(buy_orders[1], (150.0, 25)); // Combined quantities
+
+        // Only the best five levels come back, however the orders arrived.
+        for (price, quantity) in [(140.0, 1), (160.0, 2), (145.0, 3), (158.0, 4)] {
+            stock.add_buy_order(Order::new(4, price, quantity));
+        }
+        stock.add_buy_order(Order::new(5, 158.0, 6));
+        assert_eq!(
+            stock.get_buy_orders(5),
+            vec![(160.0, 2), (158.0, 10), (155.0, 5), (150.0, 25), (145.0, 3)]
+        );
     }
 
     #[test]
@@ -431,9 +1685,657 @@ mod tests {
         stock.add_sell_order(Order::new(2, 140.0, 5));
         stock.add_sell_order(Order::new(3, 145.0, 15));
 
-        let sell_orders = stock.get_sell_orders();
+        let sell_orders = stock.get_sell_orders(5);
         assert_eq!(sell_orders.len(), 2); // Only unique prices are kept
         assert_eq!(sell_orders[0], (140.0, 5)); // Lowest price first
         assert_eq!(sell_orders[1], (145.0, 25)); // Combined quantities
+
+        assert_eq!(stock.get_sell_orders(1), vec![(140.0, 5)]);
+        // Asking for more levels than there are returns them all.
+        assert_eq!(stock.get_sell_orders(20), vec![(140.0, 5), (145.0, 25)]);
+    }
+
+    /// Tests that executed volume is bucketed by trade price and cleared with the session.
+    #[test]
+    fn test_volume_profile() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_sell_order(Order::new(1, 150.0, 5));
+        stock.add_sell_order(Order::new(1, 151.0, 5));
+        stock.add_buy_order(Order::new(2, 151.0, 8));
+        stock.resolve(0, None, SelfTradePrevention::Skip);
+        stock.add_sell_order(Order::new(3, 150.0, 4));
+        stock.add_buy_order(Order::new(2, 150.0, 4));
+        stock.resolve(0, None, SelfTradePrevention::Skip);
+
+        assert_eq!(stock.get_volume_profile(), vec![(150.0, 9), (151.0, 3)]);
+
+        stock.reset_session();
+        assert!(stock.get_volume_profile().is_empty());
+    }
+
+    /// Tests that the indicative price maximises matched volume without touching the book.
+    #[test]
+    fn test_indicative_price() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        assert_eq!(stock.get_indicative_price(), None);
+
+        stock.add_buy_order(Order::new(1, 101.0, 10));
+        stock.add_buy_order(Order::new(2, 100.0, 10));
+        stock.add_sell_order(Order::new(3, 102.0, 5));
+        assert_eq!(stock.get_indicative_price(), None);
+
+        stock.add_sell_order(Order::new(4, 99.0, 5));
+        stock.add_sell_order(Order::new(5, 100.0, 10));
+        assert_eq!(stock.get_indicative_price(), Some((100.0, 15)));
+
+        // Nothing was executed.
+        assert_eq!(stock.get_buy_orders(5), vec![(101.0, 10), (100.0, 10)]);
+        assert_eq!(stock.get_sell_orders(5), vec![(99.0, 5), (100.0, 10), (102.0, 5)]);
+    }
+
+    /// Tests that each candle records the time-weighted spread of the book, or `None` if the book was one-sided.
+    #[test]
+    fn test_spread_history() {
+        let start = Utc::now();
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_buy_order(Order::new(1, 99.0, 10));
+        stock.add_sell_order(Order::new(2, 101.0, 10));
+        stock.sample_spread(start);
+        stock.add_sell_order(Order::new(2, 100.0, 10));
+        stock.sample_spread(start + chrono::Duration::seconds(30));
+        stock.roll_candle(start + chrono::Duration::seconds(60));
+
+        stock.cancel_all();
+        stock.sample_spread(start + chrono::Duration::seconds(60));
+        stock.roll_candle(start + chrono::Duration::seconds(120));
+
+        let candles = stock.get_candles(10);
+        assert_eq!(candles.len(), 2);
+        assert_eq!(candles[0].avg_spread, Some(1.5));
+        assert_eq!(candles[0].end, start + chrono::Duration::seconds(60));
+        assert_eq!(candles[1].avg_spread, None);
+        assert_eq!(stock.get_candles(1), candles[1..]);
+    }
+
+    /// Tests that trades in the same window share a candle, and that each candle's prices start afresh.
+    #[test]
+    fn test_candle_ohlc() {
+        let start = Utc::now();
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        for (price, quantity) in [(100.0, 5), (103.0, 5)] {
+            stock.add_sell_order(Order::new(1, price, quantity));
+            stock.add_buy_order(Order::new(2, price, quantity));
+            stock.resolve(0, None, SelfTradePrevention::Skip);
+        }
+        stock.roll_candle(start + chrono::Duration::seconds(60));
+
+        let candles = stock.get_candles(10);
+        assert_eq!(candles.len(), 1);
+        assert_eq!((candles[0].open, candles[0].high, candles[0].low, candles[0].close), (Some(100.0), Some(103.0), Some(100.0), Some(103.0)));
+
+        stock.add_sell_order(Order::new(1, 98.0, 5));
+        stock.add_buy_order(Order::new(2, 98.0, 5));
+        stock.resolve(0, None, SelfTradePrevention::Skip);
+        stock.roll_candle(start + chrono::Duration::seconds(120));
+        stock.roll_candle(start + chrono::Duration::seconds(180));
+
+        let candles = stock.get_candles(10);
+        assert_eq!(candles.len(), 3);
+        assert_eq!((candles[1].open, candles[1].high, candles[1].low, candles[1].close), (Some(98.0), Some(98.0), Some(98.0), Some(98.0)));
+        assert_eq!(candles[2].open, None);
+        // The session's OHLC still covers every trade.
+        assert_eq!(stock.get_ohlc(), (Some(100.0), Some(103.0), Some(98.0), Some(98.0)));
+    }
+
+    /// Tests that the VWAP weights each trade's price by its quantity, and starts afresh with each candle.
+    #[test]
+    fn test_vwap() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        assert_eq!(stock.get_vwap(), None);
+        for (price, quantity) in [(100.0, 10), (110.0, 30), (95.5, 60)] {
+            stock.add_sell_order(Order::new(1, price, quantity));
+            stock.add_buy_order(Order::new(2, price, quantity));
+            stock.resolve(0, None, SelfTradePrevention::Skip);
+        }
+        // (100 * 10 + 110 * 30 + 95.5 * 60) / 100 = 100.3
+        assert!((stock.get_vwap().unwrap() - 100.3).abs() < 1e-9);
+
+        stock.roll_candle(Utc::now());
+        assert_eq!(stock.get_vwap(), None);
+    }
+
+    /// Tests that the full book lists each resting order in priority order, hides iceberg reserves, and keeps to the limit.
+    #[test]
+    fn test_get_book() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        let bid = Order::new(1, 99.0, 5);
+        let bid_id = bid.get_id();
+        stock.add_buy_order(bid);
+        stock.add_buy_order(Order::new(2, 100.0, 50).with_display_qty(Some(10)));
+        stock.add_sell_order(Order::new(3, 101.0, 7));
+        stock.add_sell_order(Order::new(3, 102.0, 8));
+
+        let (bids, asks, truncated) = stock.get_book(10);
+        assert!(!truncated);
+        assert_eq!(bids.iter().map(|entry| (entry.creator_id, entry.price, entry.quantity)).collect::<Vec<_>>(), vec![(2, 100.0, 10), (1, 99.0, 5)]);
+        assert_eq!(bids[1].order_id, bid_id);
+        assert_eq!(asks.iter().map(|entry| (entry.price, entry.quantity)).collect::<Vec<_>>(), vec![(101.0, 7), (102.0, 8)]);
+
+        let (bids, asks, truncated) = stock.get_book(3);
+        assert!(truncated);
+        assert_eq!((bids.len(), asks.len()), (2, 1));
+        assert_eq!(asks[0].price, 101.0);
+    }
+
+    /// Tests that the ticker follows the latest trade price and sums volume across fills and sessions.
+    #[test]
+    fn test_ticker() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        assert_eq!(stock.get_ticker(), (None, 0));
+
+        stock.add_sell_order(Order::new(1, 100.0, 4));
+        stock.add_sell_order(Order::new(1, 101.0, 6));
+        stock.add_buy_order(Order::new(2, 101.0, 10));
+        stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(stock.get_ticker(), (Some(101.0), 10));
+
+        stock.reset_session();
+        stock.add_sell_order(Order::new(1, 99.5, 3));
+        stock.add_buy_order(Order::new(2, 99.5, 3));
+        stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(stock.get_ticker(), (Some(99.5), 13));
+    }
+
+    /// Tests that a trade happens at the price of the order added to the book first, even if the other order was created
+    /// earlier.
+    #[test]
+    fn test_maker_sets_price() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        let buy = Order::new(2, 151.0, 5);
+        let sell = Order::new(1, 150.0, 5);
+        stock.add_sell_order(sell);
+        stock.add_buy_order(buy);
+
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price, 150.0);
+        assert_eq!(trades[0].aggressor, Side::Buy);
+    }
+
+    /// Tests that a user's crossing orders don't trade with each other, and that the buy still trades with other users.
+    #[test]
+    fn test_self_trade_skipped() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_sell_order(Order::new(1, 100.0, 5));
+        stock.add_buy_order(Order::new(1, 101.0, 5));
+        assert!(stock.resolve(0, None, SelfTradePrevention::Skip).is_empty());
+        assert_eq!(stock.get_buy_orders(5), vec![(101.0, 5)]);
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 5)]);
+
+        stock.add_sell_order(Order::new(2, 101.0, 5));
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(trades.len(), 1);
+        assert_eq!((trades[0].buyer_id, trades[0].seller_id, trades[0].price), (1, 2, 101.0));
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 5)]);
+    }
+
+    /// Tests that self-trade prevention can cancel either the resting or the incoming order instead.
+    #[test]
+    fn test_self_trade_cancelled() {
+        for (self_trade, left) in [(SelfTradePrevention::CancelResting, Side::Buy), (SelfTradePrevention::CancelIncoming, Side::Sell)] {
+            let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+            stock.add_sell_order(Order::new(1, 100.0, 5));
+            stock.add_buy_order(Order::new(1, 100.0, 5));
+            assert!(stock.resolve(0, None, self_trade).is_empty());
+            assert_eq!(stock.get_buy_orders(5).is_empty(), left == Side::Sell, "{}", self_trade);
+            assert_eq!(stock.get_sell_orders(5).is_empty(), left == Side::Buy, "{}", self_trade);
+        }
+    }
+
+    /// Tests that an immediate-or-cancel order fills what it can and doesn't rest.
+    #[test]
+    fn test_immediate_or_cancel() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_sell_order(Order::new(1, 100.0, 5));
+        stock.add_buy_order(Order::new(2, 100.0, 8).with_time_in_force(TimeInForce::Ioc));
+
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(trades.iter().map(|trade| trade.quantity).sum::<usize>(), 5);
+        assert!(stock.get_buy_orders(5).is_empty());
+        assert!(stock.get_sell_orders(5).is_empty());
+    }
+
+    /// Tests that a fill-or-kill order is cancelled without trading if the book is too thin, and fills across levels
+    /// otherwise.
+    #[test]
+    fn test_fill_or_kill() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_sell_order(Order::new(1, 100.0, 5));
+        stock.add_sell_order(Order::new(1, 102.0, 5));
+        stock.add_buy_order(Order::new(2, 101.0, 8).with_time_in_force(TimeInForce::Fok));
+
+        assert!(stock.resolve(0, None, SelfTradePrevention::Skip).is_empty());
+        assert!(stock.get_buy_orders(5).is_empty());
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 5), (102.0, 5)]);
+
+        stock.add_buy_order(Order::new(2, 102.0, 8).with_time_in_force(TimeInForce::Fok));
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(trades.iter().map(|trade| trade.quantity).sum::<usize>(), 8);
+        assert_eq!(stock.get_sell_orders(5), vec![(102.0, 2)]);
+    }
+
+    /// Tests that a fill-or-kill order only counts what the orders ahead of it on its own side leave.
+    #[test]
+    fn test_fill_or_kill_behind_orders() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_sell_order(Order::new(1, 100.0, 10).with_id(1));
+        stock.add_buy_order(Order::new(2, 101.0, 5).with_id(2));
+        stock.add_buy_order(Order::new(3, 100.0, 8).with_id(3).with_time_in_force(TimeInForce::Fok));
+
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].buyer_id, 2);
+        assert!(stock.get_buy_orders(5).is_empty());
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 5)]);
+    }
+
+    /// Tests that a cap on matches never leaves an immediate-or-cancel or fill-or-kill order partly filled and resting.
+    #[test]
+    fn test_time_in_force_under_max_matches() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        for seller_id in 1..=5 {
+            stock.add_sell_order(Order::new(seller_id, 100.0, 2).with_id(seller_id as u64));
+        }
+        stock.add_buy_order(Order::new(6, 100.0, 10).with_id(6).with_time_in_force(TimeInForce::Fok));
+        assert!(stock.resolve(0, Some(2), SelfTradePrevention::Skip).is_empty());
+        assert!(stock.get_buy_orders(5).is_empty());
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 10)]);
+
+        stock.add_buy_order(Order::new(6, 100.0, 10).with_id(7).with_time_in_force(TimeInForce::Ioc));
+        assert_eq!(stock.resolve(0, Some(2), SelfTradePrevention::Skip).len(), 2);
+        assert!(stock.get_buy_orders(5).is_empty());
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 6)]);
+    }
+
+    /// Tests that only the creator's earliest or latest order on the requested side is cancelled.
+    #[test]
+    fn test_cancel_oldest_and_newest() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        let (first_id, middle_id, last_id) = (1, 3, 4);
+        stock.add_buy_order(Order::new(1, 140.0, 1).with_id(first_id));
+        stock.add_buy_order(Order::new(2, 142.0, 2).with_id(2));
+        stock.add_buy_order(Order::new(1, 141.0, 3).with_id(middle_id));
+        stock.add_buy_order(Order::new(1, 139.0, 4).with_id(last_id));
+        stock.add_buy_order(Order::new(2, 138.0, 5).with_id(5));
+
+        assert!(stock.cancel_oldest(1, Side::Sell).is_none());
+        assert_eq!(stock.cancel_oldest(1, Side::Buy).map(|order| order.get_id()), Some(first_id));
+        assert_eq!(stock.cancel_newest(1, Side::Buy).map(|order| order.get_id()), Some(last_id));
+        assert_eq!(stock.get_buy_orders(5), vec![(142.0, 2), (141.0, 3), (138.0, 5)]);
+        assert_eq!(stock.cancel_newest(1, Side::Buy).map(|order| order.get_id()), Some(middle_id));
+        assert!(stock.cancel_oldest(1, Side::Buy).is_none());
+    }
+
+    /// Tests that prices that would overflow the order's storage are recognised.
+    #[test]
+    fn test_price_representable() {
+        assert!(Order::is_price_representable(150.25));
+        assert!(!Order::is_price_representable(f64::MAX));
+        assert!(!Order::is_price_representable(f64::INFINITY));
+        assert!(!Order::is_price_representable(f64::NAN));
+        assert!(!Order::is_price_representable(-0.01));
+    }
+
+    /// Tests that prices between two ticks of precision are rounded to the nearest one rather than truncated.
+    #[test]
+    fn test_price_rounding() {
+        assert_eq!(Order::new(1, 150.999, 1).get_unadjusted_price(), 15100);
+        assert_eq!(Order::new(1, 150.994, 1).get_unadjusted_price(), 15099);
+        assert_eq!(Order::new(1, 0.29, 1).get_unadjusted_price(), 29);
+    }
+
+    /// Tests that queue depth only counts orders at exactly the given price and side.
+    #[test]
+    fn test_queue_depth() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_buy_order(Order::new(1, 99.5, 3));
+        stock.add_buy_order(Order::new(2, 99.5, 4));
+        stock.add_buy_order(Order::new(3, 99.5, 5));
+        stock.add_buy_order(Order::new(4, 99.0, 6));
+        stock.add_sell_order(Order::new(5, 101.0, 7));
+
+        assert_eq!(stock.get_queue_depth(Side::Buy, 99.5), (3, 12));
+        assert_eq!(stock.get_queue_depth(Side::Buy, 99.0), (1, 6));
+        assert_eq!(stock.get_queue_depth(Side::Sell, 99.5), (0, 0));
+    }
+
+    /// Tests that depth covers every level of each side, beyond those the order queries return.
+    #[test]
+    fn test_depth() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        assert_eq!(stock.get_depth(Side::Buy), (0, 0));
+        for price in 1..=7 {
+            stock.add_buy_order(Order::new(1, 90.0 + price as f64, price));
+        }
+        stock.add_buy_order(Order::new(2, 97.0, 10));
+        stock.add_sell_order(Order::new(3, 101.0, 4));
+        stock.add_sell_order(Order::new(3, 102.0, 5));
+
+        assert_eq!(stock.get_depth(Side::Buy), (38, 7));
+        assert_eq!(stock.get_depth(Side::Sell), (9, 2));
+    }
+
+    /// Tests that a cap on matches spreads a large crossing over several calls.
+    #[test]
+    fn test_max_matches() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        for seller_id in 1..=10 {
+            stock.add_sell_order(Order::new(seller_id, 100.0, 1));
+        }
+        stock.add_buy_order(Order::new(11, 100.0, 10));
+
+        let batches: Vec<usize> = (0..5).map(|_| stock.resolve(0, Some(3), SelfTradePrevention::Skip).len()).collect();
+        assert_eq!(batches, vec![3, 3, 3, 1, 0]);
+        assert!(stock.get_buy_orders(5).is_empty());
+        assert!(stock.get_sell_orders(5).is_empty());
+    }
+
+    /// Tests that order flow counts buy-initiated volume as positive and sell-initiated volume as negative, with trades
+    /// timed by the stock's clock.
+    #[test]
+    fn test_order_flow() {
+        use crate::types::MockClock;
+        use chrono::TimeZone;
+
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+        let clock = MockClock::new(start);
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.set_clock(Arc::new(clock.clone()));
+        stock.add_sell_order(Order::new(1, 100.0, 10));
+        stock.add_buy_order(Order::new(2, 100.0, 10));
+        assert_eq!(stock.resolve(0, None, SelfTradePrevention::Skip)[0].aggressor, Side::Buy);
+        clock.set(start + Duration::seconds(90));
+        stock.add_buy_order(Order::new(3, 99.0, 4));
+        stock.add_sell_order(Order::new(4, 99.0, 4));
+        assert_eq!(stock.resolve(0, None, SelfTradePrevention::Skip)[0].aggressor, Side::Sell);
+
+        let now = start + Duration::seconds(120);
+        assert_eq!(stock.get_order_flow(now, Duration::seconds(60)), -4);
+        assert_eq!(stock.get_order_flow(now + Duration::seconds(120), Duration::seconds(60)), 0);
+        assert_eq!(stock.get_order_flow(now, Duration::max_value()), 6);
+    }
+
+    /// Tests that a resting order below the minimum fill quantity is skipped and left in the book.
+    #[test]
+    fn test_min_fill_qty() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_sell_order(Order::new(1, 100.0, 2));
+        stock.add_sell_order(Order::new(2, 101.0, 10));
+        stock.add_buy_order(Order::new(3, 101.0, 10));
+
+        let trades = stock.resolve(5, None, SelfTradePrevention::Skip);
+        assert_eq!(trades.len(), 1);
+        assert_eq!((trades[0].seller_id, trades[0].price, trades[0].quantity), (2, 101.0, 10));
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 2)]);
+        assert!(stock.get_buy_orders(5).is_empty());
+    }
+
+    /// Tests that only orders placed before the cutoff expire.
+    #[test]
+    fn test_expire_orders() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        let old = Order::new(1, 100.0, 5);
+        let old_id = old.get_id();
+        stock.add_buy_order(old);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let cutoff = Utc::now();
+        stock.add_sell_order(Order::new(2, 110.0, 5));
+
+        let expired = stock.expire_orders(cutoff);
+        assert_eq!(expired.iter().map(|order| order.get_id()).collect::<Vec<_>>(), vec![old_id]);
+        assert!(stock.get_buy_orders(5).is_empty());
+        assert_eq!(stock.get_sell_orders(5), vec![(110.0, 5)]);
+    }
+
+    /// Tests that changes to the book move its last change time forward, and that other calls leave it alone.
+    #[test]
+    fn test_last_book_change() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        let created = stock.get_last_book_change();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        assert!(stock.cancel_oldest(1, Side::Buy).is_none());
+        stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(stock.get_last_book_change(), created);
+
+        stock.add_buy_order(Order::new(1, 100.0, 5));
+        let added = stock.get_last_book_change();
+        assert!(added > created);
+        assert!(Utc::now() - added < Utc::now() - created);
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        stock.cancel_oldest(1, Side::Buy);
+        assert!(stock.get_last_book_change() > added);
+    }
+
+    /// Tests that reducing an order keeps its queue position, and that reducing it to nothing removes it.
+    #[test]
+    fn test_reduce_order() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        let (first_id, second_id) = (1, 2);
+        stock.add_sell_order(Order::new(1, 100.0, 10).with_id(first_id));
+        stock.add_sell_order(Order::new(2, 100.0, 10).with_id(second_id));
+
+        assert_eq!(stock.reduce_order(2, first_id, 4), None);
+        assert_eq!(stock.reduce_order(1, first_id, 4), Some(6));
+        stock.add_buy_order(Order::new(3, 100.0, 6));
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!((trades[0].seller_id, trades[0].quantity), (1, 6));
+
+        assert_eq!(stock.reduce_order(2, second_id, 25), Some(0));
+        assert!(stock.get_sell_orders(5).is_empty());
+        assert_eq!(stock.reduce_order(2, second_id, 1), None);
+    }
+
+    /// Tests that only the creator's orders are cancelled, on both sides.
+    #[test]
+    fn test_cancel_user_orders() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_buy_order(Order::new(1, 140.0, 1));
+        stock.add_buy_order(Order::new(2, 141.0, 2));
+        stock.add_sell_order(Order::new(1, 150.0, 3));
+        stock.add_sell_order(Order::new(2, 151.0, 4));
+
+        let cancelled = stock.cancel_user_orders(1);
+        assert_eq!(cancelled.len(), 2);
+        assert!(cancelled.iter().all(|order| order.get_creator_id() == 1));
+        assert_eq!(stock.get_buy_orders(5), vec![(141.0, 2)]);
+        assert_eq!(stock.get_sell_orders(5), vec![(151.0, 4)]);
+    }
+
+    /// Tests that a partially filled order can be cancelled by its creator, but not by anyone else.
+    #[test]
+    fn test_cancel_order() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        let order = Order::new(1, 140.0, 10);
+        let order_id = order.get_id();
+        stock.add_buy_order(order);
+        stock.add_sell_order(Order::new(2, 140.0, 4));
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].buy_order_id, order_id);
+        assert_eq!(stock.get_buy_orders(5), vec![(140.0, 6)]);
+
+        assert!(!stock.cancel_order(order_id, 2));
+        assert_eq!(stock.get_buy_orders(5), vec![(140.0, 6)]);
+        assert!(stock.cancel_order(order_id, 1));
+        assert!(stock.get_buy_orders(5).is_empty());
+        assert!(!stock.cancel_order(order_id, 1));
+    }
+
+    /// Tests that a market buy sweeps several price levels at their own prices, and its remainder is cancelled.
+    #[test]
+    fn test_market_order_sweeps_levels() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_sell_order(Order::new(1, 100.0, 2));
+        stock.add_sell_order(Order::new(2, 101.0, 3));
+        stock.add_buy_order(Order::market(3, Side::Buy, 10));
+
+        let trades: Vec<(f64, usize)> = stock.resolve(0, None, SelfTradePrevention::Skip).iter().map(|trade| (trade.price, trade.quantity)).collect();
+        assert_eq!(trades, vec![(100.0, 2), (101.0, 3)]);
+        assert!(stock.get_buy_orders(5).is_empty());
+        assert!(stock.get_sell_orders(5).is_empty());
+
+        stock.add_sell_order(Order::new(1, 102.0, 4));
+        stock.add_sell_order(Order::market(2, Side::Sell, 1));
+        stock.add_buy_order(Order::market(3, Side::Buy, 2));
+        assert_eq!(stock.resolve(0, None, SelfTradePrevention::Skip).iter().map(|trade| trade.price).collect::<Vec<_>>(), vec![102.0]);
+        assert_eq!(stock.get_sell_orders(5), vec![(102.0, 2)]);
+    }
+
+    /// Tests that a market order against an empty book trades nothing and doesn't rest.
+    #[test]
+    fn test_market_order_empty_book() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_sell_order(Order::market(1, Side::Sell, 5));
+        assert!(stock.resolve(0, None, SelfTradePrevention::Skip).is_empty());
+        assert!(stock.get_sell_orders(5).is_empty());
+    }
+
+    /// Tests that a market order waiting to be resolved never shows up as a price level.
+    #[test]
+    fn test_market_order_hidden_from_levels() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_buy_order(Order::new(1, 99.0, 5));
+        stock.add_buy_order(Order::market(2, Side::Buy, 7));
+        stock.add_sell_order(Order::market(3, Side::Sell, 4));
+
+        assert_eq!(stock.get_buy_orders(5), vec![(99.0, 5)]);
+        assert!(stock.get_sell_orders(5).is_empty());
+        assert_eq!(stock.get_depth(Side::Buy), (5, 1));
+        assert_eq!(stock.get_depth(Side::Sell), (0, 0));
+        assert_eq!((stock.get_best_bid(), stock.get_best_ask()), (Some(99.0), None));
+        assert_eq!(stock.get_queue_depth(Side::Sell, 0.0), (0, 0));
+    }
+
+    /// Tests that a deep book can be built and swept without re-sorting on every insert.
+    #[test]
+    fn test_many_orders() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        for i in 0..10_000 {
+            let price = 100.0 + (i % 500) as f64 / 100.0;
+            stock.add_sell_order(Order::new(1, price, 1));
+            stock.add_buy_order(Order::new(2, price - 10.0, 1));
+        }
+        assert_eq!(stock.get_best_ask(), Some(100.0));
+        assert_eq!(stock.get_best_bid(), Some(94.99));
+
+        stock.add_buy_order(Order::market(3, Side::Buy, 10_000));
+        assert_eq!(stock.resolve(0, None, SelfTradePrevention::Skip).len(), 10_000);
+        assert!(stock.get_sell_orders(5).is_empty());
+    }
+
+    /// Tests that many buy orders crossing many sell levels match in price order without rescanning filled sells.
+    #[test]
+    fn test_many_crossing_levels() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        for i in 0..20_000 {
+            stock.add_sell_order(Order::new(1, 100.0 + (i % 1000) as f64 / 100.0, 1));
+        }
+        for i in 0..20_000 {
+            stock.add_buy_order(Order::new(2, 110.0 + (i % 1000) as f64 / 100.0, 1));
+        }
+
+        let trades = stock.resolve(0, None, SelfTradePrevention::Skip);
+        assert_eq!(trades.len(), 20_000);
+        assert!(trades.windows(2).all(|pair| pair[0].price <= pair[1].price));
+        assert_eq!(trades.iter().map(|trade| trade.quantity).sum::<usize>(), 20_000);
+        assert!(stock.get_buy_orders(5).is_empty());
+        assert!(stock.get_sell_orders(5).is_empty());
+    }
+
+    /// Tests that a halted stock keeps its crossing orders resting until trading resumes.
+    #[test]
+    fn test_halted_resolve() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_buy_order(Order::new(1, 100.0, 5));
+        stock.add_sell_order(Order::new(2, 100.0, 5));
+
+        stock.set_halted(true);
+        assert!(stock.resolve(0, None, SelfTradePrevention::Skip).is_empty());
+        assert_eq!(stock.get_buy_orders(5), vec![(100.0, 5)]);
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 5)]);
+
+        stock.set_halted(false);
+        assert_eq!(stock.resolve(0, None, SelfTradePrevention::Skip).len(), 1);
+        assert!(stock.get_buy_orders(5).is_empty());
+    }
+
+    /// Tests that cancelling everything empties both sides and hands back every order.
+    #[test]
+    fn test_cancel_all() {
+        let mut stock = Stock::new("Orchard de Rosa et Tulipan");
+        stock.add_buy_order(Order::new(1, 140.0, 10));
+        stock.add_sell_order(Order::new(2, 145.0, 5));
+        stock.add_sell_order(Order::new(3, 146.0, 5));
+
+        let cancelled = stock.cancel_all();
+        assert_eq!(cancelled.len(), 3);
+        assert!(stock.get_buy_orders(5).is_empty());
+        assert!(stock.get_sell_orders(5).is_empty());
+    }
+
+    /// Tests prices on and off the tick grid.
+    #[test]
+    fn test_is_valid_tick() {
+        for price in [0.0, 0.05, 1.0, 99.95, 100.1, 12345.65] {
+            assert!(is_valid_tick(price, 0.05), "{} should be on a 0.05 grid", price);
+        }
+        for price in [0.01, 0.04, 99.99, 100.12] {
+            assert!(!is_valid_tick(price, 0.05), "{} shouldn't be on a 0.05 grid", price);
+        }
+        assert!(is_valid_tick(100.12, 0.01));
+        assert!(is_valid_tick(150.0, 0.25) && !is_valid_tick(150.1, 0.25));
+        assert!(!is_valid_tick(1.0, 0.001));
+    }
+
+    /// Tests that a trade reports the aggressor's limit price alongside the price it executed at, and no limit for a
+    /// market order.
+    #[test]
+    fn test_aggressor_limit() {
+        let mut stock = Stock::new("Vulyenne");
+        stock.add_sell_order(Order::new(1, 150.0, 5));
+        stock.add_buy_order(Order::new(2, 151.0, 3));
+        let trades = stock.resolve(0, None, SelfTradePrevention::default());
+        assert_eq!((trades[0].price, trades[0].aggressor_limit), (150.0, Some(151.0)));
+
+        let json: serde_json::Value = serde_json::to_value(trades[0]).unwrap();
+        assert_eq!((&json["price"], &json["aggressor_limit"]), (&serde_json::json!(150.0), &serde_json::json!(151.0)));
+
+        stock.add_buy_order(Order::market(3, Side::Buy, 2));
+        let trades = stock.resolve(0, None, SelfTradePrevention::default());
+        assert_eq!((trades[0].price, trades[0].aggressor_limit), (150.0, None));
+        assert!(serde_json::to_value(trades[0]).unwrap().get("aggressor_limit").is_none());
+    }
+
+    /// Tests that an iceberg order only shows its display quantity in the book, refilled as it fills, while matching
+    /// against all of it.
+    #[test]
+    fn test_iceberg_order() {
+        let mut stock = Stock::new("Vulyenne");
+        stock.add_sell_order(Order::new(1, 100.0, 50).with_display_qty(Some(10)));
+        stock.add_sell_order(Order::new(2, 101.0, 5));
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 10), (101.0, 5)]);
+        assert_eq!(stock.get_depth(Side::Sell), (15, 2));
+        assert_eq!(stock.get_queue_depth(Side::Sell, 100.0), (1, 10));
+
+        stock.add_buy_order(Order::new(3, 100.0, 45));
+        let trades = stock.resolve(0, None, SelfTradePrevention::default());
+        assert_eq!(trades.iter().map(|trade| trade.quantity).sum::<usize>(), 45);
+        assert_eq!(stock.get_sell_orders(5), vec![(100.0, 5), (101.0, 5)]);
+
+        stock.add_buy_order(Order::new(3, 100.0, 5));
+        stock.resolve(0, None, SelfTradePrevention::default());
+        assert_eq!(stock.get_sell_orders(5), vec![(101.0, 5)]);
     }
 }
@@ -1,4 +1,162 @@
+use super::{Balances, PendingSettlement, PendingSnapshot, Rejection, RejectionLog, Symbol, UserSnapshot};
+use chrono::{DateTime, Utc};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+/// A participant in the market.
+///
+/// Users are keyed by their connection ID in the market.
 pub struct User {
-    id: usize,
-    name: String
+    /// Counts of the user's order activity.
+    stats: OrderStats,
+    /// When the user last posted an order, if ever.
+    last_order_time: Option<DateTime<Utc>>,
+    /// The user's cash and holdings, settled and pending.
+    balances: Balances,
+    /// The user's most recently refused orders.
+    rejections: RejectionLog,
+}
+
+impl User {
+    /// Creates a user with no activity.
+    pub fn new() -> Self {
+        Self {
+            stats: OrderStats::default(),
+            last_order_time: None,
+            balances: Balances::default(),
+            rejections: RejectionLog::default(),
+        }
+    }
+
+    /// Creates a user with no activity and the given balances.
+    pub fn with_balances(balances: Balances) -> Self {
+        Self { balances, ..Self::new() }
+    }
+
+    /// Recreates a user from a snapshot taken with `to_snapshot`, on what is now tick `tick`.
+    ///
+    /// Holdings of symbols that are no longer valid are dropped.
+    pub fn from_snapshot(snapshot: UserSnapshot, tick: u64) -> Self {
+        let mut balances = Balances::default();
+        balances.set_available_cash_cents(snapshot.cash_cents);
+        for (symbol, shares) in snapshot.holdings {
+            if let Ok(symbol) = Symbol::try_from(symbol.as_str()) {
+                balances.set_available_shares(symbol, shares);
+            }
+        }
+        for pending in snapshot.pending {
+            if let Ok(symbol) = Symbol::try_from(pending.symbol.as_str()) {
+                balances.add_pending(PendingSettlement {
+                    settles_at: tick + pending.ticks_left,
+                    symbol,
+                    cash_cents: pending.cash_cents,
+                    shares: pending.shares,
+                });
+            }
+        }
+
+        Self {
+            stats: OrderStats { placed: snapshot.placed, cancelled: snapshot.cancelled, traded: snapshot.traded },
+            ..Self::with_balances(balances)
+        }
+    }
+
+    /// Returns a snapshot of the user's order counts and balances, taken on tick `tick`.
+    ///
+    /// Rejections and when the user last posted an order are not kept.
+    pub fn to_snapshot(&self, id: usize, tick: u64) -> UserSnapshot {
+        let mut holdings: Vec<_> = self.balances.get_available_holdings().iter().map(|(symbol, shares)| (symbol.to_string(), *shares)).collect();
+        holdings.sort();
+        UserSnapshot {
+            id,
+            placed: self.stats.placed,
+            cancelled: self.stats.cancelled,
+            traded: self.stats.traded,
+            cash_cents: self.balances.get_available_cash_cents(),
+            holdings,
+            pending: self.balances.get_pending().iter().map(|pending| PendingSnapshot {
+                ticks_left: pending.settles_at.saturating_sub(tick),
+                symbol: pending.symbol.to_string(),
+                cash_cents: pending.cash_cents,
+                shares: pending.shares,
+            }).collect(),
+        }
+    }
+
+    /// Returns counts of the user's order activity.
+    pub fn get_stats(&self) -> OrderStats {
+        self.stats
+    }
+
+    /// Returns when the user last posted an order, if ever.
+    pub fn get_last_order_time(&self) -> Option<DateTime<Utc>> {
+        self.last_order_time
+    }
+
+    /// Returns the user's cash and holdings, settled and pending.
+    pub fn get_balances(&self) -> &Balances {
+        &self.balances
+    }
+
+    /// Returns the user's cash and holdings for updating.
+    pub fn get_balances_mut(&mut self) -> &mut Balances {
+        &mut self.balances
+    }
+
+    /// Records that the user posted an order at the given time.
+    pub fn record_order_placed(&mut self, time: DateTime<Utc>) {
+        self.stats.placed += 1;
+        self.last_order_time = Some(time);
+    }
+
+    /// Records that one of the user's orders was refused.
+    pub fn record_rejection(&mut self, rejection: Rejection) {
+        self.rejections.push(rejection);
+    }
+
+    /// Returns up to `limit` of the user's most recent rejections, oldest first.
+    pub fn get_rejections(&self, limit: usize) -> Vec<Rejection> {
+        self.rejections.recent(limit)
+    }
+
+    /// Records that the user cancelled an order.
+    pub fn record_order_cancelled(&mut self) {
+        self.stats.cancelled += 1;
+    }
+
+    /// Records that one of the user's orders traded.
+    pub fn record_trade(&mut self) {
+        self.stats.traded += 1;
+    }
+}
+
+/// Counts of a user's order activity, used to spot quote stuffing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OrderStats {
+    /// Orders posted.
+    pub placed: usize,
+    /// Orders cancelled by the user.
+    pub cancelled: usize,
+    /// Trades the user's orders took part in.
+    pub traded: usize,
+}
+
+impl OrderStats {
+    /// Returns the number of orders posted per trade.
+    ///
+    /// A user that has never traded is treated as having traded once, so the ratio is the number of orders posted.
+    pub fn order_to_trade_ratio(&self) -> f64 {
+        self.placed as f64 / self.traded.max(1) as f64
+    }
+}
+
+impl Serialize for OrderStats {
+    /// Serializes the counts along with the order-to-trade ratio, rounded to two decimals.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut stats = serializer.serialize_struct("OrderStats", 4)?;
+        stats.serialize_field("placed", &self.placed)?;
+        stats.serialize_field("cancelled", &self.cancelled)?;
+        stats.serialize_field("traded", &self.traded)?;
+        stats.serialize_field("order_to_trade_ratio", &((self.order_to_trade_ratio() * 100.0).round() / 100.0))?;
+        stats.end()
+    }
 }